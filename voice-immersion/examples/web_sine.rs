@@ -0,0 +1,69 @@
+//! Sine circling the listener in a web page, played through Web Audio. From this directory:
+//!
+//! ```sh
+//! cargo build --example web_sine --target wasm32-unknown-unknown --features wasm
+//! wasm-bindgen --target web --out-dir examples/web \
+//!     ../target/wasm32-unknown-unknown/debug/examples/web_sine.wasm
+//! ```
+//!
+//! then serve `examples/web` and open its `index.html`.
+
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+
+    use fundsp::hacker32::*;
+    use nalgebra::Vector3;
+    use voice_immersion::{EngineBuilder, InputSource, SourceInfo};
+    use wasm_bindgen::closure::Closure;
+    use wasm_bindgen::JsCast;
+
+    /// Milliseconds between two control passes.
+    const CONTROL_PERIOD: i32 = 20;
+
+    let window = web_sys::window().expect("No window.");
+    let button = window
+        .document()
+        .and_then(|document| document.get_element_by_id("start"))
+        .expect("No start button.");
+    // Browsers only let audio start from a user gesture.
+    let start = Closure::once(move || {
+        let source = Arc::new(RwLock::new(SourceInfo::default()));
+        let handle = EngineBuilder::new()
+            .input(InputSource::Wave(Wave::render(
+                44100.0,
+                1.0,
+                &mut (sine_hz(440.0) * 0.2),
+            )))
+            .source(source.clone())
+            .build()
+            .expect("Could not start the engine.");
+        let mut angle = 0.0f32;
+        let step = Closure::<dyn FnMut()>::new(move || {
+            angle += CONTROL_PERIOD as f32 / 1000.0;
+            if let Ok(mut source) = source.write() {
+                source.relative_position = Vector3::new(angle.cos(), 0.0, angle.sin()) * 2.0;
+            }
+            let _ = handle.step_control(Duration::from_millis(CONTROL_PERIOD as u64));
+        });
+        web_sys::window()
+            .expect("No window.")
+            .set_interval_with_callback_and_timeout_and_arguments_0(
+                step.as_ref().unchecked_ref(),
+                CONTROL_PERIOD,
+            )
+            .expect("Could not schedule the control passes.");
+        // Runs for as long as the page.
+        step.forget();
+    });
+    button
+        .add_event_listener_with_callback("click", start.as_ref().unchecked_ref())
+        .expect("Could not listen to the start button.");
+    start.forget();
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    eprintln!("This example runs in browsers, see how to build it at the top of web_sine.rs.");
+}
@@ -0,0 +1,52 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use assert_no_alloc::{permit_alloc, violation_count, AllocDisabler};
+
+/// Allocator only warning on allocations in `assert_no_alloc` scopes, the first one with a
+/// backtrace, so a regression in the audio thread does not abort a debugging session.
+pub struct WarningAllocator;
+
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+impl WarningAllocator {
+    fn report(&self, violations: u32) {
+        if violation_count() > violations && !REPORTED.swap(true, Ordering::Relaxed) {
+            permit_alloc(|| {
+                eprintln!(
+                    "Allocation in the audio thread, further ones are only counted:\n{}",
+                    std::backtrace::Backtrace::force_capture()
+                )
+            });
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for WarningAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let violations = violation_count();
+        let ptr = AllocDisabler.alloc(layout);
+        self.report(violations);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let violations = violation_count();
+        AllocDisabler.dealloc(ptr, layout);
+        self.report(violations);
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let violations = violation_count();
+        let ptr = AllocDisabler.alloc_zeroed(layout);
+        self.report(violations);
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let violations = violation_count();
+        let ptr = AllocDisabler.realloc(ptr, layout, new_size);
+        self.report(violations);
+        ptr
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use fundsp::fft::real_fft;
+use num_complex::Complex32;
+
+/// Seconds of output the analysis queue holds, samples past it being dropped when the
+/// worker falls behind.
+const SPECTRUM_QUEUE: f64 = 0.5;
+
+/// Window sizes `EngineConfig::spectrum_window` is kept within.
+const WINDOW_RANGE: (usize, usize) = (64, 16384);
+
+/// How often the worker checks whether the analyzer was dropped.
+const SPECTRUM_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Audio thread side of the analyzer: samples are queued without allocating, the FFT
+/// being left to the worker.
+pub(crate) struct SpectrumTap {
+    samples: Sender<f32>,
+}
+
+impl SpectrumTap {
+    #[inline]
+    pub(crate) fn push(&self, sample: f32) {
+        // A full queue drops the sample rather than blocking the audio thread.
+        let _ = self.samples.try_send(sample);
+    }
+}
+
+/// Magnitude spectrum of the output of the first listener over a sliding window, updated
+/// by a worker thread every quarter of it, see `SpatialHandle::spectrum`.
+pub(crate) struct SpectrumAnalyzer {
+    tap: Arc<SpectrumTap>,
+    spectrum: Arc<Mutex<Vec<f32>>>,
+    running: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl SpectrumAnalyzer {
+    /// Analyzer over the last `window` samples, rounded up to a power of two.
+    pub(crate) fn new(sample_rate: f64, window: usize) -> Self {
+        let window = window
+            .clamp(WINDOW_RANGE.0, WINDOW_RANGE.1)
+            .next_power_of_two();
+        let (sender, samples) = bounded((sample_rate * SPECTRUM_QUEUE) as usize);
+        let spectrum = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let worker = {
+            let (spectrum, running) = (spectrum.clone(), running.clone());
+            std::thread::spawn(move || analyze(samples, window, &spectrum, &running))
+        };
+        SpectrumAnalyzer {
+            tap: Arc::new(SpectrumTap { samples: sender }),
+            spectrum,
+            running,
+            worker: Some(worker),
+        }
+    }
+
+    pub(crate) fn tap(&self) -> Arc<SpectrumTap> {
+        self.tap.clone()
+    }
+
+    /// Latest spectrum, empty until the first window filled.
+    pub(crate) fn spectrum(&self) -> Vec<f32> {
+        self.spectrum
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl Drop for SpectrumAnalyzer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Worker loop: the Hann windowed FFT of the last `window` samples every quarter window,
+/// scaled so that a full scale sine peaks at 1 in its bin.
+fn analyze(
+    samples: Receiver<f32>,
+    window: usize,
+    spectrum: &Mutex<Vec<f32>>,
+    running: &AtomicBool,
+) {
+    let hop = window / 4;
+    let hann: Vec<f32> = (0..window)
+        .map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / window as f32).cos())
+        .collect();
+    let scale = 2.0 / hann.iter().sum::<f32>();
+    let mut history = vec![0.0; window];
+    let mut input = vec![0.0; window];
+    let mut bins = vec![Complex32::default(); window / 2 + 1];
+    let (mut position, mut filled, mut fresh) = (0, 0, 0);
+    while running.load(Ordering::Relaxed) {
+        match samples.recv_timeout(SPECTRUM_POLL_INTERVAL) {
+            Ok(sample) => {
+                history[position] = sample;
+                position = (position + 1) % window;
+                filled = Ord::min(filled + 1, window);
+                fresh += 1;
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if filled < window || fresh < hop {
+            continue;
+        }
+        fresh = 0;
+        for (i, x) in input.iter_mut().enumerate() {
+            *x = history[(position + i) % window] * hann[i];
+        }
+        real_fft(&input, &mut bins);
+        let mut spectrum = spectrum.lock().unwrap_or_else(PoisonError::into_inner);
+        spectrum.clear();
+        spectrum.extend(bins.iter().map(|bin| bin.norm() * scale));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tones_peak_in_their_bin() {
+        let analyzer = SpectrumAnalyzer::new(48000.0, 1000);
+        let tap = analyzer.tap();
+        assert!(analyzer.spectrum().is_empty());
+        // 1500 Hz, bin 32 of a 1024 sample window at 48 kHz.
+        for i in 0..2048 {
+            tap.push(0.5 * (std::f32::consts::TAU * 1500.0 * i as f32 / 48000.0).sin());
+        }
+        let mut spectrum = analyzer.spectrum();
+        for _ in 0..100 {
+            if !spectrum.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            spectrum = analyzer.spectrum();
+        }
+        assert_eq!(spectrum.len(), 513);
+        let peak = (0..spectrum.len())
+            .max_by(|&a, &b| spectrum[a].total_cmp(&spectrum[b]))
+            .unwrap();
+        assert_eq!(peak, 32);
+        assert!((spectrum[32] - 0.5).abs() < 0.01, "{}", spectrum[32]);
+        assert!(spectrum[100] < 1e-3);
+    }
+}
@@ -0,0 +1,415 @@
+/// Gain of a source against its distance to the listener, in meters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttenuationModel {
+    /// `1 / (1 + rolloff * (d / reference_distance)^2)`: with a unit rolloff, halves the
+    /// amplitude at `reference_distance`.
+    InverseSquare {
+        reference_distance: f32,
+        rolloff: f32,
+    },
+    /// Full gain up to `min_distance`, then `min / (min + rolloff * (d - min))`,
+    /// held constant past `max_distance`.
+    Inverse {
+        min_distance: f32,
+        max_distance: f32,
+        rolloff: f32,
+    },
+    /// Full gain up to `min_distance`, fading linearly to silence at `max_distance`.
+    Linear {
+        min_distance: f32,
+        max_distance: f32,
+    },
+    /// Near field and far field as straight lines in dB against octaves of distance, meeting
+    /// at 0 dB at `crossover` meters: `near_slope` dB per doubling closer, e.g. -3 for a
+    /// boost of 3 dB per halving or 0 for full gain, and `far_slope` dB per doubling past
+    /// it, -6 for the inverse-square law. The corner is rounded over `blend` octaves
+    /// centered on the crossover, the slope gliding from one to the other so that the gain
+    /// and its derivative stay continuous, with no kink to hear when moving through it.
+    /// Held closer than `PIECEWISE_FLOOR` octaves below the crossover.
+    Piecewise {
+        crossover: f32,
+        near_slope: f32,
+        far_slope: f32,
+        blend: f32,
+    },
+    /// No distance attenuation.
+    None,
+}
+
+/// Octaves below the crossover of `AttenuationModel::Piecewise` past which the near field
+/// holds its gain, so that a zero distance stays finite.
+pub const PIECEWISE_FLOOR: f32 = 4.0;
+
+impl Default for AttenuationModel {
+    fn default() -> Self {
+        AttenuationModel::InverseSquare {
+            reference_distance: 10.0,
+            rolloff: 1.0,
+        }
+    }
+}
+
+impl AttenuationModel {
+    /// Unity's logarithmic rolloff with its default 1 m min and 500 m max distances.
+    pub fn unity_default() -> Self {
+        AttenuationModel::Inverse {
+            min_distance: 1.0,
+            max_distance: 500.0,
+            rolloff: 1.0,
+        }
+    }
+
+    /// Unreal's default linear attenuation: 400 uu inner radius and 3600 uu falloff distance.
+    pub fn unreal_default() -> Self {
+        AttenuationModel::Linear {
+            min_distance: 4.0,
+            max_distance: 40.0,
+        }
+    }
+
+    /// FMOD's default inverse rolloff with 1 m min and 10000 m max distances.
+    pub fn fmod_default() -> Self {
+        AttenuationModel::Inverse {
+            min_distance: 1.0,
+            max_distance: 10000.0,
+            rolloff: 1.0,
+        }
+    }
+
+    /// Near-field boost of 3 dB per halving blending over an octave into the inverse-square
+    /// law past `crossover` meters, see `AttenuationModel::Piecewise`.
+    pub fn near_far(crossover: f32) -> Self {
+        AttenuationModel::Piecewise {
+            crossover,
+            near_slope: -3.0,
+            far_slope: -6.0,
+            blend: 1.0,
+        }
+    }
+
+    pub fn gain(&self, distance: f32) -> f32 {
+        match *self {
+            AttenuationModel::InverseSquare {
+                reference_distance,
+                rolloff,
+            } => 1.0 / (1.0 + rolloff * (distance / reference_distance).powi(2)),
+            AttenuationModel::Inverse {
+                min_distance,
+                max_distance,
+                rolloff,
+            } => {
+                let distance = distance.clamp(min_distance, max_distance);
+                min_distance / (min_distance + rolloff * (distance - min_distance))
+            }
+            AttenuationModel::Linear {
+                min_distance,
+                max_distance,
+            } => {
+                if distance <= min_distance {
+                    1.0
+                } else {
+                    (1.0 - (distance - min_distance) / (max_distance - min_distance)).max(0.0)
+                }
+            }
+            AttenuationModel::Piecewise {
+                crossover,
+                near_slope,
+                far_slope,
+                blend,
+            } => {
+                let crossover = crossover.max(f32::EPSILON);
+                let octaves = (distance / crossover)
+                    .max(PIECEWISE_FLOOR.exp2().recip())
+                    .log2();
+                let db = piecewise_db(octaves, near_slope, far_slope, blend);
+                10f32.powf(db / 20.0)
+            }
+            AttenuationModel::None => 1.0,
+        }
+    }
+}
+
+/// Gain in dB `octaves` of distance from the crossover of `AttenuationModel::Piecewise`: the
+/// two lines, joined within half a blend of the crossover by the parabola tangent to both.
+fn piecewise_db(octaves: f32, near_slope: f32, far_slope: f32, blend: f32) -> f32 {
+    let half = blend.max(0.0) / 2.0;
+    if octaves <= -half {
+        near_slope * octaves
+    } else if octaves >= half {
+        far_slope * octaves
+    } else {
+        near_slope * octaves + (far_slope - near_slope) * (octaves + half).powi(2) / (4.0 * half)
+    }
+}
+
+/// Level and bass boost of sources close to the head, on top of the attenuation model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NearField {
+    /// Distance in meters under which the boost starts, as `distance / d`.
+    pub distance: f32,
+    /// Largest gain, reached at `distance / max_gain` and held closer.
+    pub max_gain: f32,
+    /// Low shelf boost in dB at the largest gain, scaling with the level boost in dB.
+    pub bass_boost_db: f32,
+}
+
+impl Default for NearField {
+    fn default() -> Self {
+        NearField {
+            distance: 0.5,
+            max_gain: 2.0,
+            bass_boost_db: 6.0,
+        }
+    }
+}
+
+impl NearField {
+    pub fn gain(&self, distance: f32) -> f32 {
+        // Clamped before dividing so a zero distance stays finite.
+        let distance = distance.max(self.distance / self.max_gain.max(1.0));
+        (self.distance / distance).clamp(1.0, self.max_gain.max(1.0))
+    }
+
+    pub fn bass_boost_db(&self, distance: f32) -> f32 {
+        if self.max_gain <= 1.0 {
+            return 0.0;
+        }
+        self.bass_boost_db * self.gain(distance).ln() / self.max_gain.ln()
+    }
+}
+
+/// Frequency dependent absorption of the air after ISO 9613-1, at sea level pressure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AirModel {
+    /// Relative humidity in percent. Dry air absorbs the highs the most.
+    pub humidity: f32,
+    /// Air temperature in °C.
+    pub temperature: f32,
+}
+
+impl Default for AirModel {
+    fn default() -> Self {
+        AirModel {
+            humidity: 50.0,
+            temperature: 20.0,
+        }
+    }
+}
+
+impl AirModel {
+    /// Absorption in dB per meter at `frequency` Hz.
+    pub fn absorption_db(&self, frequency: f64) -> f32 {
+        // Reference temperature and triple point of water, in kelvins.
+        const T0: f64 = 293.15;
+        const T01: f64 = 273.16;
+        let t = self.temperature as f64 + 273.15;
+        let saturation = 10f64.powf(-6.8346 * (T01 / t).powf(1.261) + 4.6151);
+        // Molar concentration of water vapour in percent.
+        let h = self.humidity.max(0.0) as f64 * saturation;
+        // Relaxation frequencies of oxygen and nitrogen.
+        let oxygen = 24.0 + 4.04e4 * h * (0.02 + h) / (0.391 + h);
+        let nitrogen = (t / T0).powf(-0.5)
+            * (9.0 + 280.0 * h * (-4.170 * ((t / T0).powf(-1.0 / 3.0) - 1.0)).exp());
+        let f2 = frequency * frequency;
+        let alpha = 8.686
+            * f2
+            * (1.84e-11 * (t / T0).sqrt()
+                + (t / T0).powf(-2.5)
+                    * (0.01275 * (-2239.1 / t).exp() / (oxygen + f2 / oxygen)
+                        + 0.1068 * (-3352.0 / t).exp() / (nitrogen + f2 / nitrogen)));
+        alpha as f32
+    }
+}
+
+/// Directivity of a source radiating mostly forward, such as a voice or a speaker: full gain
+/// within the inner cone, fading linearly to `outer_gain` at the edge of the outer cone and
+/// held behind it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Cone {
+    /// Full apex angle in radians of the cone at full gain.
+    pub inner_angle: f32,
+    /// Full apex angle in radians beyond which the gain is `outer_gain`.
+    pub outer_angle: f32,
+    pub outer_gain: f32,
+}
+
+impl Default for Cone {
+    fn default() -> Self {
+        Cone {
+            inner_angle: std::f32::consts::FRAC_PI_2,
+            outer_angle: std::f32::consts::PI * 1.5,
+            outer_gain: 0.25,
+        }
+    }
+}
+
+impl Cone {
+    /// Gain of a listener `angle` radians off the axis the source faces.
+    pub fn gain(&self, angle: f32) -> f32 {
+        let (inner, outer) = (self.inner_angle / 2.0, self.outer_angle / 2.0);
+        if angle <= inner {
+            1.0
+        } else if angle >= outer {
+            self.outer_gain
+        } else {
+            let t = (angle - inner) / (outer - inner);
+            1.0 + (self.outer_gain - 1.0) * t
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_gain(model: AttenuationModel, distance: f32, expected: f32) {
+        let gain = model.gain(distance);
+        assert!(
+            (gain - expected).abs() < 1e-6,
+            "{model:?} at {distance} m: {gain} instead of {expected}"
+        );
+    }
+
+    #[test]
+    fn unity_preset() {
+        let unity = AttenuationModel::unity_default();
+        assert_gain(unity, 0.5, 1.0);
+        assert_gain(unity, 2.0, 0.5);
+        assert_gain(unity, 10.0, 0.1);
+        assert_gain(unity, 1000.0, 1.0 / 500.0);
+    }
+
+    #[test]
+    fn unreal_preset() {
+        let unreal = AttenuationModel::unreal_default();
+        assert_gain(unreal, 4.0, 1.0);
+        assert_gain(unreal, 22.0, 0.5);
+        assert_gain(unreal, 40.0, 0.0);
+        assert_gain(unreal, 100.0, 0.0);
+    }
+
+    #[test]
+    fn fmod_preset() {
+        let fmod = AttenuationModel::fmod_default();
+        assert_gain(fmod, 1.0, 1.0);
+        assert_gain(fmod, 4.0, 0.25);
+        assert_gain(fmod, 20000.0, 1.0 / 10000.0);
+    }
+
+    #[test]
+    fn default_halves_at_reference_distance() {
+        assert_gain(AttenuationModel::default(), 10.0, 0.5);
+        assert_gain(AttenuationModel::None, 1000.0, 1.0);
+    }
+
+    #[test]
+    fn inverse_square_rolloff() {
+        let steep = AttenuationModel::InverseSquare {
+            reference_distance: 2.0,
+            rolloff: 3.0,
+        };
+        assert_gain(steep, 0.0, 1.0);
+        assert_gain(steep, 2.0, 0.25);
+        assert_gain(steep, 6.0, 1.0 / 28.0);
+    }
+
+    #[test]
+    fn air_absorption_matches_iso_9613() {
+        // ISO 9613-1 table values at 20 °C and 50% humidity, in dB per km.
+        let air = AirModel::default();
+        for (frequency, expected) in [
+            (1000.0, 4.7),
+            (2000.0, 9.9),
+            (4000.0, 29.7),
+            (8000.0, 105.0),
+        ] {
+            let absorption = air.absorption_db(frequency) * 1000.0;
+            assert!(
+                (absorption / expected - 1.0).abs() < 0.05,
+                "{frequency} Hz: {absorption} dB/km"
+            );
+        }
+        let dry = AirModel {
+            humidity: 10.0,
+            ..air
+        };
+        assert!(dry.absorption_db(4000.0) > air.absorption_db(4000.0));
+    }
+
+    #[test]
+    fn cone_fades_between_its_angles() {
+        let cone = Cone {
+            inner_angle: 1.0,
+            outer_angle: 3.0,
+            outer_gain: 0.2,
+        };
+        assert_eq!(cone.gain(0.0), 1.0);
+        assert_eq!(cone.gain(0.5), 1.0);
+        assert!((cone.gain(1.0) - 0.6).abs() < 1e-6);
+        assert_eq!(cone.gain(1.5), 0.2);
+        assert_eq!(cone.gain(std::f32::consts::PI), 0.2);
+    }
+
+    #[test]
+    fn near_field_boost_is_bounded() {
+        let near_field = NearField::default();
+        assert_eq!(near_field.gain(1.0), 1.0);
+        assert_eq!(near_field.bass_boost_db(1.0), 0.0);
+        assert!((near_field.gain(0.4) - 1.25).abs() < 1e-6);
+        assert_eq!(near_field.gain(0.1), 2.0);
+        assert_eq!(near_field.gain(0.0), 2.0);
+        assert!((near_field.bass_boost_db(0.0) - 6.0).abs() < 1e-6);
+        assert!((near_field.bass_boost_db(0.5 / 2f32.sqrt()) - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn piecewise_joins_smoothly_at_the_crossover() {
+        let model = AttenuationModel::near_far(2.0);
+        // The lines away from the blend: +3 dB at 1/4 of the crossover, -6 dB at twice it.
+        assert!((model.gain(4.0) - 10f32.powf(-6.0 / 20.0)).abs() < 1e-5);
+        assert!((model.gain(8.0) - 10f32.powf(-12.0 / 20.0)).abs() < 1e-5);
+        assert!((model.gain(1.0 / 2.0) - 10f32.powf(6.0 / 20.0)).abs() < 1e-5);
+        assert!(model.gain(0.0).is_finite());
+        assert_eq!(model.gain(0.0), model.gain(2.0 / 16.0));
+        // No jump nor kink: the slope in dB per octave glides from -3 to -6 across the
+        // blend, without any step from one sample of the curve to the next.
+        let db = |octaves: f32| 20.0 * model.gain(2.0 * octaves.exp2()).log10();
+        let step = 1e-3;
+        let mut previous = (db(-1.0 + step) - db(-1.0)) / step;
+        assert!((previous + 3.0).abs() < 0.01, "{previous}");
+        let mut octaves = -1.0;
+        while octaves < 1.0 {
+            let slope = (db(octaves + step) - db(octaves)) / step;
+            assert!(
+                (slope - previous).abs() < 0.01,
+                "{slope} after {previous} at {octaves}"
+            );
+            assert!((-6.01..=-2.99).contains(&slope), "{slope}");
+            previous = slope;
+            octaves += step;
+        }
+        assert!((previous + 6.0).abs() < 0.01, "{previous}");
+        // Without a blend the lines meet at a corner, at 0 dB.
+        let sharp = AttenuationModel::Piecewise {
+            crossover: 2.0,
+            near_slope: 0.0,
+            far_slope: -6.0,
+            blend: 0.0,
+        };
+        assert_gain(sharp, 1.0, 1.0);
+        assert_gain(sharp, 2.0, 1.0);
+    }
+
+    #[test]
+    fn linear_cuts_off() {
+        let linear = AttenuationModel::Linear {
+            min_distance: 0.0,
+            max_distance: 10.0,
+        };
+        assert_gain(linear, 0.0, 1.0);
+        assert_gain(linear, 2.5, 0.75);
+        assert_gain(linear, 10.0, 0.0);
+        assert_gain(linear, 50.0, 0.0);
+    }
+}
@@ -0,0 +1,322 @@
+use std::any::Any;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use cpal::traits::HostTrait;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, SizedSample};
+#[cfg(not(target_arch = "wasm32"))]
+use crossbeam_channel::{bounded, never, Receiver, RecvTimeoutError};
+use crossbeam_channel::{Sender, TrySendError};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::clock::Instant;
+use crate::config::{ChannelMap, StreamRecovery};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::sink::{first_reopen, OwnerGuard};
+use crate::sink::{with_sample_type, SampleTypeVisitor};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::telemetry::EngineEvent;
+use crate::telemetry::Telemetry;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::VoiceImmersionError;
+
+/// Time between two checks of the capture stream for errors.
+#[cfg(not(target_arch = "wasm32"))]
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Most channels of a capture device read, the others being ignored.
+const MAX_INPUT_CHANNELS: usize = 32;
+
+/// Capture `device` into `sender`, its channels mapped by `map`, until the stream is
+/// dropped. `on_error` gets the stream errors, and `closed` is raised once the receiving
+/// end is gone.
+pub(crate) fn build_capture<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    sender: Sender<(f32, f32)>,
+    map: ChannelMap,
+    on_error: impl FnMut(cpal::StreamError) + Send + 'static,
+    closed: Arc<AtomicBool>,
+) -> crate::Result<cpal::Stream>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            if !read_data(data, channels, map, &sender) {
+                closed.store(true, Ordering::Relaxed);
+            }
+        },
+        on_error,
+        None,
+    )?;
+    stream.play()?;
+    Ok(stream)
+}
+
+/// Send the frames of `input` mapped to stereo by `map`, returns whether the receiving end
+/// is still there. Frames the receiver has no room for are dropped.
+pub(crate) fn read_data<T>(
+    input: &[T],
+    channels: usize,
+    map: ChannelMap,
+    sender: &Sender<(f32, f32)>,
+) -> bool
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let mut samples = [0.0; MAX_INPUT_CHANNELS];
+    for frame in input.chunks(channels) {
+        let frame = &frame[..Ord::min(frame.len(), MAX_INPUT_CHANNELS)];
+        for (sample, input) in samples.iter_mut().zip(frame) {
+            *sample = input.to_sample::<f32>();
+        }
+        if let Err(TrySendError::Disconnected(_)) =
+            sender.try_send(map.stereo(&samples[..frame.len()]))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Capture of a mic device, flagging its errors for the owner thread.
+struct MicStream {
+    sender: Sender<(f32, f32)>,
+    map: ChannelMap,
+    failed: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+}
+
+impl MicStream {
+    /// Start capturing on `device` with its default input configuration.
+    fn open(&self, device: &cpal::Device) -> crate::Result<cpal::Stream> {
+        struct Open<'a>(&'a MicStream, &'a cpal::Device, cpal::StreamConfig);
+        impl SampleTypeVisitor for Open<'_> {
+            type Output = cpal::Stream;
+
+            fn visit<T>(self) -> crate::Result<cpal::Stream>
+            where
+                T: SizedSample + FromSample<f32> + Send + 'static,
+                f32: FromSample<T>,
+            {
+                let Open(capture, device, config) = self;
+                let failed = capture.failed.clone();
+                build_capture::<T>(
+                    device,
+                    &config,
+                    capture.sender.clone(),
+                    capture.map,
+                    move |err| {
+                        eprintln!("an error occurred on stream: {}", err);
+                        failed.store(true, Ordering::Relaxed);
+                    },
+                    capture.closed.clone(),
+                )
+            }
+        }
+        let config = device.default_input_config()?;
+        with_sample_type(config.sample_format(), Open(self, device, config.into()))
+    }
+}
+
+/// Capture `device` into `sender` on a thread owning the stream: after an error it is
+/// reopened on the default input device of `host` as `recovery` tells, the outcome reported
+/// to `telemetry`. The capture stops once the returned guard or the receiving end is
+/// dropped.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn spawn_capture(
+    device: cpal::Device,
+    host: cpal::HostId,
+    sender: Sender<(f32, f32)>,
+    map: ChannelMap,
+    recovery: StreamRecovery,
+    telemetry: Option<Arc<Telemetry>>,
+) -> crate::Result<Box<dyn Any>> {
+    let (stop, stopped) = bounded::<()>(0);
+    run_capture(device, host, sender, map, recovery, telemetry, stopped)?;
+    Ok(Box::new(OwnerGuard::new(stop)))
+}
+
+/// `spawn_capture` stopping only once the receiving end is dropped.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn spawn_detached_capture(
+    device: cpal::Device,
+    host: cpal::HostId,
+    sender: Sender<(f32, f32)>,
+    map: ChannelMap,
+    recovery: StreamRecovery,
+) -> crate::Result<()> {
+    run_capture(device, host, sender, map, recovery, None, never())
+}
+
+/// Thread of `spawn_capture`, stopped by a message on `stopped` or its disconnection.
+#[cfg(not(target_arch = "wasm32"))]
+fn run_capture(
+    device: cpal::Device,
+    host: cpal::HostId,
+    sender: Sender<(f32, f32)>,
+    map: ChannelMap,
+    recovery: StreamRecovery,
+    telemetry: Option<Arc<Telemetry>>,
+    stopped: Receiver<()>,
+) -> crate::Result<()> {
+    let host = cpal::host_from_id(host)?;
+    let (ready_sender, ready) = bounded(1);
+    let report = move |event| {
+        if let Some(telemetry) = &telemetry {
+            telemetry.emit(event);
+        }
+    };
+    std::thread::spawn(move || {
+        let capture = MicStream {
+            sender,
+            map,
+            failed: Arc::new(AtomicBool::new(false)),
+            closed: Arc::new(AtomicBool::new(false)),
+        };
+        let mut stream = match capture.open(&device) {
+            Ok(stream) => stream,
+            Err(err) => {
+                let _ = ready_sender.send(Err(err));
+                return;
+            }
+        };
+        let _ = ready_sender.send(Ok(()));
+        // Failed attempts to reopen the stream and time of the next one.
+        let mut reopen: Option<(u32, Instant)> = None;
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(POLL_INTERVAL) {
+            if capture.closed.load(Ordering::Relaxed) {
+                break;
+            }
+            if capture.failed.swap(false, Ordering::Relaxed) && reopen.is_none() {
+                reopen = first_reopen(&recovery, Instant::now());
+                if reopen.is_none() {
+                    report(EngineEvent::MicFailed {
+                        error: "The mic stream failed.".into(),
+                    });
+                }
+            }
+            let Some((attempts, at)) = reopen else {
+                continue;
+            };
+            if Instant::now() < at {
+                continue;
+            }
+            let reopened = host
+                .default_input_device()
+                .ok_or_else(|| {
+                    VoiceImmersionError::DeviceUnavailable("No input device available.".into())
+                })
+                .and_then(|device| Ok((capture.open(&device)?, device)));
+            match reopened {
+                Ok((new_stream, device)) => {
+                    drop(std::mem::replace(&mut stream, new_stream));
+                    reopen = None;
+                    report(EngineEvent::MicSwitched {
+                        name: device.name().unwrap_or_default(),
+                    });
+                }
+                Err(err) if attempts + 1 >= recovery.retries => {
+                    reopen = None;
+                    report(EngineEvent::MicFailed {
+                        error: err.to_string(),
+                    });
+                }
+                Err(_) => {
+                    let backoff = recovery.backoff_after(attempts);
+                    reopen = Some((attempts + 1, Instant::now() + backoff));
+                }
+            }
+        }
+    });
+    ready.recv().map_err(|_| {
+        VoiceImmersionError::StreamBuild("The capture thread ended before capturing.".into())
+    })?
+}
+
+/// No owner thread in browsers: the capture runs until the guard is dropped, without being
+/// reopened, and leaks it when detached.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn spawn_detached_capture(
+    device: cpal::Device,
+    host: cpal::HostId,
+    sender: Sender<(f32, f32)>,
+    map: ChannelMap,
+    recovery: StreamRecovery,
+) -> crate::Result<()> {
+    let guard = spawn_capture(device, host, sender, map, recovery, None)?;
+    std::mem::forget(guard);
+    Ok(())
+}
+
+/// No owner thread in browsers: the capture runs until the guard is dropped, without being
+/// reopened.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn spawn_capture(
+    device: cpal::Device,
+    _host: cpal::HostId,
+    sender: Sender<(f32, f32)>,
+    map: ChannelMap,
+    _recovery: StreamRecovery,
+    _telemetry: Option<Arc<Telemetry>>,
+) -> crate::Result<Box<dyn Any>> {
+    let capture = MicStream {
+        sender,
+        map,
+        failed: Arc::new(AtomicBool::new(false)),
+        closed: Arc::new(AtomicBool::new(false)),
+    };
+    Ok(Box::new(capture.open(&device)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::bounded;
+
+    #[test]
+    fn input_channels_are_mapped() {
+        let read = |input: &[f32], channels, map| {
+            let (sender, receiver) = bounded(4);
+            read_data(input, channels, map, &sender);
+            receiver.try_iter().collect::<Vec<_>>()
+        };
+        let quad = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8];
+        assert_eq!(
+            read(&quad, 4, ChannelMap::FirstPair),
+            vec![(0.1, 0.2), (0.5, 0.6)]
+        );
+        assert_eq!(
+            read(&quad, 4, ChannelMap::Pair { left: 3, right: 2 }),
+            vec![(0.4, 0.3), (0.8, 0.7)]
+        );
+        let downmix = read(&quad, 4, ChannelMap::Downmix);
+        assert!((downmix[0].0 - 0.2).abs() < 1e-6 && (downmix[0].1 - 0.3).abs() < 1e-6);
+        assert!((downmix[1].0 - 0.6).abs() < 1e-6 && (downmix[1].1 - 0.7).abs() < 1e-6);
+        assert_eq!(
+            read(&[0.1, 0.2], 1, ChannelMap::FirstPair),
+            vec![(0.1, 0.1), (0.2, 0.2)]
+        );
+    }
+
+    #[test]
+    fn captures_notice_the_receiver_is_gone() {
+        let (sender, receiver) = bounded(4);
+        let map = ChannelMap::default();
+        assert!(read_data(&[0.5f32, -0.5, 0.25, -0.25], 2, map, &sender));
+        assert_eq!(receiver.try_recv(), Ok((0.5, -0.5)));
+        // A full queue drops frames but keeps capturing.
+        assert!(read_data(&[0.0f32; 16], 2, map, &sender));
+        drop(receiver);
+        assert!(!read_data(&[0.0f32; 2], 2, map, &sender));
+    }
+}
@@ -0,0 +1,45 @@
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+pub use std::time::Instant;
+
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub use web::Instant;
+
+/// Browsers have no monotonic clock for `std::time::Instant`, which panics there: the
+/// page clock stands in for it.
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+mod web {
+    use std::ops::Add;
+    use std::time::Duration;
+
+    /// Milliseconds of the page clock, see `js_sys::Date::now`.
+    #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+    pub struct Instant(f64);
+
+    impl Instant {
+        pub fn now() -> Self {
+            Instant(js_sys::Date::now())
+        }
+
+        /// Time from `earlier` to this instant, zero when it is later, as with the clock
+        /// going backwards.
+        pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+            Duration::from_secs_f64((self.0 - earlier.0).max(0.0) / 1000.0)
+        }
+
+        pub fn duration_since(&self, earlier: Instant) -> Duration {
+            self.saturating_duration_since(earlier)
+        }
+
+        pub fn elapsed(&self) -> Duration {
+            Instant::now().saturating_duration_since(*self)
+        }
+    }
+
+    impl Add<Duration> for Instant {
+        type Output = Instant;
+
+        fn add(self, duration: Duration) -> Instant {
+            Instant(self.0 + duration.as_secs_f64() * 1000.0)
+        }
+    }
+}
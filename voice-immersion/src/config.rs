@@ -0,0 +1,662 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use nalgebra::Vector3;
+
+use crate::attenuation::{AirModel, AttenuationModel, NearField};
+use crate::coordinates::CoordinateSystem;
+use crate::engine::CONTROL_PERIOD;
+use crate::hrtf::HrtfSet;
+use crate::{Aabb, InAnotherRoom, SourceInfo, VoiceImmersionError, HEAD_RADIUS, SOUND_SPEED};
+
+/// Listening setup the stereo output is processed for, switched at run time by
+/// `SpatialHandle::set_output_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputMode {
+    /// Each ear only hears its own channel: interaural time differences, a little crossfeed
+    /// making hard pans less tiring, the HRTF of `SpatializationMode::Hrtf`, and
+    /// `EngineConfig::pan_law`.
+    #[default]
+    Headphones,
+    /// Both ears hear both speakers already, and time differences between the channels
+    /// would comb filter: plain equal-power amplitude panning, without interaural time
+    /// differences, crossfeed nor HRTF.
+    Speakers,
+}
+
+/// Signal format of each listener on the graph outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Left and right channels, listener `k` on outputs `2k` and `2k + 1`.
+    #[default]
+    Stereo,
+    /// First-order ambisonics in B-format (W, X, Y, Z), for VR and 360 video decoders and
+    /// any speaker layout: listener `k` on outputs `4k` to `4k + 3`. W is the omni channel
+    /// at -3 dB, carrying the reverb, X points to the front of the listener, Y to the left
+    /// and Z up. The stereo cues (time and level differences, crossfeed, early
+    /// reflections), the width and the limiter are left out, levels and recordings
+    /// reporting W and X.
+    Ambisonic1,
+}
+
+impl OutputFormat {
+    /// Graph outputs per listener.
+    pub fn channels(self) -> usize {
+        match self {
+            OutputFormat::Stereo => 2,
+            OutputFormat::Ambisonic1 => 4,
+        }
+    }
+}
+
+/// How the direction of a source is rendered on the stereo output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpatializationMode {
+    /// Amplitude panning with interaural time differences: light, but front and back, or
+    /// above and below, sound alike.
+    #[default]
+    Panning,
+    /// Convolution with the measured head-related impulse responses of
+    /// `EngineConfig::hrtf`, whose spectral notches tell the front from the back and the
+    /// elevation, for headphones. Adds a latency of 128 samples and skips the crossfeed.
+    /// `Panning` without a set, and for graphs built in `OutputMode::Speakers`.
+    Hrtf,
+    /// The source as it is, for content spatialized upstream such as binaural clips, UI
+    /// sounds or pre-rendered mixes: stereo inputs keep their channels and mono ones play
+    /// on both, with no distance attenuation, air absorption, panning, delay nor Doppler
+    /// effect. The source and master gains, the occlusion and the low-pass of the walls
+    /// still apply, and so does the mix bus. Stereo output only: `EngineBuilder::build`
+    /// rejects it with `OutputFormat::Ambisonic1`.
+    Bypass,
+}
+
+/// How the propagation delays follow distance changes, see
+/// `EngineConfig::max_propagation_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DelayMode {
+    /// Glide to the new delay over `EngineConfig::movement_smoothing`, bending the pitch
+    /// as a moving source would.
+    #[default]
+    Interpolate,
+    /// Jump to the new delay without sweeping the delay line in between when the source
+    /// teleports, moving faster than `EngineConfig::sound_speed`, gliding otherwise.
+    /// `Scene::snap_delays` snaps a single move in either mode.
+    Snap,
+}
+
+/// How the graph parameters glide from one update to the next, see `EngineConfig::smoothing`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Smoothing {
+    /// Each over its own response time: `movement_smoothing`, `occlusion_smoothing`,
+    /// `room_crossfade`, `gain_ramp` and `pan_ramp`.
+    #[default]
+    PerParameter,
+    /// All of them over the same response time in seconds.
+    TimeConstant(f32),
+    /// None on top of the host's own interpolation, for hosts updating every audio block:
+    /// parameters follow each control pass within about a period, ramped just enough for
+    /// its steps not to click, the rooms switch at once and updates are applied as they
+    /// come rather than along `interpolate_updates` paths. Placements updated much slower
+    /// than `control_period` then step audibly, as nothing glides between them.
+    None,
+}
+
+/// How a pan position is split between the left and right gains.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PanLaw {
+    /// Gains summing to 1: a centered source sounds about 3 dB quieter than a hard panned one.
+    Linear,
+    /// Squared gains summing to 1, the loudness staying even across the pan.
+    #[default]
+    EqualPower,
+}
+
+impl PanLaw {
+    /// Left and right gains at `pan`, from 1 hard left to -1 hard right.
+    pub fn gains(self, pan: f32) -> (f32, f32) {
+        match self {
+            PanLaw::Linear => ((1.0 + pan) / 2.0, (1.0 - pan) / 2.0),
+            PanLaw::EqualPower => {
+                let angle = (1.0 - pan) * std::f32::consts::FRAC_PI_4;
+                (angle.cos(), angle.sin())
+            }
+        }
+    }
+}
+
+/// Reverb tuning shared by every room, the acoustics of each coming from its
+/// `InAnotherRoom`: see the `EngineConfig` fields of the same names.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReverbSettings {
+    pub reverb_fade: f32,
+    pub reverb_distance_factor: f32,
+    pub room_crossfade: f32,
+    pub early_reflections: usize,
+    pub wall_reflectivity: f32,
+}
+
+impl Default for ReverbSettings {
+    fn default() -> Self {
+        EngineConfig::default().reverb()
+    }
+}
+
+/// How the graph outputs, `OutputFormat::channels` per listener, are laid out on the output
+/// device channels.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputLayout {
+    /// Graph output `k` on device channel `k`, the extra channels silent. A mono device
+    /// plays the average of the first pair.
+    #[default]
+    Direct,
+    /// Graph outputs repeated over the device channels, the first pair playing on every
+    /// pair of a single listener setup.
+    Repeat,
+    /// The first pair on the front of a 5.1 or 7.1 device (front left, front right, center,
+    /// LFE, then the surround pairs), with the center and surrounds derived from it and
+    /// the LFE silent. `Direct` on other devices.
+    Surround,
+}
+
+/// Gain of the center channel derived from both sides of the front pair.
+const SURROUND_CENTER_GAIN: f32 = 0.5;
+
+/// Gain of the surround pairs derived from the front pair.
+const SURROUND_REAR_GAIN: f32 = 0.5;
+
+impl OutputLayout {
+    /// Sample of device `channel` out of `channels`, from the graph outputs `frame`.
+    #[inline]
+    pub fn sample(self, frame: &[f32], channel: usize, channels: usize) -> f32 {
+        let output = |index: usize| frame.get(index).copied().unwrap_or(0.0);
+        match self {
+            OutputLayout::Repeat => frame[channel % frame.len()],
+            OutputLayout::Surround if channels >= 6 => match channel {
+                0 | 1 => output(channel),
+                2 => (output(0) + output(1)) * SURROUND_CENTER_GAIN,
+                4..=7 => output(channel % 2) * SURROUND_REAR_GAIN,
+                _ => 0.0,
+            },
+            _ if channels == 1 => (output(0) + output(1)) / 2.0,
+            _ => output(channel),
+        }
+    }
+}
+
+/// How the channels of a capture device are mapped to the stereo input frames.
+/// Mono devices are heard on both sides whatever the map.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChannelMap {
+    /// First two channels as left and right, the others ignored.
+    #[default]
+    FirstPair,
+    /// Channels `left` and `right`, counted from 0. Missing ones are silent.
+    Pair { left: usize, right: usize },
+    /// Average of the even channels on the left and of the odd ones on the right.
+    Downmix,
+}
+
+impl ChannelMap {
+    /// Stereo frame of a capture `frame` holding one sample per channel.
+    pub fn stereo(self, frame: &[f32]) -> (f32, f32) {
+        if let [mono] = frame {
+            return (*mono, *mono);
+        }
+        let channel = |index: usize| frame.get(index).copied().unwrap_or(0.0);
+        match self {
+            ChannelMap::FirstPair => (channel(0), channel(1)),
+            ChannelMap::Pair { left, right } => (channel(left), channel(right)),
+            ChannelMap::Downmix => {
+                let average = |side: usize| {
+                    let samples = frame.iter().skip(side).step_by(2);
+                    samples.clone().sum::<f32>() / samples.count().max(1) as f32
+                };
+                (average(0), average(1))
+            }
+        }
+    }
+}
+
+/// Limiter on the mix bus of each listener, keeping the output within ±1 whatever the sum
+/// of the sources.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limiter {
+    /// Look-ahead in seconds.
+    pub attack: f32,
+    /// Time in seconds to recover once the mix gets quieter.
+    pub release: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter {
+            attack: 0.005,
+            release: 0.1,
+        }
+    }
+}
+
+/// Compressor evening out the mic level before spatialization, for voice chat where it
+/// varies wildly with the speaker and their distance to the mic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compressor {
+    /// Level in dBFS above which the mic gets compressed.
+    pub threshold_db: f32,
+    /// Decibels over the threshold in for each decibel out, 1 leaving the level alone.
+    pub ratio: f32,
+    /// Gain in dB applied after the compression, bringing quiet speech up.
+    pub makeup_db: f32,
+    /// Time in seconds for the gain to drop as the level rises.
+    pub attack: f32,
+    /// Time in seconds for the gain to recover as the level falls.
+    pub release: f32,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Compressor {
+            threshold_db: -30.0,
+            ratio: 4.0,
+            makeup_db: 12.0,
+            attack: 0.005,
+            release: 0.2,
+        }
+    }
+}
+
+/// Noise gate muting the mic between words, so that its self-noise does not get spatialized
+/// into a directional hiss.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoiseGate {
+    /// RMS level in dBFS under which the mic counts as silent.
+    pub threshold_db: f32,
+    /// Time in seconds the mic stays open once silent, bridging pauses between words.
+    pub hold: f32,
+    /// Time in seconds to open once the level rises above the threshold.
+    pub attack: f32,
+    /// Time in seconds to fade out once the hold is over.
+    pub release: f32,
+}
+
+impl Default for NoiseGate {
+    fn default() -> Self {
+        NoiseGate {
+            threshold_db: -50.0,
+            hold: 0.2,
+            attack: 0.002,
+            release: 0.1,
+        }
+    }
+}
+
+/// How the output stream is reopened after an error, e.g. a USB headset unplugged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamRecovery {
+    /// Attempts to reopen the stream on the default device, 0 to never try.
+    pub retries: u32,
+    /// Wait before the second attempt, doubling on each further one.
+    pub backoff: Duration,
+}
+
+impl StreamRecovery {
+    /// Wait after the failed attempt number `attempt`, counted from 0.
+    pub fn backoff_after(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(2u32.saturating_pow(attempt))
+    }
+}
+
+impl Default for StreamRecovery {
+    fn default() -> Self {
+        StreamRecovery {
+            retries: 5,
+            backoff: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Tuning of the spatialization engine.
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// Response time in seconds of the distance attenuation to source movements.
+    pub movement_smoothing: f32,
+    /// Overrides the response times of all the parameters at once, or turns smoothing off.
+    pub smoothing: Smoothing,
+    /// Replay the placements read by the control loop along a continuous path instead of
+    /// steps, one placement change late. Turn it off when updating them every audio block.
+    pub interpolate_updates: bool,
+    /// Response time in seconds of the velocity estimated from the successive positions of
+    /// sources, for the Doppler effect of placements that only give positions: a non-zero
+    /// `SourceInfo::velocity` or `Source::velocity` overrides it. Steps faster than the speed
+    /// of sound count as teleports. `None` keeps the given velocity, zero by default.
+    pub velocity_smoothing: Option<f32>,
+    /// Response time in seconds of the wall attenuation and filtering, so occlusion
+    /// flickering from raycasts on edges, or rooms toggling in doorways, glides over tens of
+    /// milliseconds instead of stepping. Raise it if flicker still chatters. The reverb
+    /// glides by `room_crossfade` instead.
+    pub occlusion_smoothing: f32,
+    /// Response time in seconds of user gain and mute changes.
+    pub gain_ramp: f32,
+    /// Response time in seconds of the audio rate ramp between pan updates, so their steps
+    /// do not cause zipper noise on fast rotations.
+    pub pan_ramp: f32,
+    pub attenuation: AttenuationModel,
+    /// Floor of the distance attenuation, so far away sources stay faintly audible.
+    /// 0 lets the `Linear` model cut them off.
+    pub min_gain: f32,
+    /// Boost close sources past the attenuation model, none by default.
+    pub near_field: Option<NearField>,
+    /// High frequency absorption of the air per meter: the low-pass cutoff falls from 20 kHz
+    /// by `exp(-air_absorption * distance)`, down to 500 Hz. 0 keeps far sources bright.
+    pub air_absorption: f32,
+    /// Frequency dependent air absorption, replacing the `air_absorption` low-pass with a
+    /// mid bell and a high shelf cut more with distance. Off by default.
+    pub air_model: Option<AirModel>,
+    /// Pan law in `OutputMode::Headphones`, speakers always using `PanLaw::EqualPower`.
+    pub pan_law: PanLaw,
+    /// How far sources are panned toward their side, from 0 (all centered whatever their
+    /// direction) to 1 (fully directional): scales the pan and the interaural time
+    /// difference alike. The HRTF and ambisonic directions are left untouched.
+    pub directional_strength: f32,
+    /// Least gain of either channel, from 0 to 1, so that hard panned sources keep a little
+    /// of their level in the opposite ear, less tiring on headphones and still heard in a
+    /// mono check, e.g. 0.05. 0 lets a side source leave the opposite channel silent.
+    pub pan_floor: f32,
+    /// Distance in meters past which per-ear cues (crossfeed) are faded out and skipped,
+    /// as they are not perceptible on far away sources anyway.
+    pub binaural_cue_distance: f32,
+    /// Share of each channel bled, delayed and darkened, into the other one in
+    /// `OutputMode::Headphones`, so hard pans sound less in the head. 0 turns it off.
+    pub crossfeed: f32,
+    /// Time in seconds for the room reverb to fade in when the source enters a room, and out
+    /// when it leaves.
+    pub reverb_fade: f32,
+    /// Rise of the reverb level with the distance of the source, the wet signal being scaled
+    /// by `1 + reverb_distance_factor * distance` in meters, up to +12 dB: near sources sound
+    /// mostly dry and far ones mostly wet. 0 keeps the wet level constant. Independent of the
+    /// low-pass of `air_absorption`.
+    pub reverb_distance_factor: f32,
+    /// Walls of the source room, when the listener stands in it, mirroring the source into
+    /// first-order early reflections: the four vertical walls up to 4, the floor and the
+    /// ceiling up to 6. Each costs a delay line read per sample, 0 turns them off.
+    pub early_reflections: usize,
+    /// Amplitude the walls reflect, 0 for fully absorbing walls and 1 for perfect mirrors.
+    pub wall_reflectivity: f32,
+    /// Absorption coefficient of the ground plane, y = 0 in the engine convention, from 0 for
+    /// a perfect mirror to 1 for a fully absorbing ground, e.g. 0.05 for pavement and 0.5
+    /// for grass: sources and listeners above it hear a delayed copy of the source mirrored
+    /// below it, its comb filtering telling distance and elevation apart outdoors. The
+    /// ground reflects `sqrt(1 - absorption)` of the amplitude, see
+    /// `SourceInfo::listener_height`. One delay line read per sample in stereo, `None`
+    /// turns it off.
+    pub ground_reflection: Option<f32>,
+    /// Response time in seconds of the reverb settings, gliding from the acoustics of one
+    /// room to the next one's when moving between rooms.
+    pub room_crossfade: f32,
+    /// Distance in meters over which a wall fades in across its plane, as the sound bends
+    /// around it: walking through a wall muffles the source over this distance rather than
+    /// at once. 0 switches the wall filter and attenuation right at the wall.
+    pub wall_diffraction: f32,
+    /// Depth of the elevation cue: sources above get their highs boosted and sources below
+    /// get them cut, by up to 6 dB times this strength. 0 disables it.
+    pub elevation_strength: f32,
+    /// Head radius in meters of listeners placed relatively to their sources, setting the
+    /// interaural time difference and the size of the centered zone within the head.
+    /// World listeners bring their own, see `Listener::head_radius`.
+    pub head_radius: f32,
+    /// Initial output mode, see `SpatialHandle::set_output_mode`.
+    pub output_mode: OutputMode,
+    /// Stereo or ambisonic outputs, see `OutputFormat`.
+    pub output_format: OutputFormat,
+    /// Rendering of the source directions on stereo outputs.
+    pub spatialization: SpatializationMode,
+    /// Measurements of `SpatializationMode::Hrtf`, see `HrtfSet::new`.
+    pub hrtf: Option<Arc<HrtfSet>>,
+    /// Fade out time in seconds at the end of one-shot inputs, so they do not click when
+    /// not ending on a zero crossing.
+    pub one_shot_fade: f32,
+    /// Time in seconds the end of looping wave inputs is crossfaded into their start, so
+    /// the seam does not click.
+    pub loop_crossfade: f32,
+    /// Time in seconds the last mic frame is held and faded out when the capture stream
+    /// falls behind. Longer holds bridge longer gaps, at the cost of an audible buzz from
+    /// the repeated frame; 0 drops straight to silence.
+    pub mic_underrun_hold: f32,
+    /// Channels of the mic device heard as its left and right sides.
+    pub mic_channels: ChannelMap,
+    /// Capacity in frames of the channel from the mic capture to the output, `None` sizing
+    /// it with `mic_capacity`: four output buffers, at least 50 ms. A longer queue rides
+    /// out more scheduling jitter between the capture and playback callbacks before
+    /// dropping out, but lets up to that much audio back up behind the output when the
+    /// capture runs ahead, see `SpatialHandle::latency`. For voice chat, lower it along
+    /// with the buffer size and raise it back when `EngineStats::mic_underruns` climbs.
+    pub mic_queue: Option<usize>,
+    /// Compressor of the mic input, `None` by default so that music and effects played
+    /// through the mic keep their dynamics. Unlike the limiter, it acts on the mic alone.
+    pub mic_compressor: Option<Compressor>,
+    /// Noise gate of the mic input, ahead of its compressor. Off by default.
+    pub mic_gate: Option<NoiseGate>,
+    /// Time in seconds the clip indicator of `EngineStats` stays on after a full scale sample.
+    pub clip_hold: f32,
+    /// Samples of the sliding window of `SpatialHandle::spectrum`, rounded up to a power of
+    /// two from 64 to 16384: 2048 resolves 23 Hz at 48 kHz. No spectrum is computed when
+    /// `None`, the default.
+    pub spectrum_window: Option<usize>,
+    /// Convention of the positions and directions given to the engine.
+    pub coordinate_system: CoordinateSystem,
+    /// Pause the output stream once every source got silent, muted or out of earshot,
+    /// for this long, resuming as soon as one gets audible again. Never by default.
+    pub idle_pause_timeout: Option<Duration>,
+    /// Stop rendering a source of the scene once it got silent, muted or out of earshot,
+    /// see `SourceInfo::max_distance`, for this long, fading it back in over 20 ms as soon
+    /// as it gets audible again. Its playback pauses meanwhile, waves resuming where they
+    /// stopped, and what still rang in its reverb and delay lines is cut: the timeout should
+    /// outlast them. Culled sources cost nothing in the audio thread: in a release build at
+    /// 44.1 kHz, 32 sources in a reverberant room with 4 early reflections took 65% of a core,
+    /// 29% with half of them culled and 2% with all but one. Never by default.
+    pub cull_timeout: Option<Duration>,
+    /// Most sources of the scene heard at once: past it, those ranked last by their
+    /// priority (see `Scene::set_priority`) times the gain they are heard with get silenced,
+    /// ramped over `gain_ramp`, and culled after `cull_timeout`. Unlimited by default.
+    pub max_voices: Option<usize>,
+    /// Longest time between two control updates, pushed updates waking the control thread
+    /// up earlier (see `SpatialHandle::updates`). Shorter periods follow fast sources more
+    /// closely and lower the latency of shared descriptions, at the cost of CPU time and
+    /// battery; longer ones leave the smoothing to glide over bigger steps. At least 1 ms.
+    pub control_period: Duration,
+    /// Reopening of the output and mic streams after an error, see `EngineEvent::StreamFailed`
+    /// and `EngineEvent::MicFailed`.
+    pub stream_recovery: StreamRecovery,
+    /// Time in seconds for the output to fade in when the stream starts, and out when the
+    /// engine pauses or stops, avoiding a click on both.
+    pub output_fade: f32,
+    /// Layout of the listeners on the output device channels.
+    pub output_layout: OutputLayout,
+    /// Swap the left and right channels of every listener, mirroring the Y channel in
+    /// ambisonics, as a diagnostic aid: when a source on the left then plays on the right
+    /// channel, the pan is right and the device or the wiring swaps the channels. Applies
+    /// to the device, recordings and captures alike, toggled at run time by
+    /// `SpatialHandle::set_swap_channels`.
+    pub swap_channels: bool,
+    /// Corner in Hz of the DC blocker on the mix bus, ahead of the limiter: a one-pole
+    /// high-pass taking out the DC offset and subsonic rumble of mics and synths, which waste
+    /// headroom and thump speakers. The default 20 Hz is below hearing, `None` passes DC.
+    pub dc_blocker: Option<f32>,
+    /// Limiter on the mix bus, `None` letting loud mixes clip on output conversion.
+    pub limiter: Option<Limiter>,
+    /// Longest propagation delay in seconds, farther sources are not delayed any more.
+    /// Bounds the memory of each delay line.
+    pub max_propagation_delay: f32,
+    /// How the propagation delays follow distance changes.
+    pub delay_mode: DelayMode,
+    /// Speed of sound in meters per second for the propagation delay and the Doppler effect,
+    /// e.g. from `sound_speed` for a given air temperature.
+    pub sound_speed: f32,
+    /// World units in a meter, positions and sizes being scaled to meters on ingestion:
+    /// 100 for centimeters, 1 for meters.
+    pub units_per_meter: f32,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            movement_smoothing: 0.1,
+            smoothing: Smoothing::PerParameter,
+            interpolate_updates: true,
+            velocity_smoothing: Some(0.1),
+            occlusion_smoothing: 0.05,
+            gain_ramp: 0.005,
+            pan_ramp: 0.005,
+            attenuation: AttenuationModel::default(),
+            min_gain: 0.0,
+            near_field: None,
+            air_absorption: 0.005,
+            air_model: None,
+            pan_law: PanLaw::default(),
+            directional_strength: 1.0,
+            pan_floor: 0.0,
+            binaural_cue_distance: f32::INFINITY,
+            crossfeed: 0.3,
+            reverb_fade: 0.1,
+            reverb_distance_factor: 0.0,
+            early_reflections: 4,
+            wall_reflectivity: 0.5,
+            ground_reflection: None,
+            room_crossfade: 0.3,
+            wall_diffraction: 0.5,
+            elevation_strength: 1.0,
+            head_radius: HEAD_RADIUS,
+            output_mode: OutputMode::default(),
+            output_format: OutputFormat::default(),
+            spatialization: SpatializationMode::default(),
+            hrtf: None,
+            one_shot_fade: 0.01,
+            loop_crossfade: 0.01,
+            mic_underrun_hold: 0.005,
+            mic_channels: ChannelMap::default(),
+            mic_queue: None,
+            mic_compressor: None,
+            mic_gate: None,
+            clip_hold: 1.0,
+            spectrum_window: None,
+            coordinate_system: CoordinateSystem::default(),
+            idle_pause_timeout: None,
+            cull_timeout: None,
+            max_voices: None,
+            control_period: CONTROL_PERIOD,
+            stream_recovery: StreamRecovery::default(),
+            dc_blocker: Some(20.0),
+            limiter: Some(Limiter::default()),
+            output_layout: OutputLayout::default(),
+            swap_channels: false,
+            output_fade: 0.02,
+            max_propagation_delay: 2.0,
+            delay_mode: DelayMode::Interpolate,
+            sound_speed: SOUND_SPEED,
+            units_per_meter: 1.0,
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Errors on settings the graph cannot render together.
+    pub(crate) fn check(&self) -> crate::Result<()> {
+        if self.spatialization == SpatializationMode::Bypass
+            && self.output_format != OutputFormat::Stereo
+        {
+            return Err(VoiceImmersionError::InvalidConfig(
+                "SpatializationMode::Bypass needs a stereo output format.".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reverb fields of the configuration.
+    pub fn reverb(&self) -> ReverbSettings {
+        ReverbSettings {
+            reverb_fade: self.reverb_fade,
+            reverb_distance_factor: self.reverb_distance_factor,
+            room_crossfade: self.room_crossfade,
+            early_reflections: self.early_reflections,
+            wall_reflectivity: self.wall_reflectivity,
+        }
+    }
+
+    /// Set the reverb fields of the configuration.
+    pub fn set_reverb(&mut self, settings: ReverbSettings) {
+        self.reverb_fade = settings.reverb_fade;
+        self.reverb_distance_factor = settings.reverb_distance_factor;
+        self.room_crossfade = settings.room_crossfade;
+        self.early_reflections = settings.early_reflections;
+        self.wall_reflectivity = settings.wall_reflectivity;
+    }
+
+    /// Pan law of the `output_mode`.
+    pub(crate) fn output_pan_law(&self) -> PanLaw {
+        match self.output_mode {
+            OutputMode::Headphones => self.pan_law,
+            OutputMode::Speakers => PanLaw::EqualPower,
+        }
+    }
+
+    /// Configuration with the response times of `smoothing` in place of the per-parameter
+    /// ones.
+    pub(crate) fn resolve_smoothing(&self) -> EngineConfig {
+        let mut config = self.clone();
+        let time = match self.smoothing {
+            Smoothing::PerParameter => return config,
+            Smoothing::TimeConstant(time) => time,
+            Smoothing::None => {
+                // De-zippering the control rate steps only.
+                let period = self.control_period.as_secs_f32();
+                config.movement_smoothing = period;
+                config.occlusion_smoothing = period;
+                config.room_crossfade = 0.0;
+                config.gain_ramp = self.gain_ramp.min(period);
+                config.pan_ramp = self.pan_ramp.min(period);
+                config.interpolate_updates = false;
+                return config;
+            }
+        };
+        config.movement_smoothing = time;
+        config.occlusion_smoothing = time;
+        config.room_crossfade = time;
+        config.gain_ramp = time;
+        config.pan_ramp = time;
+        config
+    }
+
+    /// Position in the caller convention and units, in the engine convention and meters.
+    pub(crate) fn ingest_position(&self, position: &Vector3<f32>) -> Vector3<f32> {
+        self.coordinate_system.to_engine(position) / self.units_per_meter
+    }
+
+    /// Source description in the caller convention and units, in the engine convention and meters.
+    pub(crate) fn ingest_source_info(&self, info: &SourceInfo) -> SourceInfo {
+        SourceInfo {
+            relative_position: self.ingest_position(&info.relative_position),
+            direction: self.coordinate_system.to_engine(&info.direction),
+            source_direction: self.coordinate_system.to_engine(&info.source_direction),
+            radius: self.ingest_length(info.radius),
+            min_distance: self.ingest_length(info.min_distance),
+            max_distance: info.max_distance.map(|max| self.ingest_length(max)),
+            stereo_width: self.ingest_length(info.stereo_width),
+            listener_height: self.ingest_length(info.listener_height),
+            velocity: self.ingest_position(&info.velocity),
+            room: info.room.clone().map(|room| InAnotherRoom {
+                bounds: room.bounds.map(|bounds| {
+                    Aabb::new(
+                        self.ingest_position(&bounds.min),
+                        self.ingest_position(&bounds.max),
+                    )
+                }),
+                ..room
+            }),
+            ..info.clone()
+        }
+    }
+
+    /// Length in world units, in meters.
+    pub(crate) fn ingest_length(&self, length: f32) -> f32 {
+        length / self.units_per_meter
+    }
+}
@@ -0,0 +1,134 @@
+use nalgebra::Vector3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handedness {
+    Left,
+    Right,
+}
+
+/// Signed world axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+impl Axis {
+    pub fn vector(self) -> Vector3<f32> {
+        match self {
+            Axis::PosX => Vector3::x(),
+            Axis::NegX => -Vector3::x(),
+            Axis::PosY => Vector3::y(),
+            Axis::NegY => -Vector3::y(),
+            Axis::PosZ => Vector3::z(),
+            Axis::NegZ => -Vector3::z(),
+        }
+    }
+}
+
+/// Convention of the positions and directions handed to the engine, converted on ingestion
+/// to the engine one: right-handed, +Y up and +X forward, hence +Z on the right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoordinateSystem {
+    pub handedness: Handedness,
+    pub up_axis: Axis,
+    pub forward_axis: Axis,
+}
+
+impl Default for CoordinateSystem {
+    fn default() -> Self {
+        CoordinateSystem {
+            handedness: Handedness::Right,
+            up_axis: Axis::PosY,
+            forward_axis: Axis::PosX,
+        }
+    }
+}
+
+impl CoordinateSystem {
+    /// Right-handed, +Y up and -Z forward, as glam and macroquad.
+    pub fn opengl() -> Self {
+        CoordinateSystem {
+            handedness: Handedness::Right,
+            up_axis: Axis::PosY,
+            forward_axis: Axis::NegZ,
+        }
+    }
+
+    /// Left-handed, +Y up and +Z forward.
+    pub fn unity() -> Self {
+        CoordinateSystem {
+            handedness: Handedness::Left,
+            up_axis: Axis::PosY,
+            forward_axis: Axis::PosZ,
+        }
+    }
+
+    /// Left-handed, +Z up and +X forward.
+    pub fn unreal() -> Self {
+        CoordinateSystem {
+            handedness: Handedness::Left,
+            up_axis: Axis::PosZ,
+            forward_axis: Axis::PosX,
+        }
+    }
+
+    /// Right-handed, +Y up and +Z forward, as the front of glTF assets, hence -X on the right.
+    pub fn gltf() -> Self {
+        CoordinateSystem {
+            handedness: Handedness::Right,
+            up_axis: Axis::PosY,
+            forward_axis: Axis::PosZ,
+        }
+    }
+
+    /// Unit vector on the right of someone facing the forward axis, up on top.
+    pub fn right(&self) -> Vector3<f32> {
+        let (up, forward) = (self.up_axis.vector(), self.forward_axis.vector());
+        match self.handedness {
+            Handedness::Right => forward.cross(&up),
+            Handedness::Left => up.cross(&forward),
+        }
+    }
+
+    /// Express `v`, given in this convention, in the engine one.
+    pub fn to_engine(&self, v: &Vector3<f32>) -> Vector3<f32> {
+        Vector3::new(
+            v.dot(&self.forward_axis.vector()),
+            v.dot(&self.up_axis.vector()),
+            v.dot(&self.right()),
+        )
+    }
+
+    /// Express `v`, given in the engine convention, in this one.
+    pub fn from_engine(&self, v: &Vector3<f32>) -> Vector3<f32> {
+        self.forward_axis.vector() * v.x + self.up_axis.vector() * v.y + self.right() * v.z
+    }
+
+    /// Express `v`, given in this convention, in `other`.
+    pub fn convert(&self, v: &Vector3<f32>, other: &CoordinateSystem) -> Vector3<f32> {
+        other.from_engine(&self.to_engine(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_between_presets() {
+        // 1 m right, 2 m up and 3 m forward.
+        let unity = Vector3::new(1.0, 2.0, 3.0);
+        let unreal = CoordinateSystem::unity().convert(&unity, &CoordinateSystem::unreal());
+        assert_eq!(unreal, Vector3::new(3.0, 1.0, 2.0));
+        let opengl = CoordinateSystem::unreal().convert(&unreal, &CoordinateSystem::opengl());
+        assert_eq!(opengl, Vector3::new(1.0, 2.0, -3.0));
+        let engine = CoordinateSystem::opengl().to_engine(&opengl);
+        assert_eq!(engine, Vector3::new(3.0, 2.0, 1.0));
+        assert_eq!(CoordinateSystem::default().to_engine(&engine), engine);
+    }
+}
@@ -0,0 +1,330 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use fundsp::wave::Wave;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::VoiceImmersionError;
+
+/// Seconds of decoded audio a `FileStream` buffers ahead of the playback.
+const STREAM_BUFFER: f64 = 2.0;
+
+/// Decoder of the first audio track of a file, yielding its samples packet by packet.
+pub(crate) struct AudioFile {
+    path: PathBuf,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    codec: &'static str,
+    sample_rate: Option<u32>,
+    samples: Option<SampleBuffer<f32>>,
+}
+
+impl AudioFile {
+    /// Probe `path`, erroring with its detected container or codec when it cannot be decoded.
+    pub(crate) fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+        let file = File::open(path).map_err(|e| {
+            VoiceImmersionError::WaveLoad(format!("Could not open {}: {}", path.display(), e))
+        })?;
+        let mut hint = Hint::new();
+        hint.with_extension(extension);
+        let probed = symphonia::default::get_probe()
+            .format(
+                &hint,
+                MediaSourceStream::new(Box::new(file), Default::default()),
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .map_err(|e| {
+                VoiceImmersionError::WaveLoad(format!(
+                    "Unsupported audio format for {} (extension \"{}\"): {}",
+                    path.display(),
+                    extension,
+                    e
+                ))
+            })?;
+        let format = probed.format;
+        let track = format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| {
+                VoiceImmersionError::WaveLoad(format!("No audio track in {}.", path.display()))
+            })?;
+        let codec = symphonia::default::get_codecs()
+            .get_codec(track.codec_params.codec)
+            .map_or("unknown", |codec| codec.short_name);
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|e| {
+                VoiceImmersionError::WaveLoad(format!(
+                    "No decoder for {} ({} codec): {}",
+                    path.display(),
+                    codec,
+                    e
+                ))
+            })?;
+        Ok(AudioFile {
+            path: path.to_path_buf(),
+            track_id: track.id,
+            sample_rate: track.codec_params.sample_rate,
+            format,
+            decoder,
+            codec,
+            samples: None,
+        })
+    }
+
+    /// Sample rate of the track, known from its header or once a packet was decoded.
+    pub(crate) fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    /// Planar samples of the next packet, `None` at the end of the track.
+    pub(crate) fn next_packet(&mut self) -> crate::Result<Option<PlanarPacket<'_>>> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => {
+                    return Err(VoiceImmersionError::WaveLoad(format!(
+                        "Could not read {}: {}",
+                        self.path.display(),
+                        e
+                    )))
+                }
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A corrupt packet is skipped, as players do.
+                Err(Error::DecodeError(_)) => continue,
+                Err(e) => {
+                    return Err(VoiceImmersionError::WaveLoad(format!(
+                        "Could not decode {} ({} codec): {}",
+                        self.path.display(),
+                        self.codec,
+                        e
+                    )))
+                }
+            };
+            let spec = *decoded.spec();
+            let channels = spec.channels.count();
+            self.sample_rate.get_or_insert(spec.rate);
+            // The buffer is kept while packets fit in it.
+            let needed = decoded.capacity() * channels;
+            if self
+                .samples
+                .as_ref()
+                .is_some_and(|buffer| buffer.capacity() < needed)
+            {
+                self.samples = None;
+            }
+            let buffer = self
+                .samples
+                .get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+            buffer.copy_planar_ref(decoded);
+            let frames = buffer.len() / channels.max(1);
+            return Ok(Some(PlanarPacket {
+                samples: buffer.samples(),
+                frames,
+            }));
+        }
+    }
+}
+
+/// Decoded samples of a packet, one channel after the other.
+pub(crate) struct PlanarPacket<'a> {
+    samples: &'a [f32],
+    frames: usize,
+}
+
+impl PlanarPacket<'_> {
+    pub(crate) fn channels(&self) -> impl Iterator<Item = &[f32]> {
+        self.samples.chunks(self.frames.max(1))
+    }
+
+    /// Mean of the channels at frame `i`.
+    pub(crate) fn mono(&self, i: usize) -> f32 {
+        let (mut sum, mut count) = (0.0, 0);
+        for channel in self.channels() {
+            sum += channel[i];
+            count += 1;
+        }
+        sum / Ord::max(count, 1) as f32
+    }
+
+    pub(crate) fn frames(&self) -> usize {
+        self.frames
+    }
+}
+
+/// Decode the first audio track of a WAV, FLAC, OGG Vorbis, MP3 or other file `symphonia`
+/// reads, keeping its channels: wave sources play the first one, see `downmix` for the others.
+/// Errors name the file and its detected container or codec. See `FileStream` for files too
+/// long to hold in memory.
+pub fn load_audio(path: impl AsRef<Path>) -> crate::Result<Wave> {
+    let mut file = AudioFile::open(path)?;
+    let mut channels: Vec<Vec<f32>> = Vec::new();
+    while let Some(packet) = file.next_packet()? {
+        if channels.is_empty() {
+            channels = packet.channels().map(<[f32]>::to_vec).collect();
+        } else {
+            for (channel, planar) in channels.iter_mut().zip(packet.channels()) {
+                channel.extend_from_slice(planar);
+            }
+        }
+    }
+    let Some(sample_rate) = file.sample_rate() else {
+        return Err(VoiceImmersionError::WaveLoad(format!(
+            "No audio decoded from {}.",
+            file.path.display()
+        )));
+    };
+    let mut wave = Wave::new(0, sample_rate as f64);
+    for channel in &channels {
+        wave.push_channel(channel);
+    }
+    Ok(wave)
+}
+
+/// Audio file decoded on a background thread as it plays, only `STREAM_BUFFER` seconds of it
+/// being held in memory whatever its length: for long ambiences and music, see
+/// `InputSource::Stream`. Its channels are mixed down to mono.
+pub struct FileStream {
+    pub(crate) samples: Receiver<f32>,
+    pub(crate) sample_rate: f64,
+}
+
+impl FileStream {
+    /// Start decoding `path`, from its start again at its end when `looping`. Errors when it
+    /// cannot be decoded, like `load_audio`.
+    pub fn open(path: impl AsRef<Path>, looping: bool) -> crate::Result<Self> {
+        let file = AudioFile::open(path)?;
+        let sample_rate = file.sample_rate().ok_or_else(|| {
+            VoiceImmersionError::WaveLoad(format!(
+                "Unknown sample rate of {}.",
+                file.path.display()
+            ))
+        })?;
+        let (sender, samples) = bounded((sample_rate as f64 * STREAM_BUFFER) as usize);
+        std::thread::spawn(move || stream_file(file, looping, sender));
+        Ok(FileStream {
+            samples,
+            sample_rate: sample_rate as f64,
+        })
+    }
+}
+
+/// Decode `file` into `samples` until the player is dropped, or the end of the file unless
+/// `looping`. A decoding error ends the stream.
+fn stream_file(mut file: AudioFile, looping: bool, samples: Sender<f32>) {
+    let mut decoded = false;
+    loop {
+        match file.next_packet() {
+            Ok(Some(packet)) => {
+                for i in 0..packet.frames() {
+                    // Blocks while the buffer is full, errors once the player is gone.
+                    if samples.send(packet.mono(i)).is_err() {
+                        return;
+                    }
+                }
+                decoded |= packet.frames() > 0;
+            }
+            // Reopened rather than seeked, which not every container supports.
+            Ok(None) if looping && decoded => match AudioFile::open(&file.path) {
+                Ok(reopened) => {
+                    file = reopened;
+                    decoded = false;
+                }
+                Err(_) => return,
+            },
+            _ => return,
+        }
+    }
+}
+
+/// Mono mix of all the channels of `wave`, for wave sources which play a single one.
+pub fn downmix(wave: &Wave) -> Wave {
+    let mut mono = Wave::new(1, wave.sample_rate());
+    let scale = 1.0 / wave.channels().max(1) as f32;
+    for i in 0..wave.length() {
+        let sum: f32 = (0..wave.channels())
+            .map(|channel| wave.at(channel, i))
+            .sum();
+        mono.push(sum * scale);
+    }
+    mono
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stereo_wav_keeps_its_channels() {
+        let path = std::env::temp_dir().join(format!("stereo-{}.wav", std::process::id()));
+        let mut wave = Wave::new(0, 44100.0);
+        wave.push_channel(&[0.5; 1000]);
+        wave.push_channel(&[-0.25; 1000]);
+        wave.save_wav32(&path).unwrap();
+
+        let loaded = load_audio(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((loaded.channels(), loaded.length()), (2, 1000));
+        assert_eq!(loaded.sample_rate(), 44100.0);
+        assert_eq!((loaded.at(0, 500), loaded.at(1, 500)), (0.5, -0.25));
+        let mono = downmix(&loaded);
+        assert_eq!((mono.channels(), mono.length()), (1, 1000));
+        assert_eq!(mono.at(0, 999), 0.125);
+    }
+
+    #[test]
+    fn stream_decodes_the_file_in_order() {
+        let path = std::env::temp_dir().join(format!("stream-{}.wav", std::process::id()));
+        let ramp: Vec<f32> = (0..1000).map(|i| i as f32 / 1000.0).collect();
+        let mut wave = Wave::new(0, 44100.0);
+        wave.push_channel(&ramp);
+        wave.push_channel(&ramp);
+        wave.save_wav32(&path).unwrap();
+
+        let once = FileStream::open(&path, false).unwrap();
+        assert_eq!(once.sample_rate, 44100.0);
+        let played: Vec<f32> = once.samples.iter().collect();
+        assert_eq!(played, ramp);
+        let looping = FileStream::open(&path, true).unwrap();
+        let played: Vec<f32> = looping.samples.iter().take(2500).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(played[..1000], ramp[..]);
+        assert_eq!(played[2000..], ramp[..500]);
+    }
+
+    #[test]
+    fn unknown_format_names_the_file() {
+        let path = std::env::temp_dir().join(format!("noise-{}.xyz", std::process::id()));
+        std::fs::write(&path, [0x42u8; 256]).unwrap();
+        let error = load_audio(&path).err().unwrap().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            error.contains("noise-") && error.contains("\"xyz\""),
+            "{}",
+            error
+        );
+        assert!(load_audio("missing.flac").is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+
+use crate::VoiceImmersionError;
+
+/// Names of the output devices of the default host, for a device picker.
+pub fn list_output_devices() -> Vec<String> {
+    device_names(cpal::default_host().output_devices())
+}
+
+/// Names of the input devices of the default host.
+pub fn list_input_devices() -> Vec<String> {
+    device_names(cpal::default_host().input_devices())
+}
+
+/// Output device of the default host named `name`, if it is still plugged.
+pub fn open_output_device(name: &str) -> Option<cpal::Device> {
+    find_output_device(cpal::default_host().id(), name).ok()
+}
+
+/// Input device of the default host named `name`, if it is still plugged.
+pub fn open_input_device(name: &str) -> Option<cpal::Device> {
+    find_input_device(cpal::default_host().id(), name).ok()
+}
+
+pub(crate) fn find_output_device(host: cpal::HostId, name: &str) -> crate::Result<cpal::Device> {
+    let devices = cpal::host_from_id(host)?.output_devices()?;
+    find_device(devices, name).ok_or_else(|| {
+        VoiceImmersionError::DeviceUnavailable(format!("Output device \"{}\" not found.", name))
+    })
+}
+
+pub(crate) fn find_input_device(host: cpal::HostId, name: &str) -> crate::Result<cpal::Device> {
+    let devices = cpal::host_from_id(host)?.input_devices()?;
+    find_device(devices, name).ok_or_else(|| {
+        VoiceImmersionError::DeviceUnavailable(format!("Input device \"{}\" not found.", name))
+    })
+}
+
+fn find_device(
+    mut devices: impl Iterator<Item = cpal::Device>,
+    name: &str,
+) -> Option<cpal::Device> {
+    devices.find(|device| device.name().is_ok_and(|device_name| device_name == name))
+}
+
+fn device_names<E>(devices: Result<impl Iterator<Item = cpal::Device>, E>) -> Vec<String> {
+    devices
+        .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_device_is_reported() {
+        let name = "No such device";
+        assert!(!list_output_devices().iter().any(|device| device == name));
+        assert!(open_output_device(name).is_none());
+        assert!(open_input_device(name).is_none());
+        assert!(find_output_device(cpal::default_host().id(), name).is_err());
+    }
+}
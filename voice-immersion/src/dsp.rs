@@ -0,0 +1,1409 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crossbeam_channel::{bounded, Receiver, Sender, TryRecvError};
+use fundsp::fft::{inverse_fft, real_fft};
+use fundsp::hacker::*;
+use fundsp::wave::Wave;
+use num_complex::Complex32;
+
+use crate::config::{Compressor, NoiseGate};
+use crate::decode::FileStream;
+use crate::hrtf::{HrirSpectra, HrtfSet, HRTF_BLOCK};
+
+/// Weights under this are treated as a fully bypassed effect.
+const BYPASS_THRESHOLD: f32 = 1.0e-4;
+
+/// Comb delays in samples at 44.1 kHz of the reverb for a `REVERB_REFERENCE_SIZE` room,
+/// from Freeverb.
+const REVERB_COMBS: [usize; 4] = [1116, 1277, 1422, 1557];
+
+/// Allpass delays in samples at 44.1 kHz of the reverb, from Freeverb.
+const REVERB_ALLPASSES: [usize; 2] = [556, 341];
+
+/// Extra delay in samples of the right channel lines, decorrelating the channels.
+const REVERB_SPREAD: usize = 23;
+
+/// Room size in meters the reverb delays are tuned for, scaled to the actual room size.
+const REVERB_REFERENCE_SIZE: f32 = 10.0;
+
+/// Range of room sizes in meters the reverb models.
+pub(crate) const ROOM_SIZE_RANGE: (f32, f32) = (1.0, 40.0);
+
+/// Longest reverb pre-delay in seconds, bounding the memory of its line.
+pub(crate) const MAX_PREDELAY: f32 = 0.25;
+
+/// Share of the high frequencies the comb feedback loses on each pass.
+const REVERB_DAMPING: f32 = 0.2;
+
+/// Gain of the input into the combs, keeping the wet signal near the dry level.
+const REVERB_INPUT_GAIN: f32 = 0.1;
+
+/// Longest delay in seconds of an early reflection behind the direct sound, later ones
+/// being left to the reverb.
+pub(crate) const MAX_REFLECTION_DELAY: f32 = 0.25;
+
+/// Time in seconds a switched wave dips to silence over, before and after the switch.
+const WAVE_SWAP_FADE: f32 = 0.01;
+
+/// Bleed a delayed, darkened copy of each channel into the other one, as the far speaker would.
+pub(crate) fn crossfeed(amount: f32) -> An<impl AudioNode<Inputs = U2, Outputs = U2>> {
+    let bleed = || delay(0.0003) >> lowpole_hz(700.0) * amount;
+    (multipass::<U2>() & ((bleed() | bleed()) >> reverse::<U2>())) * (1.0 / (1.0 + amount))
+}
+
+/// `(left, right)` with its side signal scaled by `width`: 0 collapses it to mono, 1 keeps
+/// it and more widens it.
+#[inline]
+pub(crate) fn stereo_width(left: f32, right: f32, width: f32) -> (f32, f32) {
+    let mid = (left + right) * 0.5;
+    let side = (left - right) * 0.5 * width;
+    (mid + side, mid - side)
+}
+
+/// Crossfades a stereo effect in by a shared weight (0 dry, 1 wet) and stops ticking it
+/// altogether once faded out, so unused effects cost nothing.
+#[derive(Clone)]
+pub(crate) struct Bypass<X: AudioNode<Inputs = U2, Outputs = U2>> {
+    effect: X,
+    weight: Shared,
+    fade_time: f32,
+    coeff: f32,
+    mix: f32,
+    active: bool,
+}
+
+impl<X: AudioNode<Inputs = U2, Outputs = U2>> Bypass<X> {
+    /// Fade between dry and wet over about `fade_time` seconds.
+    pub fn new(effect: X, weight: &Shared, fade_time: f32) -> Self {
+        let mut bypass = Bypass {
+            effect,
+            weight: weight.clone(),
+            fade_time,
+            coeff: 1.0,
+            mix: weight.value(),
+            active: true,
+        };
+        bypass.set_sample_rate(DEFAULT_SR);
+        bypass
+    }
+}
+
+impl<X: AudioNode<Inputs = U2, Outputs = U2>> AudioNode for Bypass<X> {
+    const ID: u64 = 88;
+    type Inputs = U2;
+    type Outputs = U2;
+
+    fn reset(&mut self) {
+        self.effect.reset();
+        self.mix = self.weight.value();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.effect.set_sample_rate(sample_rate);
+        self.coeff = 1.0 - (-1.0 / (self.fade_time as f64 * sample_rate)).exp() as f32;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let target = self.weight.value();
+        self.mix += (target - self.mix) * self.coeff;
+        if target <= BYPASS_THRESHOLD && self.mix <= BYPASS_THRESHOLD {
+            self.mix = 0.0;
+            self.active = false;
+            return *input;
+        }
+        if !self.active {
+            // Do not replay what was left in the effect when it got bypassed.
+            self.effect.reset();
+            self.active = true;
+        }
+        let wet = self.effect.tick(input);
+        let dry = 1.0 - self.mix;
+        [
+            input[0] * dry + wet[0] * self.mix,
+            input[1] * dry + wet[1] * self.mix,
+        ]
+        .into()
+    }
+}
+
+/// Renders a source branch while a shared flag is raised, fading its output out over
+/// `fade_time` seconds once lowered and then not ticking it at all, so that inaudible sources
+/// cost nothing. Ticked again from where it stopped and faded back in when raised.
+#[derive(Clone)]
+pub(crate) struct Cull {
+    unit: Box<dyn AudioUnit>,
+    active: Shared,
+    fade_time: f32,
+    step: f32,
+    gain: f32,
+}
+
+impl Cull {
+    pub fn new(unit: Box<dyn AudioUnit>, active: &Shared, fade_time: f32) -> Self {
+        let mut cull = Cull {
+            unit,
+            active: active.clone(),
+            fade_time,
+            step: 1.0,
+            gain: active.value().clamp(0.0, 1.0),
+        };
+        cull.set_sample_rate(DEFAULT_SR);
+        cull
+    }
+
+    fn target(&self) -> f32 {
+        if self.active.value() > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Move the gain one sample towards `target`.
+    #[inline]
+    fn advance(&mut self, target: f32) -> f32 {
+        self.gain = if target > self.gain {
+            (self.gain + self.step).min(target)
+        } else {
+            (self.gain - self.step).max(target)
+        };
+        self.gain
+    }
+}
+
+impl AudioUnit for Cull {
+    fn reset(&mut self) {
+        self.unit.reset();
+        self.gain = self.target();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.unit.set_sample_rate(sample_rate);
+        self.step = (1.0 / (self.fade_time as f64 * sample_rate).max(1.0)) as f32;
+    }
+
+    fn tick(&mut self, input: &[f32], output: &mut [f32]) {
+        let target = self.target();
+        if target == 0.0 && self.gain == 0.0 {
+            output.fill(0.0);
+            return;
+        }
+        self.unit.tick(input, output);
+        let gain = self.advance(target);
+        for sample in output {
+            *sample *= gain;
+        }
+    }
+
+    fn process(&mut self, size: usize, input: &BufferRef, output: &mut BufferMut) {
+        let target = self.target();
+        if target == 0.0 && self.gain == 0.0 {
+            for channel in 0..self.outputs() {
+                output.channel_mut(channel).fill(F32x::ZERO);
+            }
+            return;
+        }
+        self.unit.process(size, input, output);
+        if target == 1.0 && self.gain == 1.0 {
+            return;
+        }
+        for i in 0..size {
+            let gain = self.advance(target);
+            for channel in 0..self.outputs() {
+                output.set_f32(channel, i, output.at_f32(channel, i) * gain);
+            }
+        }
+    }
+
+    fn set(&mut self, setting: Setting) {
+        self.unit.set(setting);
+    }
+
+    fn inputs(&self) -> usize {
+        self.unit.inputs()
+    }
+
+    fn outputs(&self) -> usize {
+        self.unit.outputs()
+    }
+
+    fn route(&mut self, input: &SignalFrame, frequency: f64) -> SignalFrame {
+        self.unit.route(input, frequency)
+    }
+
+    fn get_id(&self) -> u64 {
+        97
+    }
+
+    fn ping(&mut self, probe: bool, hash: AttoHash) -> AttoHash {
+        self.unit.ping(probe, hash.hash(self.get_id()))
+    }
+
+    fn footprint(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn allocate(&mut self) {
+        self.unit.allocate();
+    }
+}
+
+/// Gain moving linearly to a shared target over `ramp_time` seconds, from silence when the
+/// graph starts: unlike `follow`, it does not jump to its first input.
+#[derive(Clone)]
+pub(crate) struct Ramp {
+    target: Shared,
+    ramp_time: f32,
+    step: f32,
+    value: f32,
+}
+
+impl Ramp {
+    pub fn new(target: &Shared, ramp_time: f32) -> Self {
+        let mut ramp = Ramp {
+            target: target.clone(),
+            ramp_time,
+            step: 1.0,
+            value: 0.0,
+        };
+        ramp.set_sample_rate(DEFAULT_SR);
+        ramp
+    }
+}
+
+impl AudioNode for Ramp {
+    const ID: u64 = 92;
+    type Inputs = U0;
+    type Outputs = U1;
+
+    fn reset(&mut self) {
+        self.value = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.step = if self.ramp_time > 0.0 {
+            (1.0 / (self.ramp_time as f64 * sample_rate)) as f32
+        } else {
+            f32::INFINITY
+        };
+    }
+
+    #[inline]
+    fn tick(&mut self, _input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let target = self.target.value();
+        self.value = if self.value < target {
+            (self.value + self.step).min(target)
+        } else {
+            (self.value - self.step).max(target)
+        };
+        [self.value].into()
+    }
+}
+
+/// Delay in seconds following a shared target over `response_time` as `follow` does, but
+/// jumping to it whenever the shared `snaps` counter changes, see `DelayMode::Snap`.
+#[derive(Clone)]
+pub(crate) struct DelayGlide {
+    target: Shared,
+    snaps: Shared,
+    /// Counter value last jumped on.
+    seen: f32,
+    follow: An<Follow<f64>>,
+}
+
+impl DelayGlide {
+    pub fn new(target: &Shared, snaps: &Shared, response_time: f32) -> Self {
+        DelayGlide {
+            target: target.clone(),
+            snaps: snaps.clone(),
+            seen: snaps.value(),
+            follow: follow(response_time),
+        }
+    }
+}
+
+impl AudioNode for DelayGlide {
+    const ID: u64 = 86;
+    type Inputs = U0;
+    type Outputs = U1;
+
+    fn reset(&mut self) {
+        self.follow.reset();
+        self.seen = self.snaps.value();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.follow.set_sample_rate(sample_rate);
+    }
+
+    #[inline]
+    fn tick(&mut self, _input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let snaps = self.snaps.value();
+        if snaps != self.seen {
+            // A reset follower jumps to its next input.
+            self.seen = snaps;
+            self.follow.reset();
+        }
+        self.follow.tick(&[self.target.value()].into())
+    }
+}
+
+/// Mono compressor of `Compressor`, following the peak level of its input in decibels.
+#[derive(Clone)]
+pub(crate) struct Compression {
+    settings: Compressor,
+    /// Share of the level gap caught up by the follower in a sample, rising and falling.
+    attack: f32,
+    release: f32,
+    /// Gain reduction in dB per dB over the threshold.
+    slope: f32,
+    makeup: f32,
+    level: f32,
+}
+
+impl Compression {
+    pub fn new(settings: Compressor) -> Self {
+        let mut compression = Compression {
+            settings,
+            attack: 1.0,
+            release: 1.0,
+            slope: 1.0 - 1.0 / settings.ratio.max(1.0),
+            makeup: db_amp(settings.makeup_db),
+            level: 0.0,
+        };
+        compression.set_sample_rate(DEFAULT_SR);
+        compression
+    }
+}
+
+/// Share of a gap a one-pole follower with time constant `time` seconds closes per sample.
+fn follower_step(time: f32, sample_rate: f64) -> f32 {
+    if time > 0.0 {
+        1.0 - (-1.0 / (time as f64 * sample_rate)).exp() as f32
+    } else {
+        1.0
+    }
+}
+
+impl AudioNode for Compression {
+    const ID: u64 = 98;
+    type Inputs = U1;
+    type Outputs = U1;
+
+    fn reset(&mut self) {
+        self.level = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.attack = follower_step(self.settings.attack, sample_rate);
+        self.release = follower_step(self.settings.release, sample_rate);
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let peak = input[0].abs();
+        let step = if peak > self.level {
+            self.attack
+        } else {
+            self.release
+        };
+        self.level += (peak - self.level) * step;
+        let over = (amp_db(self.level) - self.settings.threshold_db).max(0.0);
+        [input[0] * self.makeup * db_amp(-over * self.slope)].into()
+    }
+}
+
+/// Time constant in seconds of the RMS level a `Gate` compares to its threshold.
+const GATE_RMS_TIME: f32 = 0.01;
+
+/// Mono gate of `NoiseGate`, closing once the RMS level of its input stayed under the
+/// threshold for the hold time.
+#[derive(Clone)]
+pub(crate) struct Gate {
+    settings: NoiseGate,
+    /// Squared threshold, compared to the mean square.
+    threshold: f32,
+    rms_step: f32,
+    /// Gain change per sample while opening and closing.
+    open_step: f32,
+    close_step: f32,
+    hold_samples: u64,
+    mean_square: f32,
+    /// Samples since the level was last above the threshold.
+    silent: u64,
+    gain: f32,
+}
+
+impl Gate {
+    pub fn new(settings: NoiseGate) -> Self {
+        let mut gate = Gate {
+            settings,
+            threshold: db_amp(settings.threshold_db).powi(2),
+            rms_step: 1.0,
+            open_step: 1.0,
+            close_step: 1.0,
+            hold_samples: 0,
+            mean_square: 0.0,
+            silent: u64::MAX,
+            gain: 0.0,
+        };
+        gate.set_sample_rate(DEFAULT_SR);
+        gate
+    }
+}
+
+impl AudioNode for Gate {
+    const ID: u64 = 99;
+    type Inputs = U1;
+    type Outputs = U1;
+
+    fn reset(&mut self) {
+        self.mean_square = 0.0;
+        self.silent = u64::MAX;
+        self.gain = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        let step = |time: f32| (1.0 / (time as f64 * sample_rate).max(1.0)) as f32;
+        self.rms_step = follower_step(GATE_RMS_TIME, sample_rate);
+        self.open_step = step(self.settings.attack);
+        self.close_step = step(self.settings.release);
+        self.hold_samples = (self.settings.hold.max(0.0) as f64 * sample_rate) as u64;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        self.mean_square += (input[0] * input[0] - self.mean_square) * self.rms_step;
+        self.silent = if self.mean_square >= self.threshold {
+            0
+        } else {
+            self.silent.saturating_add(1)
+        };
+        self.gain = if self.silent <= self.hold_samples {
+            (self.gain + self.open_step).min(1.0)
+        } else {
+            (self.gain - self.close_step).max(0.0)
+        };
+        [input[0] * self.gain].into()
+    }
+}
+
+/// Circular buffer of a comb, allpass or plain delay, its length changed in place up to
+/// the capacity allocated with the sample rate.
+#[derive(Clone, Default)]
+struct Line {
+    buffer: Vec<f32>,
+    index: usize,
+    length: usize,
+    /// Low-pass state of the comb feedback.
+    damped: f32,
+}
+
+impl Line {
+    fn allocate(&mut self, capacity: usize) {
+        self.buffer = vec![0.0; Ord::max(capacity, 1)];
+        self.index = 0;
+        self.length = self.buffer.len();
+        self.damped = 0.0;
+    }
+
+    fn set_length(&mut self, length: usize) {
+        self.length = length.clamp(1, self.buffer.len());
+        if self.index >= self.length {
+            self.index = 0;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+        self.damped = 0.0;
+    }
+
+    fn advance(&mut self) {
+        self.index = (self.index + 1) % self.length;
+    }
+
+    fn delay(&mut self, input: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.buffer[self.index] = input;
+        self.advance();
+        output
+    }
+
+    fn comb(&mut self, input: f32, feedback: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.damped = output + (self.damped - output) * REVERB_DAMPING;
+        self.buffer[self.index] = input + self.damped * feedback;
+        self.advance();
+        output
+    }
+
+    fn allpass(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.index];
+        self.buffer[self.index] = input + delayed * 0.5;
+        self.advance();
+        delayed - input
+    }
+}
+
+/// Stereo room reverb, wet only: Freeverb's parallel combs and serial allpasses behind a
+/// pre-delay. The decay time, room size and pre-delay in seconds are read from shareds, the
+/// lines being resized in place so changing them never allocates.
+#[derive(Clone)]
+pub(crate) struct Reverb {
+    time: Shared,
+    size: Shared,
+    predelay: Shared,
+    /// Settings the lines are currently tuned for.
+    tuned: (f32, f32, f32),
+    sample_rate: f64,
+    predelay_line: Line,
+    /// Pre-delay in samples, 0 bypassing the line.
+    predelay_samples: usize,
+    combs: [[Line; 4]; 2],
+    feedback: [[f32; 4]; 2],
+    allpasses: [[Line; 2]; 2],
+}
+
+impl Reverb {
+    pub fn new(time: &Shared, size: &Shared, predelay: &Shared) -> Self {
+        let mut reverb = Reverb {
+            time: time.clone(),
+            size: size.clone(),
+            predelay: predelay.clone(),
+            tuned: (f32::NAN, f32::NAN, f32::NAN),
+            sample_rate: DEFAULT_SR,
+            predelay_line: Line::default(),
+            predelay_samples: 0,
+            combs: Default::default(),
+            feedback: [[0.0; 4]; 2],
+            allpasses: Default::default(),
+        };
+        reverb.set_sample_rate(DEFAULT_SR);
+        reverb
+    }
+
+    /// Delay in samples of a line tuned to `samples` at 44.1 kHz, scaled by `scale`.
+    fn scaled(&self, samples: usize, scale: f32) -> usize {
+        (samples as f64 * scale as f64 * self.sample_rate / 44100.0).round() as usize
+    }
+
+    fn tune(&mut self, time: f32, size: f32, predelay: f32) {
+        self.tuned = (time, size, predelay);
+        let scale = size.clamp(ROOM_SIZE_RANGE.0, ROOM_SIZE_RANGE.1) / REVERB_REFERENCE_SIZE;
+        for channel in 0..2 {
+            let spread = channel * REVERB_SPREAD;
+            for (k, &samples) in REVERB_COMBS.iter().enumerate() {
+                let length = self.scaled(samples + spread, scale);
+                self.combs[channel][k].set_length(length);
+                // Loses 60 dB over the decay time.
+                self.feedback[channel][k] = if time > 0.0 {
+                    10.0_f32.powf(-3.0 * length as f32 / (self.sample_rate as f32 * time))
+                } else {
+                    0.0
+                };
+            }
+            for (k, &samples) in REVERB_ALLPASSES.iter().enumerate() {
+                let length = self.scaled(samples + spread, scale);
+                self.allpasses[channel][k].set_length(length);
+            }
+        }
+        self.predelay_samples =
+            (predelay.clamp(0.0, MAX_PREDELAY) as f64 * self.sample_rate).round() as usize;
+        self.predelay_line.set_length(self.predelay_samples);
+    }
+}
+
+impl AudioNode for Reverb {
+    const ID: u64 = 91;
+    type Inputs = U2;
+    type Outputs = U2;
+
+    fn reset(&mut self) {
+        self.predelay_line.clear();
+        let combs = self.combs.iter_mut().flatten();
+        for line in combs.chain(self.allpasses.iter_mut().flatten()) {
+            line.clear();
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        let max_scale = ROOM_SIZE_RANGE.1 / REVERB_REFERENCE_SIZE;
+        for channel in 0..2 {
+            let spread = channel * REVERB_SPREAD;
+            for (k, &samples) in REVERB_COMBS.iter().enumerate() {
+                let capacity = self.scaled(samples + spread, max_scale);
+                self.combs[channel][k].allocate(capacity);
+            }
+            for (k, &samples) in REVERB_ALLPASSES.iter().enumerate() {
+                let capacity = self.scaled(samples + spread, max_scale);
+                self.allpasses[channel][k].allocate(capacity);
+            }
+        }
+        self.predelay_line
+            .allocate((MAX_PREDELAY as f64 * sample_rate).round() as usize);
+        self.tune(self.time.value(), self.size.value(), self.predelay.value());
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let settings = (self.time.value(), self.size.value(), self.predelay.value());
+        if settings != self.tuned {
+            self.tune(settings.0, settings.1, settings.2);
+        }
+        let mut mono = (input[0] + input[1]) * REVERB_INPUT_GAIN;
+        if self.predelay_samples > 0 {
+            mono = self.predelay_line.delay(mono);
+        }
+        let mut output = [0.0; 2];
+        for (channel, output) in output.iter_mut().enumerate() {
+            let mut wet = 0.0;
+            for (comb, &feedback) in self.combs[channel].iter_mut().zip(&self.feedback[channel]) {
+                wet += comb.comb(mono, feedback);
+            }
+            for allpass in &mut self.allpasses[channel] {
+                wet = allpass.allpass(wet);
+            }
+            *output = wet;
+        }
+        output.into()
+    }
+}
+
+/// Delay in seconds behind the direct sound and left and right gains of an early reflection,
+/// set by the control thread.
+#[derive(Clone)]
+pub(crate) struct Reflection {
+    pub delay: Shared,
+    pub left: Shared,
+    pub right: Shared,
+}
+
+impl Reflection {
+    pub fn new() -> Self {
+        Reflection {
+            delay: shared(0.0),
+            left: shared(0.0),
+            right: shared(0.0),
+        }
+    }
+}
+
+/// Early reflections of a mono source, wet only: one delay line read at the delay of each
+/// reflection and panned with its gains, all of them glided over `smoothing` seconds so
+/// moving sources do not zip. The line holds `MAX_REFLECTION_DELAY` seconds, allocated with
+/// the sample rate.
+#[derive(Clone)]
+pub(crate) struct EarlyReflections {
+    reflections: Vec<Reflection>,
+    /// Glided delay in samples and gains of each reflection.
+    glided: Vec<(f32, f32, f32)>,
+    smoothing: f32,
+    coefficient: f32,
+    sample_rate: f32,
+    line: Vec<f32>,
+    index: usize,
+}
+
+impl EarlyReflections {
+    pub fn new(reflections: &[Reflection], smoothing: f32) -> Self {
+        let mut early = EarlyReflections {
+            reflections: reflections.to_vec(),
+            glided: vec![(0.0, 0.0, 0.0); reflections.len()],
+            smoothing,
+            coefficient: 1.0,
+            sample_rate: DEFAULT_SR as f32,
+            line: Vec::new(),
+            index: 0,
+        };
+        early.set_sample_rate(DEFAULT_SR);
+        early
+    }
+}
+
+impl AudioNode for EarlyReflections {
+    const ID: u64 = 95;
+    type Inputs = U1;
+    type Outputs = U2;
+
+    fn reset(&mut self) {
+        self.line.fill(0.0);
+        self.glided.fill((0.0, 0.0, 0.0));
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate as f32;
+        self.coefficient = if self.smoothing > 0.0 {
+            1.0 - (-1.0 / (self.smoothing as f64 * sample_rate)).exp() as f32
+        } else {
+            1.0
+        };
+        // Room for the longest delay and the sample after it.
+        let capacity = (MAX_REFLECTION_DELAY as f64 * sample_rate).ceil() as usize + 2;
+        self.line = vec![0.0; capacity];
+        self.index = 0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let length = self.line.len();
+        self.line[self.index] = input[0];
+        let max_delay = (length - 2) as f32;
+        let (mut left, mut right) = (0.0, 0.0);
+        for (reflection, glided) in self.reflections.iter().zip(&mut self.glided) {
+            let delay = (reflection.delay.value() * self.sample_rate).clamp(0.0, max_delay);
+            glided.0 += (delay - glided.0) * self.coefficient;
+            glided.1 += (reflection.left.value() - glided.1) * self.coefficient;
+            glided.2 += (reflection.right.value() - glided.2) * self.coefficient;
+            if glided.1 == 0.0 && glided.2 == 0.0 {
+                continue;
+            }
+            // Linear interpolation between the samples around the fractional delay.
+            let whole = glided.0 as usize;
+            let a = self.line[(self.index + length - whole) % length];
+            let b = self.line[(self.index + length - whole - 1) % length];
+            let sample = a + (b - a) * (glided.0 - whole as f32);
+            left += sample * glided.1;
+            right += sample * glided.2;
+        }
+        self.index = (self.index + 1) % length;
+        [left, right].into()
+    }
+}
+
+/// Uniformly partitioned convolution of a mono source with the left and right responses of
+/// the `HrtfSet` measurement picked by `selected`, overlap-save in blocks of `HRTF_BLOCK`
+/// samples: the output lags one block behind. A change of measurement is crossfaded over
+/// the next block. The spectra are computed again on sample rate changes.
+#[derive(Clone)]
+pub(crate) struct HrtfConvolver {
+    set: Arc<HrtfSet>,
+    spectra: Arc<HrirSpectra>,
+    sample_rate: f64,
+    selected: Shared,
+    /// Measurement heard over the last block.
+    current: usize,
+    /// Last two input blocks, the latest one being filled.
+    window: Vec<f32>,
+    /// Spectra of the latest windows, one per partition, the newest one at `newest`.
+    history: Vec<Complex32>,
+    newest: usize,
+    /// Output of the last block, played while the next one fills.
+    output: Vec<(f32, f32)>,
+    position: usize,
+    spectrum: Vec<Complex32>,
+    full: Vec<Complex32>,
+    time: Vec<Complex32>,
+}
+
+impl HrtfConvolver {
+    pub fn new(set: Arc<HrtfSet>, selected: &Shared) -> Self {
+        let spectra = Arc::new(set.spectra(DEFAULT_SR));
+        let mut convolver = HrtfConvolver {
+            set,
+            spectra,
+            sample_rate: DEFAULT_SR,
+            selected: selected.clone(),
+            current: 0,
+            window: vec![0.0; 2 * HRTF_BLOCK],
+            history: Vec::new(),
+            newest: 0,
+            output: vec![(0.0, 0.0); HRTF_BLOCK],
+            position: 0,
+            spectrum: vec![Complex32::default(); HRTF_BLOCK + 1],
+            full: vec![Complex32::default(); 2 * HRTF_BLOCK],
+            time: vec![Complex32::default(); 2 * HRTF_BLOCK],
+        };
+        convolver.reset();
+        convolver
+    }
+
+    /// Convolve the input history with the response of `ear` of `measurement` into `time`,
+    /// whose second half holds the block.
+    fn convolve(&mut self, measurement: usize, ear: usize) {
+        let (partitions, bins) = (self.spectra.partitions, self.spectra.bins);
+        self.spectrum.fill(Complex32::default());
+        for partition in 0..partitions {
+            let slot = (self.newest + partitions - partition) % partitions * bins;
+            let input = &self.history[slot..slot + bins];
+            let filter = self.spectra.partition(measurement, ear, partition);
+            for ((sum, x), h) in self.spectrum.iter_mut().zip(input).zip(filter) {
+                *sum += x * h;
+            }
+        }
+        // Hermitian spectrum of the real block.
+        self.full[..bins].copy_from_slice(&self.spectrum);
+        for k in 1..HRTF_BLOCK {
+            self.full[2 * HRTF_BLOCK - k] = self.spectrum[k].conj();
+        }
+        inverse_fft(&self.full, &mut self.time);
+    }
+
+    fn process_block(&mut self) {
+        let (partitions, bins) = (self.spectra.partitions, self.spectra.bins);
+        self.newest = (self.newest + 1) % partitions;
+        let slot = self.newest * bins;
+        real_fft(&self.window, &mut self.history[slot..slot + bins]);
+        self.window.copy_within(HRTF_BLOCK.., 0);
+
+        let last = self.spectra.measurements() - 1;
+        let target = Ord::min(self.selected.value().max(0.0) as usize, last);
+        for ear in 0..2 {
+            self.convolve(target, ear);
+            for (output, sample) in self.output.iter_mut().zip(&self.time[HRTF_BLOCK..]) {
+                let output = if ear == 0 {
+                    &mut output.0
+                } else {
+                    &mut output.1
+                };
+                *output = sample.re;
+            }
+        }
+        if target != self.current {
+            for ear in 0..2 {
+                self.convolve(self.current, ear);
+                let previous = self.time[HRTF_BLOCK..].iter();
+                for (i, (output, sample)) in self.output.iter_mut().zip(previous).enumerate() {
+                    let fade = (i + 1) as f32 / HRTF_BLOCK as f32;
+                    let output = if ear == 0 {
+                        &mut output.0
+                    } else {
+                        &mut output.1
+                    };
+                    *output = sample.re + (*output - sample.re) * fade;
+                }
+            }
+            self.current = target;
+        }
+    }
+}
+
+impl AudioNode for HrtfConvolver {
+    const ID: u64 = 96;
+    type Inputs = U1;
+    type Outputs = U2;
+
+    fn reset(&mut self) {
+        let (partitions, bins) = (self.spectra.partitions, self.spectra.bins);
+        self.window.fill(0.0);
+        self.history = vec![Complex32::default(); partitions * bins];
+        self.newest = 0;
+        self.output.fill((0.0, 0.0));
+        self.position = 0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.spectra = Arc::new(self.set.spectra(sample_rate));
+            self.reset();
+        }
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let (left, right) = self.output[self.position];
+        self.window[HRTF_BLOCK + self.position] = input[0];
+        self.position += 1;
+        if self.position == HRTF_BLOCK {
+            self.position = 0;
+            self.process_block();
+        }
+        [left, right].into()
+    }
+}
+
+/// Sample of the first channel of `wave` at fractional `position`, linearly interpolated
+/// with the next one, wrapping around at the end when `looping`.
+fn sample_at(wave: &Wave, position: f64, looping: bool) -> f32 {
+    let index = position as usize;
+    let next = if looping {
+        (index + 1) % wave.length()
+    } else {
+        Ord::min(index + 1, wave.length() - 1)
+    };
+    let a = wave.at(0, index);
+    a + (wave.at(0, next) - a) * (position - index as f64) as f32
+}
+
+/// Wave samples to step over per output sample at unit playback rate, resampling waves
+/// recorded at another rate than the output one.
+fn wave_step(wave: &Wave, sample_rate: f64) -> f64 {
+    wave.sample_rate() / sample_rate
+}
+
+/// Loops the first channel of a wave at the playback rate given by input 0, crossfading
+/// its last `crossfade` seconds into its start so the seam does not click.
+#[derive(Clone)]
+pub(crate) struct LoopWave {
+    wave: Arc<Wave>,
+    crossfade: f32,
+    fade_samples: f64,
+    position: f64,
+    step: f64,
+    sample_rate: f64,
+}
+
+impl LoopWave {
+    pub fn new(wave: Arc<Wave>, crossfade: f32) -> Self {
+        let mut loop_wave = LoopWave {
+            wave,
+            crossfade,
+            fade_samples: 0.0,
+            position: 0.0,
+            step: 1.0,
+            sample_rate: DEFAULT_SR,
+        };
+        loop_wave.set_sample_rate(DEFAULT_SR);
+        loop_wave
+    }
+}
+
+impl AudioNode for LoopWave {
+    const ID: u64 = 90;
+    type Inputs = U1;
+    type Outputs = U1;
+
+    fn reset(&mut self) {
+        self.position = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        // The crossfade counts wave samples, at most half the wave.
+        let fade = (self.crossfade.max(0.0) as f64 * self.wave.sample_rate()).round();
+        self.fade_samples = fade.min((self.wave.length() / 2) as f64);
+        self.step = wave_step(&self.wave, sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let length = self.wave.length() as f64;
+        let fade_start = length - self.fade_samples;
+        let mut sample = sample_at(&self.wave, self.position, true);
+        if self.position > fade_start {
+            // The start of the wave fades in over its end, playback then resuming past it.
+            let t = ((self.position - fade_start) / self.fade_samples) as f32;
+            let start = sample_at(&self.wave, self.position - fade_start, true);
+            sample += (start - sample) * t;
+        }
+        self.position += input[0].max(0.0) as f64 * self.step;
+        if self.position >= length {
+            self.position = self.fade_samples + (self.position - length) % fade_start;
+        }
+        [sample].into()
+    }
+}
+
+impl WavePlayer for LoopWave {
+    fn set_wave(&mut self, wave: Arc<Wave>) {
+        self.wave = wave;
+        self.set_sample_rate(self.sample_rate);
+        self.reset();
+    }
+}
+
+/// Plays the first channel of a wave once at the playback rate given by input 0, fading it
+/// out over its last `fade_time` seconds, and raises `finished` after its last sample.
+#[derive(Clone)]
+pub(crate) struct OneShot {
+    wave: Arc<Wave>,
+    fade_time: f32,
+    fade_samples: usize,
+    position: f64,
+    step: f64,
+    sample_rate: f64,
+    finished: Shared,
+}
+
+impl OneShot {
+    pub fn new(wave: Arc<Wave>, fade_time: f32, finished: &Shared) -> Self {
+        let mut one_shot = OneShot {
+            wave,
+            fade_time,
+            fade_samples: 1,
+            position: 0.0,
+            step: 1.0,
+            sample_rate: DEFAULT_SR,
+            finished: finished.clone(),
+        };
+        one_shot.set_sample_rate(DEFAULT_SR);
+        one_shot
+    }
+}
+
+impl AudioNode for OneShot {
+    const ID: u64 = 89;
+    type Inputs = U1;
+    type Outputs = U1;
+
+    fn reset(&mut self) {
+        self.position = 0.0;
+        self.finished.set_value(0.0);
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        // The fade counts wave samples.
+        let wave_rate = self.wave.sample_rate();
+        self.fade_samples = Ord::max((self.fade_time as f64 * wave_rate).round() as usize, 1);
+        self.step = wave_step(&self.wave, sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let length = self.wave.length() as f64;
+        if self.position >= length {
+            return [0.0].into();
+        }
+        let remaining = (length - 1.0 - self.position) as f32;
+        let gain = (remaining / self.fade_samples as f32).clamp(0.0, 1.0);
+        let sample = sample_at(&self.wave, self.position, false) * gain;
+        self.position += input[0].max(0.0) as f64 * self.step;
+        if self.position >= length {
+            self.finished.set_value(1.0);
+        }
+        [sample].into()
+    }
+}
+
+impl WavePlayer for OneShot {
+    fn set_wave(&mut self, wave: Arc<Wave>) {
+        self.wave = wave;
+        self.set_sample_rate(self.sample_rate);
+        self.reset();
+    }
+}
+
+/// Plays the samples of a `FileStream` at the playback rate given by input 0, silent while
+/// its decoder falls behind, and raises `finished` once a stream played once ran out.
+#[derive(Clone)]
+pub(crate) struct StreamWave {
+    samples: Receiver<f32>,
+    wave_rate: f64,
+    step: f64,
+    /// Fractional position between the current and next samples.
+    position: f64,
+    current: f32,
+    next: f32,
+    finished: Shared,
+}
+
+impl StreamWave {
+    pub fn new(stream: FileStream, finished: &Shared) -> Self {
+        StreamWave {
+            samples: stream.samples,
+            wave_rate: stream.sample_rate,
+            step: stream.sample_rate / DEFAULT_SR,
+            position: 0.0,
+            current: 0.0,
+            next: 0.0,
+            finished: finished.clone(),
+        }
+    }
+}
+
+impl AudioNode for StreamWave {
+    const ID: u64 = 94;
+    type Inputs = U1;
+    type Outputs = U1;
+
+    fn reset(&mut self) {
+        // A stream cannot rewind: playback goes on from where the decoder is.
+        self.position = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.step = self.wave_rate / sample_rate;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        let sample = self.current + (self.next - self.current) * self.position as f32;
+        self.position += input[0].max(0.0) as f64 * self.step;
+        while self.position >= 1.0 {
+            self.position -= 1.0;
+            self.current = self.next;
+            self.next = match self.samples.try_recv() {
+                Ok(next) => next,
+                Err(TryRecvError::Empty) => 0.0,
+                Err(TryRecvError::Disconnected) => {
+                    self.finished.set_value(1.0);
+                    0.0
+                }
+            };
+        }
+        [sample].into()
+    }
+}
+
+/// Wave player whose wave can be switched while it plays, see `WaveSwap`.
+pub(crate) trait WavePlayer: AudioNode<Inputs = U1, Outputs = U1> {
+    /// Play `wave` from its start, resampled from its own sample rate to the output one.
+    fn set_wave(&mut self, wave: Arc<Wave>);
+}
+
+/// Control side of a `WaveSwap`, handing it new waves.
+#[derive(Clone)]
+pub(crate) struct WaveHandoff {
+    sender: Sender<Arc<Wave>>,
+    receiver: Receiver<Arc<Wave>>,
+    /// Every wave handed over, kept alive here so the audio thread never frees one.
+    waves: Arc<Mutex<Vec<Arc<Wave>>>>,
+}
+
+impl WaveHandoff {
+    /// Handoff to a player of `wave`, along with the receiving end for its `WaveSwap`.
+    pub(crate) fn new(wave: &Arc<Wave>) -> (Self, Receiver<Arc<Wave>>) {
+        let (sender, receiver) = bounded(1);
+        let handoff = WaveHandoff {
+            sender,
+            receiver: receiver.clone(),
+            waves: Arc::new(Mutex::new(vec![wave.clone()])),
+        };
+        (handoff, receiver)
+    }
+
+    /// Switch the player to `wave`, replacing a wave sent before and not picked up yet.
+    pub(crate) fn send(&self, wave: Wave) {
+        let wave = Arc::new(wave);
+        let mut waves = self.waves.lock().unwrap_or_else(PoisonError::into_inner);
+        for _ in self.receiver.try_iter() {}
+        // Only held here, the player dropped them.
+        waves.retain(|wave| Arc::strong_count(wave) > 1);
+        waves.push(wave.clone());
+        let _ = self.sender.try_send(wave);
+    }
+}
+
+/// Switches the wave of `player` to the ones handed over by a `WaveHandoff`, dipping to
+/// silence over `WAVE_SWAP_FADE` on each side of the switch. Nothing is allocated nor freed.
+#[derive(Clone)]
+pub(crate) struct WaveSwap<P> {
+    player: P,
+    waves: Receiver<Arc<Wave>>,
+    pending: Option<Arc<Wave>>,
+    gain: f32,
+    fade_step: f32,
+}
+
+impl<P: WavePlayer> WaveSwap<P> {
+    pub fn new(player: P, waves: Receiver<Arc<Wave>>) -> Self {
+        let mut swap = WaveSwap {
+            player,
+            waves,
+            pending: None,
+            gain: 1.0,
+            fade_step: 1.0,
+        };
+        swap.set_sample_rate(DEFAULT_SR);
+        swap
+    }
+}
+
+impl<P: WavePlayer> AudioNode for WaveSwap<P> {
+    const ID: u64 = 93;
+    type Inputs = U1;
+    type Outputs = U1;
+
+    fn reset(&mut self) {
+        self.player.reset();
+        self.gain = 1.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.fade_step =
+            1.0 / Ord::max((WAVE_SWAP_FADE as f64 * sample_rate).round() as usize, 1) as f32;
+        self.player.set_sample_rate(sample_rate);
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        if self.pending.is_none() {
+            self.pending = self.waves.try_recv().ok();
+        }
+        if self.pending.is_some() {
+            self.gain = (self.gain - self.fade_step).max(0.0);
+            if self.gain == 0.0 {
+                if let Some(wave) = self.pending.take() {
+                    self.player.set_wave(wave);
+                }
+            }
+        } else {
+            self.gain = (self.gain + self.fade_step).min(1.0);
+        }
+        let sample = self.player.tick(input)[0] * self.gain;
+        [sample].into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hrtf::Hrir;
+
+    #[test]
+    fn stereo_width_scales_the_side() {
+        assert_eq!(stereo_width(1.0, 0.0, 0.0), (0.5, 0.5));
+        assert_eq!(stereo_width(1.0, 0.0, 1.0), (1.0, 0.0));
+        assert_eq!(stereo_width(1.0, 0.0, 2.0), (1.5, -0.5));
+        // Mid stays whatever the width.
+        assert_eq!(stereo_width(0.3, 0.3, 2.0), (0.3, 0.3));
+    }
+
+    #[test]
+    fn looping_wave_repeats_without_a_seam() {
+        // A sawtooth, jumping from 1 back to 0 when looped as is.
+        let saw: Vec<f32> = (0..1000).map(|i| i as f32 / 1000.0).collect();
+        let mut wave = Wave::new(0, 1000.0);
+        wave.push_channel(&saw);
+        let mut player = dc(1.0) >> An(LoopWave::new(Arc::new(wave), 0.1));
+        player.set_sample_rate(1000.0);
+        let output: Vec<f32> = (0..5000).map(|_| player.get_mono()).collect();
+        let seam = output
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max);
+        assert!(seam < 0.01, "{seam}");
+        // Repeating every 900 samples past the first pass rather than going silent.
+        for i in 1000..4000 {
+            assert!((output[i] - output[i + 900]).abs() < 1e-5);
+        }
+        assert!(output[4000..].iter().any(|&s| s > 0.5));
+    }
+
+    #[test]
+    fn swapped_wave_dips_to_silence_around_the_switch() {
+        let constant = |value, sample_rate| Wave::render(sample_rate, 0.1, &mut dc(value));
+        let first = Arc::new(constant(1.0, 1000.0));
+        let (handoff, waves) = WaveHandoff::new(&first);
+        let mut player = dc(1.0) >> An(WaveSwap::new(LoopWave::new(first, 0.0), waves));
+        player.set_sample_rate(1000.0);
+        assert_eq!(player.get_mono(), 1.0);
+        // At another sample rate than the output, resampled.
+        handoff.send(constant(-0.5, 500.0));
+        let output: Vec<f32> = (0..100).map(|_| player.get_mono()).collect();
+        let jump = output
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max);
+        assert!(jump <= 0.1 + 1e-6, "{jump}");
+        assert!(output.contains(&0.0));
+        assert_eq!(output[99], -0.5);
+        // The first wave is no longer played, and freed on the next switch.
+        handoff.send(constant(0.25, 1000.0));
+        assert_eq!(handoff.waves.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn culled_units_pause_and_fade_back_in() {
+        let saw: Vec<f32> = (0..1000).map(|i| i as f32 / 1000.0).collect();
+        let mut wave = Wave::new(0, 1000.0);
+        wave.push_channel(&saw);
+        let player = dc(1.0) >> An(LoopWave::new(Arc::new(wave), 0.0));
+        let active = shared(1.0);
+        let mut cull = Cull::new(Box::new(player), &active, 0.01);
+        cull.set_sample_rate(1000.0);
+        let output: Vec<f32> = (0..100).map(|_| cull.get_mono()).collect();
+        assert!((output[99] - 0.099).abs() < 1e-6);
+        active.set_value(0.0);
+        let output: Vec<f32> = (0..500).map(|_| cull.get_mono()).collect();
+        assert!(output[..9].iter().all(|&sample| sample > 0.0));
+        assert!(output[9..].iter().all(|&sample| sample == 0.0));
+        // Resumed from where it stopped, not ticked meanwhile.
+        active.set_value(1.0);
+        let output: Vec<f32> = (0..20).map(|_| cull.get_mono()).collect();
+        assert!((output[0] - 0.1 * 0.11).abs() < 1e-6, "{}", output[0]);
+        assert!((output[19] - 0.129).abs() < 1e-6, "{}", output[19]);
+    }
+
+    #[test]
+    fn compression_narrows_the_dynamic_range() {
+        let settings = Compressor::default();
+        let mut compression = Compression::new(settings);
+        compression.set_sample_rate(48000.0);
+        // Half a second of whisper, then half a second of shouting 40 dB louder.
+        let mut peaks = [0.0f32; 2];
+        for i in 0..48000 {
+            let amplitude = if i < 24000 { 0.005 } else { 0.5 };
+            let tone = amplitude * (i as f32 * 0.05).sin();
+            let out = compression.tick(&[tone].into())[0];
+            // Past the attack and release.
+            if i % 24000 > 12000 {
+                peaks[i / 24000] = peaks[i / 24000].max(out.abs());
+            }
+        }
+        let range = amp_db(peaks[1]) - amp_db(peaks[0]);
+        // 40 dB in, 4 to 1 over the threshold.
+        assert!(range > 20.0 && range < 25.0, "{}", range);
+        // The whisper under the threshold only gets the makeup gain.
+        assert!((amp_db(peaks[0]) - amp_db(0.005) - settings.makeup_db).abs() < 0.5);
+    }
+
+    #[test]
+    fn gate_mutes_the_noise_between_words() {
+        let mut gate = Gate::new(NoiseGate::default());
+        gate.set_sample_rate(48000.0);
+        // Output samples over the input ones, 0 when muted and 1 when open.
+        let mut gains = |amplitude: f32, samples: usize| -> Vec<f32> {
+            (0..samples)
+                .map(|i| {
+                    let tone = amplitude * (i as f32 * 0.05 + 0.5).sin().signum();
+                    gate.tick(&[tone].into())[0] / tone
+                })
+                .collect()
+        };
+        // Hiss at -66 dB stays muted, speech at -20 dB opens the gate within the attack.
+        assert!(gains(0.0005, 4800).iter().all(|&gain| gain == 0.0));
+        assert!(gains(0.1, 4800)[2400..].iter().all(|&gain| gain == 1.0));
+        // Open through the hold of 200 ms, closed 100 ms later.
+        let pause = gains(0.0005, 24000);
+        assert_eq!(pause[4800], 1.0);
+        assert!(pause[19200..].iter().all(|&gain| gain == 0.0));
+    }
+
+    #[test]
+    fn hrtf_convolver_applies_the_selected_response() {
+        let mut right = vec![0.0; 201];
+        right[200] = 0.5;
+        let delayed = Hrir {
+            azimuth: 90.0,
+            elevation: 0.0,
+            left: vec![1.0],
+            right,
+        };
+        let silent = Hrir {
+            azimuth: -90.0,
+            elevation: 0.0,
+            left: vec![0.0],
+            right: vec![0.0],
+        };
+        let set = Arc::new(HrtfSet::new(48000.0, vec![delayed, silent]).unwrap());
+        let selected = shared(0.0);
+        let mut convolver = HrtfConvolver::new(set, &selected);
+        convolver.set_sample_rate(48000.0);
+        let mut ears = |x: f32| {
+            let output = convolver.tick(&[x].into());
+            (output[0], output[1])
+        };
+        let input: Vec<f32> = (0..1000)
+            .map(|i| ((i * 7919) % 101) as f32 / 100.0)
+            .collect();
+        let output: Vec<(f32, f32)> = input.iter().map(|&x| ears(x)).collect();
+        // Delayed by a block, the right ear by 200 samples more across two partitions.
+        for i in HRTF_BLOCK..1000 {
+            assert!((output[i].0 - input[i - HRTF_BLOCK]).abs() < 1e-4);
+        }
+        for i in HRTF_BLOCK + 200..1000 {
+            assert!((output[i].1 - 0.5 * input[i - HRTF_BLOCK - 200]).abs() < 1e-4);
+        }
+        // Switching measurement fades to the other response over a block.
+        selected.set_value(1.0);
+        let output: Vec<(f32, f32)> = (0..4 * HRTF_BLOCK).map(|_| ears(1.0)).collect();
+        assert!(output[3 * HRTF_BLOCK..]
+            .iter()
+            .all(|&(l, r)| l == 0.0 && r == 0.0));
+    }
+}
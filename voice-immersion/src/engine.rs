@@ -0,0 +1,4246 @@
+use std::any::Any;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use cpal::traits::HostTrait;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use fundsp::hacker::*;
+use fundsp::wave::Wave;
+use nalgebra::Vector3;
+
+use crate::analyzer::SpectrumAnalyzer;
+use crate::attenuation::AttenuationModel;
+use crate::capture::spawn_capture;
+use crate::clock::Instant;
+use crate::config::{
+    DelayMode, EngineConfig, OutputFormat, OutputMode, PanLaw, ReverbSettings, SpatializationMode,
+};
+use crate::decode::FileStream;
+use crate::devices::{find_input_device, find_output_device};
+use crate::dsp::{
+    crossfeed, Bypass, Compression, DelayGlide, EarlyReflections, Gate, HrtfConvolver, LoopWave,
+    OneShot, Reflection, Reverb, StreamWave, WaveHandoff, WaveSwap, MAX_REFLECTION_DELAY,
+};
+use crate::gain::Decibels;
+use crate::recorder::Recorder;
+use crate::room::{image_sources, MAX_REFLECTIONS};
+use crate::scene::{Scene, SourceId, SourceUpdate};
+use crate::sink::{AudioSink, CpalSink, OutputConfig, Renderer};
+use crate::spatial::{
+    ear_delays, elevation, head_direction, interaural_time_difference, relative_info, spatialize,
+    stereo_channel, Listener, Source, MAX_ITD,
+};
+use crate::telemetry::{EngineEvent, EngineStats, Latency, OutputLevels, SourceMetrics, Telemetry};
+use crate::VoiceImmersionError;
+use crate::{
+    doppler_factor, mic_capacity, obstruction_cutoff, obstruction_gain, propagation_delay,
+    wall_amount, wall_transmission, walls_crossed, InAnotherRoom, InputNode, RoomId, SourceInfo,
+    WallFilter, World, FORWARD_VECTOR, UP_VECTOR,
+};
+
+/// Signal fed into the spatializer.
+pub enum InputSource {
+    /// Loop the first channel of a decoded wave, crossfading over
+    /// `EngineConfig::loop_crossfade` at the seam.
+    Wave(Wave),
+    /// Play the first channel of a decoded wave once, fading out over
+    /// `EngineConfig::one_shot_fade`, then publish `EngineEvent::SourceFinished`.
+    OneShot(Wave),
+    /// Play a file decoded as it plays, see `FileStream`. Its wave cannot be switched.
+    Stream(FileStream),
+    /// Loop the first two channels of a decoded wave, such as a stereo ambience or music,
+    /// from two virtual point sources `SourceInfo::stereo_width` apart across the line of
+    /// sight, so that the stereo field stays as wide around the source. Each channel gets a
+    /// spatialization chain of its own, at twice the cost of a mono source. Building fails
+    /// on waves with fewer channels, and its wave cannot be switched.
+    Stereo(Wave),
+    /// Play any unit with no inputs and one output, such as a synth, noise or a network
+    /// stream: the wave inputs are built on such units. Building fails on other arities. The
+    /// unit gets the output sample rate through `AudioUnit::set_sample_rate`, again on device
+    /// switches, and must render at it. It takes the place of the wave, its pitch not
+    /// following the Doppler effect and its wave not being switchable.
+    Node(Box<dyn AudioUnit>),
+    /// Stereo frames pushed by a capture stream (see `start_input`).
+    Mic(Receiver<(f32, f32)>),
+}
+
+impl InputSource {
+    pub fn kind(&self) -> SourceKind {
+        match self {
+            InputSource::Wave(_)
+            | InputSource::OneShot(_)
+            | InputSource::Stream(_)
+            | InputSource::Stereo(_)
+            | InputSource::Node(_) => SourceKind::Wave,
+            InputSource::Mic(_) => SourceKind::Mic,
+        }
+    }
+}
+
+/// Where a source is heard from by one listener.
+#[derive(Clone)]
+pub enum Placement {
+    /// Source relative to the listener, kept up to date by the caller.
+    Relative(Arc<RwLock<SourceInfo>>),
+    /// Listener and source in world coordinates, the engine working out where the source
+    /// stands from the listener.
+    World {
+        listener: Arc<RwLock<Listener>>,
+        source: Arc<RwLock<Source>>,
+    },
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement::Relative(Default::default())
+    }
+}
+
+impl From<Arc<RwLock<SourceInfo>>> for Placement {
+    fn from(source_info: Arc<RwLock<SourceInfo>>) -> Self {
+        Placement::Relative(source_info)
+    }
+}
+
+/// Which of the configured inputs is heard, see `SpatialHandle::set_active_source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    Wave,
+    Mic,
+}
+
+/// Default time between two control updates, see `EngineConfig::control_period`.
+pub(crate) const CONTROL_PERIOD: Duration = Duration::from_millis(5);
+
+/// Shortest control period, keeping the control thread from spinning.
+const MIN_CONTROL_PERIOD: Duration = Duration::from_millis(1);
+
+/// Source gain under which the engine counts as idle.
+const IDLE_GAIN: f32 = 1.0e-4;
+
+/// Output peak level under which the engine counts as idle, -60 dB as for reverb decay
+/// times: tails keep the output going after their sources got silent.
+const IDLE_LEVEL: f32 = 1.0e-3;
+
+/// Material filter cutoff in Hz out of any room.
+const OPEN_AIR_CUTOFF: f32 = 20000.0;
+
+/// Lowest material filter cutoff in Hz, keeping its log domain ramp finite.
+const MIN_CUTOFF: f32 = 10.0;
+
+/// Lowest Q of the band-pass and notch walls, keeping their filters stable.
+const MIN_WALL_Q: f32 = 0.1;
+
+/// Gain of the omni W channel of the ambisonic output, -3 dB as in the FuMa convention.
+const W_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Corner frequency in Hz of the elevation shelf, where the pinna cues lie.
+const ELEVATION_SHELF_HZ: f32 = 7000.0;
+
+/// Shelf gain in dB of a source right above the listener at full elevation strength,
+/// negated right below.
+const ELEVATION_SHELF_DB: f32 = 6.0;
+
+/// Corner frequency in Hz of the near field bass boost.
+const NEAR_FIELD_SHELF_HZ: f32 = 200.0;
+
+/// Center in Hz of the mid band of `AirModel`, cut by the air absorption at that frequency.
+const AIR_MID_HZ: f32 = 2000.0;
+
+/// Corner in Hz of the high band of `AirModel`, cut by the air absorption at twice it.
+const AIR_HIGH_HZ: f32 = 5000.0;
+
+/// Deepest cut in dB of each `AirModel` band.
+const MAX_AIR_CUT_DB: f32 = 60.0;
+
+/// Lowest cutoff in Hz the air absorption alone brings the material filter down to.
+const MIN_AIR_CUTOFF: f32 = 500.0;
+
+/// Time in seconds to crossfade from one input to the other.
+const SOURCE_CROSSFADE: f32 = 0.02;
+
+/// Highest reverb gain of `EngineConfig::reverb_distance_factor`, +12 dB.
+const MAX_REVERB_DISTANCE_GAIN: f32 = 4.0;
+
+/// Inputs built into the graph, all of them running and crossfaded by the active one.
+#[derive(Default)]
+pub(crate) struct Inputs {
+    /// Wave slot input, the last one given.
+    player: Option<InputSource>,
+    mic: Option<Receiver<(f32, f32)>>,
+    active: Option<SourceKind>,
+}
+
+impl Inputs {
+    fn add(&mut self, input: InputSource) {
+        self.active.get_or_insert(input.kind());
+        match input {
+            InputSource::Mic(receiver) => self.mic = Some(receiver),
+            player => self.player = Some(player),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.player.is_none() && self.mic.is_none()
+    }
+
+    /// Error on a node input of other arities than no inputs and one output.
+    pub(crate) fn check(&self) -> crate::Result<()> {
+        match &self.player {
+            Some(InputSource::Node(node)) if (node.inputs(), node.outputs()) != (0, 1) => {
+                Err(VoiceImmersionError::InvalidConfig(format!(
+                    "Node inputs need no inputs and one output, not {} and {}.",
+                    node.inputs(),
+                    node.outputs()
+                )))
+            }
+            Some(InputSource::Stereo(wave)) if wave.channels() < 2 => {
+                Err(VoiceImmersionError::InvalidConfig(format!(
+                    "Stereo inputs need two channels, the wave has {}.",
+                    wave.channels()
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<InputSource> for Inputs {
+    fn from(input: InputSource) -> Self {
+        let mut inputs = Inputs::default();
+        inputs.add(input);
+        inputs
+    }
+}
+
+/// Single entry point wiring the input, the spatialization graph and the output stream.
+#[derive(Default)]
+pub struct EngineBuilder {
+    output_device: Option<cpal::Device>,
+    host: Option<cpal::HostId>,
+    stream_config: Option<cpal::StreamConfig>,
+    /// Sample rate and buffer size to negotiate, see `preferred_stream`.
+    preferred_stream: Option<(u32, Option<u32>)>,
+    inputs: Inputs,
+    mic_device: Option<cpal::Device>,
+    /// Devices picked by name, looked up on `build`.
+    output_device_name: Option<String>,
+    mic_device_name: Option<String>,
+    placement: Option<Placement>,
+    listeners: Vec<Placement>,
+    /// Extra listeners of the world source, see `world_listener`.
+    world_listeners: Vec<Arc<RwLock<Listener>>>,
+    sink: Option<Box<dyn AudioSink>>,
+    follow_default_device: bool,
+    config: EngineConfig,
+    customize: Option<Customize>,
+    on_tick: Option<OnTick>,
+    rooms: Option<Arc<RwLock<World>>>,
+    manual_control: bool,
+}
+
+/// Graph edit given to `EngineBuilder::customize`.
+type Customize = Box<dyn FnOnce(&mut Net)>;
+
+/// Callback given to `EngineBuilder::on_tick`.
+type OnTick = Box<dyn FnMut(&SourceMetrics) + Send>;
+
+impl EngineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Output device to play on, the host default one otherwise.
+    /// Ignored when a custom sink is set.
+    pub fn output_device(mut self, device: cpal::Device) -> Self {
+        self.output_device = Some(device);
+        self
+    }
+
+    /// Output device to play on by name, see `list_output_devices`. Building fails when it
+    /// is gone. Ignored when a custom sink is set.
+    pub fn output_device_named(mut self, name: impl Into<String>) -> Self {
+        self.output_device_name = Some(name.into());
+        self
+    }
+
+    /// Audio backend to play on, e.g. ASIO or JACK, the platform default one otherwise.
+    /// See `host_by_name`. Ignored when a custom sink is set.
+    pub fn host(mut self, host: cpal::HostId) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// Stream configuration, the device default one otherwise. See `make_config`.
+    pub fn stream_config(mut self, config: cpal::StreamConfig) -> Self {
+        self.stream_config = Some(config);
+        self
+    }
+
+    /// Stream configuration of the device closest to `sample_rate` and `buffer` frames, see
+    /// `negotiate_config`: building fails when none is close enough. `stream_config` takes
+    /// precedence, see `SpatialHandle::output_config` for the one picked.
+    pub fn preferred_stream(mut self, sample_rate: u32, buffer: Option<u32>) -> Self {
+        self.preferred_stream = Some((sample_rate, buffer));
+        self
+    }
+
+    /// Signal to spatialize. Give both a wave and a mic to switch between them at runtime,
+    /// the first one given being heard at start.
+    pub fn input(mut self, input: InputSource) -> Self {
+        self.inputs.add(input);
+        self
+    }
+
+    /// Capture `device` as the mic input, its channel sized after the output buffers. The
+    /// capture reopens on the default input device after an error as
+    /// `EngineConfig::stream_recovery` tells, and stops with the handle.
+    pub fn mic(mut self, device: cpal::Device) -> Self {
+        self.inputs.active.get_or_insert(SourceKind::Mic);
+        self.mic_device = Some(device);
+        self
+    }
+
+    /// Capture the input device named `name` as the mic input, see `list_input_devices`.
+    /// Building fails when it is gone.
+    pub fn mic_named(mut self, name: impl Into<String>) -> Self {
+        self.inputs.active.get_or_insert(SourceKind::Mic);
+        self.mic_device_name = Some(name.into());
+        self
+    }
+
+    /// Input heard at start when both are given.
+    pub fn active_source(mut self, kind: SourceKind) -> Self {
+        self.inputs.active = Some(kind);
+        self
+    }
+
+    pub fn config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn attenuation(mut self, model: AttenuationModel) -> Self {
+        self.config.attenuation = model;
+        self
+    }
+
+    /// Pan law in headphones, see `EngineConfig::pan_law`.
+    pub fn pan_law(mut self, law: PanLaw) -> Self {
+        self.config.pan_law = law;
+        self
+    }
+
+    /// Reverb tuning shared by every room, see `ReverbSettings`.
+    pub fn reverb(mut self, settings: ReverbSettings) -> Self {
+        self.config.set_reverb(settings);
+        self
+    }
+
+    /// Floor of the distance attenuation, see `EngineConfig::min_gain`.
+    pub fn min_gain(mut self, gain: f32) -> Self {
+        self.config.min_gain = gain;
+        self
+    }
+
+    /// `min_gain` in decibels.
+    pub fn min_gain_db(self, gain: Decibels) -> Self {
+        self.min_gain(gain.to_linear())
+    }
+
+    /// Shared source description updated by the caller, a fresh default one otherwise.
+    pub fn source(mut self, source_info: Arc<RwLock<SourceInfo>>) -> Self {
+        self.placement = Some(Placement::Relative(source_info));
+        self
+    }
+
+    /// Describe the listener and the source in world coordinates instead, both updated by
+    /// the caller.
+    pub fn world(mut self, listener: Arc<RwLock<Listener>>, source: Arc<RwLock<Source>>) -> Self {
+        self.placement = Some(Placement::World { listener, source });
+        self
+    }
+
+    /// Rooms of the world placements, hearing each source through the walls and with the
+    /// reverb of its room, or of the listener one, see `World::heard_room`. Sources with a
+    /// `Source::room` of their own keep it.
+    pub fn rooms(mut self, world: Arc<RwLock<World>>) -> Self {
+        self.rooms = Some(world);
+        self
+    }
+
+    /// Also render the source as heard by another listener, for split-screen: `source_info`
+    /// describes the source from that listener. Listener `k`, the one of `source` being 0,
+    /// plays on output channels `2k` and `2k + 1`.
+    pub fn listener(mut self, source_info: Arc<RwLock<SourceInfo>>) -> Self {
+        self.listeners.push(Placement::Relative(source_info));
+        self
+    }
+
+    /// Also render the world source, see `world`, as heard by another listener in world
+    /// coordinates. Listeners are numbered like with `listener`.
+    pub fn world_listener(mut self, listener: Arc<RwLock<Listener>>) -> Self {
+        self.world_listeners.push(listener);
+        self
+    }
+
+    /// Move to the system default output device whenever it changes, see `EngineEvent::DeviceSwitched`.
+    /// Ignored when a custom sink is set.
+    pub fn follow_default_device(mut self, follow: bool) -> Self {
+        self.follow_default_device = follow;
+        self
+    }
+
+    /// Drive the engine from a custom sink instead of a cpal output stream.
+    pub fn sink(mut self, sink: impl AudioSink + 'static) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Edit the graph once the spatialization is wired, before it starts playing, to insert
+    /// custom processing. The net has no inputs and two outputs per listener, listener `k`
+    /// on outputs `2k` and `2k + 1`, all fed by the mix bus limiter: nodes chained onto them
+    /// with `Net::chain` process the whole mix, sources added later included.
+    pub fn customize(mut self, customize: impl FnOnce(&mut Net) + 'static) -> Self {
+        self.customize = Some(Box::new(customize));
+        self
+    }
+
+    /// Call `on_tick` on the control thread after each control pass, with the state of the
+    /// builder source as heard by the first listener, e.g. to log it. The engine prints nothing.
+    pub fn on_tick(mut self, on_tick: impl FnMut(&SourceMetrics) + Send + 'static) -> Self {
+        self.on_tick = Some(Box::new(on_tick));
+        self
+    }
+
+    /// Leave the control passes to `SpatialHandle::step_control` instead of a control
+    /// thread, e.g. to run them from the animation frames of a web page, where blocking
+    /// threads are not available: always the case on `wasm32`.
+    pub fn manual_control(mut self, manual: bool) -> Self {
+        self.manual_control = manual;
+        self
+    }
+
+    pub fn build(self) -> crate::Result<SpatialHandle> {
+        self.config.check()?;
+        let sink: Box<dyn AudioSink> = match self.sink {
+            Some(sink) => sink,
+            None => {
+                let host = self.host.unwrap_or_else(|| cpal::default_host().id());
+                let device = match (self.output_device, &self.output_device_name) {
+                    (Some(device), _) => Some(device),
+                    (None, Some(name)) => Some(find_output_device(host, name)?),
+                    (None, None) => None,
+                };
+                let mut sink = match device {
+                    Some(device) => CpalSink::new(device)?,
+                    None => CpalSink::host_default_device(host)?,
+                };
+                sink.set_host(host);
+                if let Some(config) = self.stream_config {
+                    sink.set_checked_config(config)?;
+                } else {
+                    if let Some((sample_rate, buffer)) = self.preferred_stream {
+                        sink.set_negotiated_config(sample_rate, buffer)?;
+                    }
+                    // As many channels as the listeners need in the output format, when the
+                    // device has them: warned about below otherwise.
+                    let listeners = 1 + self.listeners.len() + self.world_listeners.len();
+                    let needed = listeners * self.config.output_format.channels();
+                    if sink.channels() < needed {
+                        let _ = sink.set_channels(needed as u16);
+                    }
+                }
+                sink.set_follow_default_device(self.follow_default_device);
+                sink.set_pause_when_idle(self.config.idle_pause_timeout.is_some());
+                sink.set_output_fade(Duration::from_secs_f32(self.config.output_fade.max(0.0)));
+                sink.set_recovery(self.config.stream_recovery);
+                Box::new(sink)
+            }
+        };
+        let clip_hold = Duration::from_secs_f32(self.config.clip_hold.max(0.0));
+        let (telemetry, events) = Telemetry::new(clip_hold);
+        let mut inputs = self.inputs;
+        let capacity = self
+            .config
+            .mic_queue
+            .unwrap_or_else(|| mic_capacity(sink.sample_rate(), sink.buffer_frames()));
+        let host = self.host.unwrap_or_else(|| cpal::default_host().id());
+        let mic_device = match (self.mic_device, &self.mic_device_name) {
+            (Some(device), _) => Some(device),
+            (None, Some(name)) => Some(find_input_device(host, name)?),
+            (None, None) => None,
+        };
+        // Owned by the handle, stopped with it.
+        let mut mic = None;
+        if let Some(device) = mic_device {
+            let (sender, receiver) = crossbeam_channel::bounded(capacity);
+            mic = Some(spawn_capture(
+                device,
+                host,
+                sender,
+                self.config.mic_channels,
+                self.config.stream_recovery,
+                Some(telemetry.clone()),
+            )?);
+            inputs.mic = Some(receiver);
+        }
+        if inputs.is_empty() {
+            return Err(VoiceImmersionError::InvalidConfig(
+                "No input source configured.".into(),
+            ));
+        }
+        inputs.check()?;
+        if let Some(given) = inputs.mic.as_ref().and_then(Receiver::capacity) {
+            if given < capacity {
+                eprintln!(
+                    "Mic channel capacity of {} frames is under the {} advised for this output, expect dropouts.",
+                    given, capacity
+                );
+            }
+        }
+        let mic_queue = inputs
+            .mic
+            .as_ref()
+            .map(|mic| mic.capacity().unwrap_or(capacity));
+        let placement = self.placement.unwrap_or_default();
+        let mut placements = vec![placement.clone()];
+        placements.extend(self.listeners);
+        if !self.world_listeners.is_empty() {
+            let Placement::World { source, .. } = placement else {
+                return Err(VoiceImmersionError::InvalidConfig(
+                    "World listeners need a world source.".into(),
+                ));
+            };
+            placements.extend(
+                self.world_listeners
+                    .into_iter()
+                    .map(|listener| Placement::World {
+                        listener,
+                        source: source.clone(),
+                    }),
+            );
+        }
+        let channels = placements.len() * self.config.output_format.channels();
+        if sink.channels() < channels {
+            eprintln!(
+                "{} listeners need {} output channels, only {} available.",
+                placements.len(),
+                channels,
+                sink.channels()
+            );
+        }
+
+        let master = MasterParams::new(&self.config);
+        let sample_rate = sink.sample_rate();
+        let scene = Scene::new(&self.config, placements.len(), sample_rate, master.clone());
+        let primary = scene.insert(inputs, placements.clone());
+        let (mic_mix, finished, wave) = {
+            let params = &scene.lock().sources[0].listeners[0].1.params;
+            (
+                params.mic_mix.clone(),
+                params.finished.clone(),
+                params.wave.clone(),
+            )
+        };
+        if let Some(customize) = self.customize {
+            scene.customize(customize);
+        }
+        let recorder = Recorder::new(sample_rate);
+        let mut renderer = Renderer::new(scene.backend(), telemetry.clone(), sample_rate);
+        renderer.set_record_tap(recorder.tap());
+        let analyzer = self
+            .config
+            .spectrum_window
+            .map(|window| SpectrumAnalyzer::new(sample_rate, window));
+        if let Some(analyzer) = &analyzer {
+            renderer.set_spectrum_tap(analyzer.tap());
+        }
+        renderer.set_layout(self.config.output_layout);
+        let output = OutputConfig {
+            sample_rate,
+            channels: sink.channels(),
+            buffer_frames: sink.buffer_frames(),
+        };
+        let latency = Latency::estimate(
+            sample_rate,
+            output.buffer_frames,
+            mic_queue,
+            self.config.control_period.max(MIN_CONTROL_PERIOD),
+        );
+        let sink = sink.start(renderer)?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let (updates, pushed) = crossbeam_channel::unbounded();
+        let control = Control::new(
+            scene.clone(),
+            primary,
+            telemetry.clone(),
+            self.on_tick,
+            self.rooms,
+            pushed,
+        );
+        // Browsers cannot run a blocking thread.
+        let (control, manual) = if self.manual_control || cfg!(target_arch = "wasm32") {
+            (None, Some(Mutex::new(control)))
+        } else {
+            let control_running = running.clone();
+            let thread = std::thread::spawn(move || control_loop(control, control_running));
+            (Some(thread), None)
+        };
+
+        Ok(SpatialHandle {
+            _sink: sink,
+            _mic: mic,
+            master,
+            mic_mix,
+            finished,
+            wave,
+            control,
+            manual,
+            running,
+            primary,
+            updates,
+            scene,
+            placements,
+            telemetry,
+            events,
+            recorder,
+            analyzer,
+            output,
+            latency,
+        })
+    }
+}
+
+/// Running engine: owns the sink and the control thread, both stopped when dropped.
+pub struct SpatialHandle {
+    _sink: Box<dyn Any>,
+    /// Owner of the mic capture, see `EngineBuilder::mic`.
+    _mic: Option<Box<dyn Any>>,
+    control: Option<JoinHandle<()>>,
+    /// Control passes run by `step_control` instead of the thread.
+    manual: Option<Mutex<Control>>,
+    /// Cleared to stop the control thread.
+    running: Arc<AtomicBool>,
+    /// Scene source of the builder input.
+    primary: SourceId,
+    updates: Sender<SourceUpdate>,
+    scene: Scene,
+    placements: Vec<Placement>,
+    telemetry: Arc<Telemetry>,
+    events: Receiver<EngineEvent>,
+    master: MasterParams,
+    /// Input crossfade of the source given to the builder.
+    mic_mix: Shared,
+    /// End of the one-shot wave of the source given to the builder.
+    finished: Shared,
+    /// Wave switch of the source given to the builder, unless it has no wave.
+    wave: Option<WaveHandoff>,
+    recorder: Recorder,
+    /// Spectrum worker, with `EngineConfig::spectrum_window`.
+    analyzer: Option<SpectrumAnalyzer>,
+    output: OutputConfig,
+    latency: Latency,
+}
+
+/// Builder of `SpatialHandle::new_default`: the capture of `input`, or silence without one.
+fn default_builder(input: Option<cpal::Device>) -> EngineBuilder {
+    match input {
+        Some(device) => EngineBuilder::new().mic(device),
+        None => EngineBuilder::new().input(InputSource::Node(Box::new(zero()))),
+    }
+}
+
+impl SpatialHandle {
+    /// Engine with the defaults throughout, in three lines for a basic app: the default
+    /// input device of the default host spatialized on its default output device, whatever
+    /// their sample formats. Place the source through `source_info`, and see `EngineBuilder`
+    /// for other devices, inputs or settings. Without any input device, as on headless
+    /// machines, the engine starts output only with a silent source, for scene sources to
+    /// be added to; without an output device it fails with `DeviceUnavailable`.
+    pub fn new_default() -> crate::Result<SpatialHandle> {
+        default_builder(cpal::default_host().default_input_device()).build()
+    }
+
+    /// Source description of the first listener, unless given in world coordinates.
+    pub fn source_info(&self) -> Option<Arc<RwLock<SourceInfo>>> {
+        self.listener_source_info(0)
+    }
+
+    /// Scene source the builder input plays on.
+    pub fn source_id(&self) -> SourceId {
+        self.primary
+    }
+
+    /// Push placement changes instead of writing the shared descriptions: the engine wakes up
+    /// on them right away and only applies the latest of a burst.
+    pub fn updates(&self) -> Sender<SourceUpdate> {
+        self.updates.clone()
+    }
+
+    /// Move the builder source without writing the rest of its description, which other
+    /// parts of the app may own, see `SourceUpdate::Position`.
+    pub fn set_position(&self, position: Vector3<f32>) {
+        self.push(SourceUpdate::Position {
+            source: self.primary,
+            position,
+        });
+    }
+
+    /// Turn the builder source, see `SourceUpdate::Direction`.
+    pub fn set_direction(&self, direction: Vector3<f32>) {
+        self.push(SourceUpdate::Direction {
+            source: self.primary,
+            direction,
+        });
+    }
+
+    /// Move the builder source into `room`, or out of any, see `SourceUpdate::Room`.
+    pub fn set_room(&self, room: Option<InAnotherRoom>) {
+        self.push(SourceUpdate::Room {
+            source: self.primary,
+            room,
+        });
+    }
+
+    /// Queue `update` for the next control pass, dropped once the engine stopped.
+    fn push(&self, update: SourceUpdate) {
+        let _ = self.updates.send(update);
+    }
+
+    /// Sources mixed into the output, the builder one first, to add more at runtime.
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    /// Source description of listener `index`, see `EngineBuilder::listener`.
+    pub fn listener_source_info(&self, index: usize) -> Option<Arc<RwLock<SourceInfo>>> {
+        match self.placements.get(index)? {
+            Placement::Relative(source_info) => Some(source_info.clone()),
+            Placement::World { .. } => None,
+        }
+    }
+
+    /// Gain applied to every source on top of the spatialization, ramped over `EngineConfig::gain_ramp`.
+    pub fn set_gain(&self, gain: f32) {
+        self.master.volume.set_value(gain);
+    }
+
+    /// `set_gain` in decibels.
+    pub fn set_gain_db(&self, gain: Decibels) {
+        self.set_gain(gain.to_linear());
+    }
+
+    /// Master gain as a `Shared`, e.g. to bind it to a volume slider. Distance attenuation
+    /// stays independent of it.
+    pub fn master_gain(&self) -> Shared {
+        self.master.volume.clone()
+    }
+
+    /// Ramp the output to silence, or back to the master gain, over `EngineConfig::gain_ramp`.
+    pub fn set_muted(&self, muted: bool) {
+        self.master.unmuted.set_value(if muted { 0.0 } else { 1.0 });
+    }
+
+    /// Stereo width of the output as a `Shared`: 0 for mono, 1 as rendered, more to widen.
+    /// Changes are ramped over `EngineConfig::gain_ramp`.
+    pub fn width(&self) -> Shared {
+        self.master.width.clone()
+    }
+
+    /// Swap the left and right channels on output, or swap them back, see
+    /// `EngineConfig::swap_channels`. Ramped over `EngineConfig::gain_ramp`.
+    pub fn set_swap_channels(&self, swapped: bool) {
+        self.master.side.set_value(if swapped { -1.0 } else { 1.0 });
+    }
+
+    pub fn channels_swapped(&self) -> bool {
+        self.master.side.value() < 0.0
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.master.unmuted.value() == 0.0
+    }
+
+    /// Pause the control thread and fade the output out over `EngineConfig::output_fade`,
+    /// the stream pausing once it faded, e.g. while the game is paused. Returns right away.
+    /// Placement changes are picked up on `resume`.
+    pub fn pause(&self) {
+        self.master.output.set_value(0.0);
+        self.telemetry.set_paused(true);
+    }
+
+    /// Restart the stream and fade the output back in.
+    pub fn resume(&self) {
+        self.telemetry.set_paused(false);
+        self.master.output.set_value(1.0);
+    }
+
+    /// Whether the output plays, that is not paused by `pause`. It may still be paused
+    /// while idle, see `EngineConfig::idle_pause_timeout`.
+    pub fn is_playing(&self) -> bool {
+        !self.telemetry.is_paused()
+    }
+
+    /// Switch the output processing, crossfading between both modes.
+    pub fn set_output_mode(&self, mode: OutputMode) {
+        self.master.headphones.set_value(headphones_value(mode));
+    }
+
+    /// Crossfade the builder source to its other input, silence if it was not given.
+    pub fn set_active_source(&self, kind: SourceKind) {
+        self.mic_mix.set_value(mic_mix_value(kind));
+    }
+
+    /// Switch the wave of the builder source without rebuilding the graph, dipping to silence
+    /// for a few milliseconds around the switch. A looping input loops the new wave, a
+    /// one-shot plays it from its start, even once the previous one finished. A wave recorded
+    /// at another sample rate than the output is resampled, playing at its original pitch.
+    pub fn set_wave(&self, wave: Wave) -> crate::Result<()> {
+        let handoff = self.wave.as_ref().ok_or_else(|| {
+            VoiceImmersionError::InvalidState("The source has no wave input to switch.".into())
+        })?;
+        handoff.send(wave);
+        self.finished.set_value(0.0);
+        Ok(())
+    }
+
+    /// Stream the engine plays on, e.g. the one negotiated with
+    /// `EngineBuilder::preferred_stream`.
+    pub fn output_config(&self) -> OutputConfig {
+        self.output
+    }
+
+    /// Worst case delays from the mic to the speakers for the stream the engine plays on,
+    /// to tune `EngineBuilder::preferred_stream` and `EngineConfig::mic_queue` against
+    /// dropouts, see `Latency`.
+    pub fn latency(&self) -> Latency {
+        self.latency
+    }
+
+    /// Sum of the `latency` delays.
+    pub fn estimated_latency_ms(&self) -> f64 {
+        self.latency.total_ms()
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        EngineStats {
+            mic_underruns: self.master.mic_underruns.load(Ordering::Relaxed),
+            ..self.telemetry.stats()
+        }
+    }
+
+    /// RMS and peak levels of the first listener output, for level meters.
+    pub fn levels(&self) -> OutputLevels {
+        self.telemetry.levels()
+    }
+
+    /// Magnitude spectrum of the first listener output, left and right mixed, for
+    /// spectrum analyzers: `window / 2 + 1` bins of `EngineConfig::spectrum_window`, bin
+    /// `i` at `i * sample_rate / window` Hz, a full scale sine peaking at 1 in its own.
+    /// Computed off the audio thread every quarter window; empty without a window or
+    /// until the first one filled.
+    pub fn spectrum(&self) -> Vec<f32> {
+        self.analyzer
+            .as_ref()
+            .map_or_else(Vec::new, SpectrumAnalyzer::spectrum)
+    }
+
+    /// Tee the output of the first listener to a WAV file at `path`, written once
+    /// `stop_recording` is called or the handle dropped.
+    pub fn start_recording(&self, path: impl Into<PathBuf>) -> crate::Result<()> {
+        self.recorder.start(path.into())
+    }
+
+    /// Stop the recording and save it.
+    pub fn stop_recording(&self) -> crate::Result<()> {
+        self.recorder.stop()
+    }
+
+    /// Room of the world the first listener stands in, `None` out of any room or without
+    /// world placements, see `EngineBuilder::rooms`. As of the last control pass, and cheap
+    /// enough to poll every frame; see `EngineEvent::ListenerRoomChanged` for the transitions.
+    pub fn listener_room(&self) -> Option<RoomId> {
+        self.telemetry.listener_room()
+    }
+
+    /// Notable events published by the engine while it runs.
+    pub fn events(&self) -> &Receiver<EngineEvent> {
+        &self.events
+    }
+
+    /// Run one control pass `dt` after the previous one, every update pushed since the last
+    /// one applied first, with `EngineBuilder::manual_control`. Nothing moves between the
+    /// calls: timeouts such as `EngineConfig::idle_pause_timeout` count the `dt` of the
+    /// passes rather than the time elapsed.
+    pub fn step_control(&self, dt: Duration) -> crate::Result<()> {
+        let manual = self.manual.as_ref().ok_or_else(|| {
+            VoiceImmersionError::InvalidState("The engine runs its own control thread.".into())
+        })?;
+        let mut control = manual.lock().unwrap_or_else(PoisonError::into_inner);
+        control.apply_pending();
+        if !self.telemetry.is_paused() {
+            let now = control.clock + dt;
+            control.clock = now;
+            control.step(now, dt.as_secs_f32());
+        }
+        Ok(())
+    }
+
+    /// Block the calling thread for as long as the engine runs.
+    pub fn wait(mut self) {
+        if let Some(control) = self.control.take() {
+            let _ = control.join();
+        }
+    }
+
+    /// Stop the control thread and close the output, same as dropping the handle.
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for SpatialHandle {
+    fn drop(&mut self) {
+        // The output fades out before the sink closes the stream.
+        self.master.output.set_value(0.0);
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(control) = self.control.take() {
+            let _ = control.join();
+        }
+        if self.recorder.is_recording() {
+            if let Err(err) = self.recorder.stop() {
+                eprintln!("{}", err);
+            }
+        }
+    }
+}
+
+/// Engine wide controls and counters, shared by the chains of every source.
+#[derive(Clone)]
+pub(crate) struct MasterParams {
+    pub(crate) volume: Shared,
+    pub(crate) unmuted: Shared,
+    /// Stereo width of the output, see `SpatialHandle::width`.
+    pub(crate) width: Shared,
+    /// -1 with the channels swapped, 1 otherwise, see `EngineConfig::swap_channels`.
+    pub(crate) side: Shared,
+    /// Target of the output fade, see `EngineConfig::output_fade`.
+    pub(crate) output: Shared,
+    pub(crate) headphones: Shared,
+    pub(crate) mic_underruns: Arc<AtomicU64>,
+}
+
+impl MasterParams {
+    pub(crate) fn new(config: &EngineConfig) -> Self {
+        MasterParams {
+            volume: shared(1.0),
+            unmuted: shared(1.0),
+            output: shared(1.0),
+            width: shared(1.0),
+            side: shared(if config.swap_channels { -1.0 } else { 1.0 }),
+            headphones: shared(headphones_value(config.output_mode)),
+            mic_underruns: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+/// Build the source and one spatialization chain per listener, along with the parameters
+/// driving each chain. Listener `k` is rendered on outputs `2k` and `2k + 1` in stereo, see
+/// `OutputFormat`.
+pub(crate) fn build_graph(
+    inputs: Inputs,
+    config: &EngineConfig,
+    listeners: usize,
+    master: &MasterParams,
+) -> (Net, Vec<ControlParams>) {
+    let config = &config.resolve_smoothing();
+    let MasterParams {
+        volume,
+        unmuted,
+        headphones,
+        ..
+    } = master;
+
+    let finished = shared(0.0);
+    let doppler = shared(1.0);
+    let rate = var(&doppler) >> follow(config.movement_smoothing);
+    // Wave players are units with no inputs and one output, as given nodes.
+    let (player, handoff): (Box<dyn AudioUnit>, _) = match inputs.player {
+        Some(InputSource::Wave(wave)) => {
+            let wave = Arc::new(wave);
+            let (handoff, waves) = WaveHandoff::new(&wave);
+            let player = LoopWave::new(wave, config.loop_crossfade);
+            (
+                Box::new(rate >> An(WaveSwap::new(player, waves))),
+                Some(handoff),
+            )
+        }
+        Some(InputSource::OneShot(wave)) => {
+            let wave = Arc::new(wave);
+            let (handoff, waves) = WaveHandoff::new(&wave);
+            let player = OneShot::new(wave, config.one_shot_fade, &finished);
+            (
+                Box::new(rate >> An(WaveSwap::new(player, waves))),
+                Some(handoff),
+            )
+        }
+        Some(InputSource::Stream(stream)) => {
+            let player = StreamWave::new(stream, &finished);
+            (Box::new(rate >> An(player)), None)
+        }
+        Some(InputSource::Stereo(wave)) => {
+            let channel = |i| {
+                let mut mono = Wave::new(0, wave.sample_rate());
+                mono.push_channel(wave.channel(i));
+                Arc::new(mono)
+            };
+            let player = |i| An(LoopWave::new(channel(i), config.loop_crossfade));
+            // Both channels at the same rate stay in step.
+            (Box::new(rate >> (player(0) ^ player(1))), None)
+        }
+        Some(InputSource::Node(node)) => (node, None),
+        Some(InputSource::Mic(_)) | None => (Box::new(zero()), None),
+    };
+    let stereo = player.outputs() == 2;
+    let wave = Net::wrap(player);
+    let mic = match inputs.mic {
+        Some(receiver) => {
+            let node = InputNode::new(
+                receiver,
+                config.mic_underrun_hold,
+                master.mic_underruns.clone(),
+            );
+            // Gated before the makeup gain of the compressor raises the noise.
+            let mut voice = Net::wrap(Box::new(pass()));
+            if let Some(gate) = config.mic_gate {
+                voice = voice >> Net::wrap(Box::new(An(Gate::new(gate))));
+            }
+            if let Some(compressor) = config.mic_compressor {
+                voice = voice >> Net::wrap(Box::new(An(Compression::new(compressor))));
+            }
+            Net::wrap(Box::new(An(node))) >> (voice | Net::wrap(Box::new(sink())))
+        }
+        None => Net::wrap(Box::new(zero())),
+    };
+    let mic_mix = shared(mic_mix_value(inputs.active.unwrap_or(SourceKind::Wave)));
+    let mix = var(&mic_mix) >> follow(SOURCE_CROSSFADE);
+    // The mic, mono, plays from both channels of stereo sources.
+    let source = if stereo {
+        (wave | mic | mix)
+            >> map(|f: &Frame<f32, U4>| (f[0] + (f[2] - f[0]) * f[3], f[1] + (f[2] - f[1]) * f[3]))
+    } else {
+        (wave | mic | mix) >> map(|f: &Frame<f32, U3>| f[0] + (f[1] - f[0]) * f[2])
+    };
+
+    let gain = shared(1.0);
+    if config.spatialization == SpatializationMode::Bypass {
+        let (chains, mut params) =
+            build_bypass(config, listeners, master, &gain, &mic_mix, &finished);
+        // Played at its own rate, with no Doppler effect.
+        params[0].wave = handoff;
+        let source = if stereo {
+            source
+        } else {
+            source >> Net::wrap(Box::new(split::<U2>()))
+        };
+        return (source >> chains, params);
+    }
+    let build = || {
+        build_chain(
+            config, volume, unmuted, &gain, headphones, &mic_mix, &finished,
+        )
+    };
+    let build_listeners = || {
+        let (mut chains, first) = build();
+        let mut params = vec![first];
+        for _ in 1..listeners {
+            let (chain, chain_params) = build();
+            chains = chains ^ chain;
+            params.push(chain_params);
+        }
+        (chains, params)
+    };
+    let (mut chains, mut params) = build_listeners();
+    // The input is shared by the listeners, the first one drives its playback rate.
+    params[0].doppler = Some(doppler);
+    params[0].wave = handoff;
+    if stereo {
+        let (right, twins) = build_listeners();
+        for (params, twin) in params.iter_mut().zip(twins) {
+            params.twin = Some(Box::new(twin));
+        }
+        chains = chains + right;
+    }
+    (source >> chains, params)
+}
+
+/// Chains of `SpatializationMode::Bypass` for `listeners` listeners, each passing the two
+/// channels of the source through its gains and wall low-pass.
+fn build_bypass(
+    config: &EngineConfig,
+    listeners: usize,
+    master: &MasterParams,
+    gain: &Shared,
+    mic_mix: &Shared,
+    finished: &Shared,
+) -> (Net, Vec<ControlParams>) {
+    let MasterParams {
+        volume,
+        unmuted,
+        headphones,
+        ..
+    } = master;
+    let build = || {
+        // The controller writes the parameters of a full chain, only the gains and the
+        // cutoff of which are read here.
+        let params = ControlParams::new(volume, unmuted, gain, headphones, mic_mix, finished);
+        let level = || {
+            (var(&params.occlusion) >> follow(config.occlusion_smoothing))
+                * ((var(volume) * var(unmuted) * var(gain)) >> follow(config.gain_ramp))
+        };
+        let cutoff = || {
+            var(&params.material_cutoff)
+                >> map(|f: &Frame<f32, U1>| f[0].ln())
+                >> follow(config.occlusion_smoothing)
+                >> map(|f: &Frame<f32, U1>| f[0].exp())
+        };
+        // Crossfaded in over the octave below the open air cutoff, so that the source
+        // stays untouched in the open.
+        let channel = || {
+            ((pass() * level()) | cutoff())
+                >> ((pass() | sink()) ^ lowpole() ^ (sink() | pass()))
+                >> map(|f: &Frame<f32, U3>| {
+                    let weight = (OPEN_AIR_CUTOFF / f[2]).log2().clamp(0.0, 1.0);
+                    f[0] + (f[1] - f[0]) * weight
+                })
+        };
+        (Net::wrap(Box::new(channel() | channel())), params)
+    };
+    let (mut chains, first) = build();
+    let mut params = vec![first];
+    for _ in 1..listeners {
+        let (chain, chain_params) = build();
+        chains = chains ^ chain;
+        params.push(chain_params);
+    }
+    (chains, params)
+}
+
+/// Spatialization graph of `input` heard from `info` by a single listener, as the audio
+/// callback renders it at `sample_rate` but without a device or control thread: the source
+/// stays where it was placed. Lets tests and tools drive the DSP, for instance checking it
+/// under `assert_no_alloc` as the real-time thread does. Errors on inputs the engine rejects.
+pub fn build_backend(
+    config: &EngineConfig,
+    input: InputSource,
+    info: &SourceInfo,
+    sample_rate: f64,
+) -> crate::Result<impl AudioUnit> {
+    config.check()?;
+    let inputs = Inputs::from(input);
+    inputs.check()?;
+    let (mut net, mut params) = build_graph(inputs, config, 1, &MasterParams::new(config));
+    net.set_sample_rate(sample_rate);
+    let mut controller = Controller::new(params.remove(0), config.clone());
+    controller.update(info, 0.0);
+    Ok(net.backend())
+}
+
+/// Spatialization chain of one listener, from the mono source to its stereo or ambisonic
+/// output.
+fn build_chain(
+    config: &EngineConfig,
+    volume: &Shared,
+    unmuted: &Shared,
+    gain: &Shared,
+    headphones: &Shared,
+    mic_mix: &Shared,
+    finished: &Shared,
+) -> (Net, ControlParams) {
+    let params = ControlParams::new(volume, unmuted, gain, headphones, mic_mix, finished);
+    let ControlParams {
+        amplitude,
+        occlusion,
+        binaural,
+        left_amp,
+        right_amp,
+        material_cutoff,
+        wall_band,
+        wall_notch,
+        wall_center,
+        wall_q,
+        elevation_db,
+        near_bass_db,
+        air_mid_db,
+        air_high_db,
+        delay,
+        left_delay,
+        right_delay,
+        delay_snaps,
+        reverb_send,
+        reverb_wet,
+        reverb_time,
+        room_size,
+        reverb_predelay,
+        ..
+    } = &params;
+
+    let mut spatial = Net::new(1, config.output_format.channels());
+    // Propagation delay, glided with the movements to avoid zipper noise unless snapped,
+    // see `DelayMode`. The panned direct sound rather folds it into its per-ear delays, see
+    // `ear_delays`.
+    let glide = |delay: &Shared| {
+        An(DelayGlide::new(
+            delay,
+            delay_snaps,
+            config.movement_smoothing,
+        ))
+    };
+    let propagation = || (pass() | glide(delay)) >> tap(0.0, config.max_propagation_delay);
+    // Direct sound, from the mono source to both ears or the ambisonic channels.
+    let mut direct = Net::new(1, config.output_format.channels());
+    // Movements, occlusion and user gain changes are smoothed separately.
+    direct.chain(Box::new(
+        tick()
+            * (var(amplitude) >> follow(config.movement_smoothing))
+            * (var(occlusion) >> follow(config.occlusion_smoothing))
+            * ((var(volume) * var(unmuted) * var(gain)) >> follow(config.gain_ramp)),
+    ));
+    // Cutoff driven by a shared: `listen` does not forward settings to the filter it wraps.
+    // Ramped in the log domain for an even sweep over the octaves.
+    let ramped_cutoff = var(material_cutoff)
+        >> map(|f: &Frame<f32, U1>| f[0].ln())
+        >> follow(config.occlusion_smoothing)
+        >> map(|f: &Frame<f32, U1>| f[0].exp());
+    direct.chain(Box::new((pass() | ramped_cutoff) >> lowpole()));
+    // Band-pass and notch walls, crossfaded in by their weights.
+    let weight = |weight: &Shared| var(weight) >> follow(config.occlusion_smoothing);
+    let q = || var(wall_q) >> follow(config.occlusion_smoothing);
+    let shaped = || pass() | (var(wall_center) >> follow(config.occlusion_smoothing)) | q();
+    // The band-pass peaks at Q, brought back to unity.
+    let band_gain = q() >> map(|f: &Frame<f32, U1>| 1.0 / f[0]);
+    direct.chain(Box::new(
+        (pass() * (dc(1.0) - weight(wall_band) - weight(wall_notch)))
+            & ((shaped() >> bandpass()) * (weight(wall_band) * band_gain))
+            & ((shaped() >> notch()) * weight(wall_notch)),
+    ));
+    // Frequency dependent air absorption, ramped in dB.
+    if config.air_model.is_some() {
+        let band = |gain_db: &Shared| {
+            var(gain_db)
+                >> follow(config.movement_smoothing)
+                >> map(|f: &Frame<f32, U1>| db_amp(f[0]))
+        };
+        direct.chain(Box::new(
+            (pass() | dc((AIR_MID_HZ, 0.7)) | band(air_mid_db)) >> bell(),
+        ));
+        direct.chain(Box::new(
+            (pass() | dc((AIR_HIGH_HZ, std::f32::consts::FRAC_1_SQRT_2)) | band(air_high_db))
+                >> highshelf(),
+        ));
+    }
+    // Elevation as a spectral tilt, ramped in dB.
+    let shelf_gain = var(elevation_db)
+        >> follow(config.movement_smoothing)
+        >> map(|f: &Frame<f32, U1>| db_amp(f[0]));
+    direct.chain(Box::new(
+        (pass() | dc((ELEVATION_SHELF_HZ, std::f32::consts::FRAC_1_SQRT_2)) | shelf_gain)
+            >> highshelf(),
+    ));
+    // Near field bass boost, ramped in dB.
+    let bass_gain = var(near_bass_db)
+        >> follow(config.movement_smoothing)
+        >> map(|f: &Frame<f32, U1>| db_amp(f[0]));
+    direct.chain(Box::new(
+        (pass() | dc((NEAR_FIELD_SHELF_HZ, std::f32::consts::FRAC_1_SQRT_2)) | bass_gain)
+            >> lowshelf(),
+    ));
+    // Room reverb, only ticked within rooms.
+    let wet = var(reverb_wet) >> follow(config.movement_smoothing) >> split::<U2>();
+    let reverb =
+        multipass::<U2>() & (An(Reverb::new(reverb_time, room_size, reverb_predelay)) * wet);
+    let reverb = An(Bypass::new(reverb.0, reverb_send, config.reverb_fade));
+    let (reflections, ground, ambisonic, hrir) = match config.output_format {
+        OutputFormat::Stereo => {
+            let hrir = match (config.spatialization, &config.hrtf) {
+                (SpatializationMode::Hrtf, Some(set))
+                    if config.output_mode == OutputMode::Headphones =>
+                {
+                    spatial.chain(Box::new(propagation()));
+                    let selected = shared(set.nearest(&FORWARD_VECTOR) as f32);
+                    direct.chain(Box::new(An(HrtfConvolver::new(set.clone(), &selected))));
+                    Some(selected)
+                }
+                _ => {
+                    // Stereo effects: propagation and interaural time difference in a single
+                    // delay per ear, then level difference.
+                    let ear = |delay: &Shared, amp: &Shared| {
+                        ((pass() | glide(delay))
+                            >> tap(0.0, config.max_propagation_delay + MAX_ITD))
+                            * (var(amp) >> follow(config.pan_ramp))
+                    };
+                    direct.chain(Box::new(
+                        ear(left_delay, left_amp) ^ ear(right_delay, right_amp),
+                    ));
+                    None
+                }
+            };
+            // First-order reflections off the walls of the room, added to the direct sound.
+            let reflections: Vec<Reflection> =
+                (0..Ord::min(config.early_reflections, MAX_REFLECTIONS))
+                    .map(|_| Reflection::new())
+                    .collect();
+            // The ground one read from the same delay line.
+            let ground = config.ground_reflection.map(|_| Reflection::new());
+            let taps: Vec<Reflection> = reflections.iter().chain(&ground).cloned().collect();
+            if taps.is_empty() {
+                spatial.chain(Box::new(direct));
+            } else {
+                let early = (pass()
+                    * ((var(volume) * var(unmuted) * var(gain)) >> follow(config.gain_ramp)))
+                    >> An(EarlyReflections::new(&taps, config.movement_smoothing));
+                // Reflection delays are relative to the propagation one.
+                let early = match hrir {
+                    Some(_) => Net::wrap(Box::new(early)),
+                    None => Net::wrap(Box::new(propagation() >> early)),
+                };
+                spatial.chain(Box::new(direct & early));
+            }
+            let mut output_node = spatial.chain(Box::new(reverb));
+            // Per-ear cues, only for headphones, the HRTF bringing its own.
+            if hrir.is_none() {
+                output_node = spatial.chain(Box::new(An(Bypass::new(
+                    crossfeed(config.crossfeed.max(0.0)).0,
+                    binaural,
+                    0.02,
+                ))));
+            }
+            spatial.connect_output(output_node, 0, 0);
+            spatial.connect_output(output_node, 1, 1);
+            (reflections, ground, None, hrir)
+        }
+        OutputFormat::Ambisonic1 => {
+            let axes = [shared(0.0), shared(0.0), shared(0.0)];
+            spatial.chain(Box::new(propagation()));
+            let axis = |gain: &Shared| pass() * (var(gain) >> follow(config.movement_smoothing));
+            // The omni channel also carries the diffuse reverb.
+            let omni = (split::<U2>() >> reverb >> join::<U2>()) * W_GAIN;
+            direct.chain(Box::new(
+                omni ^ axis(&axes[0]) ^ axis(&axes[1]) ^ axis(&axes[2]),
+            ));
+            spatial.chain(Box::new(direct));
+            (Vec::new(), None, Some(axes), None)
+        }
+    };
+
+    let params = ControlParams {
+        reflections,
+        ground,
+        ambisonic,
+        hrir,
+        ..params
+    };
+    (spatial, params)
+}
+
+/// Low-pass cutoff in Hz of the air between the listener and a source `distance` meters away.
+fn air_cutoff(distance: f32, absorption: f32) -> f32 {
+    (OPEN_AIR_CUTOFF * (-absorption * distance).exp()).clamp(MIN_AIR_CUTOFF, OPEN_AIR_CUTOFF)
+}
+
+/// Reverb gain of a source `distance` meters away, see `EngineConfig::reverb_distance_factor`.
+fn reverb_distance_gain(distance: f32, factor: f32) -> f32 {
+    (1.0 + factor.max(0.0) * distance).min(MAX_REVERB_DISTANCE_GAIN)
+}
+
+fn mic_mix_value(kind: SourceKind) -> f32 {
+    match kind {
+        SourceKind::Wave => 0.0,
+        SourceKind::Mic => 1.0,
+    }
+}
+
+fn headphones_value(mode: OutputMode) -> f32 {
+    match mode {
+        OutputMode::Headphones => 1.0,
+        OutputMode::Speakers => 0.0,
+    }
+}
+
+pub(crate) struct ControlParams {
+    amplitude: Shared,
+    /// Gain let through the walls.
+    occlusion: Shared,
+    volume: Shared,
+    /// Gain of the source, see `Scene::set_source_gain`.
+    gain: Shared,
+    unmuted: Shared,
+    headphones: Shared,
+    /// Weight of the mic input against the wave one.
+    mic_mix: Shared,
+    /// Raised once a one-shot wave faded out.
+    finished: Shared,
+    /// Weight of the per-ear cues, following the output mode and the source distance.
+    binaural: Shared,
+    left_amp: Shared,
+    right_amp: Shared,
+    /// Cutoff in Hz of the filter standing for the walls and the air absorption.
+    material_cutoff: Shared,
+    /// Weights of the band-pass and notch walls, see `WallFilter`.
+    wall_band: Shared,
+    wall_notch: Shared,
+    /// Center frequency in Hz and Q of those walls.
+    wall_center: Shared,
+    wall_q: Shared,
+    /// Gain in dB of the elevation high shelf.
+    elevation_db: Shared,
+    /// Gain in dB of the near field low shelf.
+    near_bass_db: Shared,
+    /// Gains in dB of the `AirModel` bands.
+    air_mid_db: Shared,
+    air_high_db: Shared,
+    /// Propagation delay in seconds, of the reflections, HRTF and ambisonic paths.
+    delay: Shared,
+    /// Delays in seconds of the panned ears, propagation included, see `ear_delays`.
+    left_delay: Shared,
+    right_delay: Shared,
+    /// Counter bumped to jump the delays to their targets, see `DelayMode::Snap`.
+    delay_snaps: Shared,
+    /// Weight of the room reverb, raised within reverberant rooms.
+    reverb_send: Shared,
+    /// Distance gain of the reverb, see `EngineConfig::reverb_distance_factor`.
+    reverb_wet: Shared,
+    /// Reverb settings of the room, see `InAnotherRoom`.
+    reverb_time: Shared,
+    room_size: Shared,
+    reverb_predelay: Shared,
+    /// Early reflections off the walls, see `EngineConfig::early_reflections`.
+    reflections: Vec<Reflection>,
+    /// Reflection off the ground, see `EngineConfig::ground_reflection`.
+    ground: Option<Reflection>,
+    /// Gains of the X, Y and Z channels with `OutputFormat::Ambisonic1`.
+    ambisonic: Option<[Shared; 3]>,
+    /// Measurement of `EngineConfig::hrtf` heard with `SpatializationMode::Hrtf`.
+    hrir: Option<Shared>,
+    /// Playback rate of the waves, set by the first listener only.
+    doppler: Option<Shared>,
+    /// Switches the wave input, held by the first listener only.
+    wave: Option<WaveHandoff>,
+    /// Chain of the right channel of `InputSource::Stereo`, this one playing the left one.
+    twin: Option<Box<ControlParams>>,
+}
+
+impl ControlParams {
+    /// Parameters of a chain at rest in the open, sharing the master and source ones, with
+    /// none of the optional paths.
+    fn new(
+        volume: &Shared,
+        unmuted: &Shared,
+        gain: &Shared,
+        headphones: &Shared,
+        mic_mix: &Shared,
+        finished: &Shared,
+    ) -> Self {
+        ControlParams {
+            amplitude: shared(1.0),
+            occlusion: shared(1.0),
+            volume: volume.clone(),
+            gain: gain.clone(),
+            unmuted: unmuted.clone(),
+            headphones: headphones.clone(),
+            mic_mix: mic_mix.clone(),
+            finished: finished.clone(),
+            binaural: shared(headphones.value()),
+            left_amp: shared(1.0),
+            right_amp: shared(1.0),
+            material_cutoff: shared(OPEN_AIR_CUTOFF),
+            wall_band: shared(0.0),
+            wall_notch: shared(0.0),
+            wall_center: shared(1000.0),
+            wall_q: shared(1.0),
+            elevation_db: shared(0.0),
+            near_bass_db: shared(0.0),
+            air_mid_db: shared(0.0),
+            air_high_db: shared(0.0),
+            delay: shared(0.0),
+            left_delay: shared(0.0),
+            right_delay: shared(0.0),
+            delay_snaps: shared(0.0),
+            reverb_send: shared(0.0),
+            reverb_wet: shared(1.0),
+            reverb_time: shared(0.0),
+            room_size: shared(10.0),
+            reverb_predelay: shared(0.0),
+            reflections: Vec::new(),
+            ground: None,
+            ambisonic: None,
+            hrir: None,
+            doppler: None,
+            wave: None,
+            twin: None,
+        }
+    }
+}
+
+/// Longest time in seconds a description change is spread over: past it, the placements
+/// are taken as rarely updated and their changes as jumps, for the smoothers to glide over.
+const MAX_UPDATE_SPAN: f32 = 0.1;
+
+/// Replays the source descriptions along a continuous path, one description late: each new
+/// one is reached from where the path was when it arrived, over the time since the
+/// previous one arrived.
+#[derive(Default)]
+struct UpdatePath {
+    /// Start and end of the current segment, unset until the first update.
+    segment: Option<(SourceInfo, SourceInfo)>,
+    /// Seconds since the segment started, and its length.
+    since: f32,
+    span: f32,
+}
+
+impl UpdatePath {
+    /// Description to play `dt` seconds after the previous call, `info` being the latest one.
+    fn advance(&mut self, info: &SourceInfo, dt: f32) -> SourceInfo {
+        self.since += dt;
+        match &self.segment {
+            None => self.segment = Some((info.clone(), info.clone())),
+            Some((_, target)) if target != info => {
+                self.segment = Some((self.played(), info.clone()));
+                self.span = self.since.min(MAX_UPDATE_SPAN);
+                self.since = 0.0;
+            }
+            Some(_) => {}
+        }
+        self.played()
+    }
+
+    /// Point of the segment reached, with everything else from its end.
+    fn played(&self) -> SourceInfo {
+        let Some((from, to)) = &self.segment else {
+            return SourceInfo::default();
+        };
+        let t = if self.span > 0.0 {
+            (self.since / self.span).min(1.0)
+        } else {
+            1.0
+        };
+        SourceInfo {
+            relative_position: from.relative_position
+                + (to.relative_position - from.relative_position) * t,
+            direction: from.direction + (to.direction - from.direction) * t,
+            source_direction: from.source_direction
+                + (to.source_direction - from.source_direction) * t,
+            ..to.clone()
+        }
+    }
+}
+
+/// Velocity of a source estimated from its successive positions, for placements without
+/// one, see `EngineConfig::velocity_smoothing`.
+#[derive(Default)]
+struct VelocityEstimate {
+    /// Latest position, unset until the first one.
+    last: Option<Vector3<f32>>,
+    /// Seconds since it changed, and between its last two changes.
+    since: f32,
+    interval: f32,
+    velocity: Vector3<f32>,
+    /// Whether the last update moved the source, and faster than `max_speed` once replayed
+    /// along the `UpdatePath`.
+    moved: bool,
+    teleported: bool,
+}
+
+impl VelocityEstimate {
+    /// Velocity once at `position`, `dt` seconds after the previous call: steps between
+    /// positions over their time, glided over `smoothing` seconds so that jittery updates do
+    /// not wobble the pitch. Zero from the first position, and back to zero when the
+    /// positions stop changing, or jump faster than `max_speed` or after more than
+    /// `MAX_UPDATE_SPAN`, as with teleports.
+    fn update(
+        &mut self,
+        position: &Vector3<f32>,
+        dt: f32,
+        smoothing: f32,
+        max_speed: f32,
+    ) -> Vector3<f32> {
+        self.since += dt;
+        (self.moved, self.teleported) = (false, false);
+        let glide = |time: f32| {
+            if smoothing > 0.0 {
+                1.0 - (-time / smoothing).exp()
+            } else {
+                1.0
+            }
+        };
+        match self.last {
+            None => {
+                self.last = Some(*position);
+                self.since = 0.0;
+            }
+            Some(last) if last != *position => {
+                let step = (position - last) / self.since.max(f32::EPSILON);
+                let span = self.since.clamp(f32::EPSILON, MAX_UPDATE_SPAN);
+                self.moved = true;
+                self.teleported = (position - last).norm() / span > max_speed;
+                if step.norm() > max_speed || self.since > MAX_UPDATE_SPAN {
+                    self.velocity = Vector3::zeros();
+                } else {
+                    self.velocity += (step - self.velocity) * glide(self.since);
+                }
+                self.last = Some(*position);
+                self.interval = self.since;
+                self.since = 0.0;
+            }
+            // Updates late by more than their usual interval: the source stopped.
+            Some(_) if self.since > 2.0 * self.interval.max(CONTROL_PERIOD.as_secs_f32()) => {
+                self.velocity -= self.velocity * glide(dt);
+            }
+            Some(_) => {}
+        }
+        self.velocity
+    }
+}
+
+/// Turns source descriptions into graph parameters.
+pub(crate) struct Controller {
+    params: ControlParams,
+    config: EngineConfig,
+    /// Smoothed (left, right) gains, unset until the first update.
+    pan: Option<(f32, f32)>,
+    path: UpdatePath,
+    motion: VelocityEstimate,
+    /// Snap the delays on the next move, see `snap_delays`, and on the coming `apply`.
+    snap_next: bool,
+    snapping: bool,
+    metrics: SourceMetrics,
+    /// Gliding reverb time, room size and pre-delay, unset until the first room.
+    reverb: Option<(f32, f32, f32)>,
+    /// Share of `SourceInfo::stereo_width` the chain plays from, see `stereo_channel`:
+    /// -0.5 for the left channel of stereo sources, 0.5 for the right one, 0 for mono ones.
+    side: f32,
+    /// Controller of the right channel of stereo sources.
+    twin: Option<Box<Controller>>,
+}
+
+impl Controller {
+    pub(crate) fn new(mut params: ControlParams, config: EngineConfig) -> Self {
+        let config = config.resolve_smoothing();
+        let twin = params.twin.take().map(|twin| {
+            let mut twin = Controller::new(*twin, config.clone());
+            twin.side = 0.5;
+            Box::new(twin)
+        });
+        Controller {
+            params,
+            config,
+            pan: None,
+            path: UpdatePath::default(),
+            motion: VelocityEstimate::default(),
+            snap_next: false,
+            snapping: false,
+            metrics: SourceMetrics::default(),
+            reverb: None,
+            side: if twin.is_some() { -0.5 } else { 0.0 },
+            twin,
+        }
+    }
+
+    /// Move the reverb settings `dt` seconds towards those of `room` over
+    /// `EngineConfig::room_crossfade`.
+    fn glide_reverb(&mut self, room: &InAnotherRoom, dt: f32) -> (f32, f32, f32) {
+        let target = (room.reverb_time, room.room_size, room.reverb_predelay);
+        let reverb = match self.reverb {
+            Some((time, size, predelay)) => {
+                let crossfade = self.config.room_crossfade.max(f32::EPSILON);
+                let k = 1.0 - (-dt / crossfade).exp();
+                (
+                    time + (target.0 - time) * k,
+                    size + (target.1 - size) * k,
+                    predelay + (target.2 - predelay) * k,
+                )
+            }
+            None => target,
+        };
+        self.reverb = Some(reverb);
+        reverb
+    }
+
+    /// Gain of the source, shared by the controllers of all its listeners.
+    pub(crate) fn source_gain(&self) -> &Shared {
+        &self.params.gain
+    }
+
+    /// Jump the propagation delays to the next position instead of gliding there, see
+    /// `Scene::snap_delays`.
+    pub(crate) fn snap_delays(&mut self) {
+        self.snap_next = true;
+    }
+
+    /// State last applied, see `EngineBuilder::on_tick`.
+    pub(crate) fn metrics(&self) -> SourceMetrics {
+        self.metrics
+    }
+
+    /// Move the pan `dt` seconds towards `target` over `EngineConfig::movement_smoothing`,
+    /// whatever the control loop period.
+    fn smooth_pan(&mut self, target: (f32, f32), dt: f32) -> (f32, f32) {
+        let pan = match self.pan {
+            Some((left, right)) => {
+                let smoothing = self.config.movement_smoothing.max(f32::EPSILON);
+                let k = 1.0 - (-dt / smoothing).exp();
+                (left + (target.0 - left) * k, right + (target.1 - right) * k)
+            }
+            None => target,
+        };
+        self.pan = Some(pan);
+        pan
+    }
+
+    /// Whether the source is loud enough to be heard, from the gains last set.
+    pub(crate) fn audible(&self) -> bool {
+        self.loudness() > IDLE_GAIN
+    }
+
+    /// Gain the source is heard with, from the gains last set, zero once it finished.
+    pub(crate) fn loudness(&self) -> f32 {
+        let params = &self.params;
+        if params.finished.value() > 0.0 {
+            return 0.0;
+        }
+        let gain = params.amplitude.value()
+            * params.occlusion.value()
+            * params.volume.value()
+            * params.gain.value()
+            * params.unmuted.value();
+        let twin = self.twin.as_ref().map_or(0.0, |twin| twin.loudness());
+        gain.max(twin)
+    }
+
+    /// Update the graph parameters from `info`, `dt` seconds after the previous update.
+    pub(crate) fn update(&mut self, info: &SourceInfo, dt: f32) {
+        let info = self.config.ingest_source_info(info);
+        let info = self.interpolate(info, dt);
+        self.apply(&info, &UP_VECTOR, self.config.head_radius, dt);
+    }
+
+    /// Description to apply, see `EngineConfig::interpolate_updates`, with the estimated
+    /// velocity when given none, see `EngineConfig::velocity_smoothing`. Snapped moves jump
+    /// rather than being replayed, see `DelayMode::Snap`.
+    fn interpolate(&mut self, info: SourceInfo, dt: f32) -> SourceInfo {
+        let estimate = self.motion.update(
+            &info.relative_position,
+            dt,
+            self.config.velocity_smoothing.unwrap_or(0.0),
+            self.config.sound_speed,
+        );
+        let estimate = self.config.velocity_smoothing.map(|_| estimate);
+        self.snapping = self.motion.moved
+            && (self.snap_next
+                || (self.config.delay_mode == DelayMode::Snap && self.motion.teleported));
+        if self.snapping {
+            self.snap_next = false;
+            self.path = UpdatePath::default();
+        }
+        let mut info = if self.config.interpolate_updates {
+            self.path.advance(&info, dt)
+        } else {
+            info
+        };
+        if let Some(estimate) = estimate.filter(|_| info.velocity == Vector3::zeros()) {
+            info.velocity = estimate;
+        }
+        info
+    }
+
+    /// Update the graph parameters from world coordinates, see `update`, in the rooms of
+    /// `world` if any.
+    pub(crate) fn update_in_world(
+        &mut self,
+        listener: &Listener,
+        source: &Source,
+        world: Option<&World>,
+        dt: f32,
+    ) {
+        let mut info = relative_info(listener, source);
+        if let (None, Some(world)) = (&info.room, world) {
+            info.room = world
+                .heard_room(&listener.position, &source.position)
+                .map(|room| InAnotherRoom {
+                    bounds: room
+                        .bounds
+                        .map(|bounds| bounds.translated(&-listener.position)),
+                    ..room.clone()
+                });
+        }
+        let mut info = self.config.ingest_source_info(&info);
+        // Above the ground plane of the engine convention, whatever the up axis of the caller.
+        info.listener_height = self.config.ingest_position(&listener.position).y;
+        let info = self.interpolate(info, dt);
+        let up = self.config.coordinate_system.to_engine(&listener.up);
+        let head_radius = self.config.ingest_length(listener.head_radius);
+        self.apply(&info, &up, head_radius, dt);
+        self.metrics.listener_room = world.and_then(|world| world.room_at(&listener.position));
+    }
+
+    /// Update the graph parameters from `info` in engine coordinates and meters, heard by a
+    /// listener with `up` on top and a head of `head_radius` meters.
+    fn apply(&mut self, info: &SourceInfo, up: &Vector3<f32>, head_radius: f32, dt: f32) {
+        if let Some(twin) = &mut self.twin {
+            twin.snapping = self.snapping;
+            twin.apply(info, up, head_radius, dt);
+        }
+        let info = &stereo_channel(info, up, self.side);
+        // Following `SpatialHandle::set_output_mode`.
+        self.config.output_mode = if self.params.headphones.value() > 0.0 {
+            OutputMode::Headphones
+        } else {
+            OutputMode::Speakers
+        };
+        let (left, right, amplitude) = spatialize(info, up, head_radius, &self.config);
+        let (left, right) = self.smooth_pan((left, right), dt);
+        let reverb = match &info.room {
+            Some(room) if room.reverb_time > 0.0 => Some(self.glide_reverb(room, dt)),
+            _ => None,
+        };
+        let params = &self.params;
+        let distance = info.relative_position.norm();
+        let propagation = propagation_delay(
+            distance,
+            self.config.max_propagation_delay,
+            self.config.sound_speed,
+        );
+        params.delay.set_value(propagation);
+        // Time differences between speakers would comb filter.
+        let itd = match self.config.output_mode {
+            OutputMode::Headphones => {
+                interaural_time_difference(info, up, head_radius)
+                    * self.config.directional_strength.clamp(0.0, 1.0)
+            }
+            OutputMode::Speakers => 0.0,
+        };
+        let (left_delay, right_delay) = ear_delays(propagation, itd);
+        params.left_delay.set_value(left_delay);
+        params.right_delay.set_value(right_delay);
+        if std::mem::take(&mut self.snapping) {
+            // Wrapped to stay exact in a float.
+            let snaps = (params.delay_snaps.value() + 1.0) % 1024.0;
+            params.delay_snaps.set_value(snaps);
+        }
+        params.left_amp.set_value(left);
+        params.right_amp.set_value(right);
+        if let Some(axes) = &params.ambisonic {
+            let direction = head_direction(info, up);
+            for (axis, gain) in axes.iter().zip(direction.iter()) {
+                axis.set_value(*gain);
+            }
+        }
+        if let (Some(hrir), Some(set)) = (&params.hrir, &self.config.hrtf) {
+            hrir.set_value(set.nearest(&head_direction(info, up)) as f32);
+        }
+        if let Some(doppler) = &params.doppler {
+            doppler.set_value(doppler_factor(
+                &info.relative_position,
+                &info.velocity,
+                self.config.sound_speed,
+            ));
+        }
+        // Per-ear cues are not worth it for far away sources.
+        let near = distance <= self.config.binaural_cue_distance;
+        params
+            .binaural
+            .set_value(if near { params.headphones.value() } else { 0.0 });
+        // Room effects, the walls or the air, whichever muffles the most. Sources spatialized
+        // upstream carry their own distance cues.
+        let bypass = self.config.spatialization == SpatializationMode::Bypass;
+        let air = match &self.config.air_model {
+            _ if bypass => OPEN_AIR_CUTOFF,
+            Some(model) => {
+                let cut = |frequency: f64| {
+                    -(model.absorption_db(frequency) * distance).min(MAX_AIR_CUT_DB)
+                };
+                params.air_mid_db.set_value(cut(AIR_MID_HZ as f64));
+                params.air_high_db.set_value(cut(AIR_HIGH_HZ as f64 * 2.0));
+                OPEN_AIR_CUTOFF
+            }
+            None => air_cutoff(distance, self.config.air_absorption),
+        };
+        // Obstacles muffle as more air would, the walls on top.
+        let air = obstruction_cutoff(info.obstruction, air);
+        let walls = info.room.as_ref().map_or(0.0, |room| {
+            wall_amount(room, &info.relative_position, self.config.wall_diffraction)
+        });
+        // Near a wall only part of it muffles, see `EngineConfig::wall_diffraction`.
+        let muffling = walls.min(1.0);
+        let through_walls = info
+            .room
+            .as_ref()
+            .filter(|_| muffling > 0.0)
+            .map(|room| room.wall_filter);
+        // The band settings are kept out of such walls, so the filter fades out unchanged.
+        let (cutoff, band, notch) = match through_walls {
+            Some(WallFilter::LowPass(cutoff)) => {
+                let cutoff = cutoff.clamp(MIN_CUTOFF, OPEN_AIR_CUTOFF).min(air);
+                // Gliding in octaves from the open air one.
+                let cutoff = if muffling < 1.0 {
+                    air * (cutoff / air).powf(muffling)
+                } else {
+                    cutoff
+                };
+                (cutoff, 0.0, 0.0)
+            }
+            Some(WallFilter::BandPass { center, q }) => {
+                params
+                    .wall_center
+                    .set_value(center.clamp(MIN_CUTOFF, OPEN_AIR_CUTOFF));
+                params.wall_q.set_value(q.max(MIN_WALL_Q));
+                (air, muffling, 0.0)
+            }
+            Some(WallFilter::Notch { center, q }) => {
+                params
+                    .wall_center
+                    .set_value(center.clamp(MIN_CUTOFF, OPEN_AIR_CUTOFF));
+                params.wall_q.set_value(q.max(MIN_WALL_Q));
+                (air, 0.0, muffling)
+            }
+            None => (air, 0.0, 0.0),
+        };
+        params.material_cutoff.set_value(cutoff);
+        params.wall_band.set_value(band);
+        params.wall_notch.set_value(notch);
+        let near_bass = match &self.config.near_field {
+            Some(near_field) => near_field.bass_boost_db((distance - info.radius).max(0.0)),
+            None => 0.0,
+        };
+        params.near_bass_db.set_value(near_bass);
+        params
+            .elevation_db
+            .set_value(ELEVATION_SHELF_DB * self.config.elevation_strength * elevation(info, up));
+        // Reverb settings are kept on leaving the room, so the tail fades out unchanged.
+        match reverb {
+            Some((time, size, predelay)) => {
+                params.reverb_time.set_value(time);
+                params.room_size.set_value(size);
+                params.reverb_predelay.set_value(predelay);
+                params.reverb_send.set_value(1.0);
+                params.reverb_wet.set_value(reverb_distance_gain(
+                    info.relative_position.norm(),
+                    self.config.reverb_distance_factor,
+                ));
+            }
+            None => params.reverb_send.set_value(0.0),
+        }
+        self.reflect(info, up, head_radius);
+        let occlusion = info
+            .room
+            .as_ref()
+            .map_or(1.0, |room| wall_transmission(room, walls))
+            * obstruction_gain(info.obstruction);
+        params.amplitude.set_value(amplitude);
+        params.occlusion.set_value(occlusion);
+        self.metrics = SourceMetrics {
+            distance,
+            gain: amplitude * occlusion,
+            pan: (left, right),
+            in_room: info.room.is_some(),
+            listener_room: None,
+        };
+    }
+
+    /// Image sources of the walls of the room both the listener and the source stand in,
+    /// delayed by their extra path and heard from their own direction; none elsewhere.
+    fn reflect(&self, info: &SourceInfo, up: &Vector3<f32>, head_radius: f32) {
+        let reflections = &self.params.reflections;
+        let bounds = match &info.room {
+            Some(room) if walls_crossed(room, &info.relative_position) == 0 => room.bounds,
+            _ => None,
+        };
+        let images = bounds
+            .filter(|bounds| bounds.contains(&Vector3::zeros()))
+            .into_iter()
+            .flat_map(|bounds| image_sources(&bounds, &info.relative_position, reflections.len()));
+        let mut count = 0;
+        for (reflection, image) in reflections.iter().zip(images) {
+            count += 1;
+            let extra = (image.norm() - info.relative_position.norm()) / self.config.sound_speed;
+            let image_info = SourceInfo {
+                relative_position: image,
+                ..info.clone()
+            };
+            let (left, right, amplitude) = spatialize(&image_info, up, head_radius, &self.config);
+            // Reflections past the delay line are left to the reverb.
+            let gain = if extra <= MAX_REFLECTION_DELAY {
+                amplitude * self.config.wall_reflectivity.clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            reflection.delay.set_value(extra);
+            reflection.left.set_value(left * gain);
+            reflection.right.set_value(right * gain);
+        }
+        for reflection in &reflections[count..] {
+            reflection.left.set_value(0.0);
+            reflection.right.set_value(0.0);
+        }
+        if let (Some(reflection), Some(absorption)) =
+            (&self.params.ground, self.config.ground_reflection)
+        {
+            self.reflect_ground(reflection, absorption, info, up, head_radius);
+        }
+    }
+
+    /// Image of the source below the ground plane y = 0, when both the source and the
+    /// listener stand above it and no wall comes in between.
+    fn reflect_ground(
+        &self,
+        reflection: &Reflection,
+        absorption: f32,
+        info: &SourceInfo,
+        up: &Vector3<f32>,
+        head_radius: f32,
+    ) {
+        let height = info.listener_height;
+        let source_height = height + info.relative_position.y;
+        let walls = info
+            .room
+            .as_ref()
+            .map_or(0, |room| walls_crossed(room, &info.relative_position));
+        if height <= 0.0 || source_height <= 0.0 || walls > 0 {
+            reflection.left.set_value(0.0);
+            reflection.right.set_value(0.0);
+            return;
+        }
+        let mut image = info.relative_position;
+        image.y = -source_height - height;
+        let extra = (image.norm() - info.relative_position.norm()) / self.config.sound_speed;
+        let image_info = SourceInfo {
+            relative_position: image,
+            ..info.clone()
+        };
+        let (left, right, amplitude) = spatialize(&image_info, up, head_radius, &self.config);
+        let gain = if extra <= MAX_REFLECTION_DELAY {
+            amplitude
+                * (1.0 - absorption.clamp(0.0, 1.0)).sqrt()
+                * obstruction_gain(info.obstruction)
+        } else {
+            0.0
+        };
+        reflection.delay.set_value(extra);
+        reflection.left.set_value(left * gain);
+        reflection.right.set_value(right * gain);
+    }
+}
+
+/// Tells when the engine went silent for long enough to pause the output, and when it
+/// needs to resume.
+pub(crate) struct IdleDetector {
+    timeout: Option<Duration>,
+    silent_since: Option<Instant>,
+    paused: bool,
+}
+
+impl IdleDetector {
+    pub(crate) fn new(timeout: Option<Duration>) -> Self {
+        IdleDetector {
+            timeout,
+            silent_since: None,
+            paused: false,
+        }
+    }
+
+    /// Record whether anything is `audible` at `now`, returning the new paused state on change.
+    pub(crate) fn update(&mut self, now: Instant, audible: bool) -> Option<bool> {
+        let timeout = self.timeout?;
+        let paused = if audible {
+            self.silent_since = None;
+            false
+        } else {
+            let since = *self.silent_since.get_or_insert(now);
+            now.duration_since(since) >= timeout
+        };
+        if paused == self.paused {
+            return None;
+        }
+        self.paused = paused;
+        Some(paused)
+    }
+}
+
+/// One pass over the sources of a scene, run by the control thread or by the caller, see
+/// `EngineBuilder::manual_control`.
+struct Control {
+    scene: Scene,
+    /// Source whose end is reported and whose metrics go to `on_tick`.
+    primary: SourceId,
+    telemetry: Arc<Telemetry>,
+    on_tick: Option<OnTick>,
+    rooms: Option<Arc<RwLock<World>>>,
+    /// Placement changes pushed through `SpatialHandle::updates`.
+    updates: Receiver<SourceUpdate>,
+    /// Whether the end of the primary source was reported.
+    finished: bool,
+    idle: IdleDetector,
+    /// Time of the last pass run by `SpatialHandle::step_control`.
+    clock: Instant,
+}
+
+impl Control {
+    fn new(
+        scene: Scene,
+        primary: SourceId,
+        telemetry: Arc<Telemetry>,
+        on_tick: Option<OnTick>,
+        rooms: Option<Arc<RwLock<World>>>,
+        updates: Receiver<SourceUpdate>,
+    ) -> Self {
+        let idle_timeout = scene.lock().config.idle_pause_timeout;
+        Control {
+            scene,
+            primary,
+            telemetry,
+            on_tick,
+            rooms,
+            updates,
+            finished: false,
+            idle: IdleDetector::new(idle_timeout),
+            clock: Instant::now(),
+        }
+    }
+
+    /// Update every source `dt` seconds after the previous pass, at `now`.
+    fn step(&mut self, now: Instant, dt: f32) {
+        let mut state = self.scene.lock();
+        let primary_finished = state
+            .source(self.primary)
+            .is_some_and(|source| source.listeners[0].1.params.finished.value() > 0.0);
+        // Rearmed when a new wave is set.
+        if self.finished != primary_finished {
+            self.finished = primary_finished;
+            if primary_finished {
+                self.telemetry.emit(EngineEvent::SourceFinished);
+            }
+        }
+        state.limit_voices();
+        let mut audible = false;
+        for source in &mut state.sources {
+            // Sources over the voice budget get culled as silent ones.
+            let heard = source.voiced()
+                && source
+                    .listeners
+                    .iter()
+                    .any(|(_, controller)| controller.audible());
+            source.cull(now, heard);
+            audible |= heard;
+        }
+        let levels = self.telemetry.levels();
+        audible |= levels.peak.0.max(levels.peak.1) > IDLE_LEVEL;
+        if let Some(paused) = self.idle.update(now, audible) {
+            self.telemetry.set_idle(paused);
+        }
+        // World placements wait for the rooms when they are being edited.
+        let world = self.rooms.as_ref().map(|rooms| rooms.try_read().ok());
+        for source in &mut state.sources {
+            for (placement, controller) in &mut source.listeners {
+                match placement {
+                    Placement::Relative(source_info) => {
+                        if let Ok(info) = source_info.try_read() {
+                            controller.update(&info, dt);
+                        }
+                    }
+                    Placement::World { listener, source } => {
+                        let world = match &world {
+                            Some(Some(world)) => Some(&**world),
+                            Some(None) => continue,
+                            None => None,
+                        };
+                        if let (Ok(listener), Ok(source)) = (listener.try_read(), source.try_read())
+                        {
+                            controller.update_in_world(&listener, &source, world, dt);
+                        }
+                    }
+                }
+            }
+        }
+        let metrics = state
+            .source(self.primary)
+            .map(|source| source.listeners[0].1.metrics());
+        drop(state);
+        // Kept while the rooms are being edited.
+        if world.is_none_or(|world| world.is_some()) {
+            self.telemetry
+                .set_listener_room(metrics.and_then(|metrics| metrics.listener_room));
+        }
+        if let (Some(on_tick), Some(metrics)) = (&mut self.on_tick, metrics) {
+            on_tick(&metrics);
+        }
+    }
+
+    /// Apply `update` and every other update already pushed.
+    fn apply(&self, update: SourceUpdate) {
+        let mut state = self.scene.lock();
+        state.apply(update);
+        while let Ok(update) = self.updates.try_recv() {
+            state.apply(update);
+        }
+    }
+
+    /// Apply every update already pushed, in order.
+    fn apply_pending(&self) {
+        let mut state = self.scene.lock();
+        while let Ok(update) = self.updates.try_recv() {
+            state.apply(update);
+        }
+    }
+}
+
+/// Run `control` every control period until `running` gets cleared, waking up early on
+/// pushed updates.
+fn control_loop(mut control: Control, running: Arc<AtomicBool>) {
+    let period = {
+        let config = &control.scene.lock().config;
+        config.control_period.max(MIN_CONTROL_PERIOD)
+    };
+    let mut last = Instant::now();
+    while running.load(Ordering::Relaxed) {
+        // Actual time elapsed, the thread may have been preempted.
+        let now = Instant::now();
+        let dt = now.duration_since(last).as_secs_f32();
+        last = now;
+        if control.telemetry.is_paused() {
+            std::thread::sleep(period);
+            continue;
+        }
+        control.step(now, dt);
+
+        // Wake up early on pushed updates, a burst collapsing into one control pass.
+        match control.updates.recv_timeout(period) {
+            Ok(update) => control.apply(update),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => std::thread::sleep(period),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AirModel, Hrir, HrtfSet, InAnotherRoom, Smoothing, TransmissionLaw};
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    fn dc_input() -> InputSource {
+        InputSource::Wave(Wave::render(SAMPLE_RATE, 1.0, &mut dc(1.0)))
+    }
+
+    fn sine_input(hz: f32) -> InputSource {
+        InputSource::Wave(Wave::render(SAMPLE_RATE, 1.0, &mut sine_hz(hz)))
+    }
+
+    fn graph(config: &EngineConfig) -> (NetBackend, ControlParams) {
+        let (mut net, mut params) =
+            build_graph(dc_input().into(), config, 1, &MasterParams::new(config));
+        net.set_sample_rate(SAMPLE_RATE);
+        (net.backend(), params.remove(0))
+    }
+
+    #[test]
+    fn stop_joins_the_control_thread() {
+        let (sink, frames) = crate::RingBufferSink::new(SAMPLE_RATE, 1024);
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .build()
+            .unwrap();
+        frames.recv().unwrap();
+        assert_eq!(handle.output_config().sample_rate, SAMPLE_RATE);
+        assert_eq!(handle.output_config().channels, 2);
+        let running = handle.running.clone();
+        handle.stop();
+        assert!(!running.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn manual_control_steps_on_demand() {
+        let (sink, _frames) = crate::RingBufferSink::new(SAMPLE_RATE, 1024);
+        let (sender, metrics) = crossbeam_channel::unbounded();
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .manual_control(true)
+            .on_tick(move |metrics| {
+                let _ = sender.send(*metrics);
+            })
+            .build()
+            .unwrap();
+        assert!(metrics.recv_timeout(Duration::from_millis(100)).is_err());
+        handle.step_control(Duration::from_millis(10)).unwrap();
+        handle.step_control(Duration::from_millis(10)).unwrap();
+        assert_eq!(metrics.try_iter().count(), 2);
+        handle.stop();
+
+        let (sink, _frames) = crate::RingBufferSink::new(SAMPLE_RATE, 1024);
+        let threaded = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .build()
+            .unwrap();
+        assert!(threaded.step_control(Duration::from_millis(10)).is_err());
+        threaded.stop();
+    }
+
+    #[test]
+    fn captured_output_follows_the_source() {
+        let (sink, capture) = crate::CaptureSink::new(SAMPLE_RATE, 2);
+        assert!(capture.render(64).is_empty());
+        let info = Arc::new(RwLock::new(SourceInfo {
+            relative_position: Vector3::new(0.0, 0.0, -2.0),
+            ..Default::default()
+        }));
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(sine_input(500.0))
+            .source(info.clone())
+            .manual_control(true)
+            .build()
+            .unwrap();
+        // Energy of each channel once the source settled where it is.
+        let levels = || {
+            for _ in 0..50 {
+                handle.step_control(Duration::from_millis(10)).unwrap();
+                capture.render(441);
+            }
+            let frames = capture.render(4410);
+            assert_eq!(frames.len(), 2 * 4410);
+            let energy = |channel: usize| {
+                frames
+                    .iter()
+                    .skip(channel)
+                    .step_by(2)
+                    .map(|x| x * x)
+                    .sum::<f32>()
+            };
+            (energy(0), energy(1))
+        };
+        let (left, right) = levels();
+        assert!(left > 4.0 * right, "{left} {right}");
+        info.write().unwrap().relative_position = Vector3::new(0.0, 0.0, 2.0);
+        let (left, right) = levels();
+        assert!(right > 4.0 * left, "{left} {right}");
+        handle.stop();
+        assert!(capture.render(64).is_empty());
+    }
+
+    #[test]
+    fn builder_sets_pan_law_and_reverb() {
+        let reverb = ReverbSettings {
+            reverb_fade: 0.5,
+            reverb_distance_factor: 0.2,
+            early_reflections: 6,
+            ..Default::default()
+        };
+        let builder = EngineBuilder::new().pan_law(PanLaw::Linear).reverb(reverb);
+        assert_eq!(builder.config.pan_law, PanLaw::Linear);
+        assert_eq!(builder.config.reverb(), reverb);
+        assert_eq!(builder.config.reverb_fade, 0.5);
+        assert_eq!(builder.config.early_reflections, 6);
+        // The rest of the configuration is left as it was.
+        assert_eq!(
+            builder.config.room_crossfade,
+            EngineConfig::default().room_crossfade
+        );
+    }
+
+    #[test]
+    fn defaults_without_input_device_play_output_only() {
+        let (sink, capture) = crate::CaptureSink::new(SAMPLE_RATE, 2);
+        let handle = default_builder(None)
+            .sink(sink)
+            .manual_control(true)
+            .build()
+            .unwrap();
+        handle.step_control(Duration::from_millis(10)).unwrap();
+        let frames = capture.render(441);
+        assert_eq!(frames.len(), 2 * 441);
+        assert!(frames.iter().all(|&x| x == 0.0));
+        // Scene sources play on it all the same.
+        let info = Arc::new(RwLock::new(SourceInfo {
+            relative_position: Vector3::new(0.0, 0.0, -2.0),
+            ..Default::default()
+        }));
+        handle
+            .scene()
+            .add_source(sine_input(500.0), vec![info.into()])
+            .unwrap();
+        for _ in 0..20 {
+            handle.step_control(Duration::from_millis(10)).unwrap();
+            capture.render(441);
+        }
+        assert!(capture.render(441).iter().any(|&x| x.abs() > 1e-3));
+    }
+
+    #[test]
+    fn latency_counts_the_mic_queue() {
+        let (sink, _capture) = crate::CaptureSink::new(48000.0, 2);
+        let (_frames, mic) = crossbeam_channel::bounded(4800);
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(InputSource::Mic(mic))
+            .manual_control(true)
+            .build()
+            .unwrap();
+        let latency = handle.latency();
+        assert!((latency.queue_ms - 100.0).abs() < 1e-9);
+        assert!((latency.output_ms - 1024.0 / 48.0).abs() < 1e-9);
+        assert!((latency.control_ms - 5.0).abs() < 1e-9);
+        assert_eq!(handle.estimated_latency_ms(), latency.total_ms());
+    }
+
+    #[test]
+    fn partial_updates_compose() {
+        let (sink, _capture) = crate::CaptureSink::new(SAMPLE_RATE, 2);
+        let info = Arc::new(RwLock::new(SourceInfo {
+            gain: 0.5,
+            ..Default::default()
+        }));
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .source(info.clone())
+            .manual_control(true)
+            .build()
+            .unwrap();
+        let room = InAnotherRoom::builder().build(SAMPLE_RATE as f32).unwrap();
+        handle.set_position(Vector3::new(1.0, 0.0, 0.0));
+        handle.set_room(Some(room.clone()));
+        handle.set_direction(Vector3::new(0.0, 0.0, 1.0));
+        handle.set_position(Vector3::new(2.0, 0.0, 0.0));
+        handle.step_control(Duration::from_millis(10)).unwrap();
+        // Each update only wrote its own field, the latest position winning.
+        let applied = info.read().unwrap().clone();
+        assert_eq!(applied.relative_position, Vector3::new(2.0, 0.0, 0.0));
+        assert_eq!(applied.room, Some(room));
+        assert_eq!(applied.source_direction, Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(applied.gain, 0.5);
+        handle.set_room(None);
+        handle.step_control(Duration::from_millis(10)).unwrap();
+        let applied = info.read().unwrap().clone();
+        assert!(applied.room.is_none());
+        assert_eq!(applied.relative_position, Vector3::new(2.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn one_control_step_applies_every_pushed_update() {
+        let (sink, _capture) = crate::CaptureSink::new(SAMPLE_RATE, 2);
+        let info = Arc::new(RwLock::new(SourceInfo::default()));
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .source(info.clone())
+            .manual_control(true)
+            .build()
+            .unwrap();
+        let updates = handle.updates();
+        updates
+            .send(SourceUpdate::Relative {
+                source: handle.source_id(),
+                listener: 0,
+                info: SourceInfo {
+                    gain: 0.25,
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        for x in 1..=50 {
+            handle.set_position(Vector3::new(x as f32, 0.0, 0.0));
+        }
+        handle.step_control(Duration::from_millis(10)).unwrap();
+        assert!(updates.is_empty());
+        let applied = info.read().unwrap().clone();
+        assert_eq!(applied.gain, 0.25);
+        assert_eq!(applied.relative_position, Vector3::new(50.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn swapped_channels_mirror_the_output() {
+        let (sink, capture) = crate::CaptureSink::new(SAMPLE_RATE, 2);
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(sine_input(500.0))
+            .source(Arc::new(RwLock::new(SourceInfo {
+                relative_position: Vector3::new(0.0, 0.0, -2.0),
+                ..Default::default()
+            })))
+            .config(EngineConfig {
+                swap_channels: true,
+                ..Default::default()
+            })
+            .manual_control(true)
+            .build()
+            .unwrap();
+        let levels = || {
+            for _ in 0..50 {
+                handle.step_control(Duration::from_millis(10)).unwrap();
+                capture.render(441);
+            }
+            let frames = capture.render(4410);
+            let energy = |channel: usize| {
+                frames
+                    .iter()
+                    .skip(channel)
+                    .step_by(2)
+                    .map(|x| x * x)
+                    .sum::<f32>()
+            };
+            (energy(0), energy(1))
+        };
+        // On the left, played on the right.
+        assert!(handle.channels_swapped());
+        let (left, right) = levels();
+        assert!(right > 4.0 * left, "{left} {right}");
+        handle.set_swap_channels(false);
+        assert!(!handle.channels_swapped());
+        let (left, right) = levels();
+        assert!(left > 4.0 * right, "{left} {right}");
+        handle.stop();
+    }
+
+    #[test]
+    fn listener_room_follows_the_listener() {
+        let (sink, _frames) = crate::RingBufferSink::new(SAMPLE_RATE, 1024);
+        let mut world = World::new();
+        let room = world
+            .add_room(
+                InAnotherRoom::builder()
+                    .bounds(Vector3::new(0.0, -2.0, -2.0), Vector3::new(4.0, 2.0, 2.0))
+                    .build(SAMPLE_RATE as f32)
+                    .unwrap(),
+            )
+            .unwrap();
+        let listener = Arc::new(RwLock::new(Listener {
+            position: Vector3::new(-1.0, 0.0, 0.0),
+            ..Default::default()
+        }));
+        let source = Arc::new(RwLock::new(Source::default()));
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .world(listener.clone(), source)
+            .rooms(Arc::new(RwLock::new(world)))
+            .manual_control(true)
+            .build()
+            .unwrap();
+        let dt = Duration::from_millis(10);
+        handle.step_control(dt).unwrap();
+        assert_eq!(handle.listener_room(), None);
+        listener.write().unwrap().position.x = 1.0;
+        handle.step_control(dt).unwrap();
+        assert_eq!(handle.listener_room(), Some(room));
+        listener.write().unwrap().position.x = 5.0;
+        handle.step_control(dt).unwrap();
+        assert_eq!(handle.listener_room(), None);
+        let changes: Vec<_> = handle
+            .events()
+            .try_iter()
+            .filter(|event| matches!(event, EngineEvent::ListenerRoomChanged { .. }))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                EngineEvent::ListenerRoomChanged { room: Some(room) },
+                EngineEvent::ListenerRoomChanged { room: None },
+            ]
+        );
+        handle.stop();
+    }
+
+    #[test]
+    fn on_tick_reports_the_builder_source() {
+        let (sink, _frames) = crate::RingBufferSink::new(SAMPLE_RATE, 1024);
+        let (sender, metrics) = crossbeam_channel::unbounded();
+        let source_info = Arc::new(RwLock::new(SourceInfo {
+            relative_position: Vector3::new(3.0, 0.0, 4.0),
+            ..Default::default()
+        }));
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .source(source_info)
+            .on_tick(move |metrics| {
+                let _ = sender.send(*metrics);
+            })
+            .build()
+            .unwrap();
+        let metrics = metrics.recv().unwrap();
+        handle.stop();
+        assert_eq!(metrics.distance, 5.0);
+        assert!(metrics.gain > 0.0 && metrics.gain < 1.0);
+        assert!(metrics.pan.0 > 0.0 && metrics.pan.1 > 0.0);
+        assert!(!metrics.in_room);
+    }
+
+    #[test]
+    fn control_loop_runs_at_the_configured_period() {
+        let ticks = |period: Duration| {
+            let (sink, _frames) = crate::RingBufferSink::new(SAMPLE_RATE, 1024);
+            let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let counter = count.clone();
+            let handle = EngineBuilder::new()
+                .sink(sink)
+                .input(dc_input())
+                .config(EngineConfig {
+                    control_period: period,
+                    ..Default::default()
+                })
+                .on_tick(move |_| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                })
+                .build()
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+            handle.stop();
+            count.load(Ordering::Relaxed)
+        };
+        let slow = ticks(Duration::from_millis(50));
+        assert!((2..=8).contains(&slow), "{slow}");
+        assert!(ticks(CONTROL_PERIOD) > 3 * slow);
+    }
+
+    #[test]
+    fn paused_output_stops_producing_frames() {
+        let (sink, frames) = crate::RingBufferSink::new(SAMPLE_RATE, 64);
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .build()
+            .unwrap();
+        frames.recv().unwrap();
+        assert!(handle.is_playing());
+        handle.pause();
+        assert!(!handle.is_playing());
+        // Frames rendered ahead before the pause, the last one once the queue has room.
+        let timeout = Duration::from_millis(100);
+        for _ in frames.try_iter() {}
+        std::thread::sleep(timeout);
+        for _ in frames.try_iter() {}
+        assert!(frames.recv_timeout(timeout).is_err());
+        handle.resume();
+        assert!(handle.is_playing());
+        assert!(frames.recv_timeout(timeout).is_ok());
+    }
+
+    #[test]
+    fn pausing_does_not_wait_for_the_fade() {
+        let (sink, _frames) = crate::RingBufferSink::new(SAMPLE_RATE, 64);
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .config(EngineConfig {
+                output_fade: 10.0,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        handle.pause();
+        assert!(!handle.is_playing());
+        handle.resume();
+        assert!(handle.is_playing());
+        handle.pause();
+        drop(handle);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn still_source_plays_wave_untouched() {
+        let wave = Arc::new(Wave::render(
+            SAMPLE_RATE,
+            0.01,
+            &mut (noise() >> lowpass_hz(2000.0, 1.0)),
+        ));
+        let mut player = dc(1.0) >> An(LoopWave::new(wave.clone(), 0.0));
+        for i in 0..wave.length() * 2 {
+            assert_eq!(player.get_mono(), wave.at(0, i % wave.length()));
+        }
+    }
+
+    #[test]
+    fn approaching_source_pitches_up() {
+        let (_, params) = graph(&EngineConfig::default());
+        let mut controller = Controller::new(params, EngineConfig::default());
+        let mut info = SourceInfo {
+            relative_position: Vector3::new(10.0, 0.0, 0.0),
+            velocity: Vector3::new(-30.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        controller.update(&info, CONTROL_PERIOD.as_secs_f32());
+        let doppler = controller.params.doppler.clone().unwrap();
+        assert!(doppler.value() > 1.0);
+        info.velocity = -info.velocity;
+        controller.update(&info, CONTROL_PERIOD.as_secs_f32());
+        assert!(doppler.value() < 1.0);
+    }
+
+    #[test]
+    fn ambisonic_output_encodes_the_direction() {
+        let config = EngineConfig {
+            output_format: OutputFormat::Ambisonic1,
+            ..Default::default()
+        };
+        let (mut net, params) = graph(&config);
+        assert_eq!(net.outputs(), 4);
+        assert!(params.reflections.is_empty());
+        let mut controller = Controller::new(params, config);
+        // 90 degrees of azimuth, on the left.
+        let info = SourceInfo {
+            relative_position: Vector3::new(0.0, 0.0, -2.0),
+            ..Default::default()
+        };
+        controller.update(&info, 0.0);
+        let mut frame = [0.0; 4];
+        let mut energy = [0.0; 4];
+        for i in 0..SAMPLE_RATE as usize {
+            net.tick(&[], &mut frame);
+            if i >= SAMPLE_RATE as usize / 2 {
+                for (energy, sample) in energy.iter_mut().zip(frame) {
+                    *energy += sample * sample;
+                }
+            }
+        }
+        let [w, x, y, z] = energy;
+        assert!(y > 0.1, "{:?}", energy);
+        assert!(x < y * 1e-6 && z < y * 1e-6, "{:?}", energy);
+        // W at -3 dB of the source, as Y on its axis.
+        assert!((w / y - 0.5).abs() < 0.01, "{:?}", energy);
+    }
+
+    #[test]
+    fn hrtf_mode_convolves_the_nearest_measurement() {
+        let ear = |left: f32, right: f32, azimuth| Hrir {
+            azimuth,
+            elevation: 0.0,
+            left: vec![left],
+            right: vec![right],
+        };
+        let set = HrtfSet::new(SAMPLE_RATE, vec![ear(1.0, 0.0, 0.0), ear(0.0, 1.0, 180.0)]);
+        let config = EngineConfig {
+            spatialization: SpatializationMode::Hrtf,
+            hrtf: Some(Arc::new(set.unwrap())),
+            ..Default::default()
+        };
+        let energy = |x: f32| {
+            let (mut net, params) = graph(&config);
+            assert!(params.hrir.is_some());
+            let mut controller = Controller::new(params, config.clone());
+            let info = SourceInfo {
+                relative_position: Vector3::new(x, 0.0, 0.0),
+                ..Default::default()
+            };
+            controller.update(&info, 0.0);
+            let mut energy = (0.0, 0.0);
+            for i in 0..SAMPLE_RATE as usize {
+                let (left, right) = net.get_stereo();
+                if i >= SAMPLE_RATE as usize / 2 {
+                    energy.0 += left * left;
+                    energy.1 += right * right;
+                }
+            }
+            energy
+        };
+        let front = energy(2.0);
+        assert!(front.0 > 0.1 && front.1 < front.0 * 1e-3, "{:?}", front);
+        let back = energy(-2.0);
+        assert!(back.1 > 0.1 && back.0 < back.1 * 1e-3, "{:?}", back);
+
+        // Without a set, panned as by default.
+        let config = EngineConfig {
+            spatialization: SpatializationMode::Hrtf,
+            ..Default::default()
+        };
+        assert!(graph(&config).1.hrir.is_none());
+    }
+
+    #[test]
+    fn one_delay_per_ear_carries_propagation_and_itd() {
+        let config = EngineConfig {
+            head_radius: 0.08,
+            ..Default::default()
+        };
+        let (_, params) = graph(&config);
+        let mut controller = Controller::new(params, config);
+        // 90 degrees of azimuth, on the left.
+        let info = SourceInfo {
+            relative_position: Vector3::new(0.0, 0.0, -2.0),
+            ..Default::default()
+        };
+        controller.update(&info, 0.0);
+        let params = &controller.params;
+        let (left, right) = (params.left_delay.value(), params.right_delay.value());
+        let itd = 0.08 / crate::SOUND_SPEED * (std::f32::consts::FRAC_PI_2 + 1.0);
+        assert!(
+            (right - left - itd).abs() < 1e-7,
+            "{} {}",
+            right - left,
+            itd
+        );
+        assert!(((left + right) / 2.0 - 2.0 / crate::SOUND_SPEED).abs() < 1e-7);
+        // Within the head, the nearer ear is not delayed before the emission.
+        assert_eq!(ear_delays(0.0, itd), (0.0, itd));
+        assert_eq!(ear_delays(0.0, -itd), (itd, 0.0));
+    }
+
+    #[test]
+    fn speakers_have_no_itd() {
+        let config = EngineConfig {
+            output_mode: OutputMode::Speakers,
+            pan_law: crate::PanLaw::Linear,
+            ..Default::default()
+        };
+        let (_, params) = graph(&config);
+        let mut controller = Controller::new(params, config);
+        let info = SourceInfo {
+            relative_position: Vector3::new(2.0, 0.0, -2.0),
+            ..Default::default()
+        };
+        controller.update(&info, 0.0);
+        let params = &controller.params;
+        assert_eq!(params.left_delay.value(), params.right_delay.value());
+        assert!((params.left_delay.value() - 8f32.sqrt() / crate::SOUND_SPEED).abs() < 1e-7);
+        // Equal-power panning whatever the headphone pan law.
+        let (left, right) = (params.left_amp.value(), params.right_amp.value());
+        assert!((left * left + right * right - 1.0).abs() < 1e-5);
+        // Switched to headphones at run time.
+        params
+            .headphones
+            .set_value(headphones_value(OutputMode::Headphones));
+        // Long enough for the pan to settle.
+        controller.update(&info, 1.0);
+        let params = &controller.params;
+        assert!(params.right_delay.value() > params.left_delay.value());
+        let (left, right) = (params.left_amp.value(), params.right_amp.value());
+        assert!((left + right - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn world_placement_is_relative_to_the_listener() {
+        let (_, params) = graph(&EngineConfig::default());
+        let mut controller = Controller::new(params, EngineConfig::default());
+        // Facing +Z with +Y up, +X is on the left.
+        let listener = Listener {
+            position: Vector3::new(5.0, 0.0, 5.0),
+            forward: Vector3::new(0.0, 0.0, 1.0),
+            ..Default::default()
+        };
+        let source = Source {
+            position: Vector3::new(7.0, 0.0, 5.0),
+            ..Default::default()
+        };
+        controller.update_in_world(&listener, &source, None, CONTROL_PERIOD.as_secs_f32());
+        let params = &controller.params;
+        assert!((params.left_amp.value() - 1.0).abs() < 1e-6);
+        assert!(params.right_amp.value().abs() < 1e-6);
+        assert!((params.delay.value() - 2.0 / crate::SOUND_SPEED).abs() < 1e-6);
+    }
+
+    #[test]
+    fn walls_reflect_sources_in_the_listener_room() {
+        let config = EngineConfig {
+            interpolate_updates: false,
+            ..Default::default()
+        };
+        let (_, params) = graph(&config);
+        assert_eq!(params.reflections.len(), 4);
+        let mut controller = Controller::new(params, config);
+        let room = InAnotherRoom::builder()
+            .bounds(Vector3::new(-2.0, -2.0, -3.0), Vector3::new(4.0, 2.0, 3.0))
+            .build(SAMPLE_RATE as f32)
+            .unwrap();
+        let at = |x: f32| SourceInfo {
+            relative_position: Vector3::new(x, 0.0, 0.0),
+            room: Some(room.clone()),
+            ..Default::default()
+        };
+        let dt = CONTROL_PERIOD.as_secs_f32();
+
+        controller.update(&at(2.0), dt);
+        let reflections = &controller.params.reflections;
+        // Off the wall behind: 6 m away instead of 2, centered.
+        let behind = &reflections[0];
+        assert!((behind.delay.value() - 4.0 / crate::SOUND_SPEED).abs() < 1e-6);
+        assert!(behind.left.value() > 0.0);
+        assert!((behind.left.value() - behind.right.value()).abs() < 1e-6);
+        // The side walls mirror the source to either side.
+        let side = |i: usize| reflections[i].left.value() - reflections[i].right.value();
+        assert!(side(2).abs() > 0.01);
+        assert!((side(2) + side(3)).abs() < 1e-6);
+        // Out of the room, only the reverb is left.
+        controller.update(&at(8.0), dt);
+        assert!(controller
+            .params
+            .reflections
+            .iter()
+            .all(|reflection| reflection.left.value() == 0.0 && reflection.right.value() == 0.0));
+    }
+
+    #[test]
+    fn the_ground_reflects_sources_above_it() {
+        assert!(graph(&EngineConfig::default()).1.ground.is_none());
+        let config = |absorption| EngineConfig {
+            interpolate_updates: false,
+            early_reflections: 0,
+            ground_reflection: Some(absorption),
+            ..Default::default()
+        };
+        let ground = |absorption, listener_y: f32, source_y: f32| {
+            let (_, params) = graph(&config(absorption));
+            let mut controller = Controller::new(params, config(absorption));
+            let listener = Listener {
+                position: Vector3::new(0.0, listener_y, 0.0),
+                ..Default::default()
+            };
+            let source = Source {
+                position: Vector3::new(4.0, source_y, 0.0),
+                ..Default::default()
+            };
+            controller.update_in_world(&listener, &source, None, CONTROL_PERIOD.as_secs_f32());
+            controller.params.ground.clone().unwrap()
+        };
+        // Both 1.5 m above the ground 4 m apart: the image is 3 m below and 5 m away.
+        let pavement = ground(0.05, 1.5, 1.5);
+        assert!((pavement.delay.value() - 1.0 / crate::SOUND_SPEED).abs() < 1e-6);
+        assert!(pavement.left.value() > 0.0);
+        let grass = ground(0.5, 1.5, 1.5);
+        let ratio = grass.left.value() / pavement.left.value();
+        assert!((ratio - (0.5f32 / 0.95).sqrt()).abs() < 1e-4, "{}", ratio);
+        // Nothing reflects from below the ground.
+        let buried = ground(0.05, 1.5, -1.0);
+        assert_eq!((buried.left.value(), buried.right.value()), (0.0, 0.0));
+    }
+
+    #[test]
+    fn world_rooms_set_the_walls_and_glide_the_reverb() {
+        let config = EngineConfig {
+            interpolate_updates: false,
+            ..Default::default()
+        };
+        let (_, params) = graph(&config);
+        let mut controller = Controller::new(params, config);
+        let mut world = World::new();
+        let room = |x: f32, reverb_time: f32| {
+            InAnotherRoom::builder()
+                .material(crate::Material::Wood)
+                .reverb_time(reverb_time)
+                .bounds(Vector3::new(x, -2.0, -2.0), Vector3::new(x + 4.0, 2.0, 2.0))
+                .build(SAMPLE_RATE as f32)
+                .unwrap()
+        };
+        world.add_room(room(0.0, 1.0)).unwrap();
+        world.add_room(room(4.0, 2.0)).unwrap();
+        let mut listener = Listener {
+            position: Vector3::new(1.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        let source = |x: f32| Source {
+            position: Vector3::new(x, 0.0, 0.0),
+            ..Default::default()
+        };
+        let dt = CONTROL_PERIOD.as_secs_f32();
+
+        // Same room: its reverb, no wall in between.
+        controller.update_in_world(&listener, &source(3.0), Some(&world), dt);
+        assert_eq!(controller.params.occlusion.value(), 1.0);
+        assert_eq!(controller.params.reverb_send.value(), 1.0);
+        assert_eq!(controller.params.reverb_time.value(), 1.0);
+        // Out of the rooms, heard through the listener room wall.
+        controller.update_in_world(&listener, &source(12.0), Some(&world), dt);
+        assert!(controller.params.occlusion.value() < 0.1);
+        // Walking into the next room glides to its reverb.
+        listener.position.x = 6.0;
+        controller.update_in_world(&listener, &source(7.0), Some(&world), dt);
+        let reverb_time = controller.params.reverb_time.value();
+        assert!(reverb_time > 1.0 && reverb_time < 1.1, "{reverb_time}");
+        for _ in 0..600 {
+            controller.update_in_world(&listener, &source(7.0), Some(&world), dt);
+        }
+        assert!((controller.params.reverb_time.value() - 2.0).abs() < 1e-3);
+        assert_eq!(controller.params.occlusion.value(), 1.0);
+    }
+
+    /// Samples needed for the left output to fall under 10% of its settled value.
+    fn samples_to_fall(backend: &mut NetBackend) -> usize {
+        let settled = backend.get_stereo().0;
+        (1..SAMPLE_RATE as usize)
+            .find(|_| backend.get_stereo().0 < settled * 0.1)
+            .unwrap()
+    }
+
+    #[test]
+    fn gain_and_movement_ramps_are_independent() {
+        let config = EngineConfig {
+            movement_smoothing: 0.1,
+            gain_ramp: 0.005,
+            ..Default::default()
+        };
+
+        let (mut backend, params) = graph(&config);
+        for _ in 0..SAMPLE_RATE as usize {
+            backend.get_stereo();
+        }
+        params.unmuted.set_value(0.0);
+        let mute = samples_to_fall(&mut backend);
+
+        let (mut backend, params) = graph(&config);
+        for _ in 0..SAMPLE_RATE as usize {
+            backend.get_stereo();
+        }
+        params.amplitude.set_value(0.0);
+        let movement = samples_to_fall(&mut backend);
+
+        assert!(mute * 10 < movement, "mute: {mute}, movement: {movement}");
+    }
+
+    #[test]
+    fn steady_state_rendering_never_allocates() {
+        let set = HrtfSet::new(
+            SAMPLE_RATE,
+            vec![Hrir {
+                azimuth: 0.0,
+                elevation: 0.0,
+                left: vec![1.0; 300],
+                right: vec![0.5; 300],
+            }],
+        );
+        let configs = [
+            EngineConfig::default(),
+            EngineConfig {
+                early_reflections: MAX_REFLECTIONS,
+                air_model: Some(AirModel::default()),
+                mic_compressor: Some(crate::Compressor::default()),
+                mic_gate: Some(crate::NoiseGate::default()),
+                ..Default::default()
+            },
+            EngineConfig {
+                output_format: OutputFormat::Ambisonic1,
+                ..Default::default()
+            },
+            EngineConfig {
+                spatialization: SpatializationMode::Hrtf,
+                hrtf: Some(Arc::new(set.unwrap())),
+                ..Default::default()
+            },
+        ];
+        let info = SourceInfo {
+            relative_position: Vector3::new(3.0, 0.5, -2.0),
+            velocity: Vector3::new(-5.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        for config in &configs {
+            let (_mic, frames) = crossbeam_channel::bounded(16);
+            let inputs = [
+                dc_input(),
+                InputSource::OneShot(Wave::render(SAMPLE_RATE, 0.1, &mut sine_hz(440.0))),
+                InputSource::Node(Box::new(noise())),
+                InputSource::Mic(frames),
+            ];
+            for input in inputs {
+                let mut backend = build_backend(config, input, &info, SAMPLE_RATE).unwrap();
+                let mut frame = vec![0.0; backend.outputs()];
+                // Past the first blocks, which may size buffers lazily.
+                for _ in 0..1024 {
+                    backend.tick(&[], &mut frame);
+                }
+                // Aborts the test run on any allocation in debug builds.
+                assert_no_alloc::assert_no_alloc(|| {
+                    for _ in 0..SAMPLE_RATE as usize {
+                        backend.tick(&[], &mut frame);
+                    }
+                });
+                assert!(frame.iter().all(|sample| sample.is_finite()));
+            }
+        }
+    }
+
+    #[test]
+    fn node_inputs_play_at_the_output_rate() {
+        let node = Box::new(sine_hz(1000.0)) as Box<dyn AudioUnit>;
+        let (mut net, _params) = build_graph(
+            InputSource::Node(node).into(),
+            &EngineConfig::default(),
+            1,
+            &MasterParams::new(&EngineConfig::default()),
+        );
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        let left: Vec<f32> = (0..SAMPLE_RATE as usize)
+            .map(|_| backend.get_stereo().0)
+            .collect();
+        // 500 periods of the sine over the last half second.
+        let second_half = &left[left.len() / 2..];
+        let rising = second_half
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        assert!((499..=501).contains(&rising), "{}", rising);
+
+        let (sink, _frames) = crate::RingBufferSink::new(SAMPLE_RATE, 1024);
+        let stereo = Box::new(noise() | noise()) as Box<dyn AudioUnit>;
+        let error = EngineBuilder::new()
+            .sink(sink)
+            .input(InputSource::Node(stereo))
+            .build()
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("one output"), "{}", error);
+    }
+
+    #[test]
+    fn active_source_crossfades() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut inputs = Inputs::from(dc_input());
+        inputs.add(InputSource::Mic(receiver));
+        let (mut net, mut params) = build_graph(
+            inputs,
+            &EngineConfig::default(),
+            1,
+            &MasterParams::new(&EngineConfig::default()),
+        );
+        let params = params.remove(0);
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        for _ in 0..SAMPLE_RATE as usize {
+            backend.get_stereo();
+        }
+        let wave = backend.get_stereo().0;
+        assert!(wave > 0.0);
+
+        // The mic stays silent, the wave fades out instead of being cut.
+        params.mic_mix.set_value(mic_mix_value(SourceKind::Mic));
+        let first = backend.get_stereo().0;
+        assert!(first > wave * 0.9, "first: {first}, wave: {wave}");
+        let fall = samples_to_fall(&mut backend);
+        assert!(
+            fall > 10 && fall < SAMPLE_RATE as usize / 10,
+            "fall: {fall}"
+        );
+        for _ in 0..SAMPLE_RATE as usize / 10 {
+            backend.get_stereo();
+        }
+        assert!(backend.get_stereo().0.abs() < wave * 1e-3);
+        drop(sender);
+    }
+
+    #[test]
+    fn occlusion_flicker_is_smoothed() {
+        let (mut backend, params) = graph(&EngineConfig::default());
+        for _ in 0..SAMPLE_RATE as usize {
+            backend.get_stereo();
+        }
+        let settled = backend.get_stereo().0;
+
+        // Occlusion toggling every millisecond, as a ray grazing a wall edge.
+        let (mut low, mut high) = (f32::MAX, f32::MIN);
+        for block in 0..1000 {
+            params
+                .occlusion
+                .set_value(if block % 2 == 0 { 0.0 } else { 1.0 });
+            for _ in 0..SAMPLE_RATE as usize / 1000 {
+                let left = backend.get_stereo().0;
+                if block >= 500 {
+                    low = low.min(left);
+                    high = high.max(left);
+                }
+            }
+        }
+        assert!(high - low < settled * 0.05, "{low}..{high} of {settled}");
+    }
+
+    #[test]
+    fn listeners_get_their_own_channels() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        let (mut net, params) =
+            build_graph(dc_input().into(), &config, 2, &MasterParams::new(&config));
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        assert_eq!(backend.outputs(), 4);
+        // The source is on the left of the first listener and on the right of the second.
+        for (params, z) in params.into_iter().zip([-1.0, 1.0]) {
+            let info = SourceInfo {
+                relative_position: Vector3::new(0.0, 0.0, z),
+                ..Default::default()
+            };
+            Controller::new(params, config.clone()).update(&info, CONTROL_PERIOD.as_secs_f32());
+        }
+        let mut frame = [0.0; 4];
+        for _ in 0..SAMPLE_RATE as usize {
+            backend.tick(&[], &mut frame);
+        }
+        assert!(frame[0] > 0.5 && frame[1].abs() < 1e-6, "{frame:?}");
+        assert!(frame[2].abs() < 1e-6 && frame[3] > 0.5, "{frame:?}");
+    }
+
+    /// Room filtering the source without attenuating it.
+    fn open_room() -> InAnotherRoom {
+        InAnotherRoom {
+            wall_width: 0.0,
+            wall_attenuation_factor: 0.0,
+            wall_filter: WallFilter::LowPass(2000.0),
+            transmission_law: TransmissionLaw::Exponential,
+            min_transmission: 0.0,
+            reverb_time: 0.0,
+            room_size: 10.0,
+            reverb_predelay: 0.0,
+            bounds: None,
+        }
+    }
+
+    /// Left channel peak over the second after `info` is applied.
+    /// Run one second of control passes on `info`, as the control loop would.
+    fn settle(backend: &mut NetBackend, controller: &mut Controller, info: &SourceInfo) {
+        let period = CONTROL_PERIOD.as_secs_f32();
+        for _ in 0..(1.0 / period) as usize {
+            controller.update(info, period);
+            for _ in 0..(SAMPLE_RATE as f32 * period) as usize {
+                backend.get_stereo();
+            }
+        }
+    }
+
+    fn settled_peak(
+        backend: &mut NetBackend,
+        controller: &mut Controller,
+        info: &SourceInfo,
+    ) -> f32 {
+        settle(backend, controller, info);
+        (0..1000)
+            .map(|_| backend.get_stereo().0.abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn room_exit_restores_open_air_cutoff() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        let (mut net, mut params) = build_graph(
+            sine_input(5000.0).into(),
+            &config,
+            1,
+            &MasterParams::new(&config),
+        );
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        let mut controller = Controller::new(params.remove(0), config);
+        let outside = SourceInfo {
+            relative_position: Vector3::new(1.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        let inside = SourceInfo {
+            room: Some(open_room()),
+            ..outside.clone()
+        };
+
+        let open_air = settled_peak(&mut backend, &mut controller, &outside);
+        let muffled = settled_peak(&mut backend, &mut controller, &inside);
+        assert!(muffled < open_air * 0.5, "{muffled} vs {open_air}");
+        let restored = settled_peak(&mut backend, &mut controller, &outside);
+        assert!(
+            (restored - open_air).abs() < open_air * 0.05,
+            "{restored} vs {open_air}"
+        );
+    }
+
+    #[test]
+    fn far_sources_sound_duller() {
+        assert_eq!(air_cutoff(0.0, 0.005), OPEN_AIR_CUTOFF);
+        assert!(air_cutoff(2.0, 0.005) > 19000.0);
+        assert!((air_cutoff(200.0, 0.005) - OPEN_AIR_CUTOFF / std::f32::consts::E).abs() < 1.0);
+        assert_eq!(air_cutoff(1e5, 0.005), MIN_AIR_CUTOFF);
+        assert_eq!(air_cutoff(1e5, 0.0), OPEN_AIR_CUTOFF);
+
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            max_propagation_delay: 0.0,
+            ..Default::default()
+        };
+        let (mut net, mut params) = build_graph(
+            sine_input(5000.0).into(),
+            &config,
+            1,
+            &MasterParams::new(&config),
+        );
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        let mut controller = Controller::new(params.remove(0), config);
+        let at = |x| SourceInfo {
+            relative_position: Vector3::new(x, 0.0, 0.0),
+            ..Default::default()
+        };
+        let near = settled_peak(&mut backend, &mut controller, &at(2.0));
+        let far = settled_peak(&mut backend, &mut controller, &at(300.0));
+        assert!(far < near * 0.85, "{far} vs {near}");
+        // Walls duller than the air win.
+        let walled = SourceInfo {
+            room: Some(open_room()),
+            ..at(300.0)
+        };
+        controller.update(&walled, CONTROL_PERIOD.as_secs_f32());
+        assert_eq!(controller.params.material_cutoff.value(), 2000.0);
+    }
+
+    #[test]
+    fn elevation_tilts_the_highs() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        let (mut net, mut params) = build_graph(
+            sine_input(12000.0).into(),
+            &config,
+            1,
+            &MasterParams::new(&config),
+        );
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        let mut controller = Controller::new(params.remove(0), config);
+        let peaks: Vec<f32> = [-3.0, 0.0, 3.0]
+            .into_iter()
+            .map(|y| {
+                let info = SourceInfo {
+                    relative_position: Vector3::new(1.0, y, 0.0),
+                    ..Default::default()
+                };
+                // Level of both channels, whatever the pan.
+                settle(&mut backend, &mut controller, &info);
+                let (left, right) = (0..1000)
+                    .map(|_| backend.get_stereo())
+                    .fold((0.0_f32, 0.0_f32), |(left, right), (l, r)| {
+                        (left.max(l.abs()), right.max(r.abs()))
+                    });
+                left.hypot(right)
+            })
+            .collect();
+        let (below, level, above) = (peaks[0], peaks[1], peaks[2]);
+        assert!(below < level * 0.7 && above > level * 1.4, "{peaks:?}");
+    }
+
+    #[test]
+    fn room_exit_ramps_cutoff() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        let (mut net, mut params) = build_graph(
+            sine_input(5000.0).into(),
+            &config,
+            1,
+            &MasterParams::new(&config),
+        );
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        let mut controller = Controller::new(params.remove(0), config);
+        let outside = SourceInfo {
+            relative_position: Vector3::new(1.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        let inside = SourceInfo {
+            room: Some(open_room()),
+            ..outside.clone()
+        };
+
+        let open_air = settled_peak(&mut backend, &mut controller, &outside);
+        settled_peak(&mut backend, &mut controller, &inside);
+        // Leaving the room reopens the filter progressively.
+        controller.update(&outside, CONTROL_PERIOD.as_secs_f32());
+        let first_ms = (0..SAMPLE_RATE as usize / 1000)
+            .map(|_| backend.get_stereo().0.abs())
+            .fold(0.0, f32::max);
+        assert!(first_ms < open_air * 0.5, "{first_ms} vs {open_air}");
+        let restored = settled_peak(&mut backend, &mut controller, &outside);
+        assert!(
+            (restored - open_air).abs() < open_air * 0.05,
+            "{restored} vs {open_air}"
+        );
+    }
+
+    #[test]
+    fn walls_toggled_for_a_tick_glide() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        let (mut backend, params) = graph(&config);
+        let mut controller = Controller::new(params, config);
+        let outside = SourceInfo {
+            relative_position: Vector3::new(1.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        // Standing in a doorway: behind a thick wall for one control pass.
+        let inside = SourceInfo {
+            room: Some(InAnotherRoom {
+                wall_width: 0.1,
+                wall_attenuation_factor: 50.0,
+                ..open_room()
+            }),
+            ..outside.clone()
+        };
+        let open_air = settled_peak(&mut backend, &mut controller, &outside);
+        let period = CONTROL_PERIOD.as_secs_f32();
+        let mut lowest = f32::INFINITY;
+        for info in [&inside, &outside, &outside] {
+            controller.update(info, period);
+            for _ in 0..(SAMPLE_RATE as f32 * period) as usize {
+                lowest = lowest.min(backend.get_stereo().0.abs());
+            }
+        }
+        // A 5 ms pass dips by about a tenth with the 50 ms default.
+        assert!(lowest > 0.85 * open_air, "{lowest} {open_air}");
+        // Staying behind the wall muffles the source within a few time constants.
+        for _ in 0..40 {
+            controller.update(&inside, period);
+            for _ in 0..(SAMPLE_RATE as f32 * period) as usize {
+                backend.get_stereo();
+            }
+        }
+        let walled = (0..1000)
+            .map(|_| backend.get_stereo().0.abs())
+            .fold(0.0, f32::max);
+        assert!(walled < 0.1 * open_air, "{walled} {open_air}");
+    }
+
+    #[test]
+    fn room_cutoff_is_applied() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        let room = open_room();
+        let (mut net, mut params) = build_graph(
+            sine_input(2000.0).into(),
+            &config,
+            1,
+            &MasterParams::new(&config),
+        );
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        let mut controller = Controller::new(params.remove(0), config);
+        let outside = SourceInfo {
+            relative_position: Vector3::new(1.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        let inside = SourceInfo {
+            room: Some(room.clone()),
+            ..outside.clone()
+        };
+
+        let open_air = settled_peak(&mut backend, &mut controller, &outside);
+        let muffled = settled_peak(&mut backend, &mut controller, &inside);
+        assert_eq!(controller.params.material_cutoff.value(), 2000.0);
+        // A tone at the cutoff frequency comes out 3 dB down.
+        let ratio = muffled / open_air;
+        assert!((ratio - 0.5_f32.sqrt()).abs() < 0.05, "{ratio}");
+    }
+
+    #[test]
+    fn obstruction_muffles_on_top_of_walls() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            air_absorption: 0.0,
+            ..Default::default()
+        };
+        let (_, params) = graph(&config);
+        let mut controller = Controller::new(params, config);
+        let mut apply = |obstruction, room| {
+            let info = SourceInfo {
+                relative_position: Vector3::new(1.0, 0.0, 0.0),
+                obstruction,
+                room,
+                ..Default::default()
+            };
+            controller.update(&info, CONTROL_PERIOD.as_secs_f32());
+            let params = &controller.params;
+            (params.material_cutoff.value(), params.occlusion.value())
+        };
+        assert_eq!(apply(0.0, None), (OPEN_AIR_CUTOFF, 1.0));
+        let (cutoff, gain) = apply(1.0, None);
+        assert_eq!(cutoff, crate::OBSTRUCTION_CUTOFF);
+        assert!((gain - db_amp(-6.0)).abs() < 1e-6, "{gain}");
+        // Halfway in octaves and in decibels.
+        let (cutoff, gain) = apply(0.5, None);
+        assert!(
+            (cutoff - (OPEN_AIR_CUTOFF * 1000.0).sqrt()).abs() < 1.0,
+            "{cutoff}"
+        );
+        assert!((gain - db_amp(-3.0)).abs() < 1e-6, "{gain}");
+        // Behind a wall as well, the lowest cutoff is kept and the gains multiply.
+        let wall = InAnotherRoom {
+            wall_attenuation_factor: 100.0,
+            wall_width: 0.01,
+            wall_filter: WallFilter::LowPass(500.0),
+            ..open_room()
+        };
+        let transmission = wall_transmission(&wall, 1.0);
+        assert!(transmission < 0.5);
+        let (cutoff, gain) = apply(1.0, Some(wall.clone()));
+        assert_eq!(cutoff, 500.0);
+        assert!((gain - transmission * db_amp(-6.0)).abs() < 1e-6, "{gain}");
+        assert_eq!(apply(1.0, Some(open_room())).0, crate::OBSTRUCTION_CUTOFF);
+        assert_eq!(apply(0.0, Some(wall)).0, 500.0);
+    }
+
+    #[test]
+    fn band_pass_walls_keep_their_band() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        let room = |wall_filter| InAnotherRoom {
+            wall_filter,
+            ..open_room()
+        };
+        // Level of a tone at `hz` through the walls of `room`, against the open air.
+        let through = |hz: f32, room: InAnotherRoom| {
+            let (mut net, mut params) = build_graph(
+                sine_input(hz).into(),
+                &config,
+                1,
+                &MasterParams::new(&config),
+            );
+            net.set_sample_rate(SAMPLE_RATE);
+            let mut backend = net.backend();
+            let mut controller = Controller::new(params.remove(0), config.clone());
+            let outside = SourceInfo {
+                relative_position: Vector3::new(1.0, 0.0, 0.0),
+                ..Default::default()
+            };
+            let inside = SourceInfo {
+                room: Some(room),
+                ..outside.clone()
+            };
+            let open_air = settled_peak(&mut backend, &mut controller, &outside);
+            settled_peak(&mut backend, &mut controller, &inside) / open_air
+        };
+        let band = room(WallFilter::BandPass {
+            center: 1000.0,
+            q: 4.0,
+        });
+        let peak = through(1000.0, band.clone());
+        assert!((peak - 1.0).abs() < 0.1, "{peak}");
+        assert!(through(4000.0, band) < 0.2);
+        let notch = room(WallFilter::Notch {
+            center: 1000.0,
+            q: 4.0,
+        });
+        assert!(through(1000.0, notch.clone()) < 0.1);
+        assert!(through(4000.0, notch) > 0.9);
+    }
+
+    #[test]
+    fn velocity_is_estimated_from_positions() {
+        let period = CONTROL_PERIOD.as_secs_f32();
+        let mut motion = VelocityEstimate::default();
+        let mut estimate = |x: f32| {
+            motion
+                .update(&Vector3::new(x, 0.0, 0.0), period, 0.1, 343.0)
+                .x
+        };
+        assert_eq!(estimate(0.0), 0.0);
+        // 5 m/s, updated every 4 control passes (20 ms), jittering by 1 cm.
+        let mut estimates = Vec::new();
+        for tick in 1..400 {
+            let update = tick / 4 * 4;
+            let jitter = if update % 8 == 0 { 0.01 } else { -0.01 };
+            estimates.push(estimate(5.0 * update as f32 * period + jitter));
+        }
+        let settled = &estimates[200..];
+        assert!(settled.iter().all(|v| (v - 5.0).abs() < 0.5), "{settled:?}");
+        let mean = settled.iter().sum::<f32>() / settled.len() as f32;
+        assert!((mean - 5.0).abs() < 0.05, "{mean}");
+        // Stopped, then teleported.
+        let stopped = 5.0 * 396.0 * period + 0.01;
+        let last = (0..200).map(|_| estimate(stopped)).last().unwrap();
+        assert!(last.abs() < 0.05, "{last}");
+        for tick in 0..40 {
+            estimate(stopped + tick as f32 * period);
+        }
+        assert!(estimate(1000.0) == 0.0);
+
+        // Given velocities win over the estimate.
+        let (_, params) = graph(&EngineConfig::default());
+        let mut controller = Controller::new(params, EngineConfig::default());
+        let moving = |x: f32| SourceInfo {
+            relative_position: Vector3::new(x, 0.0, 0.0),
+            velocity: Vector3::new(0.0, 0.0, 1.0),
+            ..Default::default()
+        };
+        controller.interpolate(moving(0.0), period);
+        let info = controller.interpolate(moving(1.0), period);
+        assert_eq!(info.velocity, Vector3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn snapped_delays_do_not_sweep() {
+        let period = CONTROL_PERIOD.as_secs_f32();
+        let at = |x| SourceInfo {
+            relative_position: Vector3::new(x, 0.0, 0.0),
+            ..Default::default()
+        };
+        // Zero crossings of a 500 Hz sine over the 0.2 s after a 100 m jump, 200 unbent.
+        let crossings = |delay_mode, snap_next: bool| {
+            let config = EngineConfig {
+                attenuation: AttenuationModel::None,
+                output_mode: OutputMode::Speakers,
+                velocity_smoothing: None,
+                delay_mode,
+                ..Default::default()
+            };
+            let (mut net, mut params) = build_graph(
+                sine_input(500.0).into(),
+                &config,
+                1,
+                &MasterParams::new(&config),
+            );
+            net.set_sample_rate(SAMPLE_RATE);
+            let mut backend = net.backend();
+            let mut controller = Controller::new(params.remove(0), config);
+            settle(&mut backend, &mut controller, &at(1.0));
+            if snap_next {
+                controller.snap_delays();
+            }
+            let (mut crossings, mut previous) = (0, backend.get_stereo().0);
+            for _ in 0..(0.2 / period) as usize {
+                controller.update(&at(100.0), period);
+                for _ in 0..(SAMPLE_RATE as f32 * period) as usize {
+                    let sample = backend.get_stereo().0;
+                    if (sample < 0.0) != (previous < 0.0) {
+                        crossings += 1;
+                    }
+                    previous = sample;
+                }
+            }
+            crossings
+        };
+        let swept = crossings(DelayMode::Interpolate, false);
+        assert!(!(190..=210).contains(&swept), "{swept}");
+        let snapped = crossings(DelayMode::Snap, false);
+        assert!((198..=202).contains(&snapped), "{snapped}");
+        let snapped = crossings(DelayMode::Interpolate, true);
+        assert!((198..=202).contains(&snapped), "{snapped}");
+    }
+
+    #[test]
+    fn unsmoothed_parameters_follow_each_update() {
+        let period = CONTROL_PERIOD.as_secs_f32();
+        let at = |x| SourceInfo {
+            relative_position: Vector3::new(x, 0.0, 0.0),
+            ..Default::default()
+        };
+        // Level 100 ms after moving from 1 m to 40 m, over the one settled there, without
+        // propagation delay.
+        let lag = |smoothing| {
+            let config = EngineConfig {
+                smoothing,
+                output_mode: OutputMode::Speakers,
+                max_propagation_delay: 0.0,
+                ..Default::default()
+            };
+            let (mut net, mut params) = build_graph(
+                sine_input(1000.0).into(),
+                &config,
+                1,
+                &MasterParams::new(&config),
+            );
+            net.set_sample_rate(SAMPLE_RATE);
+            let mut backend = net.backend();
+            let mut controller = Controller::new(params.remove(0), config);
+            let peak = |backend: &mut NetBackend| {
+                (0..100)
+                    .map(|_| backend.get_stereo().0.abs())
+                    .fold(0.0, f32::max)
+            };
+            settle(&mut backend, &mut controller, &at(40.0));
+            let far = peak(&mut backend);
+            settle(&mut backend, &mut controller, &at(1.0));
+            for _ in 0..20 {
+                controller.update(&at(40.0), period);
+                for _ in 0..(SAMPLE_RATE as f32 * period) as usize {
+                    backend.get_stereo();
+                }
+            }
+            peak(&mut backend) / far
+        };
+        let unsmoothed = lag(Smoothing::None);
+        assert!((unsmoothed - 1.0).abs() < 0.05, "{unsmoothed}");
+        let smoothed = lag(Smoothing::PerParameter);
+        assert!(smoothed > 1.5, "{smoothed}");
+        let slower = lag(Smoothing::TimeConstant(0.5));
+        assert!(slower > smoothed, "{slower} vs {smoothed}");
+    }
+
+    #[test]
+    fn updates_are_replayed_along_a_path() {
+        let at = |x| SourceInfo {
+            relative_position: Vector3::new(x, 0.0, 0.0),
+            ..Default::default()
+        };
+        let mut path = UpdatePath::default();
+        let mut played = |x, dt| path.advance(&at(x), dt).relative_position.x;
+        assert_eq!(played(0.0, 0.0), 0.0);
+        // Updated every 20 ms, read every 5 ms.
+        assert_eq!(played(0.0, 0.005), 0.0);
+        assert_eq!(played(0.0, 0.005), 0.0);
+        assert_eq!(played(0.0, 0.005), 0.0);
+        assert_eq!(played(1.0, 0.005), 0.0);
+        assert!((played(1.0, 0.005) - 0.25).abs() < 1e-6);
+        assert!((played(1.0, 0.01) - 0.75).abs() < 1e-6);
+        // A change midway starts from where the path was.
+        assert!((played(2.0, 0.0025) - 0.875).abs() < 1e-6);
+        assert!((played(2.0, 0.0125) - (0.875 + 1.125 * 0.0125 / 0.0175)).abs() < 1e-5);
+        assert_eq!(played(2.0, 1.0), 2.0);
+        // Rare updates jump, for the smoothers to glide over.
+        assert!((played(3.0, 1.0) - 2.0).abs() < 1e-6);
+        assert!((played(3.0, MAX_UPDATE_SPAN) - 3.0).abs() < 1e-6);
+    }
+
+    /// Left gain after moving a source from the left to the right of the listener, updating
+    /// the controller every `steps` seconds.
+    fn left_gain_after(steps: &[f32]) -> f32 {
+        // The jump itself, not spread along a path.
+        let config = EngineConfig {
+            interpolate_updates: false,
+            ..Default::default()
+        };
+        let (_, params) = graph(&config);
+        let mut controller = Controller::new(params, config);
+        let at = |z| SourceInfo {
+            relative_position: Vector3::new(0.0, 0.0, z),
+            ..Default::default()
+        };
+        controller.update(&at(-1.0), 0.0);
+        for &dt in steps {
+            controller.update(&at(1.0), dt);
+        }
+        controller.params.left_amp.value()
+    }
+
+    #[test]
+    fn pan_smoothing_is_frame_rate_independent() {
+        let regular = left_gain_after(&[0.005; 20]);
+        let irregular = left_gain_after(&[0.001, 0.03, 0.004, 0.002, 0.063]);
+        assert!(regular > 0.1 && regular < 0.9, "{regular}");
+        assert!(
+            (regular - irregular).abs() < 1e-5,
+            "{regular} vs {irregular}"
+        );
+    }
+
+    #[test]
+    fn fast_pan_has_no_zipper_noise() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        let (mut backend, params) = graph(&config);
+        let mut controller = Controller::new(params, config);
+        let at = |z| SourceInfo {
+            relative_position: Vector3::new(0.0, 0.0, z),
+            ..Default::default()
+        };
+        controller.update(&at(-1.0), 0.0);
+        for _ in 0..SAMPLE_RATE as usize {
+            backend.get_stereo();
+        }
+        // Swinging from side to side on every update, with large steps.
+        let mut previous = backend.get_stereo().0;
+        let mut largest_jump: f32 = 0.0;
+        for step in 0..40 {
+            let z = if step % 2 == 0 { 1.0 } else { -1.0 };
+            controller.update(&at(z), 0.05);
+            for _ in 0..(CONTROL_PERIOD.as_secs_f64() * SAMPLE_RATE) as usize {
+                let left = backend.get_stereo().0;
+                largest_jump = largest_jump.max((left - previous).abs());
+                previous = left;
+            }
+        }
+        assert!(largest_jump < 0.01, "{largest_jump}");
+    }
+
+    #[test]
+    fn one_shot_fades_out_before_finishing() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            one_shot_fade: 0.01,
+            ..Default::default()
+        };
+        let wave = Wave::render(SAMPLE_RATE, 0.1, &mut dc(1.0));
+        let length = wave.length();
+        let (mut net, mut params) = build_graph(
+            InputSource::OneShot(wave).into(),
+            &config,
+            1,
+            &MasterParams::new(&config),
+        );
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        let params = params.remove(0);
+        params.left_amp.set_value(1.0);
+
+        let output: Vec<f32> = (0..length).map(|_| backend.get_stereo().0).collect();
+        let fade = (0.01 * SAMPLE_RATE) as usize;
+        let body = output[length - fade - 1];
+        assert!(body > 0.5);
+        // The tail ramps down to silence, and only then the wave counts as finished.
+        assert!(output[length - fade / 2] < body * 0.6);
+        assert!(output[length - 1] < body * 0.01);
+        assert!(output[length - fade..].windows(2).all(|w| w[1] <= w[0]));
+        assert_eq!(params.finished.value(), 1.0);
+        assert!(backend.get_stereo().0 < body * 0.01);
+    }
+
+    #[test]
+    fn room_reverb_rings_after_the_source() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        // Energy of the left channel over 0.3 s, after a 50 ms burst played from `room`.
+        let tail = |room: InAnotherRoom| {
+            let wave = Wave::render(SAMPLE_RATE, 0.05, &mut dc(1.0));
+            let (mut net, mut params) = build_graph(
+                InputSource::OneShot(wave).into(),
+                &config,
+                1,
+                &MasterParams::new(&config),
+            );
+            net.set_sample_rate(SAMPLE_RATE);
+            let mut backend = net.backend();
+            let mut controller = Controller::new(params.remove(0), config.clone());
+            let info = SourceInfo {
+                relative_position: Vector3::new(1.0, 0.0, 0.0),
+                room: Some(room),
+                ..Default::default()
+            };
+            controller.update(&info, CONTROL_PERIOD.as_secs_f32());
+            for _ in 0..SAMPLE_RATE as usize / 5 {
+                backend.get_stereo();
+            }
+            (0..SAMPLE_RATE as usize * 3 / 10)
+                .map(|_| backend.get_stereo().0.powi(2))
+                .sum::<f32>()
+        };
+        let dry = open_room();
+        let reverberant = InAnotherRoom {
+            reverb_time: 1.0,
+            ..open_room()
+        };
+        assert!(tail(dry) < 1e-6);
+        assert!(tail(reverberant) > 1e-2);
+    }
+
+    #[test]
+    fn sources_past_their_range_are_silent() {
+        let config = EngineConfig {
+            min_gain: 0.1,
+            ..Default::default()
+        };
+        let info = SourceInfo {
+            relative_position: Vector3::new(30.0, 0.0, 10.0),
+            room: Some(InAnotherRoom {
+                reverb_time: 1.0,
+                ..open_room()
+            }),
+            max_distance: Some(20.0),
+            ..Default::default()
+        };
+        let mut backend = build_backend(&config, dc_input(), &info, SAMPLE_RATE).unwrap();
+        for _ in 0..SAMPLE_RATE as usize {
+            backend.get_stereo();
+        }
+        for _ in 0..SAMPLE_RATE as usize {
+            assert_eq!(backend.get_stereo(), (0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn bypass_passes_stereo_through_the_master_gain() {
+        let config = EngineConfig {
+            spatialization: SpatializationMode::Bypass,
+            ..Default::default()
+        };
+        let mut wave = Wave::render(SAMPLE_RATE, 1.0, &mut (sine_hz(440.0) ^ sine_hz(660.0)));
+        let right: Vec<f32> = wave.channel(1).iter().map(|x| 0.5 * x).collect();
+        wave.remove_channel(1);
+        wave.push_channel(&right);
+        let master = MasterParams::new(&config);
+        master.volume.set_value(0.5);
+        let (mut net, mut params) = build_graph(
+            InputSource::Stereo(wave.clone()).into(),
+            &config,
+            1,
+            &master,
+        );
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        let mut controller = Controller::new(params.remove(0), config);
+        // Far off to the side, moving: neither panned, attenuated, delayed nor pitched.
+        let info = SourceInfo {
+            relative_position: Vector3::new(3.0, 0.0, 20.0),
+            velocity: Vector3::new(0.0, 0.0, -30.0),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            controller.update(&info, CONTROL_PERIOD.as_secs_f32());
+        }
+        for i in 0..SAMPLE_RATE as usize * 3 / 4 {
+            let (left, right) = backend.get_stereo();
+            if i >= SAMPLE_RATE as usize / 2 {
+                assert!(
+                    (left - 0.5 * wave.at(0, i)).abs() < 1e-4,
+                    "{} at {}",
+                    left,
+                    i
+                );
+                assert!(
+                    (right - 0.5 * wave.at(1, i)).abs() < 1e-4,
+                    "{} at {}",
+                    right,
+                    i
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bypass_rejects_ambisonic_output() {
+        let config = EngineConfig {
+            spatialization: SpatializationMode::Bypass,
+            output_format: OutputFormat::Ambisonic1,
+            ..Default::default()
+        };
+        let (sink, _capture) = crate::CaptureSink::new(SAMPLE_RATE, 4);
+        let built = EngineBuilder::new()
+            .sink(sink)
+            .input(dc_input())
+            .config(config.clone())
+            .manual_control(true)
+            .build();
+        assert!(matches!(built, Err(VoiceImmersionError::InvalidConfig(_))));
+        let built = build_backend(&config, dc_input(), &SourceInfo::default(), SAMPLE_RATE);
+        assert!(matches!(built, Err(VoiceImmersionError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn stereo_channels_play_from_either_side() {
+        let config = EngineConfig {
+            output_mode: OutputMode::Speakers,
+            ..Default::default()
+        };
+        let info = SourceInfo {
+            relative_position: Vector3::new(2.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        // Left and right energies of a tone on the given channels of a stereo wave.
+        let energies = |left: f32, right: f32| {
+            let mut wave = Wave::new(0, SAMPLE_RATE);
+            let tone = Wave::render(SAMPLE_RATE, 1.0, &mut sine_hz(440.0));
+            wave.push_channel(&tone.channel(0).iter().map(|x| x * left).collect::<Vec<_>>());
+            wave.push_channel(
+                &tone
+                    .channel(0)
+                    .iter()
+                    .map(|x| x * right)
+                    .collect::<Vec<_>>(),
+            );
+            let mut backend =
+                build_backend(&config, InputSource::Stereo(wave), &info, SAMPLE_RATE).unwrap();
+            let (mut left, mut right) = (0.0, 0.0);
+            for i in 0..SAMPLE_RATE as usize {
+                let (l, r) = backend.get_stereo();
+                if i > SAMPLE_RATE as usize / 2 {
+                    left += l * l;
+                    right += r * r;
+                }
+            }
+            (left, right)
+        };
+        let (left, right) = energies(1.0, 0.0);
+        assert!(left > 2.0 * right, "{} {}", left, right);
+        let (left, right) = energies(0.0, 1.0);
+        assert!(right > 2.0 * left, "{} {}", left, right);
+        let (left, right) = energies(1.0, 1.0);
+        assert!((left / right - 1.0).abs() < 1e-3, "{} {}", left, right);
+
+        let mono = Wave::render(SAMPLE_RATE, 1.0, &mut sine_hz(440.0));
+        assert!(build_backend(&config, InputSource::Stereo(mono), &info, SAMPLE_RATE).is_err());
+    }
+
+    #[test]
+    fn reverb_rises_with_distance() {
+        // Energy of the reverb tail after a 50 ms burst from `distance` meters.
+        let tail = |factor: f32, distance: f32| {
+            let config = EngineConfig {
+                attenuation: AttenuationModel::None,
+                output_mode: OutputMode::Speakers,
+                reverb_distance_factor: factor,
+                ..Default::default()
+            };
+            let wave = Wave::render(SAMPLE_RATE, 0.05, &mut dc(1.0));
+            let mut backend = build_backend(
+                &config,
+                InputSource::OneShot(wave),
+                &SourceInfo {
+                    relative_position: Vector3::new(distance, 0.0, 0.0),
+                    room: Some(InAnotherRoom {
+                        reverb_time: 1.0,
+                        ..open_room()
+                    }),
+                    ..Default::default()
+                },
+                SAMPLE_RATE,
+            )
+            .unwrap();
+            for _ in 0..SAMPLE_RATE as usize / 5 {
+                backend.get_stereo();
+            }
+            (0..SAMPLE_RATE as usize * 3 / 10)
+                .map(|_| backend.get_stereo().0.powi(2))
+                .sum::<f32>()
+        };
+        // Constant without the factor, the direct sound not being attenuated, but for the
+        // tail arriving a few milliseconds later.
+        let (near, far) = (tail(0.0, 1.0), tail(0.0, 2.0));
+        assert!((far / near - 1.0).abs() < 0.1, "{near} {far}");
+        // Wet gains of 2 and 3, their squares in energy.
+        let (near, far) = (tail(1.0, 1.0), tail(1.0, 2.0));
+        assert!((far / near - 2.25).abs() < 0.25, "{near} {far}");
+        assert_eq!(reverb_distance_gain(100.0, 0.25), MAX_REVERB_DISTANCE_GAIN);
+    }
+
+    #[test]
+    fn reverb_predelay() {
+        // Samples from an impulse to the reverb onset.
+        let onset = |predelay: f32| {
+            let mut reverb = Reverb::new(&shared(1.0), &shared(10.0), &shared(predelay));
+            reverb.set_sample_rate(SAMPLE_RATE);
+            let mut input = [1.0, 1.0];
+            (0..SAMPLE_RATE as usize)
+                .position(|_| {
+                    let output = reverb.tick(&input.into());
+                    input = [0.0, 0.0];
+                    output[0].abs() > 1e-6
+                })
+                .unwrap()
+        };
+        assert_eq!(onset(0.05) - onset(0.0), (0.05 * SAMPLE_RATE) as usize);
+    }
+
+    #[test]
+    fn waves_play_at_their_own_rate() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            ..Default::default()
+        };
+        let wave = Wave::render(48000.0, 0.5, &mut dc(1.0));
+        let (mut net, mut params) = build_graph(
+            InputSource::OneShot(wave).into(),
+            &config,
+            1,
+            &MasterParams::new(&config),
+        );
+        net.set_sample_rate(SAMPLE_RATE);
+        let mut backend = net.backend();
+        let params = params.remove(0);
+        let played = (0..SAMPLE_RATE as usize)
+            .position(|_| {
+                backend.get_stereo();
+                params.finished.value() > 0.0
+            })
+            .unwrap();
+        let expected = SAMPLE_RATE as usize / 2;
+        assert!(played.abs_diff(expected) <= 1, "{played} vs {expected}");
+    }
+
+    #[test]
+    fn idle_pause_after_timeout() {
+        let mut idle = IdleDetector::new(Some(Duration::from_secs(2)));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(idle.update(at(0), true), None);
+        assert_eq!(idle.update(at(100), false), None);
+        assert_eq!(idle.update(at(2000), false), None);
+        assert_eq!(idle.update(at(2100), false), Some(true));
+        assert_eq!(idle.update(at(3000), false), None);
+        assert_eq!(idle.update(at(3005), true), Some(false));
+        // Silence starts over.
+        assert_eq!(idle.update(at(3010), false), None);
+        assert_eq!(idle.update(at(4000), false), None);
+
+        let mut never = IdleDetector::new(None);
+        assert_eq!(never.update(at(0), false), None);
+        assert_eq!(never.update(at(100_000), false), None);
+    }
+
+    #[test]
+    fn idle_output_stops_producing_frames() {
+        let (sink, capture) = crate::CaptureSink::new(SAMPLE_RATE, 2);
+        let handle = EngineBuilder::new()
+            .sink(sink)
+            .input(sine_input(500.0))
+            .source(Arc::new(RwLock::new(SourceInfo {
+                relative_position: Vector3::new(0.0, 0.0, -2.0),
+                ..Default::default()
+            })))
+            .config(EngineConfig {
+                idle_pause_timeout: Some(Duration::from_secs(1)),
+                ..Default::default()
+            })
+            .manual_control(true)
+            .build()
+            .unwrap();
+        // 100 ms of control passes and output at a time.
+        let step = || {
+            handle.step_control(Duration::from_millis(100)).unwrap();
+            capture.render(4410)
+        };
+        for _ in 0..5 {
+            assert!(step().iter().any(|&x| x.abs() > 1e-3));
+        }
+        handle.set_muted(true);
+        let mut last = Vec::new();
+        let silent_steps = (0..100)
+            .position(|_| {
+                let frames = step();
+                let paused = frames.is_empty();
+                if !paused {
+                    last = frames;
+                }
+                paused
+            })
+            .unwrap();
+        // Paused once the output faded out, not as soon as the gains did.
+        assert!(silent_steps > 10, "{silent_steps}");
+        assert!(last.iter().all(|&x| x.abs() < IDLE_LEVEL));
+        assert!(handle.stats().idle);
+        assert!(step().is_empty());
+
+        handle.set_muted(false);
+        handle.step_control(Duration::from_millis(10)).unwrap();
+        assert!(!handle.stats().idle);
+        for _ in 0..5 {
+            step();
+        }
+        assert!(step().iter().any(|&x| x.abs() > 1e-3));
+    }
+
+    /// Right channel peak of a hard left source once settled.
+    fn hard_left_right_peak(mode: OutputMode) -> f32 {
+        let config = EngineConfig {
+            output_mode: mode,
+            ..Default::default()
+        };
+        let (mut backend, params) = graph(&config);
+        params.left_amp.set_value(1.0);
+        params.right_amp.set_value(0.0);
+        for _ in 0..SAMPLE_RATE as usize {
+            backend.get_stereo();
+        }
+        (0..1000)
+            .map(|_| backend.get_stereo().1.abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn output_mode_crossfeed() {
+        assert_eq!(hard_left_right_peak(OutputMode::Speakers), 0.0);
+        assert!(hard_left_right_peak(OutputMode::Headphones) > 0.1);
+    }
+
+    /// Right channel peak of a hard left source at `distance` once settled.
+    fn hard_left_right_peak_at(distance: f32, config: EngineConfig) -> f32 {
+        let (mut backend, params) = graph(&config);
+        let mut controller = Controller::new(params, config);
+        let info = SourceInfo {
+            relative_position: Vector3::new(0.0, 0.0, -distance),
+            ..Default::default()
+        };
+        controller.update(&info, CONTROL_PERIOD.as_secs_f32());
+        for _ in 0..SAMPLE_RATE as usize {
+            backend.get_stereo();
+        }
+        (0..1000)
+            .map(|_| backend.get_stereo().1.abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn binaural_cues_bypassed_far_away() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            binaural_cue_distance: 20.0,
+            ..Default::default()
+        };
+        assert!(hard_left_right_peak_at(10.0, config.clone()) > 0.0);
+        assert_eq!(hard_left_right_peak_at(30.0, config), 0.0);
+    }
+
+    #[test]
+    fn air_model_cuts_highs_more_with_distance() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            air_model: Some(AirModel::default()),
+            ..Default::default()
+        };
+        let drop = |frequency: f32| {
+            let (mut net, mut params) = build_graph(
+                sine_input(frequency).into(),
+                &config,
+                1,
+                &MasterParams::new(&config),
+            );
+            net.set_sample_rate(SAMPLE_RATE);
+            let mut backend = net.backend();
+            let mut controller = Controller::new(params.remove(0), config.clone());
+            let at = |x| SourceInfo {
+                relative_position: Vector3::new(x, 0.0, 0.0),
+                ..Default::default()
+            };
+            let near = settled_peak(&mut backend, &mut controller, &at(1.0));
+            settled_peak(&mut backend, &mut controller, &at(100.0)) / near
+        };
+        assert!(drop(200.0) > 0.9, "{}", drop(200.0));
+        assert!(drop(8000.0) < 0.3, "{}", drop(8000.0));
+    }
+
+    #[test]
+    fn crossfeed_amount() {
+        let config = |crossfeed| EngineConfig {
+            attenuation: AttenuationModel::None,
+            crossfeed,
+            ..Default::default()
+        };
+        assert_eq!(hard_left_right_peak_at(1.0, config(0.0)), 0.0);
+        let light = hard_left_right_peak_at(1.0, config(0.1));
+        assert!(light > 0.0);
+        assert!(hard_left_right_peak_at(1.0, config(0.5)) > light);
+    }
+}
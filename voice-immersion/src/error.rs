@@ -0,0 +1,117 @@
+use std::fmt;
+
+/// What went wrong in a public function, for embedders to handle each failure on its own
+/// rather than matching messages. Every variant carries a message naming the device, file
+/// or setting at fault.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VoiceImmersionError {
+    /// No device, none of that name, or an audio backend missing from this system.
+    DeviceUnavailable(String),
+    /// The device does not support the stream configuration or sample format asked for.
+    UnsupportedFormat(String),
+    /// An audio file could not be opened or decoded, see `load_audio`.
+    WaveLoad(String),
+    /// An input or output stream could not be built or started.
+    StreamBuild(String),
+    /// Invalid settings or inputs: rooms, HRTF sets, scenes, input nodes...
+    InvalidConfig(String),
+    /// A call the engine cannot honor in its current state, e.g. recording twice.
+    InvalidState(String),
+    /// A file could not be read or written, e.g. a scene or a recording.
+    Io(String),
+}
+
+/// Result of the public functions.
+pub type Result<T, E = VoiceImmersionError> = std::result::Result<T, E>;
+
+impl fmt::Display for VoiceImmersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoiceImmersionError::DeviceUnavailable(message)
+            | VoiceImmersionError::UnsupportedFormat(message)
+            | VoiceImmersionError::WaveLoad(message)
+            | VoiceImmersionError::StreamBuild(message)
+            | VoiceImmersionError::InvalidConfig(message)
+            | VoiceImmersionError::InvalidState(message)
+            | VoiceImmersionError::Io(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for VoiceImmersionError {}
+
+impl From<cpal::HostUnavailable> for VoiceImmersionError {
+    fn from(error: cpal::HostUnavailable) -> Self {
+        VoiceImmersionError::DeviceUnavailable(error.to_string())
+    }
+}
+
+impl From<cpal::DevicesError> for VoiceImmersionError {
+    fn from(error: cpal::DevicesError) -> Self {
+        VoiceImmersionError::DeviceUnavailable(error.to_string())
+    }
+}
+
+impl From<cpal::DefaultStreamConfigError> for VoiceImmersionError {
+    fn from(error: cpal::DefaultStreamConfigError) -> Self {
+        match error {
+            cpal::DefaultStreamConfigError::DeviceNotAvailable => {
+                VoiceImmersionError::DeviceUnavailable(error.to_string())
+            }
+            _ => VoiceImmersionError::UnsupportedFormat(error.to_string()),
+        }
+    }
+}
+
+impl From<cpal::SupportedStreamConfigsError> for VoiceImmersionError {
+    fn from(error: cpal::SupportedStreamConfigsError) -> Self {
+        match error {
+            cpal::SupportedStreamConfigsError::DeviceNotAvailable => {
+                VoiceImmersionError::DeviceUnavailable(error.to_string())
+            }
+            _ => VoiceImmersionError::UnsupportedFormat(error.to_string()),
+        }
+    }
+}
+
+impl From<cpal::BuildStreamError> for VoiceImmersionError {
+    fn from(error: cpal::BuildStreamError) -> Self {
+        match error {
+            cpal::BuildStreamError::DeviceNotAvailable => {
+                VoiceImmersionError::DeviceUnavailable(error.to_string())
+            }
+            cpal::BuildStreamError::StreamConfigNotSupported => {
+                VoiceImmersionError::UnsupportedFormat(error.to_string())
+            }
+            _ => VoiceImmersionError::StreamBuild(error.to_string()),
+        }
+    }
+}
+
+impl From<cpal::PlayStreamError> for VoiceImmersionError {
+    fn from(error: cpal::PlayStreamError) -> Self {
+        match error {
+            cpal::PlayStreamError::DeviceNotAvailable => {
+                VoiceImmersionError::DeviceUnavailable(error.to_string())
+            }
+            _ => VoiceImmersionError::StreamBuild(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_errors_keep_their_kind() {
+        let error = VoiceImmersionError::from(cpal::BuildStreamError::StreamConfigNotSupported);
+        assert!(matches!(error, VoiceImmersionError::UnsupportedFormat(_)));
+        let error = VoiceImmersionError::from(cpal::PlayStreamError::DeviceNotAvailable);
+        assert!(matches!(error, VoiceImmersionError::DeviceUnavailable(_)));
+        assert_eq!(
+            VoiceImmersionError::WaveLoad("No audio track in a.wav.".into()).to_string(),
+            "No audio track in a.wav."
+        );
+    }
+}
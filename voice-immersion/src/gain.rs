@@ -0,0 +1,47 @@
+/// Gain in decibels, for the setters taking one: the engine works on linear gains.
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Decibels(pub f32);
+
+impl Decibels {
+    /// Linear amplitude gain: 1 at 0 dB, about 0.501 at -6 dB and 0 at -inf dB.
+    pub fn to_linear(self) -> f32 {
+        10.0_f32.powf(self.0 / 20.0)
+    }
+
+    /// Gain of the linear amplitude gain `gain`, -inf dB for silence.
+    pub fn from_linear(gain: f32) -> Self {
+        Decibels(20.0 * gain.log10())
+    }
+}
+
+impl From<Decibels> for f32 {
+    fn from(gain: Decibels) -> f32 {
+        gain.to_linear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_conversions() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-3;
+        assert_eq!(Decibels(0.0).to_linear(), 1.0);
+        assert!(close(Decibels(-6.0).to_linear(), 0.501));
+        assert!(close(Decibels(6.0).to_linear(), 1.995));
+        assert!(close(Decibels(-20.0).to_linear(), 0.1));
+        assert!(close(Decibels(-40.0).to_linear(), 0.01));
+        assert_eq!(Decibels(f32::NEG_INFINITY).to_linear(), 0.0);
+
+        assert_eq!(Decibels::from_linear(1.0), Decibels(0.0));
+        assert!(close(Decibels::from_linear(0.5).0, -6.021));
+        assert!(close(Decibels::from_linear(10.0).0, 20.0));
+        assert_eq!(Decibels::from_linear(0.0).0, f32::NEG_INFINITY);
+        assert!(close(
+            Decibels::from_linear(Decibels(-12.5).to_linear()).0,
+            -12.5
+        ));
+    }
+}
@@ -0,0 +1,250 @@
+use std::fmt;
+use std::path::Path;
+
+use fundsp::fft::real_fft;
+use nalgebra::Vector3;
+use num_complex::Complex32;
+
+use crate::decode::load_audio;
+use crate::VoiceImmersionError;
+use crate::FORWARD_VECTOR;
+
+/// Samples per partition of the HRIR convolution, and latency of the HRTF path.
+pub(crate) const HRTF_BLOCK: usize = 128;
+
+/// Longest impulse response in samples at 48 kHz, bounding the convolution cost.
+const MAX_HRIR_LENGTH: usize = 2048;
+
+/// Head-related impulse responses of the left and right ears for a source in one direction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hrir {
+    /// Degrees counterclockwise from the front, 90 on the left, as in SOFA files.
+    pub azimuth: f32,
+    /// Degrees above the horizon, -90 right below to 90 right above.
+    pub elevation: f32,
+    pub left: Vec<f32>,
+    pub right: Vec<f32>,
+}
+
+impl Hrir {
+    /// Read the pair from the first two channels of an audio file, see `load_audio`. SOFA
+    /// files are not read directly yet, their sets being exported one direction per file.
+    pub fn load(azimuth: f32, elevation: f32, path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let wave = load_audio(path)?;
+        if wave.channels() < 2 {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "{} has {} channel, left and right ones are needed.",
+                path.display(),
+                wave.channels()
+            )));
+        }
+        Ok(Hrir {
+            azimuth,
+            elevation,
+            left: wave.channel(0).clone(),
+            right: wave.channel(1).clone(),
+        })
+    }
+
+    /// Unit direction in the frame of the listener: front, left and up components.
+    fn direction(&self) -> Vector3<f32> {
+        let (azimuth, elevation) = (self.azimuth.to_radians(), self.elevation.to_radians());
+        Vector3::new(
+            elevation.cos() * azimuth.cos(),
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+        )
+    }
+}
+
+/// Measured HRTF set of `SpatializationMode::Hrtf`, the nearest measurement to the source
+/// direction being convolved with it. Resampled linearly to the output rate when recorded at
+/// another one.
+pub struct HrtfSet {
+    sample_rate: f64,
+    measurements: Vec<Hrir>,
+    directions: Vec<Vector3<f32>>,
+}
+
+impl HrtfSet {
+    /// Set of `measurements` recorded at `sample_rate`. Errors without any measurement, on
+    /// undefined directions, and on empty or too long responses.
+    pub fn new(sample_rate: f64, measurements: Vec<Hrir>) -> crate::Result<Self> {
+        if !(sample_rate.is_finite() && sample_rate > 0.0) {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Invalid HRTF sample rate {}.",
+                sample_rate
+            )));
+        }
+        if measurements.is_empty() {
+            return Err(VoiceImmersionError::InvalidConfig(
+                "The HRTF set has no measurements.".into(),
+            ));
+        }
+        for (i, hrir) in measurements.iter().enumerate() {
+            if !(hrir.azimuth.is_finite() && hrir.elevation.is_finite()) {
+                return Err(VoiceImmersionError::InvalidConfig(format!(
+                    "Measurement {} has no direction.",
+                    i
+                )));
+            }
+            let length = Ord::max(hrir.left.len(), hrir.right.len());
+            if length == 0 || length as f64 / sample_rate * 48000.0 > MAX_HRIR_LENGTH as f64 {
+                return Err(VoiceImmersionError::InvalidConfig(format!(
+                    "Measurement {} lasts {} samples, HRIRs need 1 to {} at 48 kHz.",
+                    i, length, MAX_HRIR_LENGTH
+                )));
+            }
+        }
+        let directions = measurements.iter().map(Hrir::direction).collect();
+        Ok(HrtfSet {
+            sample_rate,
+            measurements,
+            directions,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.measurements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.measurements.is_empty()
+    }
+
+    /// Index of the measurement nearest to `direction`, given by its front, left and up
+    /// components. Zero directions count as the front.
+    pub(crate) fn nearest(&self, direction: &Vector3<f32>) -> usize {
+        let direction = direction
+            .try_normalize(f32::EPSILON)
+            .unwrap_or(FORWARD_VECTOR);
+        let mut nearest = (0, f32::NEG_INFINITY);
+        for (i, measured) in self.directions.iter().enumerate() {
+            let closeness = measured.dot(&direction);
+            if closeness > nearest.1 {
+                nearest = (i, closeness);
+            }
+        }
+        nearest.0
+    }
+
+    /// Spectra of the partitions of every response at `sample_rate`.
+    pub(crate) fn spectra(&self, sample_rate: f64) -> HrirSpectra {
+        let step = self.sample_rate / sample_rate;
+        let resampled = |response: &[f32]| -> Vec<f32> {
+            let length = ((response.len() as f64 / step).ceil() as usize).max(1);
+            (0..length)
+                .map(|i| {
+                    let position = i as f64 * step;
+                    let index = position as usize;
+                    let a = response.get(index).copied().unwrap_or(0.0);
+                    let b = response.get(index + 1).copied().unwrap_or(0.0);
+                    a + (b - a) * (position - index as f64) as f32
+                })
+                .collect()
+        };
+        let responses: Vec<[Vec<f32>; 2]> = self
+            .measurements
+            .iter()
+            .map(|hrir| [resampled(&hrir.left), resampled(&hrir.right)])
+            .collect();
+        let longest = responses.iter().flatten().map(Vec::len).max().unwrap_or(1);
+        let partitions = longest.div_ceil(HRTF_BLOCK);
+        let bins = HRTF_BLOCK + 1;
+        let mut data = Vec::with_capacity(responses.len() * 2 * partitions * bins);
+        let mut padded = vec![0.0; 2 * HRTF_BLOCK];
+        let mut spectrum = vec![Complex32::default(); bins];
+        for response in responses.iter().flatten() {
+            for partition in 0..partitions {
+                padded.fill(0.0);
+                let start = Ord::min(partition * HRTF_BLOCK, response.len());
+                let end = Ord::min(start + HRTF_BLOCK, response.len());
+                padded[..end - start].copy_from_slice(&response[start..end]);
+                real_fft(&padded, &mut spectrum);
+                data.extend_from_slice(&spectrum);
+            }
+        }
+        HrirSpectra {
+            partitions,
+            bins,
+            data,
+        }
+    }
+}
+
+impl fmt::Debug for HrtfSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HrtfSet")
+            .field("sample_rate", &self.sample_rate)
+            .field("measurements", &self.measurements.len())
+            .finish()
+    }
+}
+
+/// Partition spectra of an `HrtfSet` at one sample rate, for the convolution.
+pub(crate) struct HrirSpectra {
+    pub(crate) partitions: usize,
+    pub(crate) bins: usize,
+    data: Vec<Complex32>,
+}
+
+impl HrirSpectra {
+    /// Spectrum of partition `partition` of the response of `ear` (0 left, 1 right) of
+    /// measurement `measurement`.
+    #[inline]
+    pub(crate) fn partition(
+        &self,
+        measurement: usize,
+        ear: usize,
+        partition: usize,
+    ) -> &[Complex32] {
+        let start = ((measurement * 2 + ear) * self.partitions + partition) * self.bins;
+        &self.data[start..start + self.bins]
+    }
+
+    pub(crate) fn measurements(&self) -> usize {
+        self.data.len() / (2 * self.partitions * self.bins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impulse(azimuth: f32, elevation: f32) -> Hrir {
+        Hrir {
+            azimuth,
+            elevation,
+            left: vec![1.0],
+            right: vec![1.0],
+        }
+    }
+
+    #[test]
+    fn nearest_measurement_by_direction() {
+        let set = HrtfSet::new(
+            48000.0,
+            vec![
+                impulse(0.0, 0.0),
+                impulse(90.0, 0.0),
+                impulse(180.0, 0.0),
+                impulse(0.0, 90.0),
+            ],
+        )
+        .unwrap();
+        assert_eq!(set.nearest(&Vector3::new(1.0, 0.2, 0.0)), 0);
+        assert_eq!(set.nearest(&Vector3::new(0.0, 3.0, 0.0)), 1);
+        assert_eq!(set.nearest(&Vector3::new(-1.0, 0.0, 0.1)), 2);
+        assert_eq!(set.nearest(&Vector3::new(0.1, 0.0, 1.0)), 3);
+        assert_eq!(set.nearest(&Vector3::zeros()), 0);
+
+        assert!(HrtfSet::new(48000.0, Vec::new()).is_err());
+        assert!(HrtfSet::new(48000.0, vec![impulse(f32::NAN, 0.0)]).is_err());
+        let long = Hrir {
+            left: vec![0.0; 10000],
+            ..impulse(0.0, 0.0)
+        };
+        assert!(HrtfSet::new(48000.0, vec![long]).is_err());
+    }
+}
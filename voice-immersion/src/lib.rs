@@ -1,56 +1,273 @@
 #![allow(clippy::precedence)]
 
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 
-use assert_no_alloc::*;
-use cpal::traits::{DeviceTrait, StreamTrait};
 use cpal::{FromSample, SizedSample};
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{bounded, Receiver, Sender};
 use fundsp::hacker::*;
-use hacker32::sine;
 use nalgebra::Vector3;
-use numeric_array::generic_array::arr;
 
-#[cfg(all(debug_assertions, feature = "enable_alloc_disabler"))]
+#[cfg(all(
+    debug_assertions,
+    feature = "no-alloc-guard",
+    feature = "warn_on_alloc"
+))]
+mod alloc_check;
+mod analyzer;
+mod attenuation;
+mod capture;
+mod clock;
+mod config;
+mod coordinates;
+mod decode;
+mod devices;
+mod dsp;
+mod engine;
+mod error;
+mod gain;
+mod hrtf;
+mod offline;
+mod recorder;
+mod room;
+mod scene;
+mod sink;
+mod spatial;
+mod telemetry;
+
+pub use attenuation::{AirModel, AttenuationModel, Cone, NearField, PIECEWISE_FLOOR};
+pub use config::{
+    ChannelMap, Compressor, DelayMode, EngineConfig, Limiter, NoiseGate, OutputFormat,
+    OutputLayout, OutputMode, PanLaw, ReverbSettings, Smoothing, SpatializationMode,
+    StreamRecovery,
+};
+pub use coordinates::{Axis, CoordinateSystem, Handedness};
+pub use decode::{downmix, load_audio, FileStream};
+pub use devices::{list_input_devices, list_output_devices, open_input_device, open_output_device};
+pub use engine::{build_backend, EngineBuilder, InputSource, Placement, SourceKind, SpatialHandle};
+pub use error::{Result, VoiceImmersionError};
+pub use gain::Decibels;
+pub use hrtf::{Hrir, HrtfSet};
+pub use offline::{
+    render_offline, render_offline_with, render_scene, OfflineScene, OfflineSource, Trajectory,
+};
+use room::{diffracted_occlusion, wall_transmission};
+pub use room::{
+    occlusion_factor, wall_amount, walls_crossed, Aabb, Material, RoomBuilder, RoomId, WallFilter,
+    World,
+};
+pub use scene::{PlacementSnapshot, Scene, SceneSnapshot, SourceId, SourceSnapshot, SourceUpdate};
+pub use sink::{
+    make_config, negotiate_config, validate_config, AudioSink, Capture, CaptureSink, CpalSink,
+    OutputConfig, Renderer, RingBufferSink,
+};
+pub use spatial::{
+    compute_distance_attenuation, compute_spherical, compute_spherical_with, compute_stereo_gains,
+    immersion_metric, stereo_gains, Listener, Source, Spherical,
+};
+pub use telemetry::{EngineEvent, EngineStats, Latency, OutputLevels, SourceMetrics, Telemetry};
+
+#[cfg(all(
+    debug_assertions,
+    feature = "no-alloc-guard",
+    not(feature = "warn_on_alloc")
+))]
 #[global_allocator]
-static A: AllocDisabler = AllocDisabler;
+static A: assert_no_alloc::AllocDisabler = assert_no_alloc::AllocDisabler;
+
+#[cfg(all(
+    debug_assertions,
+    feature = "no-alloc-guard",
+    feature = "warn_on_alloc"
+))]
+#[global_allocator]
+static A: alloc_check::WarningAllocator = alloc_check::WarningAllocator;
+
+/// Speed of sound in air at 20 °C, in meters per second.
+pub const SOUND_SPEED: f32 = 343.0;
 
-const SOUND_SPEED: f32 = 343.0;
+/// Speed of sound in dry air at `celsius` degrees, in meters per second, see
+/// `EngineConfig::sound_speed`.
+pub fn sound_speed(celsius: f32) -> f32 {
+    331.3 * (1.0 + celsius / 273.15).max(0.0).sqrt()
+}
+/// Default head radius in meters, see `EngineConfig::head_radius` and `Listener::head_radius`.
 pub const HEAD_RADIUS: f32 = 0.10;
-const UP_VECTOR: Vector3<f32> = Vector3::new(0.0, 1.0, 0.0);
+/// Highest `SourceInfo::gain`, +12 dB, so that a mistyped level cannot reach feedback levels.
+pub const MAX_SOURCE_GAIN: f32 = 4.0;
+/// Output buffers the mic channel holds, to absorb capture and playback callbacks jitter.
+const MIC_BUFFERS: usize = 4;
+/// Output buffer size assumed when the device picks it.
+pub const DEFAULT_BUFFER_FRAMES: usize = 1024;
+/// Shortest audio duration in seconds the mic channel holds, whatever the buffer size.
+const MIN_MIC_LATENCY: f64 = 0.05;
+pub(crate) const UP_VECTOR: Vector3<f32> = Vector3::new(0.0, 1.0, 0.0);
+/// Direction the listener faces by default, and when given a zero one.
+pub(crate) const FORWARD_VECTOR: Vector3<f32> = Vector3::new(1.0, 0.0, 0.0);
+
+/// How the wall absorption `wall_width * wall_attenuation_factor` maps to a transmitted gain.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TransmissionLaw {
+    /// `exp(-absorption)`.
+    #[default]
+    Exponential,
+    /// `1 - absorption`, reaching silence at an absorption of 1.
+    Linear,
+    /// The attenuation factor is read in dB per meter of wall.
+    Decibels,
+}
 
-#[derive(Debug, Clone)]
+impl TransmissionLaw {
+    /// Gain from 0 to 1 let through by `absorption`, negative absorptions letting
+    /// everything through.
+    pub fn transmission(self, absorption: f32) -> f32 {
+        let absorption = absorption.max(0.0);
+        let gain = match self {
+            TransmissionLaw::Exponential => (-absorption).exp(),
+            TransmissionLaw::Linear => 1.0 - absorption,
+            TransmissionLaw::Decibels => 10.0_f32.powf(-absorption / 20.0),
+        };
+        gain.clamp(0.0, 1.0)
+    }
+}
+
+/// Room the source is heard from, behind a wall. See `InAnotherRoom::builder` for
+/// checked construction.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InAnotherRoom {
+    /// Thickness of the wall in meters, negative widths counting as none.
     pub wall_width: f32,
+    /// Absorption per meter of wall, read by `transmission_law`: per meter for
+    /// `Exponential` and `Linear`, in dB per meter for `Decibels`. Negative factors count
+    /// as none, walls never amplifying.
     pub wall_attenuation_factor: f32,
-    pub cutoff_frequency: f32,
+    /// Coloration of the sound going through the walls, see `WallFilter`.
+    pub wall_filter: WallFilter,
+    pub transmission_law: TransmissionLaw,
+    /// Lowest gain let through the wall, so a thick wall never fully silences the source.
+    pub min_transmission: f32,
+    /// Decay time in seconds of the room reverb to -60 dB, 0 for a dry room.
+    pub reverb_time: f32,
+    /// Size of the room in meters, from 1 to 40, setting the reverb echo density.
+    pub room_size: f32,
+    /// Gap in seconds between the direct sound and the onset of the reverb, up to 0.25:
+    /// larger rooms have longer ones.
+    pub reverb_predelay: f32,
+    /// Box of the room walls, in the frame of the source position: relative to the listener
+    /// in `SourceInfo`, world coordinates in `Source`. The walls then only attenuate and
+    /// muffle the source when on the line of sight, see `walls_crossed`. Always heard
+    /// through one wall when `None`.
+    pub bounds: Option<Aabb>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SourceInfo {
     pub relative_position: Vector3<f32>,
+    /// Direction the listener faces, of any non-zero length.
     pub direction: Vector3<f32>,
     pub room: Option<InAnotherRoom>,
+    /// Size of the source in world units: within it the sound surrounds the listener instead of
+    /// coming from a point. 0 for point sources.
+    pub radius: f32,
+    /// Velocity of the source relative to the listener in world units per second, pitching
+    /// waves up as it comes closer and down as it goes away. Estimated from the successive
+    /// positions when zero, see `EngineConfig::velocity_smoothing`.
+    pub velocity: Vector3<f32>,
+    /// Direction the source faces, of any non-zero length, for its directivity.
+    pub source_direction: Vector3<f32>,
+    /// Directivity of the source, omnidirectional when `None`.
+    pub directivity: Option<Cone>,
+    /// Linear level of the source, multiplying the distance attenuation so that it can be
+    /// balanced independently of the distance tuning. Clamped to `MAX_SOURCE_GAIN`.
+    pub gain: f32,
+    /// Distance in world units from the surface of the source within which it plays at full
+    /// level, `EngineConfig::attenuation` rolling off from there.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub min_distance: f32,
+    /// Distance in world units from the surface of the source past which it is exactly
+    /// silent, whatever `EngineConfig::min_gain`, so that it can be culled. Heard at any
+    /// distance when `None`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_distance: Option<f32>,
+    /// Distance in world units between the left and right channels of
+    /// `InputSource::Stereo`, played from either side of the source across the line of
+    /// sight: seen from `d` away, the stereo field spans `2 * atan(stereo_width / (2 * d))`,
+    /// 53 degrees for the default 2 m at 2 m. Unused by mono inputs.
+    #[cfg_attr(feature = "serde", serde(default = "default_stereo_width"))]
+    pub stereo_width: f32,
+    /// Share from 0 to 1 of the source hidden behind obstacles in the same room, e.g. from
+    /// raycasts against furniture: it muffles the source down to `OBSTRUCTION_CUTOFF` and
+    /// lowers it by up to 6 dB, see `obstruction_gain`. Walls occlude on top of it: the
+    /// lowest of the wall, air and obstruction cutoffs is kept, and their gains multiply.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub obstruction: f32,
+    /// Height in world units of the listener above the ground plane of
+    /// `EngineConfig::ground_reflection`, taken from the listener position for world
+    /// placements. There is no ground reflection from or to below the ground.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub listener_height: f32,
+}
+
+/// Default `SourceInfo::stereo_width` in world units.
+#[cfg(feature = "serde")]
+fn default_stereo_width() -> f32 {
+    STEREO_WIDTH
 }
 
+/// Default `SourceInfo::stereo_width`, wide enough for the channels to sound apart a few
+/// meters away.
+pub const STEREO_WIDTH: f32 = 2.0;
+
 impl Default for SourceInfo {
     fn default() -> Self {
         SourceInfo {
             relative_position: Vector3::new(0.0, 0.0, 0.0),
-            direction: Vector3::new(1.0, 0.0, 0.0),
+            direction: FORWARD_VECTOR,
             room: None,
+            radius: 0.0,
+            velocity: Vector3::new(0.0, 0.0, 0.0),
+            source_direction: FORWARD_VECTOR,
+            directivity: None,
+            gain: 1.0,
+            min_distance: 0.0,
+            max_distance: None,
+            stereo_width: STEREO_WIDTH,
+            obstruction: 0.0,
+            listener_height: 0.0,
         }
     }
 }
 
+/// Plays the stereo frames pushed by a capture stream. When the stream falls behind, the
+/// last frame is held and faded out over `hold_time` seconds rather than dropping straight
+/// to silence, each such underrun being counted in `underruns`. Reading the channel never
+/// allocates, as the audio thread requires.
 #[derive(Clone)]
 pub struct InputNode {
     receiver: Receiver<(f32, f32)>,
+    hold_time: f32,
+    hold_samples: usize,
+    last: (f32, f32),
+    /// Frames missed in a row.
+    starved: usize,
+    underruns: Arc<AtomicU64>,
 }
 
 impl InputNode {
-    pub fn new(receiver: Receiver<(f32, f32)>) -> Self {
-        InputNode { receiver }
+    pub fn new(receiver: Receiver<(f32, f32)>, hold_time: f32, underruns: Arc<AtomicU64>) -> Self {
+        let mut node = InputNode {
+            receiver,
+            hold_time,
+            hold_samples: 0,
+            last: (0.0, 0.0),
+            starved: 0,
+            underruns,
+        };
+        node.set_sample_rate(DEFAULT_SR);
+        node
     }
 }
 
@@ -59,183 +276,505 @@ impl AudioNode for InputNode {
     type Inputs = U0;
     type Outputs = U2;
 
+    fn reset(&mut self) {
+        self.last = (0.0, 0.0);
+        self.starved = 0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.hold_samples = (self.hold_time.max(0.0) as f64 * sample_rate).round() as usize;
+    }
+
     #[inline]
     fn tick(&mut self, _input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
-        let (left, right) = self.receiver.try_recv().unwrap_or((0.0, 0.0));
-        [left, right].into()
+        if let Ok(frame) = self.receiver.try_recv() {
+            self.last = frame;
+            self.starved = 0;
+            return [frame.0, frame.1].into();
+        }
+        if self.starved == 0 {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        self.starved = self.starved.saturating_add(1);
+        // Held frames fade out linearly, silence past the hold time.
+        let gain = if self.starved < self.hold_samples {
+            1.0 - self.starved as f32 / self.hold_samples as f32
+        } else {
+            0.0
+        };
+        [self.last.0 * gain, self.last.1 * gain].into()
     }
 }
 
-pub fn run_in<T>(device: &cpal::Device, config: &cpal::StreamConfig, sender: Sender<(f32, f32)>)
+/// Capture `device` into `sender`, its channels mapped by `map`, until the returned stream
+/// is dropped. Errors are only printed, see `start_input` for a capture reopened after them.
+pub fn run_in<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    sender: Sender<(f32, f32)>,
+    map: ChannelMap,
+) -> Result<cpal::Stream>
 where
     T: SizedSample,
     f32: FromSample<T>,
 {
-    let channels = config.channels as usize;
-    let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
-    let stream = device.build_input_stream(
+    capture::build_capture::<T>(
+        device,
         config,
-        move |data: &[T], _: &cpal::InputCallbackInfo| read_data(data, channels, sender.clone()),
-        err_fn,
-        None,
-    );
-
-    if let Ok(stream) = stream {
-        if let Ok(()) = stream.play() {
-            std::mem::forget(stream);
-        }
-    }
-    println!("Input stream built.");
+        sender,
+        map,
+        |err| eprintln!("an error occurred on stream: {}", err),
+        Default::default(),
+    )
 }
 
-fn read_data<T>(input: &[T], channels: usize, sender: Sender<(f32, f32)>)
-where
-    T: SizedSample,
-    f32: FromSample<T>,
-{
-    for frame in input.chunks(channels) {
-        let mut left = 0.0;
-        let mut right = 0.0;
-        for (channel, sample) in frame.iter().enumerate() {
-            if channel & 1 == 0 {
-                left = sample.to_sample::<f32>();
-            } else {
-                right = sample.to_sample::<f32>();
-            }
+/// Mic channel capacity in frames for an output pulling `buffer_frames` at a time at `sample_rate`.
+pub fn mic_capacity(sample_rate: f64, buffer_frames: Option<usize>) -> usize {
+    let buffers = buffer_frames.unwrap_or(DEFAULT_BUFFER_FRAMES) * MIC_BUFFERS;
+    Ord::max(buffers, (sample_rate * MIN_MIC_LATENCY).ceil() as usize)
+}
+
+/// Audio backend named `name`, case insensitively, e.g. "ASIO" or "JACK".
+/// Fails when it is not compiled in this build of cpal or not available on this system.
+pub fn host_by_name(name: &str) -> Result<cpal::Host> {
+    let available = cpal::available_hosts();
+    match available
+        .iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+    {
+        Some(&id) => Ok(cpal::host_from_id(id)?),
+        None => {
+            let names: Vec<_> = available.iter().map(|id| id.name()).collect();
+            Err(VoiceImmersionError::DeviceUnavailable(format!(
+                "Audio host {} is not available, available ones: {}.",
+                name,
+                names.join(", ")
+            )))
         }
-        if let Ok(()) = sender.try_send((left, right)) {}
     }
 }
 
+/// Start capturing on `device` and return the receiving end of its stereo frames, taken
+/// from its first two channels. The capture runs until the receiver is dropped, reopened on
+/// the default input device of the default host after an error as
+/// `StreamRecovery::default` tells.
+pub fn start_input(device: &cpal::Device, capacity: usize) -> Result<Receiver<(f32, f32)>> {
+    start_input_mapped(device, capacity, ChannelMap::default())
+}
+
+/// `start_input` with the channels of `device` mapped to stereo frames by `map`.
+pub fn start_input_mapped(
+    device: &cpal::Device,
+    capacity: usize,
+    map: ChannelMap,
+) -> Result<Receiver<(f32, f32)>> {
+    let (sender, receiver) = bounded(capacity);
+    capture::spawn_detached_capture(
+        device.clone(),
+        cpal::default_host().id(),
+        sender,
+        map,
+        StreamRecovery::default(),
+    )?;
+    Ok(receiver)
+}
+
+/// Gain from 0 to 1 through one wall of `room`, 1 out of any room. See `occlusion_factor`
+/// for rooms with bounds.
 pub fn room_amplitude_factor(room: Option<InAnotherRoom>) -> f32 {
-    if let Some(room) = room {
-        let wall_attenuation = room.wall_attenuation_factor;
-        let wall_width = room.wall_width;
+    match room {
+        Some(room) => wall_transmission(&room, 1.0),
+        None => 1.0,
+    }
+}
 
-        (-(wall_width) * wall_attenuation).exp()
-    } else {
-        1.0
+/// Low-pass cutoff in Hz of fully obstructed sources, see `SourceInfo::obstruction`.
+pub const OBSTRUCTION_CUTOFF: f32 = 1000.0;
+
+/// Level change in dB of fully obstructed sources, mild next to walls.
+const OBSTRUCTION_DB: f32 = -6.0;
+
+/// Gain of a source `obstruction` hidden, from 1 in the clear to -6 dB fully obstructed.
+pub fn obstruction_gain(obstruction: f32) -> f32 {
+    db_amp(OBSTRUCTION_DB * obstruction.clamp(0.0, 1.0))
+}
+
+/// Low-pass cutoff in Hz of a source `obstruction` hidden, gliding in octaves from `open`
+/// in the clear to `OBSTRUCTION_CUTOFF` fully obstructed, never above `open`.
+pub fn obstruction_cutoff(obstruction: f32, open: f32) -> f32 {
+    let cutoff = OBSTRUCTION_CUTOFF.min(open);
+    open * (cutoff / open).powf(obstruction.clamp(0.0, 1.0))
+}
+
+/// Time in seconds for sound to travel `distance` meters at `sound_speed` meters per second,
+/// at most `max_delay`.
+pub fn propagation_delay(distance: f32, max_delay: f32, sound_speed: f32) -> f32 {
+    (distance / sound_speed).clamp(0.0, max_delay)
+}
+
+/// Playback rate range the Doppler effect is kept within, so a source moving at about
+/// the speed of sound does not blow up.
+const DOPPLER_RANGE: (f32, f32) = (0.5, 2.0);
+
+/// Doppler pitch factor of a source at `relative_position` meters from the listener moving
+/// at `velocity` meters per second: `c / (c - v)`, `v` being the speed toward the listener
+/// and `c` the `sound_speed`.
+pub fn doppler_factor(
+    relative_position: &Vector3<f32>,
+    velocity: &Vector3<f32>,
+    sound_speed: f32,
+) -> f32 {
+    let distance = relative_position.norm();
+    if distance < f32::EPSILON {
+        return 1.0;
     }
+    let approaching = -velocity.dot(relative_position) / distance;
+    (sound_speed / (sound_speed - approaching)).clamp(DOPPLER_RANGE.0, DOPPLER_RANGE.1)
 }
 
+/// Play `source_info` on `device` until the process exits, see `start_out`.
 pub fn run_out<T>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
     receiver: Receiver<(f32, f32)>,
     wave: Option<fundsp::wave::Wave>,
+    looping: bool,
+    source_info: Arc<RwLock<SourceInfo>>,
+) -> Result<()>
+where
+    T: SizedSample + FromSample<f32> + Send,
+{
+    start_out::<T>(device, config, receiver, wave, looping, source_info)?.wait();
+    Ok(())
+}
+
+/// `run_out` with custom processing, see `EngineBuilder::customize`.
+pub fn run_out_with<T, F>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    receiver: Receiver<(f32, f32)>,
+    wave: Option<fundsp::wave::Wave>,
+    looping: bool,
     source_info: Arc<RwLock<SourceInfo>>,
-) -> Result<(), anyhow::Error>
+    customize: F,
+) -> Result<()>
 where
     T: SizedSample + FromSample<f32> + Send,
+    F: FnOnce(&mut Net) + 'static,
 {
-    //let input = An(InputNode::new(receiver));
-    #[cfg(not(feature = "mic"))]
-    let wave = wave.unwrap();
-    #[cfg(not(feature = "mic"))]
-    let mut input = WavePlayer::new(&Arc::new(wave.clone()), 0, 0, wave.length(), Some(0));
-
-    #[cfg(feature = "mic")]
-    let input = InputNode::new(receiver);
-
-    let sample_rate = config.sample_rate.0 as f64;
-    let channels = config.channels as usize;
-    let amplitude: Shared = shared(1.0);
-    let (left_amp, right_amp) = (shared(1.0), shared(1.0));
-
-    let mut net = Net::new(1, 2);
-    //let mut net = Net::wrap(Box::new(An(input)));
-    net.set_sample_rate(sample_rate);
-    net.chain(Box::new(tick() * (var(&amplitude) >> follow(0.1))));
-
-    let (material_filter_sender, material_filter) = listen(lowpole_hz(20000.0));
-    net.chain(Box::new(material_filter));
-    // Stereo effects
-    let output_node = net.chain(Box::new(
-        (pass() * var(&left_amp)) ^ (pass() * var(&right_amp)),
-    ));
-    println!(
-        "Output node: {:?}",
-        (sine() >> (pass() * var(&left_amp)) ^ (pass() * var(&right_amp))).outputs()
-    );
-    net.connect_output(output_node, 0, 0);
-    net.connect_output(output_node, 1, 1);
-    net.check();
-
-    println!("Net checked.");
-    let mut backend = net.backend();
-    println!("output backend node: {:?}", backend.outputs());
-    // Use `assert_no_alloc` to make sure there are no allocations or deallocations in the audio thread.
-    let mut next_value = move || assert_no_alloc(|| {
-        let input_sample = input.tick(&Frame::new(arr![]));
-        let mut output = [0.0; 2];
-        backend.tick(&input_sample, &mut output);
-        (output[0], output[1])
-    }); 
-
-    let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
-
-    let stream = device.build_output_stream(
+    start_out_with::<T, F>(
+        device,
         config,
-        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            write_data(data, channels, &mut next_value)
-        },
-        err_fn,
-        None,
-    )?;
-    stream.play()?;
-
-    let mut in_room = false;
-    let mut room_amplitude = 1.0;
-    //let mut updated_source_info = SourceInfo::default();
-    loop {
-        if let Ok(info) = source_info.try_read() {
-            // Distance attenuation.
-            let distance = info.relative_position.norm();
-            let amp = 1.0 / (1.0 + (distance / 10.0).powi(2));
-
-            // Orientation hears attenuation.
-            let uv = info.relative_position.cross(&info.direction);
-            let coeff = (uv.norm() / distance) * uv.dot(&-UP_VECTOR).signum();
-
-            left_amp.set_value((1.0 + coeff) / 2.0);
-            right_amp.set_value((1.0 - coeff) / 2.0);
-            // Room effects.
-            if let Some(room) = &info.room {
-                if !in_room {
-                    in_room = true;
-                    room_amplitude = room_amplitude_factor(Some(room.clone()));
-                    material_filter_sender
-                        .try_send(Setting::center(10.0))
-                        .expect("Failed to send setting to material filter.");
-                }
-            } else if in_room {
-                in_room = false;
-                room_amplitude = room_amplitude_factor(None);
-            }
-            println!(" amplitude: {}", amp * room_amplitude);
-            print!("left: {}, right: {}", left_amp.value(), right_amp.value());
-            amplitude.set_value(amp * room_amplitude);
+        receiver,
+        wave,
+        looping,
+        source_info,
+        customize,
+    )?
+    .wait();
+    Ok(())
+}
+
+/// Play the mic frames of `receiver` with the `mic` feature, `wave` otherwise, on `device`,
+/// spatialized by `source_info`. The wave is looped when `looping` (see
+/// `InputSource::Wave`), played once otherwise. The engine runs until the returned handle is stopped or dropped.
+pub fn start_out<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    receiver: Receiver<(f32, f32)>,
+    wave: Option<fundsp::wave::Wave>,
+    looping: bool,
+    source_info: Arc<RwLock<SourceInfo>>,
+) -> Result<SpatialHandle>
+where
+    T: SizedSample + FromSample<f32> + Send,
+{
+    start_out_with::<T, _>(
+        device,
+        config,
+        receiver,
+        wave,
+        looping,
+        source_info,
+        |_: &mut Net| {},
+    )
+}
+
+/// `start_out` with custom processing, see `EngineBuilder::customize`.
+pub fn start_out_with<T, F>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    receiver: Receiver<(f32, f32)>,
+    wave: Option<fundsp::wave::Wave>,
+    looping: bool,
+    source_info: Arc<RwLock<SourceInfo>>,
+    customize: F,
+) -> Result<SpatialHandle>
+where
+    T: SizedSample + FromSample<f32> + Send,
+    F: FnOnce(&mut Net) + 'static,
+{
+    let input = if cfg!(feature = "mic") {
+        InputSource::Mic(receiver)
+    } else {
+        let wave =
+            wave.ok_or_else(|| VoiceImmersionError::InvalidConfig("No wave to play.".into()))?;
+        if looping {
+            InputSource::Wave(wave)
+        } else {
+            InputSource::OneShot(wave)
         }
+    };
+    EngineBuilder::new()
+        .sink(CpalSink::with_config(
+            device.clone(),
+            config.clone(),
+            T::FORMAT,
+        ))
+        .input(input)
+        .source(source_info)
+        .customize(customize)
+        .build()
+}
 
-        std::thread::sleep(std::time::Duration::from_millis(5));
-    }
+/// Play the file `stream` decodes on `device` until the process exits, see `start_out_stream`.
+pub fn run_out_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    stream: FileStream,
+    source_info: Arc<RwLock<SourceInfo>>,
+) -> Result<()>
+where
+    T: SizedSample + FromSample<f32> + Send,
+{
+    start_out_stream::<T>(device, config, stream, source_info)?.wait();
+    Ok(())
 }
 
-fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> (f32, f32))
+/// `start_out` decoding a file as it plays instead of holding a whole wave in memory, for
+/// long ambiences and music: see `FileStream`.
+pub fn start_out_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    stream: FileStream,
+    source_info: Arc<RwLock<SourceInfo>>,
+) -> Result<SpatialHandle>
 where
-    T: SizedSample + FromSample<f32>,
+    T: SizedSample + FromSample<f32> + Send,
 {
-    for frame in output.chunks_mut(channels) {
-        let sample = next_sample();
-        let left = T::from_sample(sample.0);
-        let right = T::from_sample(sample.1);
-
-        for (channel, sample) in frame.iter_mut().enumerate() {
-            if channel & 1 == 0 {
-                *sample = left;
-            } else {
-                *sample = right;
-            }
+    EngineBuilder::new()
+        .sink(CpalSink::with_config(
+            device.clone(),
+            config.clone(),
+            T::FORMAT,
+        ))
+        .input(InputSource::Stream(stream))
+        .source(source_info)
+        .build()
+}
+
+/// Play the procedural `node` on `device` until the process exits, see `start_out_node`.
+pub fn run_out_node<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    node: Box<dyn AudioUnit>,
+    source_info: Arc<RwLock<SourceInfo>>,
+) -> Result<()>
+where
+    T: SizedSample + FromSample<f32> + Send,
+{
+    start_out_node::<T>(device, config, node, source_info)?.wait();
+    Ok(())
+}
+
+/// `start_out` playing any fundsp unit with no inputs and one output, such as a synth or
+/// noise, rendered at the sample rate of `config`: see `InputSource::Node`.
+pub fn start_out_node<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    node: Box<dyn AudioUnit>,
+    source_info: Arc<RwLock<SourceInfo>>,
+) -> Result<SpatialHandle>
+where
+    T: SizedSample + FromSample<f32> + Send,
+{
+    EngineBuilder::new()
+        .sink(CpalSink::with_config(
+            device.clone(),
+            config.clone(),
+            T::FORMAT,
+        ))
+        .input(InputSource::Node(node))
+        .source(source_info)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demo_room(transmission_law: TransmissionLaw, min_transmission: f32) -> InAnotherRoom {
+        InAnotherRoom {
+            wall_width: 0.005,
+            wall_attenuation_factor: 500.,
+            wall_filter: WallFilter::LowPass(2000.),
+            transmission_law,
+            min_transmission,
+            reverb_time: 0.0,
+            room_size: 10.0,
+            reverb_predelay: 0.0,
+            bounds: None,
         }
     }
+
+    #[test]
+    fn mic_underrun_holds_then_fades() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let underruns = Arc::new(AtomicU64::new(0));
+        let mut node = An(InputNode::new(receiver, 0.001, underruns.clone()));
+        node.set_sample_rate(4000.0);
+        sender.send((0.8, -0.4)).unwrap();
+        assert_eq!(node.get_stereo(), (0.8, -0.4));
+        // Held over 4 samples: 3/4, 2/4, 1/4 of the last frame, then silence.
+        assert_eq!(node.get_stereo(), (0.6, -0.3));
+        assert_eq!(node.get_stereo(), (0.4, -0.2));
+        assert_eq!(node.get_stereo(), (0.2, -0.1));
+        assert_eq!(node.get_stereo(), (0.0, 0.0));
+        assert_eq!(node.get_stereo(), (0.0, 0.0));
+        assert_eq!(underruns.load(Ordering::Relaxed), 1);
+
+        sender.send((0.5, 0.5)).unwrap();
+        assert_eq!(node.get_stereo(), (0.5, 0.5));
+        node.get_stereo();
+        assert_eq!(underruns.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn unknown_host_is_an_error() {
+        let err = host_by_name("NoSuchHost").err().unwrap();
+        assert!(err.to_string().contains("NoSuchHost"));
+    }
+
+    #[test]
+    fn mic_capacity_sizing() {
+        assert_eq!(mic_capacity(48000.0, Some(512)), 2400);
+        assert_eq!(mic_capacity(48000.0, Some(2048)), 8192);
+        assert_eq!(mic_capacity(44100.0, None), 4096);
+        assert_eq!(mic_capacity(192000.0, None), 9600);
+    }
+
+    #[test]
+    fn room_amplitude_factor_laws() {
+        let exponential = room_amplitude_factor(Some(demo_room(TransmissionLaw::Exponential, 0.0)));
+        assert!((exponential - (-2.5_f32).exp()).abs() < 1e-6);
+        let linear = room_amplitude_factor(Some(demo_room(TransmissionLaw::Linear, 0.0)));
+        assert_eq!(linear, 0.0);
+        let decibels = room_amplitude_factor(Some(demo_room(TransmissionLaw::Decibels, 0.0)));
+        assert!((decibels - 10.0_f32.powf(-2.5 / 20.0)).abs() < 1e-6);
+        assert_eq!(room_amplitude_factor(None), 1.0);
+    }
+
+    #[test]
+    fn room_amplitude_factor_floor() {
+        for law in [
+            TransmissionLaw::Exponential,
+            TransmissionLaw::Linear,
+            TransmissionLaw::Decibels,
+        ] {
+            let mut room = demo_room(law, 0.1);
+            room.wall_width = 0.002;
+            assert!(room_amplitude_factor(Some(room.clone())) >= 0.1);
+            room.wall_attenuation_factor = 1e6;
+            assert_eq!(room_amplitude_factor(Some(room)), 0.1);
+        }
+    }
+
+    #[test]
+    fn room_amplitude_factor_never_amplifies() {
+        let mut room = demo_room(TransmissionLaw::Exponential, 0.0);
+        room.wall_width = 0.0;
+        assert_eq!(room_amplitude_factor(Some(room.clone())), 1.0);
+        room.wall_width = 0.5;
+        room.wall_attenuation_factor = 1e4;
+        assert!(room_amplitude_factor(Some(room.clone())) < 1e-6);
+        for law in [
+            TransmissionLaw::Exponential,
+            TransmissionLaw::Linear,
+            TransmissionLaw::Decibels,
+        ] {
+            let mut room = demo_room(law, 0.0);
+            room.wall_width = -0.1;
+            assert_eq!(room_amplitude_factor(Some(room.clone())), 1.0);
+            room.wall_width = 0.1;
+            room.wall_attenuation_factor = -50.0;
+            assert_eq!(room_amplitude_factor(Some(room.clone())), 1.0);
+            room.min_transmission = 2.0;
+            assert_eq!(room_amplitude_factor(Some(room)), 1.0);
+        }
+    }
+
+    #[test]
+    fn doppler_pitch() {
+        let position = Vector3::new(10.0, 0.0, 0.0);
+        assert_eq!(
+            doppler_factor(&position, &Vector3::zeros(), SOUND_SPEED),
+            1.0
+        );
+        // Coming closer at a tenth of the speed of sound.
+        let approaching = doppler_factor(
+            &position,
+            &Vector3::new(-SOUND_SPEED / 10.0, 0.0, 0.0),
+            SOUND_SPEED,
+        );
+        assert!((approaching - 10.0 / 9.0).abs() < 1e-6);
+        let leaving = doppler_factor(
+            &position,
+            &Vector3::new(SOUND_SPEED / 10.0, 0.0, 0.0),
+            SOUND_SPEED,
+        );
+        assert!((leaving - 10.0 / 11.0).abs() < 1e-6);
+        // Passing by sideways.
+        assert_eq!(
+            doppler_factor(&position, &Vector3::new(0.0, 0.0, 50.0), SOUND_SPEED),
+            1.0
+        );
+        // Supersonic.
+        let velocity = Vector3::new(-SOUND_SPEED, 0.0, 0.0);
+        assert_eq!(
+            doppler_factor(&position, &velocity, SOUND_SPEED),
+            DOPPLER_RANGE.1
+        );
+    }
+
+    #[test]
+    fn propagation_delay_from_distance() {
+        assert!((propagation_delay(343.0, 2.0, SOUND_SPEED) - 1.0).abs() < 1e-6);
+        assert_eq!(propagation_delay(0.0, 2.0, SOUND_SPEED), 0.0);
+        assert_eq!(propagation_delay(10_000.0, 2.0, SOUND_SPEED), 2.0);
+        assert!((propagation_delay(331.3, 2.0, sound_speed(0.0)) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sound_speed_from_temperature() {
+        assert!((sound_speed(0.0) - 331.3).abs() < 1e-3);
+        assert!((sound_speed(20.0) - SOUND_SPEED).abs() < 0.5);
+        assert!(sound_speed(35.0) > SOUND_SPEED);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn source_info_json_round_trip() {
+        let info = SourceInfo {
+            relative_position: Vector3::new(1.5, -2.0, 0.1),
+            room: Some(demo_room(TransmissionLaw::Decibels, 0.2)),
+            velocity: Vector3::new(0.0, 3.0, 0.0),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(
+            json.contains(r#""relative_position":[1.5,-2.0,0.1]"#),
+            "{json}"
+        );
+        assert_eq!(serde_json::from_str::<SourceInfo>(&json).unwrap(), info);
+    }
 }
@@ -1,65 +1,144 @@
-use cpal::traits::{DeviceTrait, HostTrait};
-use crossbeam_channel::bounded;
-use fundsp::wave::Wave;
+use cpal::traits::HostTrait;
 use macroquad::prelude::*;
-use std::sync::{Arc, RwLock};
-use voice_immersion::{run_in, run_out, InAnotherRoom, SourceInfo, HEAD_RADIUS};
+use nalgebra::Vector3;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+#[cfg(feature = "mic")]
+use voice_immersion::VoiceImmersionError;
+use voice_immersion::{
+    compute_spherical, Aabb, EngineBuilder, EngineConfig, InAnotherRoom, Listener, Source,
+    TransmissionLaw, WallFilter, World, HEAD_RADIUS,
+};
+#[cfg(not(feature = "mic"))]
+use voice_immersion::{downmix, load_audio, InputSource};
+#[cfg(feature = "serde")]
+use voice_immersion::{render_scene, OfflineScene};
 
-#[macroquad::main("3D")]
-async fn main() -> anyhow::Result<()> {
-    let source_info = Arc::new(RwLock::new(SourceInfo::default()));
-    let source_info_audio = source_info.clone();
-    std::thread::spawn(move || {
-        // Sender / receiver for left and right channels (stereo mic).
-        let (sender, receiver) = bounded(4096);
+/// Interactive scene by default, `render <scene.json> <output.wav>` to render a scene file
+/// without any window or audio device.
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("render") {
+        return render(args.next(), args.next());
+    }
+    macroquad::Window::new("3D", async {
+        if let Err(err) = interactive().await {
+            eprintln!("{}", err);
+        }
+    });
+    Ok(())
+}
+
+/// Render the JSON scene file `scene` to the 32-bit WAV file `output`, see `OfflineScene`.
+#[cfg(feature = "serde")]
+fn render(scene: Option<String>, output: Option<String>) -> anyhow::Result<()> {
+    let (Some(scene), Some(output)) = (scene, output) else {
+        return Err(anyhow::anyhow!(
+            "Usage: voice-immersion render <scene.json> <output.wav>"
+        ));
+    };
+    let scene = OfflineScene::load(&scene)?;
+    let wave = render_scene(&scene, |fraction| {
+        eprint!("\rRendering {:3.0}%", fraction * 100.0)
+    })?;
+    eprintln!();
+    wave.save_wav32(&output)
+        .map_err(|e| anyhow::anyhow!("Could not write {}: {}", output, e))?;
+    println!("Wrote {}", output);
+    Ok(())
+}
 
+#[cfg(not(feature = "serde"))]
+fn render(_scene: Option<String>, _output: Option<String>) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "Rendering scene files needs the serde feature."
+    ))
+}
+
+async fn interactive() -> anyhow::Result<()> {
+    let listener = Arc::new(RwLock::new(Listener::default()));
+    let source = Arc::new(RwLock::new(Source::default()));
+    let mut world = World::new();
+    world.add_room(InAnotherRoom {
+        wall_attenuation_factor: 500.,
+        wall_width: 0.005,
+        wall_filter: WallFilter::LowPass(2000.),
+        transmission_law: TransmissionLaw::Exponential,
+        min_transmission: 0.0,
+        reverb_time: 1.2,
+        room_size: 8.0,
+        reverb_predelay: 0.02,
+        bounds: Some(Aabb::new(
+            Vector3::new(-2.0, -0.5, -0.5),
+            Vector3::new(-1.0, 0.5, 0.5),
+        )),
+    })?;
+    let world = Arc::new(RwLock::new(world));
+    let (listener_audio, source_audio) = (listener.clone(), source.clone());
+    let world_audio = world.clone();
+    // Room of the listener as the engine hears it.
+    let in_room = Arc::new(AtomicBool::new(false));
+    let in_room_audio = in_room.clone();
+    let spectrum = Arc::new(Mutex::new(Vec::new()));
+    let spectrum_audio = spectrum.clone();
+    std::thread::spawn(move || {
         let host = cpal::default_host();
-        // Start input.
-        let in_device = host.default_input_device().unwrap();
-        let in_config: cpal::SupportedStreamConfig = in_device.default_input_config().unwrap();
-        #[cfg(not(feature = "mic"))]
-        let wave = Some(Wave::load("loop.flac").unwrap());
-        #[cfg(feature = "mic")]
-        let wave = None;
-
-        match in_config.sample_format() {
-            cpal::SampleFormat::F32 => run_in::<f32>(&in_device, &in_config.into(), sender),
-            cpal::SampleFormat::I16 => run_in::<i16>(&in_device, &in_config.into(), sender),
-            cpal::SampleFormat::U16 => run_in::<u16>(&in_device, &in_config.into(), sender),
-            format => eprintln!("Unsupported sample format: {}", format),
-        }
-        // Start output.
-        let out_device = host.default_output_device().unwrap();
-        let out_config = out_device.default_output_config().unwrap();
-        match out_config.sample_format() {
-            cpal::SampleFormat::F32 => {
-                let _ = run_out::<f32>(
-                    &out_device,
-                    &out_config.into(),
-                    receiver,
-                    wave,
-                    source_info_audio,
-                );
+        let engine = (|| {
+            let mut builder = EngineBuilder::new();
+            // Stereo mic frames, or the looped wave file.
+            #[cfg(feature = "mic")]
+            {
+                builder = builder.mic(host.default_input_device().ok_or_else(|| {
+                    VoiceImmersionError::DeviceUnavailable("No input device.".into())
+                })?);
             }
-            cpal::SampleFormat::I16 => {
-                let _ = run_out::<i16>(
-                    &out_device,
-                    &out_config.into(),
-                    receiver,
-                    wave,
-                    source_info_audio,
-                );
+            #[cfg(not(feature = "mic"))]
+            {
+                builder = builder.input(InputSource::Wave(downmix(&load_audio("loop.flac")?)));
             }
-            cpal::SampleFormat::U16 => {
-                let _ = run_out::<u16>(
-                    &out_device,
-                    &out_config.into(),
-                    receiver,
-                    wave,
-                    source_info_audio,
+
+            builder = builder
+                .config(EngineConfig {
+                    spectrum_window: Some(2048),
+                    ..Default::default()
+                })
+                .world(listener_audio, source_audio)
+                .rooms(world_audio)
+                .on_tick(move |metrics| {
+                    in_room_audio.store(metrics.listener_room.is_some(), Ordering::Relaxed)
+                })
+                .preferred_stream(48000, Some(128))
+                .follow_default_device(true);
+            // Output device picked by name as first argument, see `list_output_devices`.
+            match std::env::args().nth(1) {
+                Some(name) => builder = builder.output_device_named(name),
+                None => {
+                    if let Some(device) = host.default_output_device() {
+                        builder = builder.output_device(device);
+                    }
+                }
+            }
+            builder.build()
+        })();
+        match engine {
+            Ok(engine) => {
+                let output = engine.output_config();
+                println!(
+                    "Playing at {} Hz on {} channels, {} frames per buffer.",
+                    output.sample_rate,
+                    output.channels,
+                    output
+                        .buffer_frames
+                        .map_or("default".to_string(), |frames| frames.to_string())
                 );
+                // Handed over to the window for its analyzer.
+                loop {
+                    *spectrum_audio.lock().unwrap() = engine.spectrum();
+                    std::thread::sleep(Duration::from_millis(30));
+                }
             }
-            format => eprintln!("Unsupported sample format: {}", format),
+            Err(err) => eprintln!("Failed to start audio engine: {}", err),
         }
     });
 
@@ -101,38 +180,67 @@ async fn main() -> anyhow::Result<()> {
         // Draw Room
         draw_cube_wires(vec3(-1.5, 0., 0.0), vec3(1., 0., 01.), YELLOW);
 
-        let in_room =
-            player_pos.x > -2.0 && player_pos.x < -1.0 && player_pos.z > -0.5 && player_pos.z < 0.5;
+        let position = Vector3::new(player_pos.x, player_pos.y, player_pos.z);
 
         draw_sphere(player_pos, HEAD_RADIUS, None, BLUE);
         draw_line_3d(player_pos, player_pos + direction, RED);
         set_default_camera();
         draw_text(
-            &format!("Player pos in room: {}", in_room),
+            &format!("Player pos in room: {}", in_room.load(Ordering::Relaxed)),
             10.0,
             20.0,
             30.0,
             BLACK,
         );
 
-        if let Ok(mut source_info) = source_info.try_write() {
-            source_info.relative_position.x = -player_pos.x;
-            source_info.relative_position.y = -player_pos.y;
-            source_info.relative_position.z = -player_pos.z;
-            source_info.direction.x = direction.x;
-            source_info.direction.y = direction.y;
-            source_info.direction.z = direction.z;
-            source_info.room = if in_room {
-                Some(InAnotherRoom {
-                    wall_attenuation_factor: 500.,
-                    wall_width: 0.005,
-                    cutoff_frequency: 2000.,
-                })
-            } else {
-                None
-            };
+        if let Ok(mut listener) = listener.try_write() {
+            listener.position = position;
+            listener.forward = Vector3::new(direction.x, direction.y, direction.z);
+        }
+        if let (Ok(listener), Ok(source)) = (listener.try_read(), source.try_read()) {
+            let heard = compute_spherical(&listener, &source);
+            draw_text(
+                &format!(
+                    "Azimuth {:.0}°, elevation {:.0}°, {:.2} m",
+                    heard.azimuth, heard.elevation, heard.distance
+                ),
+                10.0,
+                50.0,
+                30.0,
+                BLACK,
+            );
         }
 
+        draw_spectrum(&spectrum.lock().unwrap());
+
         next_frame().await
     }
 }
+
+/// Bars of the output spectrum along the bottom of the window, log spaced from the lowest
+/// bin up, from -80 to 0 dB.
+fn draw_spectrum(spectrum: &[f32]) {
+    const BARS: usize = 48;
+    const HEIGHT: f32 = 120.0;
+    if spectrum.len() < 2 {
+        return;
+    }
+    let bins = spectrum.len() as f32;
+    let width = screen_width() / BARS as f32;
+    for bar in 0..BARS {
+        let start = bins.powf(bar as f32 / BARS as f32) as usize;
+        let end =
+            (bins.powf((bar + 1) as f32 / BARS as f32) as usize).clamp(start + 1, spectrum.len());
+        let peak = spectrum[start..end]
+            .iter()
+            .fold(0.0_f32, |peak, &bin| peak.max(bin));
+        let height = ((20.0 * peak.max(1e-6).log10() + 80.0) / 80.0).clamp(0.0, 1.0) * HEIGHT;
+        draw_rectangle(
+            bar as f32 * width,
+            screen_height() - height,
+            width - 2.0,
+            height,
+            DARKBLUE,
+        );
+    }
+}
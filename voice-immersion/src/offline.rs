@@ -0,0 +1,532 @@
+use std::path::PathBuf;
+
+use fundsp::hacker::*;
+use fundsp::wave::Wave;
+use nalgebra::{Unit, Vector3};
+
+use crate::config::EngineConfig;
+use crate::decode::{downmix, load_audio};
+use crate::engine::{build_graph, Controller, InputSource, MasterParams};
+use crate::room::World;
+use crate::spatial::{Listener, Source};
+use crate::VoiceImmersionError;
+use crate::{SourceInfo, FORWARD_VECTOR};
+
+/// Spatialize the first channel of `input` into a stereo wave of the same length, moving
+/// the source through `keyframes` of (seconds, description), in time order. Uses the graph
+/// and parameter updates of the live engine, with the default configuration.
+pub fn render_offline(input: &Wave, keyframes: &[(f32, SourceInfo)], sample_rate: f64) -> Wave {
+    render_offline_with(&EngineConfig::default(), input, keyframes, sample_rate)
+}
+
+/// `render_offline` with `config`.
+pub fn render_offline_with(
+    config: &EngineConfig,
+    input: &Wave,
+    keyframes: &[(f32, SourceInfo)],
+    sample_rate: f64,
+) -> Wave {
+    let (mut graph, mut params) = build_graph(
+        InputSource::Wave(input.clone()).into(),
+        config,
+        1,
+        &MasterParams::new(config),
+    );
+    graph.set_sample_rate(sample_rate);
+    let mut controller = Controller::new(params.remove(0), config.clone());
+
+    let length = (input.duration() * sample_rate).round() as usize;
+    let period = Ord::max(
+        (config.control_period.as_secs_f64() * sample_rate) as usize,
+        1,
+    );
+    let dt = (period as f64 / sample_rate) as f32;
+    let (mut left, mut right) = (Vec::with_capacity(length), Vec::with_capacity(length));
+    let mut frame = [0.0; 2];
+    for start in (0..length).step_by(period) {
+        if let Some(info) = interpolate(keyframes, (start as f64 / sample_rate) as f32) {
+            // The first update jumps straight to the first description.
+            controller.update(&info, if start == 0 { 0.0 } else { dt });
+        }
+        for _ in start..Ord::min(start + period, length) {
+            graph.tick(&[], &mut frame);
+            left.push(frame[0]);
+            right.push(frame[1]);
+        }
+    }
+    let mut output = Wave::new(0, sample_rate);
+    output.push_channel(&left);
+    output.push_channel(&right);
+    output
+}
+
+/// Keyframes of (seconds, description) for scripted movements such as cutscenes, sampled
+/// at any time: see `render_offline` to render them.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Trajectory {
+    keyframes: Vec<(f32, SourceInfo)>,
+}
+
+impl Trajectory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the keyframe `info` at `time` seconds, after the ones at the same time. NaN times
+    /// are ignored.
+    pub fn add(&mut self, time: f32, info: SourceInfo) -> &mut Self {
+        if !time.is_nan() {
+            let index = self.keyframes.partition_point(|(at, _)| *at <= time);
+            self.keyframes.insert(index, (time, info));
+        }
+        self
+    }
+
+    /// Keyframes in time order.
+    pub fn keyframes(&self) -> &[(f32, SourceInfo)] {
+        &self.keyframes
+    }
+
+    /// Description at `time` seconds: positions, velocities, radii and gains linearly
+    /// interpolated between the keyframes around it, directions turned along the arc between
+    /// them. Held before the first keyframe and after the last one, the default description
+    /// without any. Rooms and directivities switch at keyframes.
+    pub fn sample(&self, time: f32) -> SourceInfo {
+        interpolate(&self.keyframes, time).unwrap_or_default()
+    }
+}
+
+impl FromIterator<(f32, SourceInfo)> for Trajectory {
+    fn from_iter<I: IntoIterator<Item = (f32, SourceInfo)>>(keyframes: I) -> Self {
+        let mut trajectory = Trajectory::new();
+        for (time, info) in keyframes {
+            trajectory.add(time, info);
+        }
+        trajectory
+    }
+}
+
+/// Scene rendered by `render_scene`: sources playing audio files along their trajectories,
+/// heard by a moving listener in the rooms of a world, all in world coordinates. Read from
+/// JSON files by `OfflineScene::load` with the `serde` feature.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OfflineScene {
+    /// Output sample rate in Hz.
+    pub sample_rate: f64,
+    /// Seconds to render, as long as the longest source file when `None`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub duration: Option<f64>,
+    /// Keyframes of (seconds, listener) in time order, interpolated like the ones of
+    /// `render_offline`. The default listener when empty.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub listener: Vec<(f32, Listener)>,
+    pub sources: Vec<OfflineSource>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rooms: World,
+}
+
+/// Source of an `OfflineScene`, looping its file.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OfflineSource {
+    /// Audio file, see `load_audio`, mixed down to mono.
+    pub file: PathBuf,
+    /// Keyframes of (seconds, source) in time order, at least one.
+    pub keyframes: Vec<(f32, Source)>,
+}
+
+#[cfg(feature = "serde")]
+impl OfflineScene {
+    /// Read the JSON scene file at `path`, source files being relative to its directory.
+    /// Errors name the file, and the line and column of malformed JSON.
+    pub fn load(path: impl AsRef<std::path::Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).map_err(|e| {
+            VoiceImmersionError::Io(format!("Could not read {}: {}", path.display(), e))
+        })?;
+        let mut scene: OfflineScene = serde_json::from_str(&json).map_err(|e| {
+            VoiceImmersionError::InvalidConfig(format!(
+                "Invalid scene file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let directory = path.parent().unwrap_or(std::path::Path::new(""));
+        for source in &mut scene.sources {
+            source.file = directory.join(&source.file);
+        }
+        Ok(scene)
+    }
+}
+
+/// Render `scene` into a stereo wave, through the graph and controller of the live engine
+/// with the default configuration, the sources mixed without the limiter. `progress` gets
+/// the rendered fraction from 0 to 1 at every percent. Errors on empty or unordered
+/// keyframes and on source files that cannot be decoded.
+pub fn render_scene(scene: &OfflineScene, mut progress: impl FnMut(f32)) -> crate::Result<Wave> {
+    let sample_rate = scene.sample_rate;
+    if !(sample_rate.is_finite() && sample_rate > 0.0) {
+        return Err(VoiceImmersionError::InvalidConfig(format!(
+            "Invalid sample rate {}.",
+            sample_rate
+        )));
+    }
+    if scene.sources.is_empty() {
+        return Err(VoiceImmersionError::InvalidConfig(
+            "The scene has no sources.".into(),
+        ));
+    }
+    check_keyframes(&scene.listener, "listener")?;
+    let mut inputs = Vec::with_capacity(scene.sources.len());
+    for (i, source) in scene.sources.iter().enumerate() {
+        if source.keyframes.is_empty() {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Source {} ({}) has no keyframes.",
+                i,
+                source.file.display()
+            )));
+        }
+        check_keyframes(&source.keyframes, &format!("source {}", i))?;
+        inputs.push(downmix(&load_audio(&source.file)?));
+    }
+    let duration = match scene.duration {
+        Some(duration) if duration.is_finite() && duration >= 0.0 => duration,
+        Some(duration) => {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Invalid duration {}.",
+                duration
+            )))
+        }
+        None => inputs.iter().map(Wave::duration).fold(0.0, f64::max),
+    };
+
+    let config = EngineConfig::default();
+    let length = (duration * sample_rate).round() as usize;
+    let period = Ord::max(
+        (config.control_period.as_secs_f64() * sample_rate) as usize,
+        1,
+    );
+    let dt = (period as f64 / sample_rate) as f32;
+    let (mut left, mut right) = (vec![0.0; length], vec![0.0; length]);
+    let total = Ord::max(length * inputs.len(), 1);
+    let mut percent = 0;
+    progress(0.0);
+    for (n, (source, input)) in scene.sources.iter().zip(inputs).enumerate() {
+        let (mut graph, mut params) = build_graph(
+            InputSource::Wave(input).into(),
+            &config,
+            1,
+            &MasterParams::new(&config),
+        );
+        graph.set_sample_rate(sample_rate);
+        let mut controller = Controller::new(params.remove(0), config.clone());
+        let mut frame = [0.0; 2];
+        for start in (0..length).step_by(period) {
+            let time = (start as f64 / sample_rate) as f32;
+            let listener = interpolate_listener(&scene.listener, time).unwrap_or_default();
+            if let Some(placed) = interpolate_source(&source.keyframes, time) {
+                let dt = if start == 0 { 0.0 } else { dt };
+                controller.update_in_world(&listener, &placed, Some(&scene.rooms), dt);
+            }
+            let end = Ord::min(start + period, length);
+            for i in start..end {
+                graph.tick(&[], &mut frame);
+                left[i] += frame[0];
+                right[i] += frame[1];
+            }
+            let done = (n * length + end) * 100 / total;
+            if done > percent {
+                percent = done;
+                progress(done as f32 / 100.0);
+            }
+        }
+    }
+    let mut output = Wave::new(0, sample_rate);
+    output.push_channel(&left);
+    output.push_channel(&right);
+    Ok(output)
+}
+
+/// Error unless the times of `keyframes` of `what` are finite and in order.
+fn check_keyframes<T>(keyframes: &[(f32, T)], what: &str) -> crate::Result<()> {
+    if keyframes.iter().any(|(time, _)| !time.is_finite()) {
+        return Err(VoiceImmersionError::InvalidConfig(format!(
+            "Keyframe times of the {} must be finite.",
+            what
+        )));
+    }
+    if keyframes.windows(2).any(|pair| pair[1].0 < pair[0].0) {
+        return Err(VoiceImmersionError::InvalidConfig(format!(
+            "Keyframes of the {} are not in time order.",
+            what
+        )));
+    }
+    Ok(())
+}
+
+/// Keyframes before and after `time` with the fraction of the way between them, the first
+/// or last one twice past them.
+fn around<T>(keyframes: &[(f32, T)], time: f32) -> Option<(&T, &T, f32)> {
+    let next = keyframes.partition_point(|(at, _)| *at <= time);
+    if next == 0 || next == keyframes.len() {
+        let (_, held) = keyframes.get(next.saturating_sub(1))?;
+        return Some((held, held, 0.0));
+    }
+    let ((from_time, from), (to_time, to)) = (&keyframes[next - 1], &keyframes[next]);
+    let t = ((time - from_time) / (to_time - from_time)).clamp(0.0, 1.0);
+    Some((from, to, t))
+}
+
+/// Listener at `time` seconds, interpolated like `interpolate`.
+fn interpolate_listener(keyframes: &[(f32, Listener)], time: f32) -> Option<Listener> {
+    let (from, to, t) = around(keyframes, time)?;
+    Some(Listener {
+        position: from.position + (to.position - from.position) * t,
+        forward: slerp(&from.forward, &to.forward, t),
+        up: slerp(&from.up, &to.up, t),
+        head_radius: from.head_radius + (to.head_radius - from.head_radius) * t,
+    })
+}
+
+/// Source at `time` seconds, interpolated like `interpolate`.
+fn interpolate_source(keyframes: &[(f32, Source)], time: f32) -> Option<Source> {
+    let (from, to, t) = around(keyframes, time)?;
+    Some(Source {
+        position: from.position + (to.position - from.position) * t,
+        direction: slerp(&from.direction, &to.direction, t),
+        room: from.room.clone(),
+        radius: from.radius + (to.radius - from.radius) * t,
+        velocity: from.velocity + (to.velocity - from.velocity) * t,
+        directivity: from.directivity,
+        gain: from.gain + (to.gain - from.gain) * t,
+        min_distance: from.min_distance + (to.min_distance - from.min_distance) * t,
+        max_distance: from.max_distance,
+        stereo_width: from.stereo_width + (to.stereo_width - from.stereo_width) * t,
+        obstruction: from.obstruction + (to.obstruction - from.obstruction) * t,
+    })
+}
+
+/// Unit direction `t` of the way along the arc from `from` to `to`, zero directions
+/// counting as the forward one. Opposite directions switch halfway.
+fn slerp(from: &Vector3<f32>, to: &Vector3<f32>, t: f32) -> Vector3<f32> {
+    let unit = |direction: &Vector3<f32>| {
+        Unit::try_new(*direction, f32::EPSILON).unwrap_or(Unit::new_unchecked(FORWARD_VECTOR))
+    };
+    let (from, to) = (unit(from), unit(to));
+    match from.try_slerp(&to, t, 1.0e-6) {
+        Some(direction) => direction.into_inner(),
+        None if t < 0.5 => from.into_inner(),
+        None => to.into_inner(),
+    }
+}
+
+/// Description at `time` seconds, see `Trajectory::sample`, `None` without keyframes.
+fn interpolate(keyframes: &[(f32, SourceInfo)], time: f32) -> Option<SourceInfo> {
+    let (from, to, t) = around(keyframes, time)?;
+    Some(SourceInfo {
+        relative_position: from.relative_position
+            + (to.relative_position - from.relative_position) * t,
+        direction: slerp(&from.direction, &to.direction, t),
+        radius: from.radius + (to.radius - from.radius) * t,
+        velocity: from.velocity + (to.velocity - from.velocity) * t,
+        source_direction: slerp(&from.source_direction, &to.source_direction, t),
+        room: from.room.clone(),
+        directivity: from.directivity,
+        gain: from.gain + (to.gain - from.gain) * t,
+        min_distance: from.min_distance + (to.min_distance - from.min_distance) * t,
+        max_distance: from.max_distance,
+        stereo_width: from.stereo_width + (to.stereo_width - from.stereo_width) * t,
+        obstruction: from.obstruction + (to.obstruction - from.obstruction) * t,
+        listener_height: from.listener_height + (to.listener_height - from.listener_height) * t,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    fn at(z: f32) -> SourceInfo {
+        SourceInfo {
+            relative_position: Vector3::new(0.0, 0.0, z),
+            ..Default::default()
+        }
+    }
+
+    /// Mean absolute level of `channel` over `range` in seconds.
+    fn level(wave: &Wave, channel: usize, range: std::ops::Range<f64>) -> f32 {
+        let samples = &wave.channel(channel)
+            [(range.start * SAMPLE_RATE) as usize..(range.end * SAMPLE_RATE) as usize];
+        samples.iter().map(|x| x.abs()).sum::<f32>() / samples.len() as f32
+    }
+
+    #[test]
+    fn source_pans_along_the_trajectory() {
+        let input = Wave::render(SAMPLE_RATE, 2.0, &mut sine_hz(440.0));
+        let keyframes = [(0.5, at(-1.0)), (1.5, at(1.0))];
+        let output = render_offline(&input, &keyframes, SAMPLE_RATE);
+        assert_eq!(output.channels(), 2);
+        assert_eq!(output.length(), input.length());
+        assert!(level(&output, 0, 0.2..0.5) > 2.0 * level(&output, 1, 0.2..0.5));
+        assert!(level(&output, 1, 1.7..2.0) > 2.0 * level(&output, 0, 1.7..2.0));
+
+        // Deterministic.
+        let again = render_offline(&input, &keyframes, SAMPLE_RATE);
+        assert_eq!(output.channel(0), again.channel(0));
+    }
+
+    #[test]
+    fn trajectory_turns_directions_along_the_arc() {
+        assert_eq!(Trajectory::new().sample(1.0), SourceInfo::default());
+        let single: Trajectory = [(1.0, at(2.0))].into_iter().collect();
+        assert_eq!(single.sample(-5.0).relative_position.z, 2.0);
+        assert_eq!(single.sample(5.0).relative_position.z, 2.0);
+
+        let facing = |direction, z| SourceInfo { direction, ..at(z) };
+        let mut trajectory = Trajectory::new();
+        trajectory
+            .add(2.0, facing(Vector3::new(0.0, 0.0, 3.0), 4.0))
+            .add(f32::NAN, at(9.0))
+            .add(0.0, facing(Vector3::new(2.0, 0.0, 0.0), 0.0));
+        assert_eq!(trajectory.keyframes().len(), 2);
+        let halfway = trajectory.sample(1.0);
+        assert_eq!(halfway.relative_position.z, 2.0);
+        let diagonal = Vector3::new(1.0, 0.0, 1.0).normalize();
+        assert!((halfway.direction - diagonal).norm() < 1.0e-6);
+        assert_eq!(trajectory.sample(-1.0).direction, Vector3::x());
+        assert_eq!(trajectory.sample(3.0).relative_position.z, 4.0);
+    }
+
+    /// Sine wave file in the temporary directory, named after `name`.
+    fn sine_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.wav", name, std::process::id()));
+        Wave::render(SAMPLE_RATE, 1.0, &mut sine_hz(440.0))
+            .save_wav32(&path)
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn scene_follows_the_listener_trajectory() {
+        let file = sine_file("scene");
+        let listener = |z| Listener {
+            position: Vector3::new(0.0, 0.0, z),
+            ..Default::default()
+        };
+        let scene = OfflineScene {
+            sample_rate: SAMPLE_RATE,
+            duration: Some(2.0),
+            // The listener walks by, the source going from its left to its right.
+            listener: vec![(0.5, listener(1.0)), (1.5, listener(-1.0))],
+            sources: vec![OfflineSource {
+                file: file.clone(),
+                keyframes: vec![(0.0, Source::default())],
+            }],
+            rooms: World::new(),
+        };
+        let mut reports = Vec::new();
+        let output = render_scene(&scene, |fraction| reports.push(fraction)).unwrap();
+        assert_eq!(output.length(), 2 * SAMPLE_RATE as usize);
+        assert!(level(&output, 0, 0.2..0.5) > 2.0 * level(&output, 1, 0.2..0.5));
+        assert!(level(&output, 1, 1.7..2.0) > 2.0 * level(&output, 0, 1.7..2.0));
+        assert_eq!((reports[0], reports.last().copied()), (0.0, Some(1.0)));
+        assert!(reports.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // The listener turns round in place, the source going from its left to its right
+        // without the facing direction collapsing on the way.
+        let facing = |forward| Listener {
+            position: Vector3::new(0.0, 0.0, 1.0),
+            forward,
+            ..Default::default()
+        };
+        let scene = OfflineScene {
+            listener: vec![
+                (0.5, facing(FORWARD_VECTOR)),
+                (1.5, facing(-FORWARD_VECTOR)),
+            ],
+            ..scene
+        };
+        for step in 0..=10 {
+            let listener = interpolate_listener(&scene.listener, 0.5 + step as f32 / 10.0);
+            let forward = listener.unwrap().forward;
+            assert!((forward.norm() - 1.0).abs() < 1e-5, "{forward:?}");
+        }
+        let output = render_scene(&scene, |_| ()).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert!(level(&output, 0, 0.2..0.5) > 2.0 * level(&output, 1, 0.2..0.5));
+        assert!(level(&output, 1, 1.7..2.0) > 2.0 * level(&output, 0, 1.7..2.0));
+        assert!((0..2).all(|channel| output.channel(channel).iter().all(|x| x.is_finite())));
+    }
+
+    #[test]
+    fn malformed_scenes_fail_clearly() {
+        let source = |times: &[f32]| OfflineSource {
+            file: PathBuf::from("missing.wav"),
+            keyframes: times.iter().map(|&t| (t, Source::default())).collect(),
+        };
+        let mut scene = OfflineScene {
+            sample_rate: SAMPLE_RATE,
+            duration: None,
+            listener: Vec::new(),
+            sources: Vec::new(),
+            rooms: World::new(),
+        };
+        let error = |scene: &OfflineScene| render_scene(scene, |_| ()).err().unwrap().to_string();
+        assert_eq!(error(&scene), "The scene has no sources.");
+        scene.sources = vec![source(&[])];
+        assert!(error(&scene).contains("no keyframes"));
+        scene.sources = vec![source(&[1.0, 0.0])];
+        assert!(error(&scene).contains("not in time order"));
+        scene.sources = vec![source(&[0.0])];
+        assert!(error(&scene).contains("missing.wav"));
+        scene.sample_rate = 0.0;
+        assert!(error(&scene).contains("sample rate"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn scene_files_resolve_their_sources() {
+        let file = sine_file("loaded");
+        let path = std::env::temp_dir().join(format!("scene-{}.json", std::process::id()));
+        let json = format!(
+            r#"{{
+                "sample_rate": 22050,
+                "listener": [[0.0, {{ "position": [0, 0, 1] }}]],
+                "sources": [{{ "file": "{}", "keyframes": [[0.0, {{ "gain": 0.5 }}]] }}]
+            }}"#,
+            file.file_name().unwrap().to_str().unwrap()
+        );
+        std::fs::write(&path, json).unwrap();
+        let scene = OfflineScene::load(&path).unwrap();
+        assert_eq!(scene.sources[0].file, file);
+        assert_eq!(scene.sources[0].keyframes[0].1.gain, 0.5);
+        assert_eq!(scene.listener[0].1.head_radius, crate::HEAD_RADIUS);
+        let output = render_scene(&scene, |_| ()).unwrap();
+        assert_eq!((output.sample_rate(), output.length()), (22050.0, 22050));
+
+        std::fs::write(&path, r#"{ "sample_rate": 22050, "sources": [ }"#).unwrap();
+        let error = OfflineScene::load(&path).err().unwrap().to_string();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert!(
+            error.contains("scene-") && error.contains("line 1"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn keyframes_are_interpolated_and_held() {
+        let keyframes = [(1.0, at(-1.0)), (3.0, at(3.0))];
+        let z = |time| interpolate(&keyframes, time).unwrap().relative_position.z;
+        assert_eq!(z(0.0), -1.0);
+        assert_eq!(z(2.0), 1.0);
+        assert_eq!(z(2.5), 2.0);
+        assert_eq!(z(10.0), 3.0);
+        assert!(interpolate(&[], 1.0).is_none());
+    }
+}
@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use fundsp::wave::Wave;
+
+use crate::VoiceImmersionError;
+
+/// Seconds of output the recording queue holds, frames past it being dropped when the
+/// writer falls behind.
+const RECORD_QUEUE: f64 = 1.0;
+
+/// How often the writer checks whether the recording stopped.
+const RECORD_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Audio thread side of the recorder: frames are queued without allocating while active.
+pub(crate) struct RecordTap {
+    active: AtomicBool,
+    frames: Sender<(f32, f32)>,
+}
+
+impl RecordTap {
+    #[inline]
+    pub(crate) fn record(&self, frame: (f32, f32)) {
+        if self.active.load(Ordering::Relaxed) {
+            // A full queue drops the frame rather than blocking the audio thread.
+            let _ = self.frames.try_send(frame);
+        }
+    }
+}
+
+/// Tees the output of the first listener to a WAV file, see `SpatialHandle::start_recording`.
+pub(crate) struct Recorder {
+    tap: Arc<RecordTap>,
+    frames: Receiver<(f32, f32)>,
+    sample_rate: f64,
+    writer: Mutex<Option<JoinHandle<crate::Result<()>>>>,
+}
+
+impl Recorder {
+    pub(crate) fn new(sample_rate: f64) -> Self {
+        let (sender, frames) = bounded((sample_rate * RECORD_QUEUE) as usize);
+        Recorder {
+            tap: Arc::new(RecordTap {
+                active: AtomicBool::new(false),
+                frames: sender,
+            }),
+            frames,
+            sample_rate,
+            writer: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn tap(&self) -> Arc<RecordTap> {
+        self.tap.clone()
+    }
+
+    pub(crate) fn start(&self, path: PathBuf) -> crate::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        if writer.is_some() {
+            return Err(VoiceImmersionError::InvalidState(
+                "Already recording.".into(),
+            ));
+        }
+        // Frames left from a previous recording.
+        for _ in self.frames.try_iter() {}
+        let tap = self.tap.clone();
+        let frames = self.frames.clone();
+        let sample_rate = self.sample_rate;
+        tap.active.store(true, Ordering::Relaxed);
+        *writer = Some(std::thread::spawn(move || {
+            let (mut left, mut right) = (Vec::new(), Vec::new());
+            while tap.active.load(Ordering::Relaxed) {
+                match frames.recv_timeout(RECORD_POLL_INTERVAL) {
+                    Ok((l, r)) => {
+                        left.push(l);
+                        right.push(r);
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            for (l, r) in frames.try_iter() {
+                left.push(l);
+                right.push(r);
+            }
+            let mut wave = Wave::new(0, sample_rate);
+            wave.push_channel(&left);
+            wave.push_channel(&right);
+            wave.save_wav32(&path).map_err(|e| {
+                VoiceImmersionError::Io(format!(
+                    "Could not save the recording to {}: {}",
+                    path.display(),
+                    e
+                ))
+            })
+        }));
+        Ok(())
+    }
+
+    /// Stop recording and save the file, once the writer caught up.
+    pub(crate) fn stop(&self) -> crate::Result<()> {
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        let Some(writer) = writer else {
+            return Err(VoiceImmersionError::InvalidState("Not recording.".into()));
+        };
+        self.tap.active.store(false, Ordering::Relaxed);
+        writer
+            .join()
+            .map_err(|_| VoiceImmersionError::Io("The recording writer panicked.".into()))?
+    }
+
+    pub(crate) fn is_recording(&self) -> bool {
+        self.tap.active.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recording_saves_the_frames_in_between() {
+        let recorder = Recorder::new(48000.0);
+        let tap = recorder.tap();
+        let path = std::env::temp_dir().join(format!("recording-{}.wav", std::process::id()));
+        tap.record((1.0, 1.0));
+        assert!(recorder.stop().is_err());
+
+        recorder.start(path.clone()).unwrap();
+        assert!(recorder.start(path.clone()).is_err());
+        for i in 0..1000 {
+            tap.record((i as f32 / 1000.0, -0.5));
+        }
+        recorder.stop().unwrap();
+        tap.record((1.0, 1.0));
+
+        let wave = Wave::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((wave.channels(), wave.length()), (2, 1000));
+        assert_eq!(wave.sample_rate(), 48000.0);
+        assert_eq!(wave.at(0, 500), 0.5);
+        assert_eq!(wave.at(1, 999), -0.5);
+    }
+}
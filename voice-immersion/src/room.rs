@@ -0,0 +1,670 @@
+use nalgebra::Vector3;
+
+use crate::dsp::{MAX_PREDELAY, ROOM_SIZE_RANGE};
+use crate::VoiceImmersionError;
+use crate::{InAnotherRoom, TransmissionLaw};
+
+/// Lowest wall cutoff in Hz, the bottom of the audible range.
+const MIN_CUTOFF: f32 = 20.0;
+
+/// Walls `image_sources` mirrors sources in: the four vertical ones, then the floor and the
+/// ceiling, as an axis of the engine convention and whether it is the far side of the box.
+const REFLECTING_WALLS: [(usize, bool); 6] = [
+    (0, false),
+    (0, true),
+    (2, false),
+    (2, true),
+    (1, false),
+    (1, true),
+];
+
+/// Most early reflections a source gets, one per wall of its room.
+pub(crate) const MAX_REFLECTIONS: usize = REFLECTING_WALLS.len();
+
+/// Axis aligned box, such as the walls of a room.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    /// Box spanning two opposite corners, in any order.
+    pub fn new(a: Vector3<f32>, b: Vector3<f32>) -> Self {
+        Aabb {
+            min: a.inf(&b),
+            max: a.sup(&b),
+        }
+    }
+
+    pub fn contains(&self, point: &Vector3<f32>) -> bool {
+        (0..3).all(|i| self.min[i] <= point[i] && point[i] <= self.max[i])
+    }
+
+    /// Whether the segment from `from` to `to` goes through the box.
+    pub fn intersects_segment(&self, from: &Vector3<f32>, to: &Vector3<f32>) -> bool {
+        let direction = to - from;
+        let (mut enter, mut exit) = (0.0_f32, 1.0_f32);
+        for i in 0..3 {
+            if direction[i].abs() < f32::EPSILON {
+                if from[i] < self.min[i] || from[i] > self.max[i] {
+                    return false;
+                }
+                continue;
+            }
+            let near = (self.min[i] - from[i]) / direction[i];
+            let far = (self.max[i] - from[i]) / direction[i];
+            enter = enter.max(near.min(far));
+            exit = exit.min(near.max(far));
+            if enter > exit {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Distance from `point` to the walls, positive inside the box and negative outside.
+    pub(crate) fn depth(&self, point: &Vector3<f32>) -> f32 {
+        if self.contains(point) {
+            (0..3)
+                .map(|i| (point[i] - self.min[i]).min(self.max[i] - point[i]))
+                .fold(f32::INFINITY, f32::min)
+        } else {
+            -(point.sup(&self.min).inf(&self.max) - point).norm()
+        }
+    }
+
+    /// Box with each wall moved `margin` inward, down to a plane on narrower sides.
+    fn shrunk(&self, margin: f32) -> Self {
+        let center = (self.min + self.max) / 2.0;
+        let margin = Vector3::repeat(margin.max(0.0));
+        Aabb {
+            min: (self.min + margin).inf(&center),
+            max: (self.max - margin).sup(&center),
+        }
+    }
+
+    /// Box moved by `offset`.
+    pub fn translated(&self, offset: &Vector3<f32>) -> Self {
+        Aabb {
+            min: self.min + offset,
+            max: self.max + offset,
+        }
+    }
+}
+
+/// First-order image sources of `source` mirrored in the walls of `bounds`, both relative to
+/// the listener in the engine convention: the vertical walls first, then the floor and the
+/// ceiling, up to `count` of them.
+pub(crate) fn image_sources(
+    bounds: &Aabb,
+    source: &Vector3<f32>,
+    count: usize,
+) -> impl Iterator<Item = Vector3<f32>> {
+    let (bounds, source) = (*bounds, *source);
+    REFLECTING_WALLS
+        .into_iter()
+        .take(count)
+        .map(move |(axis, far)| {
+            let wall = if far {
+                bounds.max[axis]
+            } else {
+                bounds.min[axis]
+            };
+            let mut image = source;
+            image[axis] = 2.0 * wall - source[axis];
+            image
+        })
+}
+
+/// Walls of `room` between a listener at the origin and a source at `relative_position`:
+/// one when either is inside its bounds, two when the line of sight goes through the room,
+/// none otherwise. Always one for a room without bounds.
+pub fn walls_crossed(room: &InAnotherRoom, relative_position: &Vector3<f32>) -> u32 {
+    let Some(bounds) = &room.bounds else {
+        return 1;
+    };
+    let origin = Vector3::zeros();
+    match (bounds.contains(&origin), bounds.contains(relative_position)) {
+        (true, true) => 0,
+        (true, false) | (false, true) => 1,
+        (false, false) if bounds.intersects_segment(&origin, relative_position) => 2,
+        (false, false) => 0,
+    }
+}
+
+/// Walls of `room` between a listener at the origin and a source at `relative_position` as
+/// `walls_crossed`, but ramped in over `transition` meters across each wall plane, half of
+/// the wall counting right on it: the sound bends around the wall when passing close to it,
+/// so stepping through a wall muffles the source gradually. `transition` 0 is
+/// `walls_crossed`.
+pub fn wall_amount(room: &InAnotherRoom, relative_position: &Vector3<f32>, transition: f32) -> f32 {
+    let Some(bounds) = &room.bounds else {
+        return 1.0;
+    };
+    let transition = transition.max(0.0);
+    let inside = |point: &Vector3<f32>| {
+        if transition > 0.0 {
+            (0.5 + bounds.depth(point) / transition).clamp(0.0, 1.0)
+        } else if bounds.contains(point) {
+            1.0
+        } else {
+            0.0
+        }
+    };
+    let origin = Vector3::zeros();
+    let (listener, source) = (inside(&origin), inside(relative_position));
+    // Past the ramps the line of sight goes through the room or not, whichever side of the
+    // nearby walls the ends are on.
+    let through = if bounds
+        .shrunk(transition / 2.0)
+        .intersects_segment(&origin, relative_position)
+    {
+        2.0
+    } else {
+        0.0
+    };
+    listener * (1.0 - source)
+        + (1.0 - listener) * source
+        + (1.0 - listener) * (1.0 - source) * through
+}
+
+/// Gain through the walls of `room` on the line of sight to a source at `relative_position`
+/// from the listener, 1 out of any room or when no wall is in the way. Right at the walls,
+/// without the ramp of `EngineConfig::wall_diffraction`.
+pub fn occlusion_factor(room: Option<&InAnotherRoom>, relative_position: &Vector3<f32>) -> f32 {
+    diffracted_occlusion(room, relative_position, 0.0)
+}
+
+/// `occlusion_factor` through the walls of `wall_amount` ramped over `transition` meters.
+pub(crate) fn diffracted_occlusion(
+    room: Option<&InAnotherRoom>,
+    relative_position: &Vector3<f32>,
+    transition: f32,
+) -> f32 {
+    room.map_or(1.0, |room| {
+        wall_transmission(room, wall_amount(room, relative_position, transition))
+    })
+}
+
+/// Gain from 0 to 1 through `walls` walls of `room`, no less than its minimum transmission
+/// and 1 through none. Negative widths and attenuations count as none.
+pub(crate) fn wall_transmission(room: &InAnotherRoom, walls: f32) -> f32 {
+    if walls <= 0.0 {
+        return 1.0;
+    }
+    let absorption = room.wall_width.max(0.0) * room.wall_attenuation_factor.max(0.0);
+    room.transmission_law
+        .transmission(absorption * walls)
+        .max(room.min_transmission.clamp(0.0, 1.0))
+}
+
+/// Room of a `World`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RoomId(pub(crate) usize);
+
+/// Rooms of a building, each with its own walls and reverb, for world placements: a source
+/// is heard through the walls of its room, or of the listener one when out of any room.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct World {
+    rooms: Vec<InAnotherRoom>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `room`, which needs bounds in world coordinates. Rooms added first win where
+    /// rooms overlap.
+    pub fn add_room(&mut self, room: InAnotherRoom) -> crate::Result<RoomId> {
+        if room.bounds.is_none() {
+            return Err(VoiceImmersionError::InvalidConfig(
+                "Rooms of a world need bounds.".into(),
+            ));
+        }
+        self.rooms.push(room);
+        Ok(RoomId(self.rooms.len() - 1))
+    }
+
+    pub fn room(&self, id: RoomId) -> Option<&InAnotherRoom> {
+        self.rooms.get(id.0)
+    }
+
+    /// Room containing `point`, in world coordinates.
+    pub fn room_at(&self, point: &Vector3<f32>) -> Option<RoomId> {
+        self.rooms
+            .iter()
+            .position(|room| room.bounds.is_some_and(|bounds| bounds.contains(point)))
+            .map(RoomId)
+    }
+
+    /// Room a listener at `listener` hears a source at `source` from: the source one, sharing
+    /// its reverb without walls in between when the listener is in it too, or the listener
+    /// one. `None` out of any room.
+    pub fn heard_room(
+        &self,
+        listener: &Vector3<f32>,
+        source: &Vector3<f32>,
+    ) -> Option<&InAnotherRoom> {
+        let id = self.room_at(source).or_else(|| self.room_at(listener))?;
+        self.room(id)
+    }
+}
+
+/// Common wall surfaces, calibrated from their typical sound transmission loss, read by
+/// `TransmissionLaw::Decibels` as the loss of the whole wall spread over its width.
+/// Heavy walls block the highs far more than the lows (mass law), hence the lower cutoffs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Material {
+    /// 20 cm concrete wall, about 50 dB of loss: speech is barely a low rumble behind it.
+    Concrete,
+    /// 4 cm solid wood door or stud wall, about 25 dB of loss, the voice muffled but audible.
+    Wood,
+    /// 6 mm single glazed window, about 28 dB of loss: light, so mids leak more than
+    /// through wood of the same loss.
+    GlassWindow,
+    /// Heavy 1 cm curtain, about 5 dB of loss, mostly absorbing the highs.
+    CurtainDrape,
+}
+
+impl Material {
+    /// Wall width in meters, loss in dB per meter, and cutoff in Hz.
+    fn parameters(self) -> (f32, f32, f32) {
+        match self {
+            Material::Concrete => (0.2, 250.0, 250.0),
+            Material::Wood => (0.04, 625.0, 800.0),
+            Material::GlassWindow => (0.006, 4667.0, 1500.0),
+            Material::CurtainDrape => (0.01, 500.0, 5000.0),
+        }
+    }
+}
+
+/// Filter the walls of a room apply to the sound going through them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WallFilter {
+    /// One-pole low-pass at this cutoff in Hz: frequencies above it lose 6 dB per octave,
+    /// the cutoff itself being 3 dB down. Most walls, and the one materials use.
+    LowPass(f32),
+    /// Resonant band around `center` Hz, narrower for higher `q`, as through thin panels or
+    /// ducts.
+    BandPass { center: f32, q: f32 },
+    /// Cut band around `center` Hz, narrower for higher `q`.
+    Notch { center: f32, q: f32 },
+}
+
+/// Checked construction of an `InAnotherRoom`, see `InAnotherRoom::builder`.
+#[derive(Debug, Clone)]
+pub struct RoomBuilder {
+    wall_width: f32,
+    wall_attenuation: f32,
+    wall_filter: Option<WallFilter>,
+    transmission_law: TransmissionLaw,
+    min_transmission: f32,
+    reverb_time: f32,
+    room_size: f32,
+    reverb_predelay: f32,
+    bounds: Option<Aabb>,
+}
+
+impl InAnotherRoom {
+    /// Dry room behind a wall of `material`, without bounds.
+    pub fn from_material(material: Material) -> Self {
+        let (wall_width, wall_attenuation_factor, cutoff_frequency) = material.parameters();
+        InAnotherRoom {
+            wall_width,
+            wall_attenuation_factor,
+            wall_filter: WallFilter::LowPass(cutoff_frequency),
+            transmission_law: TransmissionLaw::Decibels,
+            min_transmission: 0.0,
+            reverb_time: 0.0,
+            room_size: 10.0,
+            reverb_predelay: 0.0,
+            bounds: None,
+        }
+    }
+
+    /// Room behind a wall of no width, letting everything through until set otherwise.
+    pub fn builder() -> RoomBuilder {
+        RoomBuilder {
+            wall_width: 0.0,
+            wall_attenuation: 0.0,
+            wall_filter: None,
+            transmission_law: TransmissionLaw::default(),
+            min_transmission: 0.0,
+            reverb_time: 0.0,
+            room_size: 10.0,
+            reverb_predelay: 0.0,
+            bounds: None,
+        }
+    }
+}
+
+impl RoomBuilder {
+    /// Wall width, attenuation, cutoff and transmission law of `material`.
+    pub fn material(mut self, material: Material) -> Self {
+        let (wall_width, wall_attenuation, cutoff_frequency) = material.parameters();
+        self.wall_width = wall_width;
+        self.wall_attenuation = wall_attenuation;
+        self.wall_filter = Some(WallFilter::LowPass(cutoff_frequency));
+        self.transmission_law = TransmissionLaw::Decibels;
+        self
+    }
+
+    /// Width of the wall in meters.
+    pub fn wall_width(mut self, meters: f32) -> Self {
+        self.wall_width = meters;
+        self
+    }
+
+    /// Attenuation per meter of wall, read by the transmission law.
+    pub fn wall_attenuation(mut self, factor: f32) -> Self {
+        self.wall_attenuation = factor;
+        self
+    }
+
+    /// Cutoff in Hz of the wall low-pass, see `WallFilter::LowPass`.
+    pub fn cutoff_frequency(mut self, hz: f32) -> Self {
+        self.wall_filter = Some(WallFilter::LowPass(hz));
+        self
+    }
+
+    /// Filter of the walls, a low-pass at the Nyquist frequency otherwise.
+    pub fn wall_filter(mut self, filter: WallFilter) -> Self {
+        self.wall_filter = Some(filter);
+        self
+    }
+
+    pub fn transmission_law(mut self, law: TransmissionLaw) -> Self {
+        self.transmission_law = law;
+        self
+    }
+
+    /// Lowest gain let through the wall, from 0 to 1.
+    pub fn min_transmission(mut self, gain: f32) -> Self {
+        self.min_transmission = gain;
+        self
+    }
+
+    /// Decay time in seconds of the room reverb, 0 for a dry room.
+    pub fn reverb_time(mut self, seconds: f32) -> Self {
+        self.reverb_time = seconds;
+        self
+    }
+
+    /// Size of the room in meters, from 1 to 40.
+    pub fn room_size(mut self, meters: f32) -> Self {
+        self.room_size = meters;
+        self
+    }
+
+    /// Gap in seconds between the direct sound and the reverb, up to 0.25.
+    pub fn reverb_predelay(mut self, seconds: f32) -> Self {
+        self.reverb_predelay = seconds;
+        self
+    }
+
+    /// Box of the walls spanning the corners `a` and `b`, see `InAnotherRoom::bounds`.
+    pub fn bounds(mut self, a: Vector3<f32>, b: Vector3<f32>) -> Self {
+        self.bounds = Some(Aabb::new(a, b));
+        self
+    }
+
+    /// Check the settings against each other and the output `sample_rate`.
+    pub fn build(self, sample_rate: f32) -> crate::Result<InAnotherRoom> {
+        let nyquist = sample_rate / 2.0;
+        let wall_filter = self.wall_filter.unwrap_or(WallFilter::LowPass(nyquist));
+        if self.wall_width.is_nan() || self.wall_width < 0.0 {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Wall width of {} m is negative.",
+                self.wall_width
+            )));
+        }
+        if self.wall_attenuation.is_nan() || self.wall_attenuation < 0.0 {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Wall attenuation of {} is negative.",
+                self.wall_attenuation
+            )));
+        }
+        let (frequency, q) = match wall_filter {
+            WallFilter::LowPass(cutoff) => (cutoff, 1.0),
+            WallFilter::BandPass { center, q } | WallFilter::Notch { center, q } => (center, q),
+        };
+        if !(MIN_CUTOFF..=nyquist).contains(&frequency) {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Wall filter frequency of {} Hz is out of the {} to {} Hz range.",
+                frequency, MIN_CUTOFF, nyquist
+            )));
+        }
+        if q.is_nan() || q <= 0.0 {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Wall filter Q of {} is not positive.",
+                q
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.min_transmission) {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Minimum transmission of {} is out of the 0 to 1 range.",
+                self.min_transmission
+            )));
+        }
+        if self.reverb_time.is_nan() || self.reverb_time < 0.0 {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Reverb time of {} s is negative.",
+                self.reverb_time
+            )));
+        }
+        let (min_size, max_size) = ROOM_SIZE_RANGE;
+        if !(min_size..=max_size).contains(&self.room_size) {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Room size of {} m is out of the {} to {} m range.",
+                self.room_size, min_size, max_size
+            )));
+        }
+        if !(0.0..=MAX_PREDELAY).contains(&self.reverb_predelay) {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "Reverb pre-delay of {} s is out of the 0 to {} s range.",
+                self.reverb_predelay, MAX_PREDELAY
+            )));
+        }
+        Ok(InAnotherRoom {
+            wall_width: self.wall_width,
+            wall_attenuation_factor: self.wall_attenuation,
+            wall_filter,
+            transmission_law: self.transmission_law,
+            min_transmission: self.min_transmission,
+            reverb_time: self.reverb_time,
+            room_size: self.room_size,
+            reverb_predelay: self.reverb_predelay,
+            bounds: self.bounds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn materials_block_as_documented() {
+        let loss_db = |material| {
+            let room = InAnotherRoom::from_material(material);
+            -20.0 * occlusion_factor(Some(&room), &Vector3::zeros()).log10()
+        };
+        assert!((loss_db(Material::Concrete) - 50.0).abs() < 0.1);
+        assert!((loss_db(Material::Wood) - 25.0).abs() < 0.1);
+        assert!((loss_db(Material::GlassWindow) - 28.0).abs() < 0.1);
+        assert!((loss_db(Material::CurtainDrape) - 5.0).abs() < 0.1);
+
+        let built = InAnotherRoom::builder()
+            .material(Material::Wood)
+            .build(48000.0)
+            .unwrap();
+        assert_eq!(built, InAnotherRoom::from_material(Material::Wood));
+    }
+
+    #[test]
+    fn walls_on_the_line_of_sight() {
+        let room = InAnotherRoom::builder()
+            .wall_width(0.1)
+            .wall_attenuation(10.0)
+            .bounds(Vector3::new(2.0, -1.0, -1.0), Vector3::new(4.0, 1.0, 1.0))
+            .build(48000.0)
+            .unwrap();
+        let walls = |x: f32, z: f32| walls_crossed(&room, &Vector3::new(x, 0.0, z));
+        assert_eq!(walls(3.0, 0.0), 1);
+        assert_eq!(walls(6.0, 0.0), 2);
+        assert_eq!(walls(6.0, 4.0), 0);
+        assert_eq!(walls(1.0, 0.0), 0);
+
+        let inside = room
+            .bounds
+            .unwrap()
+            .translated(&Vector3::new(-3.0, 0.0, 0.0));
+        let around = InAnotherRoom {
+            bounds: Some(inside),
+            ..room.clone()
+        };
+        assert_eq!(walls_crossed(&around, &Vector3::new(0.5, 0.0, 0.0)), 0);
+        assert_eq!(walls_crossed(&around, &Vector3::new(5.0, 0.0, 0.0)), 1);
+
+        let through = |walls: f32| (-0.1_f32 * 10.0 * walls).exp();
+        let occlusion = |x: f32| occlusion_factor(Some(&room), &Vector3::new(x, 0.0, 0.0));
+        assert_eq!(occlusion(1.0), 1.0);
+        assert!((occlusion(3.0) - through(1.0)).abs() < 1e-6);
+        assert!((occlusion(6.0) - through(2.0)).abs() < 1e-6);
+        let unbounded = InAnotherRoom {
+            bounds: None,
+            ..room
+        };
+        assert!(
+            (occlusion_factor(Some(&unbounded), &Vector3::zeros()) - through(1.0)).abs() < 1e-6
+        );
+    }
+
+    #[test]
+    fn walls_fade_in_across_their_plane() {
+        // The listener walks along x through the near wall of a room 4 m deep, at `wall`.
+        let amount = |wall: f32, source: f32| {
+            let room = InAnotherRoom::builder()
+                .bounds(
+                    Vector3::new(wall, -2.0, -2.0),
+                    Vector3::new(wall + 4.0, 2.0, 2.0),
+                )
+                .build(48000.0)
+                .unwrap();
+            wall_amount(&room, &Vector3::new(source, 0.0, 0.0), 0.5)
+        };
+        // Source in the room, then past its far wall.
+        for (source, outside, inside) in [(2.0, 1.0, 0.0), (10.0, 2.0, 1.0)] {
+            assert_eq!(amount(0.3, source), outside);
+            assert_eq!(amount(-0.3, source), inside);
+            let halfway = (outside + inside) / 2.0;
+            assert!((amount(0.0, source) - halfway).abs() < 1e-6);
+            assert!((amount(1e-4, source) - amount(-1e-4, source)).abs() < 1e-3);
+            let ramp: Vec<f32> = (-30..=30)
+                .map(|i| amount(i as f32 / 100.0, source))
+                .collect();
+            assert!(ramp.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+        // Same side as the source, nothing between them once out.
+        assert_eq!(amount(-0.3, -5.0), 1.0);
+        assert!((amount(0.0, -5.0) - 0.5).abs() < 1e-6);
+        assert_eq!(amount(0.3, -5.0), 0.0);
+
+        let room = InAnotherRoom::builder()
+            .bounds(Vector3::new(2.0, -1.0, -1.0), Vector3::new(4.0, 1.0, 1.0))
+            .build(48000.0)
+            .unwrap();
+        for position in [Vector3::new(3.0, 0.0, 0.0), Vector3::new(6.0, 0.0, 0.0)] {
+            let walls = walls_crossed(&room, &position) as f32;
+            assert_eq!(wall_amount(&room, &position, 0.0), walls);
+        }
+    }
+
+    #[test]
+    fn images_mirror_the_source_in_the_walls() {
+        let bounds = Aabb::new(Vector3::new(-1.0, -1.0, -2.0), Vector3::new(3.0, 2.0, 2.0));
+        let source = Vector3::new(1.0, 0.0, 1.0);
+        let images: Vec<_> = image_sources(&bounds, &source, MAX_REFLECTIONS).collect();
+        assert_eq!(
+            images,
+            [
+                Vector3::new(-3.0, 0.0, 1.0),
+                Vector3::new(5.0, 0.0, 1.0),
+                Vector3::new(1.0, 0.0, -5.0),
+                Vector3::new(1.0, 0.0, 3.0),
+                Vector3::new(1.0, -2.0, 1.0),
+                Vector3::new(1.0, 4.0, 1.0),
+            ]
+        );
+        assert_eq!(image_sources(&bounds, &source, 4).count(), 4);
+    }
+
+    #[test]
+    fn world_rooms_around_points() {
+        let mut world = World::new();
+        assert!(world
+            .add_room(InAnotherRoom::from_material(Material::Wood))
+            .is_err());
+        let room = |x: f32, reverb_time: f32| {
+            InAnotherRoom::builder()
+                .reverb_time(reverb_time)
+                .bounds(Vector3::new(x, 0.0, 0.0), Vector3::new(x + 4.0, 3.0, 4.0))
+                .build(48000.0)
+                .unwrap()
+        };
+        let kitchen = world.add_room(room(0.0, 0.5)).unwrap();
+        let hall = world.add_room(room(4.0, 2.0)).unwrap();
+        let at = |x: f32| Vector3::new(x, 1.5, 2.0);
+        assert_eq!(world.room_at(&at(1.0)), Some(kitchen));
+        assert_eq!(world.room_at(&at(6.0)), Some(hall));
+        assert_eq!(world.room_at(&at(10.0)), None);
+        assert_eq!(world.room(hall).unwrap().reverb_time, 2.0);
+
+        let heard = |listener: f32, source: f32| {
+            world
+                .heard_room(&at(listener), &at(source))
+                .map(|room| room.reverb_time)
+        };
+        assert_eq!(heard(1.0, 2.0), Some(0.5));
+        assert_eq!(heard(1.0, 6.0), Some(2.0));
+        assert_eq!(heard(1.0, 10.0), Some(0.5));
+        assert_eq!(heard(10.0, 12.0), None);
+    }
+
+    #[test]
+    fn builder_checks_the_settings() {
+        let room = InAnotherRoom::builder()
+            .wall_width(0.005)
+            .wall_attenuation(500.0)
+            .cutoff_frequency(2000.0)
+            .build(48000.0)
+            .unwrap();
+        assert_eq!(room.wall_width, 0.005);
+        assert_eq!(room.wall_attenuation_factor, 500.0);
+        assert_eq!(room.wall_filter, WallFilter::LowPass(2000.0));
+        assert_eq!(
+            InAnotherRoom::builder().build(32000.0).unwrap().wall_filter,
+            WallFilter::LowPass(16000.0)
+        );
+
+        let room = InAnotherRoom::builder;
+        assert!(room().wall_width(-0.1).build(48000.0).is_err());
+        assert!(room().wall_attenuation(-1.0).build(48000.0).is_err());
+        assert!(room().wall_attenuation(f32::NAN).build(48000.0).is_err());
+        assert!(room().cutoff_frequency(10.0).build(48000.0).is_err());
+        assert!(room().cutoff_frequency(30000.0).build(48000.0).is_err());
+        let band = |center: f32, q: f32| room().wall_filter(WallFilter::BandPass { center, q });
+        assert!(band(800.0, 2.0).build(48000.0).is_ok());
+        assert!(band(800.0, 0.0).build(48000.0).is_err());
+        assert!(band(30000.0, 2.0).build(48000.0).is_err());
+        assert!(room().min_transmission(2.0).build(48000.0).is_err());
+        assert!(room().room_size(100.0).build(48000.0).is_err());
+        assert!(room().reverb_predelay(1.0).build(48000.0).is_err());
+    }
+}
@@ -0,0 +1,1093 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use fundsp::hacker::*;
+use nalgebra::Vector3;
+
+use crate::clock::Instant;
+use crate::config::{EngineConfig, OutputFormat};
+use crate::dsp::{stereo_width, Cull, Ramp};
+use crate::engine::{
+    build_graph, Controller, IdleDetector, InputSource, Inputs, MasterParams, Placement,
+};
+use crate::gain::Decibels;
+use crate::spatial::{Listener, Source};
+use crate::VoiceImmersionError;
+use crate::{InAnotherRoom, SourceInfo};
+
+/// Time in seconds to fade a source in when added, and out when removed.
+const SOURCE_FADE: f32 = 0.02;
+
+/// Handle on a source of a `Scene`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceId(u64);
+
+/// Placement change pushed to the running engine, see `SpatialHandle::updates`.
+#[derive(Debug, Clone)]
+pub enum SourceUpdate {
+    /// New description of `source` as heard by listener `listener`, for relative placements.
+    Relative {
+        source: SourceId,
+        listener: usize,
+        info: SourceInfo,
+    },
+    /// `source` moved, for world placements.
+    Source { source: SourceId, state: Source },
+    /// Listener `listener` moved, for world placements.
+    Listener { listener: usize, state: Listener },
+    /// Room `source` stands in, the rest of its description left as it is: in coordinates
+    /// relative to the listener for relative placements, in world ones otherwise.
+    Room {
+        source: SourceId,
+        room: Option<InAnotherRoom>,
+    },
+    /// Position of `source`, the rest of its description left as it is: relative to every
+    /// listener for relative placements, in the world otherwise.
+    Position {
+        source: SourceId,
+        position: Vector3<f32>,
+    },
+    /// Direction `source` faces for its directivity, the rest of its description left as
+    /// it is.
+    Direction {
+        source: SourceId,
+        direction: Vector3<f32>,
+    },
+}
+
+/// Placements and settings of the sources of a `Scene`, without their inputs: see
+/// `Scene::snapshot`, and `Scene::to_json` with the `serde` feature.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SceneSnapshot {
+    /// Sources in insertion order.
+    pub sources: Vec<SourceSnapshot>,
+}
+
+/// Source of a `SceneSnapshot`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceSnapshot {
+    /// Where listener `k` hears the source from.
+    pub placements: Vec<PlacementSnapshot>,
+    /// See `Scene::set_source_gain`.
+    pub gain: f32,
+    /// See `Scene::set_mute`.
+    pub muted: bool,
+    /// See `Scene::set_solo`.
+    pub soloed: bool,
+    /// See `Scene::set_priority`.
+    pub priority: f32,
+}
+
+/// Description held by a `Placement`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PlacementSnapshot {
+    Relative(SourceInfo),
+    World { listener: Listener, source: Source },
+}
+
+/// Sources mixed into the output bus, shared by the caller and the running engine:
+/// sources can be added and removed while it plays.
+#[derive(Clone)]
+pub struct Scene {
+    state: Arc<Mutex<SceneState>>,
+}
+
+/// Source of the scene along with its controller for every listener.
+pub(crate) struct SceneSource {
+    pub(crate) id: SourceId,
+    node: NodeId,
+    pub(crate) listeners: Vec<(Placement, Controller)>,
+    /// Raised while the source is rendered, see `EngineConfig::cull_timeout`.
+    active: Shared,
+    silence: IdleDetector,
+    /// Level from its solo and mute states, see `Scene::set_solo`.
+    level: Shared,
+    muted: bool,
+    soloed: bool,
+    /// Weight of the source when over `EngineConfig::max_voices`, see `Scene::set_priority`.
+    priority: f32,
+    /// Level from whether it fits in `EngineConfig::max_voices`.
+    voice: Shared,
+}
+
+impl SceneSource {
+    /// Stop or resume rendering the source from whether it is `audible` at `now`, see
+    /// `EngineConfig::cull_timeout`.
+    pub(crate) fn cull(&mut self, now: Instant, audible: bool) {
+        if let Some(culled) = self.silence.update(now, audible) {
+            self.active.set_value(if culled { 0.0 } else { 1.0 });
+        }
+    }
+
+    /// Whether the source fits in `EngineConfig::max_voices`.
+    pub(crate) fn voiced(&self) -> bool {
+        self.voice.value() > 0.0
+    }
+
+    /// Weight of the source in the voice budget: its priority times the gain it is heard
+    /// with by the listener hearing it loudest, zero when muted.
+    fn rank(&self) -> f32 {
+        let loudness = self
+            .listeners
+            .iter()
+            .map(|(_, controller)| controller.loudness())
+            .fold(0.0, f32::max);
+        self.priority * loudness * self.level.value()
+    }
+}
+
+pub(crate) struct SceneState {
+    /// Frontend of the graph, see `Net::commit`.
+    net: Net,
+    limiter: NodeId,
+    listeners: usize,
+    pub(crate) config: EngineConfig,
+    master: MasterParams,
+    pub(crate) sources: Vec<SceneSource>,
+    /// Removed sources fading out, unlinked from the graph on a later edit.
+    retired: Vec<(NodeId, Instant)>,
+    next_id: u64,
+}
+
+impl Scene {
+    /// Empty scene rendered for `listeners` listeners, listener `k` on outputs `2k` and `2k + 1`
+    /// in stereo, see `OutputFormat`.
+    pub(crate) fn new(
+        config: &EngineConfig,
+        listeners: usize,
+        sample_rate: f64,
+        master: MasterParams,
+    ) -> Self {
+        let config = &config.resolve_smoothing();
+        let channels = listeners * config.output_format.channels();
+        let mut net = Net::new(0, channels);
+        net.set_sample_rate(sample_rate);
+        // Stereo width, the DC blocker, then the limiter as a wider mix gets louder. A
+        // negative width swaps the channels, as mirroring the Y channel does in ambisonics.
+        let side = || var(&master.side) >> follow(config.gain_ramp);
+        let dc_blocker = || match config.dc_blocker {
+            Some(cutoff) => Net::wrap(Box::new(dcblock_hz(cutoff))),
+            None => Net::wrap(Box::new(pass())),
+        };
+        let bus = || {
+            if config.output_format == OutputFormat::Ambisonic1 {
+                return Net::wrap(Box::new(multipass::<U2>() | (pass() * side()) | pass()))
+                    >> (dc_blocker() | dc_blocker() | dc_blocker() | dc_blocker());
+            }
+            let width = Net::wrap(Box::new(
+                (multipass::<U2>() | (var(&master.width) >> follow(config.gain_ramp)) * side())
+                    >> map(|f: &Frame<f32, U3>| stereo_width(f[0], f[1], f[2])),
+            )) >> (dc_blocker() | dc_blocker());
+            match config.limiter {
+                Some(limiter) => width >> limiter_stereo(limiter.attack, limiter.release),
+                None => width,
+            }
+        };
+        let mut limiters = bus();
+        for _ in 1..listeners {
+            limiters = limiters | bus();
+        }
+        let limiter = net.push(Box::new(limiters));
+        net.pipe_output(limiter);
+        // Fade of the whole output on start and stop.
+        let ramp = || An(Ramp::new(&master.output, config.output_fade));
+        let fade = || match config.output_format {
+            OutputFormat::Stereo => {
+                Net::wrap(Box::new(multipass::<U2>() * (ramp() >> split::<U2>())))
+            }
+            OutputFormat::Ambisonic1 => {
+                Net::wrap(Box::new(multipass::<U4>() * (ramp() >> split::<U4>())))
+            }
+        };
+        let mut fades = fade();
+        for _ in 1..listeners {
+            fades = fades | fade();
+        }
+        net.chain(Box::new(fades));
+        let state = SceneState {
+            net,
+            limiter,
+            listeners,
+            config: config.clone(),
+            master,
+            sources: Vec::new(),
+            retired: Vec::new(),
+            next_id: 0,
+        };
+        Scene {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Mix `input` into the output, heard by listener `k` from `placements[k]`.
+    /// There must be one placement per listener.
+    pub fn add_source(
+        &self,
+        input: InputSource,
+        placements: Vec<Placement>,
+    ) -> crate::Result<SourceId> {
+        let listeners = self.lock().listeners;
+        if placements.len() != listeners {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "{} placements given for {} listeners.",
+                placements.len(),
+                listeners
+            )));
+        }
+        let inputs = Inputs::from(input);
+        inputs.check()?;
+        Ok(self.insert(inputs, placements))
+    }
+
+    /// Fade the source out of the mix, returns whether it was in the scene.
+    pub fn remove_source(&self, id: SourceId) -> bool {
+        let mut state = self.lock();
+        let Some(index) = state.sources.iter().position(|source| source.id == id) else {
+            return false;
+        };
+        let source = state.sources.remove(index);
+        state.purge_retired();
+        let bypass = thru(state.net.inputs_in(source.node));
+        state
+            .net
+            .crossfade(source.node, Fade::Smooth, SOURCE_FADE, Box::new(bypass));
+        state.retired.push((source.node, Instant::now()));
+        // The others are heard again when it was the soloed one.
+        state.update_levels();
+        state.commit();
+        true
+    }
+
+    /// Gain of the source on top of its spatialization, ramped over `EngineConfig::gain_ramp`.
+    /// Returns whether the source is in the scene.
+    pub fn set_source_gain(&self, id: SourceId, gain: f32) -> bool {
+        let state = self.lock();
+        let Some(source) = state.source(id) else {
+            return false;
+        };
+        source.listeners[0].1.source_gain().set_value(gain);
+        true
+    }
+
+    /// `set_source_gain` in decibels.
+    pub fn set_source_gain_db(&self, id: SourceId, gain: Decibels) -> bool {
+        self.set_source_gain(id, gain.to_linear())
+    }
+
+    /// Silence the source, or hear it again, ramped over `EngineConfig::gain_ramp`. It
+    /// keeps playing meanwhile, as with a mixing desk. Returns whether the source is in the
+    /// scene.
+    pub fn set_mute(&self, id: SourceId, muted: bool) -> bool {
+        let mut state = self.lock();
+        let Some(source) = state.sources.iter_mut().find(|source| source.id == id) else {
+            return false;
+        };
+        source.muted = muted;
+        state.update_levels();
+        true
+    }
+
+    /// Solo the source, or not anymore: while any source is soloed, only soloed ones are
+    /// heard, muted or not. Ramped as `set_mute`, returns whether the source is in the scene.
+    pub fn set_solo(&self, id: SourceId, soloed: bool) -> bool {
+        let mut state = self.lock();
+        let Some(source) = state.sources.iter_mut().find(|source| source.id == id) else {
+            return false;
+        };
+        source.soloed = soloed;
+        state.update_levels();
+        true
+    }
+
+    /// Weight of the source when more than `EngineConfig::max_voices` are heard, 1 by
+    /// default: the sources ranked last by their priority times the gain they are heard
+    /// with are silenced, ramped over `EngineConfig::gain_ramp`, on the next control pass.
+    /// Returns whether the source is in the scene.
+    pub fn set_priority(&self, id: SourceId, priority: f32) -> bool {
+        let mut state = self.lock();
+        let Some(source) = state.sources.iter_mut().find(|source| source.id == id) else {
+            return false;
+        };
+        source.priority = priority.max(0.0);
+        true
+    }
+
+    /// Jump the propagation delays of the source to its next position rather than gliding
+    /// there, e.g. right before teleporting it, as `DelayMode::Snap` does for every jump.
+    /// Returns whether the source is in the scene.
+    pub fn snap_delays(&self, id: SourceId) -> bool {
+        let mut state = self.lock();
+        let Some(source) = state.sources.iter_mut().find(|source| source.id == id) else {
+            return false;
+        };
+        for (_, controller) in &mut source.listeners {
+            controller.snap_delays();
+        }
+        true
+    }
+
+    /// Sources currently in the scene, in insertion order.
+    pub fn sources(&self) -> Vec<SourceId> {
+        self.lock().sources.iter().map(|source| source.id).collect()
+    }
+
+    /// Current placements and settings of every source, for `restore`.
+    pub fn snapshot(&self) -> SceneSnapshot {
+        let state = self.lock();
+        let sources = state
+            .sources
+            .iter()
+            .map(|source| SourceSnapshot {
+                placements: source
+                    .listeners
+                    .iter()
+                    .map(|(placement, _)| snapshot_placement(placement))
+                    .collect(),
+                gain: source.listeners[0].1.source_gain().value(),
+                muted: source.muted,
+                soloed: source.soloed,
+                priority: source.priority,
+            })
+            .collect();
+        SceneSnapshot { sources }
+    }
+
+    /// Give the sources of the scene, in insertion order, the placements and settings of
+    /// `snapshot`, taken from a scene of the same sources heard by as many listeners.
+    /// Errors, leaving the scene as it was, when the sources or placements do not match.
+    pub fn restore(&self, snapshot: &SceneSnapshot) -> crate::Result<()> {
+        let mut state = self.lock();
+        if snapshot.sources.len() != state.sources.len() {
+            return Err(VoiceImmersionError::InvalidConfig(format!(
+                "{} sources saved for {} in the scene.",
+                snapshot.sources.len(),
+                state.sources.len()
+            )));
+        }
+        for (index, (saved, source)) in snapshot.sources.iter().zip(&state.sources).enumerate() {
+            let matches =
+                saved.placements.len() == source.listeners.len()
+                    && saved.placements.iter().zip(&source.listeners).all(
+                        |(saved, (placement, _))| {
+                            matches!(
+                                (saved, placement),
+                                (PlacementSnapshot::Relative(_), Placement::Relative(_))
+                                    | (PlacementSnapshot::World { .. }, Placement::World { .. })
+                            )
+                        },
+                    );
+            if !matches {
+                return Err(VoiceImmersionError::InvalidConfig(format!(
+                    "The placements saved for source {} do not match the scene.",
+                    index
+                )));
+            }
+        }
+        for (saved, source) in snapshot.sources.iter().zip(&mut state.sources) {
+            for (saved, (placement, _)) in saved.placements.iter().zip(&source.listeners) {
+                restore_placement(placement, saved);
+            }
+            source.listeners[0].1.source_gain().set_value(saved.gain);
+            source.muted = saved.muted;
+            source.soloed = saved.soloed;
+            source.priority = saved.priority.max(0.0);
+        }
+        state.update_levels();
+        Ok(())
+    }
+
+    pub(crate) fn insert(&self, inputs: Inputs, placements: Vec<Placement>) -> SourceId {
+        let mut state = self.lock();
+        state.purge_retired();
+        let channels = state.listeners * state.config.output_format.channels();
+        let (graph, params) = build_graph(inputs, &state.config, state.listeners, &state.master);
+        let (active, level, voice) = (shared(1.0), shared(1.0), shared(1.0));
+        let graph = graph
+            * (Net::wrap(Box::new(
+                (var(&level) * var(&voice)) >> follow(state.config.gain_ramp),
+            )) >> fan(channels));
+        let graph = Net::wrap(Box::new(Cull::new(Box::new(graph), &active, SOURCE_FADE)));
+        // Each branch adds its source to the bus it passes through, ahead of the limiter.
+        let branch = Net::bus(thru(channels), Net::new(channels, 0) >> graph);
+        let limiter = state.limiter;
+        let net = &mut state.net;
+        let node = net.push(Box::new(thru(channels)));
+        for channel in 0..channels {
+            let bus = net.source(limiter, channel);
+            net.set_source(node, channel, bus);
+            net.connect(node, channel, limiter, channel);
+        }
+        net.crossfade(node, Fade::Smooth, SOURCE_FADE, Box::new(branch));
+
+        let id = SourceId(state.next_id);
+        state.next_id += 1;
+        let listeners = placements
+            .into_iter()
+            .zip(params)
+            .map(|(placement, params)| (placement, Controller::new(params, state.config.clone())))
+            .collect();
+        let silence = IdleDetector::new(state.config.cull_timeout);
+        state.sources.push(SceneSource {
+            id,
+            node,
+            listeners,
+            active,
+            silence,
+            level,
+            muted: false,
+            soloed: false,
+            priority: 1.0,
+            voice,
+        });
+        state.update_levels();
+        state.commit();
+        id
+    }
+
+    /// Let `customize` edit the graph before it runs, see `EngineBuilder::customize`.
+    pub(crate) fn customize(&self, customize: impl FnOnce(&mut Net)) {
+        customize(&mut self.lock().net);
+    }
+
+    /// Real-time side of the graph, to be called once.
+    pub(crate) fn backend(&self) -> NetBackend {
+        let mut state = self.lock();
+        state.net.check();
+        state.net.backend()
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, SceneState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl SceneState {
+    /// Send the edits to the audio thread, once it runs.
+    fn commit(&mut self) {
+        if self.net.has_backend() {
+            self.net.commit();
+        }
+    }
+
+    /// Hear the soloed sources when there are any, the unmuted ones otherwise.
+    fn update_levels(&self) {
+        let solo = self.sources.iter().any(|source| source.soloed);
+        for source in &self.sources {
+            let heard = if solo { source.soloed } else { !source.muted };
+            source.level.set_value(if heard { 1.0 } else { 0.0 });
+        }
+    }
+
+    /// Silence all but the `EngineConfig::max_voices` sources ranked first by their
+    /// priority times the gain they are heard with, as of the last control pass.
+    pub(crate) fn limit_voices(&mut self) {
+        let Some(max_voices) = self.config.max_voices else {
+            return;
+        };
+        let mut ranked: Vec<(f32, usize)> = self
+            .sources
+            .iter()
+            .enumerate()
+            .map(|(index, source)| (source.rank(), index))
+            .collect();
+        // Stable, ties kept in insertion order.
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        for (rank, &(_, index)) in ranked.iter().enumerate() {
+            let voiced = rank < max_voices;
+            self.sources[index]
+                .voice
+                .set_value(if voiced { 1.0 } else { 0.0 });
+        }
+    }
+
+    /// Unlink the removed sources done fading out.
+    fn purge_retired(&mut self) {
+        let faded = Duration::from_secs_f32(SOURCE_FADE * 2.0);
+        let net = &mut self.net;
+        self.retired.retain(|&(node, removed)| {
+            let done = removed.elapsed() >= faded;
+            if done {
+                net.remove_link(node);
+            }
+            !done
+        });
+    }
+
+    /// Write `update` into the placements it targets, a later update overwriting an earlier one.
+    pub(crate) fn apply(&mut self, update: SourceUpdate) {
+        match update {
+            SourceUpdate::Relative {
+                source,
+                listener,
+                info,
+            } => {
+                let placement = self
+                    .sources
+                    .iter()
+                    .find(|scene_source| scene_source.id == source)
+                    .and_then(|scene_source| scene_source.listeners.get(listener));
+                if let Some((Placement::Relative(source_info), _)) = placement {
+                    if let Ok(mut source_info) = source_info.write() {
+                        *source_info = info;
+                    }
+                }
+            }
+            SourceUpdate::Source { source, state } => {
+                let Some(scene_source) = self.source(source) else {
+                    return;
+                };
+                if let Some((Placement::World { source, .. }, _)) = scene_source.listeners.first() {
+                    if let Ok(mut source) = source.write() {
+                        *source = state;
+                    }
+                }
+            }
+            SourceUpdate::Listener { listener, state } => {
+                for scene_source in &self.sources {
+                    if let Some((Placement::World { listener, .. }, _)) =
+                        scene_source.listeners.get(listener)
+                    {
+                        if let Ok(mut listener) = listener.write() {
+                            *listener = state.clone();
+                        }
+                    }
+                }
+            }
+            SourceUpdate::Room { source, room } => self.edit(
+                source,
+                |info| info.room = room.clone(),
+                |state| state.room = room.clone(),
+            ),
+            SourceUpdate::Position { source, position } => self.edit(
+                source,
+                |info| info.relative_position = position,
+                |state| state.position = position,
+            ),
+            SourceUpdate::Direction { source, direction } => self.edit(
+                source,
+                |info| info.source_direction = direction,
+                |state| state.direction = direction,
+            ),
+        }
+    }
+
+    /// Edit the descriptions of source `id` in place, `relative` for each relative placement
+    /// and `world` for its world one.
+    fn edit(
+        &self,
+        id: SourceId,
+        relative: impl Fn(&mut SourceInfo),
+        world: impl FnOnce(&mut Source),
+    ) {
+        let Some(scene_source) = self.source(id) else {
+            return;
+        };
+        for (placement, _) in &scene_source.listeners {
+            if let Placement::Relative(info) = placement {
+                if let Ok(mut info) = info.write() {
+                    relative(&mut info);
+                }
+            }
+        }
+        if let Some((Placement::World { source, .. }, _)) = scene_source.listeners.first() {
+            if let Ok(mut source) = source.write() {
+                world(&mut source);
+            }
+        }
+    }
+
+    pub(crate) fn source(&self, id: SourceId) -> Option<&SceneSource> {
+        self.sources.iter().find(|source| source.id == id)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Scene {
+    /// `snapshot` as JSON, to save the scene.
+    pub fn to_json(&self) -> crate::Result<String> {
+        serde_json::to_string_pretty(&self.snapshot()).map_err(|e| {
+            VoiceImmersionError::InvalidState(format!("Could not save the scene: {}", e))
+        })
+    }
+
+    /// `restore` the scene saved by `to_json`, erroring on malformed JSON.
+    pub fn from_json(&self, json: &str) -> crate::Result<()> {
+        let snapshot: SceneSnapshot = serde_json::from_str(json)
+            .map_err(|e| VoiceImmersionError::InvalidConfig(format!("Invalid scene: {}", e)))?;
+        self.restore(&snapshot)
+    }
+}
+
+/// Description currently held by `placement`.
+fn snapshot_placement(placement: &Placement) -> PlacementSnapshot {
+    match placement {
+        Placement::Relative(info) => {
+            PlacementSnapshot::Relative(info.read().unwrap_or_else(PoisonError::into_inner).clone())
+        }
+        Placement::World { listener, source } => PlacementSnapshot::World {
+            listener: listener
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+            source: source
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        },
+    }
+}
+
+/// Write `saved` into `placement`, of the same kind.
+fn restore_placement(placement: &Placement, saved: &PlacementSnapshot) {
+    match (placement, saved) {
+        (Placement::Relative(info), PlacementSnapshot::Relative(saved)) => {
+            *info.write().unwrap_or_else(PoisonError::into_inner) = saved.clone();
+        }
+        (
+            Placement::World { listener, source },
+            PlacementSnapshot::World {
+                listener: saved_listener,
+                source: saved_source,
+            },
+        ) => {
+            *listener.write().unwrap_or_else(PoisonError::into_inner) = saved_listener.clone();
+            *source.write().unwrap_or_else(PoisonError::into_inner) = saved_source.clone();
+        }
+        _ => {}
+    }
+}
+
+/// Network copying its input to its `channels` outputs.
+fn fan(channels: usize) -> Net {
+    let mut net = Net::new(1, channels);
+    for channel in 0..channels {
+        net.pass_through(0, channel);
+    }
+    net
+}
+
+/// Network passing its `channels` inputs through.
+fn thru(channels: usize) -> Net {
+    let mut net = Net::new(channels, channels);
+    for channel in 0..channels {
+        net.pass_through(channel, channel);
+    }
+    net
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attenuation::AttenuationModel;
+    use crate::config::OutputMode;
+    use fundsp::wave::Wave;
+    use nalgebra::Vector3;
+    use std::sync::RwLock;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    fn dc_input() -> InputSource {
+        InputSource::Wave(Wave::render(SAMPLE_RATE, 1.0, &mut dc(1.0)))
+    }
+
+    fn scene(gain: f32) -> Scene {
+        scene_with(
+            gain,
+            EngineConfig {
+                attenuation: AttenuationModel::None,
+                output_mode: OutputMode::Speakers,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn scene_with(gain: f32, config: EngineConfig) -> Scene {
+        // Levels are measured on DC inputs, which the DC blocker would take out.
+        let config = EngineConfig {
+            dc_blocker: None,
+            ..config
+        };
+        let master = MasterParams::new(&config);
+        master.volume.set_value(gain);
+        Scene::new(&config, 1, SAMPLE_RATE, master)
+    }
+
+    fn settled_left(backend: &mut NetBackend) -> f32 {
+        for _ in 0..SAMPLE_RATE as usize / 2 {
+            backend.get_stereo();
+        }
+        backend.get_stereo().0
+    }
+
+    #[test]
+    fn ambisonic_scenes_mix_four_channels() {
+        let scene = scene_with(
+            1.0,
+            EngineConfig {
+                attenuation: AttenuationModel::None,
+                output_format: OutputFormat::Ambisonic1,
+                ..Default::default()
+            },
+        );
+        scene.insert(dc_input().into(), vec![Placement::default()]);
+        scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        assert_eq!(backend.outputs(), 4);
+        let mut frame = [0.0; 4];
+        for _ in 0..SAMPLE_RATE as usize / 2 {
+            backend.tick(&[], &mut frame);
+        }
+        // Both sources at the listener position, only in the omni channel.
+        assert!((frame[0] - 2.0 * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        assert_eq!(frame[1..], [0.0; 3]);
+    }
+
+    #[test]
+    fn dc_offsets_are_blocked_on_the_bus() {
+        let config = EngineConfig {
+            attenuation: AttenuationModel::None,
+            output_mode: OutputMode::Speakers,
+            limiter: None,
+            ..Default::default()
+        };
+        let scene = Scene::new(&config, 1, SAMPLE_RATE, MasterParams::new(&config));
+        scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        assert!(settled_left(&mut backend).abs() < 1e-3);
+        // The same offset passes without the blocker.
+        let scene = scene_with(1.0, config);
+        scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        assert!(settled_left(&mut backend) > 0.5);
+    }
+
+    #[test]
+    fn sources_are_mixed() {
+        let scene = scene(0.25);
+        let first = scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        assert!((settled_left(&mut backend) - 0.25).abs() < 1e-3);
+
+        let second = scene
+            .add_source(dc_input(), vec![Placement::default()])
+            .unwrap();
+        assert!((settled_left(&mut backend) - 0.5).abs() < 1e-3);
+        assert_eq!(scene.sources(), vec![first, second]);
+
+        assert!(scene.remove_source(first));
+        assert!(!scene.remove_source(first));
+        assert!((settled_left(&mut backend) - 0.25).abs() < 1e-3);
+        assert_eq!(scene.sources(), vec![second]);
+    }
+
+    #[test]
+    fn source_gain_in_decibels() {
+        let scene = scene(0.5);
+        let first = scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        assert!(scene.set_source_gain_db(first, Decibels(-6.0)));
+        assert!((settled_left(&mut backend) - 0.5 * 0.501).abs() < 1e-3);
+        assert!(scene.remove_source(first));
+        assert!(!scene.set_source_gain(first, 1.0));
+    }
+
+    #[test]
+    fn solo_overrides_mute() {
+        let scene = scene(0.25);
+        let first = scene.insert(dc_input().into(), vec![Placement::default()]);
+        let second = scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        assert!((settled_left(&mut backend) - 0.5).abs() < 1e-3);
+
+        assert!(scene.set_mute(first, true));
+        assert!((settled_left(&mut backend) - 0.25).abs() < 1e-3);
+        // Soloing a muted source hears it alone.
+        assert!(scene.set_solo(first, true));
+        assert!((settled_left(&mut backend) - 0.25).abs() < 1e-3);
+        assert!(scene.set_solo(second, true));
+        assert!((settled_left(&mut backend) - 0.5).abs() < 1e-3);
+        // Sources added meanwhile are not soloed.
+        let third = scene
+            .add_source(dc_input(), vec![Placement::default()])
+            .unwrap();
+        assert!((settled_left(&mut backend) - 0.5).abs() < 1e-3);
+
+        assert!(scene.set_solo(first, false));
+        assert!(scene.remove_source(second));
+        // No solo left: back to the mutes.
+        assert!((settled_left(&mut backend) - 0.25).abs() < 1e-3);
+        assert!(scene.set_mute(first, false));
+        // Ramped over the gain ramp rather than stepped.
+        let output: Vec<f32> = (0..SAMPLE_RATE as usize / 20)
+            .map(|_| backend.get_stereo().0)
+            .collect();
+        let jump = output
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max);
+        assert!(jump < 0.01, "{jump}");
+        assert!((output[output.len() - 1] - 0.5).abs() < 1e-3);
+        assert!(!scene.set_mute(second, false));
+        assert!(scene.set_solo(third, false));
+    }
+
+    #[test]
+    fn loud_mix_is_limited() {
+        let scene = scene(0.5);
+        scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        for _ in 0..7 {
+            scene
+                .add_source(dc_input(), vec![Placement::default()])
+                .unwrap();
+        }
+        let peak = (0..SAMPLE_RATE as usize)
+            .map(|_| backend.get_stereo().0.abs())
+            .fold(0.0, f32::max);
+        assert!(peak <= 1.0 + 1e-3, "{peak}");
+        assert!(settled_left(&mut backend) > 0.9);
+    }
+
+    #[test]
+    fn silent_sources_are_culled_after_the_timeout() {
+        let scene = scene_with(
+            0.5,
+            EngineConfig {
+                attenuation: AttenuationModel::None,
+                output_mode: OutputMode::Speakers,
+                cull_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+        scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        assert!((settled_left(&mut backend) - 0.5).abs() < 1e-3);
+        let start = Instant::now();
+        let cull = |elapsed: u64, audible: bool| {
+            scene.lock().sources[0].cull(start + Duration::from_millis(elapsed), audible);
+        };
+        // Still rendered within the timeout.
+        cull(0, false);
+        cull(50, false);
+        assert!((settled_left(&mut backend) - 0.5).abs() < 1e-3);
+        cull(150, false);
+        assert_eq!(settled_left(&mut backend), 0.0);
+        // Faded back in as soon as audible.
+        cull(200, true);
+        let output: Vec<f32> = (0..SAMPLE_RATE as usize / 10)
+            .map(|_| backend.get_stereo().0)
+            .collect();
+        let jump = output
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max);
+        assert!(jump < 1e-3, "{jump}");
+        assert!((output[output.len() - 1] - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn low_priority_distant_sources_are_dropped_first() {
+        let scene = scene_with(
+            1.0,
+            EngineConfig {
+                max_voices: Some(2),
+                ..Default::default()
+            },
+        );
+        let at = |distance: f32| {
+            vec![Placement::Relative(Arc::new(RwLock::new(SourceInfo {
+                relative_position: Vector3::new(distance, 0.0, 0.0),
+                ..Default::default()
+            })))]
+        };
+        scene.insert(dc_input().into(), at(1.0));
+        scene.insert(dc_input().into(), at(20.0));
+        let minor = scene.insert(dc_input().into(), at(20.0));
+        assert!(scene.set_priority(minor, 0.5));
+        let voiced = || {
+            let mut state = scene.lock();
+            for source in &mut state.sources {
+                for (placement, controller) in &mut source.listeners {
+                    if let Placement::Relative(info) = placement {
+                        controller.update(&info.read().unwrap(), 0.1);
+                    }
+                }
+            }
+            state.limit_voices();
+            state
+                .sources
+                .iter()
+                .map(|source| source.voiced())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(voiced(), [true, true, false]);
+        // A high enough priority outweighs the distance.
+        scene.set_priority(minor, 100.0);
+        assert_eq!(voiced(), [true, false, true]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn saved_scenes_load_back() {
+        let build = || {
+            let scene = scene(1.0);
+            let relative = scene.insert(dc_input().into(), vec![Placement::default()]);
+            let world = scene.insert(
+                dc_input().into(),
+                vec![Placement::World {
+                    listener: Default::default(),
+                    source: Default::default(),
+                }],
+            );
+            (scene, relative, world)
+        };
+        let (saved, relative, world) = build();
+        let room = InAnotherRoom::builder().build(SAMPLE_RATE as f32).unwrap();
+        {
+            let mut state = saved.lock();
+            state.apply(SourceUpdate::Relative {
+                source: relative,
+                listener: 0,
+                info: SourceInfo {
+                    relative_position: Vector3::new(2.0, 1.0, 0.0),
+                    room: Some(room.clone()),
+                    gain: 0.5,
+                    ..Default::default()
+                },
+            });
+            state.apply(SourceUpdate::Position {
+                source: world,
+                position: Vector3::new(-3.0, 0.0, 1.0),
+            });
+            state.apply(SourceUpdate::Direction {
+                source: world,
+                direction: Vector3::new(0.0, 0.0, 1.0),
+            });
+            state.apply(SourceUpdate::Room {
+                source: world,
+                room: Some(room),
+            });
+        }
+        saved.set_source_gain(world, 0.25);
+        saved.set_mute(relative, true);
+        saved.set_priority(world, 3.0);
+        let json = saved.to_json().unwrap();
+
+        let (loaded, ..) = build();
+        assert_ne!(loaded.snapshot(), saved.snapshot());
+        loaded.from_json(&json).unwrap();
+        assert_eq!(loaded.snapshot(), saved.snapshot());
+        // Saved for another set of sources.
+        assert!(scene(1.0).from_json(&json).is_err());
+    }
+
+    #[test]
+    fn output_fades_in_and_out() {
+        let scene = scene(0.5);
+        let output = scene.lock().master.output.clone();
+        scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        // 20 ms at 44.1 kHz.
+        let start: Vec<f32> = (0..882).map(|_| backend.get_stereo().0).collect();
+        assert!(start[0] < 0.01, "{}", start[0]);
+        assert!(start.windows(2).all(|pair| pair[1] >= pair[0] - 1e-6));
+        assert!(start[441] < 0.25, "{}", start[441]);
+        assert!((settled_left(&mut backend) - 0.5).abs() < 1e-3);
+
+        output.set_value(0.0);
+        for _ in 0..882 {
+            backend.get_stereo();
+        }
+        assert_eq!(backend.get_stereo().0, 0.0);
+    }
+
+    #[test]
+    fn limiter_can_be_disabled() {
+        let scene = scene_with(
+            4.0,
+            EngineConfig {
+                attenuation: AttenuationModel::None,
+                output_mode: OutputMode::Speakers,
+                limiter: None,
+                ..Default::default()
+            },
+        );
+        scene.insert(dc_input().into(), vec![Placement::default()]);
+        let mut backend = scene.backend();
+        assert!((settled_left(&mut backend) - 4.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn updates_overwrite_placements() {
+        let scene = scene(1.0);
+        let info = Arc::new(RwLock::new(SourceInfo::default()));
+        let relative = scene.insert(dc_input().into(), vec![Placement::Relative(info.clone())]);
+        let listener = Arc::new(RwLock::new(Listener::default()));
+        let source = Arc::new(RwLock::new(Source::default()));
+        let world = scene.insert(
+            dc_input().into(),
+            vec![Placement::World {
+                listener: listener.clone(),
+                source: source.clone(),
+            }],
+        );
+
+        let mut state = scene.lock();
+        for x in 1..=3 {
+            state.apply(SourceUpdate::Relative {
+                source: relative,
+                listener: 0,
+                info: SourceInfo {
+                    relative_position: Vector3::new(x as f32, 0.0, 0.0),
+                    ..Default::default()
+                },
+            });
+        }
+        state.apply(SourceUpdate::Source {
+            source: world,
+            state: Source {
+                position: Vector3::new(0.0, 2.0, 0.0),
+                ..Default::default()
+            },
+        });
+        state.apply(SourceUpdate::Listener {
+            listener: 0,
+            state: Listener {
+                position: Vector3::new(0.0, 0.0, 4.0),
+                ..Default::default()
+            },
+        });
+        // Updates to unknown sources or listeners are dropped.
+        state.apply(SourceUpdate::Listener {
+            listener: 1,
+            state: Listener::default(),
+        });
+        drop(state);
+
+        assert_eq!(info.read().unwrap().relative_position.x, 3.0);
+        assert_eq!(source.read().unwrap().position.y, 2.0);
+        assert_eq!(listener.read().unwrap().position.z, 4.0);
+    }
+
+    #[test]
+    fn customized_output_processes_later_sources() {
+        let scene = scene(0.25);
+        scene.customize(|net| {
+            net.chain(Box::new(mul(0.5) | mul(0.5)));
+        });
+        let mut backend = scene.backend();
+        scene
+            .add_source(dc_input(), vec![Placement::default()])
+            .unwrap();
+        assert!((settled_left(&mut backend) - 0.125).abs() < 1e-3);
+    }
+
+    #[test]
+    fn one_description_per_listener() {
+        let scene = scene(1.0);
+        assert!(scene.add_source(dc_input(), Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,1087 @@
+use std::any::Any;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SizedSample};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use fundsp::hacker::*;
+
+use crate::analyzer::SpectrumTap;
+use crate::clock::Instant;
+use crate::config::{OutputLayout, StreamRecovery};
+use crate::recorder::RecordTap;
+use crate::telemetry::{EngineEvent, LevelMeter, Telemetry, XrunDetector};
+use crate::VoiceImmersionError;
+
+/// How often the default output device is checked when following it.
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the idle state is checked when pausing the stream while idle.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Largest ratio between the sample rate `negotiate_config` settles for and the requested
+/// one: 44.1 kHz passes for 48 kHz, 96 kHz does not.
+const MAX_RATE_RATIO: f64 = 1.1;
+
+/// Largest ratio between the buffer size `negotiate_config` settles for and the requested one.
+const MAX_BUFFER_RATIO: f64 = 4.0;
+
+/// Sample formats the renderer writes and the mic input reads, by preference: floats, then
+/// the deepest integers.
+const SAMPLE_FORMATS: [cpal::SampleFormat; 10] = [
+    cpal::SampleFormat::F32,
+    cpal::SampleFormat::F64,
+    cpal::SampleFormat::I32,
+    cpal::SampleFormat::U32,
+    cpal::SampleFormat::I64,
+    cpal::SampleFormat::U64,
+    cpal::SampleFormat::I16,
+    cpal::SampleFormat::U16,
+    cpal::SampleFormat::I8,
+    cpal::SampleFormat::U8,
+];
+
+/// Stream code generic over its sample type, run on the type of a format by `with_sample_type`.
+pub(crate) trait SampleTypeVisitor {
+    type Output;
+
+    fn visit<T>(self) -> crate::Result<Self::Output>
+    where
+        T: SizedSample + FromSample<f32> + Send + 'static,
+        f32: FromSample<T>;
+}
+
+/// Run `visitor` on the sample type of `format`, failing on those out of `SAMPLE_FORMATS`.
+pub(crate) fn with_sample_type<V: SampleTypeVisitor>(
+    format: cpal::SampleFormat,
+    visitor: V,
+) -> crate::Result<V::Output> {
+    match format {
+        cpal::SampleFormat::F32 => visitor.visit::<f32>(),
+        cpal::SampleFormat::F64 => visitor.visit::<f64>(),
+        cpal::SampleFormat::I32 => visitor.visit::<i32>(),
+        cpal::SampleFormat::U32 => visitor.visit::<u32>(),
+        cpal::SampleFormat::I64 => visitor.visit::<i64>(),
+        cpal::SampleFormat::U64 => visitor.visit::<u64>(),
+        cpal::SampleFormat::I16 => visitor.visit::<i16>(),
+        cpal::SampleFormat::U16 => visitor.visit::<u16>(),
+        cpal::SampleFormat::I8 => visitor.visit::<i8>(),
+        cpal::SampleFormat::U8 => visitor.visit::<u8>(),
+        format => Err(VoiceImmersionError::UnsupportedFormat(format!(
+            "Unsupported sample format: {}",
+            format
+        ))),
+    }
+}
+
+/// Stream configuration forcing a sample rate, channel count and optionally buffer size.
+pub fn make_config(sample_rate: u32, channels: u16, buffer: Option<u32>) -> cpal::StreamConfig {
+    cpal::StreamConfig {
+        channels,
+        sample_rate: cpal::SampleRate(sample_rate),
+        buffer_size: match buffer {
+            Some(frames) => cpal::BufferSize::Fixed(frames),
+            None => cpal::BufferSize::Default,
+        },
+    }
+}
+
+/// Sample format to open `config` with among the `supported` ranges of a device, f32 first.
+pub fn validate_config(
+    config: &cpal::StreamConfig,
+    supported: impl IntoIterator<Item = cpal::SupportedStreamConfigRange>,
+) -> crate::Result<cpal::SampleFormat> {
+    let accepts = |range: &cpal::SupportedStreamConfigRange| {
+        let buffer_ok = match (config.buffer_size, range.buffer_size()) {
+            (cpal::BufferSize::Fixed(frames), cpal::SupportedBufferSize::Range { min, max }) => {
+                (*min..=*max).contains(&frames)
+            }
+            _ => true,
+        };
+        range.channels() == config.channels
+            && (range.min_sample_rate()..=range.max_sample_rate()).contains(&config.sample_rate)
+            && buffer_ok
+    };
+    let formats: Vec<_> = supported
+        .into_iter()
+        .filter(accepts)
+        .map(|range| range.sample_format())
+        .collect();
+    SAMPLE_FORMATS
+        .into_iter()
+        .find(|format| formats.contains(format))
+        .ok_or_else(|| {
+            VoiceImmersionError::UnsupportedFormat(format!(
+                "Unsupported stream configuration: {} channels at {} Hz, {:?}.",
+                config.channels, config.sample_rate.0, config.buffer_size
+            ))
+        })
+}
+
+/// Stream configuration closest to `sample_rate` and `buffer` frames among the `supported`
+/// ranges of a device, with the sample format to open it with: for low latency setups asking
+/// e.g. 48 kHz and 128 frames of devices which may not offer them. Rates and buffer sizes are
+/// clamped to each range, then mono only comes last, then stereo is preferred, then the fewest
+/// channels past two, then f32. Errors when the closest rate is off by more than `MAX_RATE_RATIO` or the closest
+/// buffer size by more than `MAX_BUFFER_RATIO`.
+pub fn negotiate_config(
+    sample_rate: u32,
+    buffer: Option<u32>,
+    supported: impl IntoIterator<Item = cpal::SupportedStreamConfigRange>,
+) -> crate::Result<(cpal::StreamConfig, cpal::SampleFormat)> {
+    let off = |value: u32, requested: u32| {
+        (Ord::max(value, 1) as f64 / Ord::max(requested, 1) as f64)
+            .ln()
+            .abs()
+    };
+    // Out of tolerance, mono, rate and buffer size gaps, channel and format ranks.
+    type Closeness = (bool, bool, f64, f64, u16, usize);
+    let mut closest: Option<(Closeness, cpal::StreamConfig, cpal::SampleFormat)> = None;
+    for range in supported {
+        let Some(format_rank) = SAMPLE_FORMATS
+            .iter()
+            .position(|format| *format == range.sample_format())
+        else {
+            continue;
+        };
+        let rate = Ord::min(
+            Ord::max(sample_rate, range.min_sample_rate().0),
+            range.max_sample_rate().0,
+        );
+        let frames = match (buffer, range.buffer_size()) {
+            (Some(frames), cpal::SupportedBufferSize::Range { min, max }) => {
+                Some(Ord::min(Ord::max(frames, *min), *max))
+            }
+            (frames, _) => frames,
+        };
+        // Surround layouts play stereo on their front pair, mono folds the spatialization away.
+        let (mono, channel_rank) = match range.channels() {
+            0 => continue,
+            1 => (true, 0),
+            2 => (false, 0),
+            channels => (false, channels),
+        };
+        let rate_off = off(rate, sample_rate);
+        let buffer_off = buffer
+            .zip(frames)
+            .map_or(0.0, |(asked, got)| off(got, asked));
+        let too_far = rate_off > MAX_RATE_RATIO.ln() || buffer_off > MAX_BUFFER_RATIO.ln();
+        let score = (
+            too_far,
+            mono,
+            rate_off,
+            buffer_off,
+            channel_rank,
+            format_rank,
+        );
+        if closest.as_ref().is_none_or(|(best, ..)| score < *best) {
+            closest = Some((
+                score,
+                make_config(rate, range.channels(), frames),
+                range.sample_format(),
+            ));
+        }
+    }
+    match closest {
+        Some(((false, ..), config, format)) => Ok((config, format)),
+        Some((_, config, _)) => Err(VoiceImmersionError::UnsupportedFormat(format!(
+            "No stream configuration close to {} Hz and {:?} frames, the closest is {} Hz and {:?}.",
+            sample_rate,
+            buffer,
+            config.sample_rate.0,
+            config.buffer_size
+        ))),
+        None => Err(VoiceImmersionError::UnsupportedFormat("The device has no stream configuration to play on.".into())),
+    }
+}
+
+/// Stream an engine plays on, see `SpatialHandle::output_config`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputConfig {
+    pub sample_rate: f64,
+    pub channels: usize,
+    /// Frames per device buffer, unless left to the device.
+    pub buffer_frames: Option<usize>,
+}
+
+/// Run `f` under `assert_no_alloc` with the `no-alloc-guard` feature, aborting debug builds
+/// on allocations. Without it `f` runs as is, for heap profilers to follow the audio path.
+#[inline]
+fn no_alloc<T>(f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "no-alloc-guard")]
+    return assert_no_alloc::assert_no_alloc(f);
+    #[cfg(not(feature = "no-alloc-guard"))]
+    f()
+}
+
+/// Pulls frames out of the spatialization graph, `OutputFormat::channels` outputs per
+/// listener.
+pub struct Renderer {
+    backend: NetBackend,
+    telemetry: Arc<Telemetry>,
+    xruns: XrunDetector,
+    meter: LevelMeter,
+    frame: Vec<f32>,
+    record: Option<Arc<RecordTap>>,
+    spectrum: Option<Arc<SpectrumTap>>,
+    layout: OutputLayout,
+}
+
+impl Renderer {
+    pub(crate) fn new(backend: NetBackend, telemetry: Arc<Telemetry>, sample_rate: f64) -> Self {
+        Renderer {
+            frame: vec![0.0; backend.outputs()],
+            backend,
+            telemetry,
+            xruns: XrunDetector::new(sample_rate),
+            meter: LevelMeter::new(sample_rate),
+            record: None,
+            spectrum: None,
+            layout: OutputLayout::default(),
+        }
+    }
+
+    /// Lay the graph outputs out on the device channels by `layout`.
+    pub fn set_layout(&mut self, layout: OutputLayout) {
+        self.layout = layout;
+    }
+
+    /// Queue every frame of the first listener to `tap` while it records.
+    pub(crate) fn set_record_tap(&mut self, tap: Arc<RecordTap>) {
+        self.record = Some(tap);
+    }
+
+    /// Queue every frame of the first listener, mixed to mono, to `tap`.
+    pub(crate) fn set_spectrum_tap(&mut self, tap: Arc<SpectrumTap>) {
+        self.spectrum = Some(tap);
+    }
+
+    pub fn telemetry(&self) -> &Arc<Telemetry> {
+        &self.telemetry
+    }
+
+    /// Retune the graph when the sink moved to another sample rate.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.backend.set_sample_rate(sample_rate);
+        self.xruns.set_sample_rate(sample_rate);
+        self.meter.set_sample_rate(sample_rate);
+    }
+
+    /// Graph outputs, see `next_outputs`.
+    pub fn outputs(&self) -> usize {
+        self.frame.len()
+    }
+
+    /// Render the next (left, right) frame of the first listener, or its W and X channels
+    /// in ambisonics.
+    #[inline]
+    pub fn next_frame(&mut self) -> (f32, f32) {
+        self.tick();
+        (self.frame[0], self.frame[1])
+    }
+
+    /// Render the next frame of every graph output, before the `OutputLayout`.
+    #[inline]
+    pub fn next_outputs(&mut self) -> &[f32] {
+        self.tick();
+        &self.frame
+    }
+
+    #[inline]
+    fn tick(&mut self) {
+        // Make sure there are no allocations or deallocations in the audio thread.
+        no_alloc(|| {
+            self.backend.tick(&[], &mut self.frame);
+            if let Some(record) = &self.record {
+                record.record((self.frame[0], self.frame[1]));
+            }
+            if let Some(spectrum) = &self.spectrum {
+                spectrum.push((self.frame[0] + self.frame[1]) / 2.0);
+            }
+        })
+    }
+
+    /// Fill an interleaved buffer of `channels` channels, called once per device buffer.
+    pub fn fill<T>(&mut self, output: &mut [T], channels: usize)
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let now = Instant::now();
+        if self.xruns.callback(now, output.len() / channels) {
+            self.telemetry.record_xrun();
+        }
+        let mut peak = 0.0_f32;
+        // One device channel per graph output, as with a stereo device playing a single
+        // stereo listener: copied without going through the layout.
+        let direct = channels == self.frame.len() && self.layout != OutputLayout::Surround;
+        // Levels of the first listener.
+        let (mut squares, mut peaks) = ((0.0, 0.0), (0.0_f32, 0.0_f32));
+        for frame in output.chunks_mut(channels) {
+            self.tick();
+            let (left, right) = (self.frame[0], self.frame[1]);
+            squares = (squares.0 + left * left, squares.1 + right * right);
+            peaks = (peaks.0.max(left.abs()), peaks.1.max(right.abs()));
+            if direct {
+                for (sample, &value) in frame.iter_mut().zip(&self.frame) {
+                    peak = peak.max(value.abs());
+                    *sample = T::from_sample(value);
+                }
+            } else {
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    let value = self.layout.sample(&self.frame, channel, channels);
+                    peak = peak.max(value.abs());
+                    *sample = T::from_sample(value);
+                }
+            }
+        }
+        if peak >= 1.0 {
+            self.telemetry.record_clip(now);
+        }
+        let levels = self.meter.buffer(output.len() / channels, squares, peaks);
+        self.telemetry.set_levels(levels);
+    }
+}
+
+/// Destination driving the renderer: a cpal stream, a web audio bridge, a ring buffer...
+pub trait AudioSink {
+    /// Sample rate the graph is rendered at.
+    fn sample_rate(&self) -> f64;
+
+    /// Interleaved output channels.
+    fn channels(&self) -> usize {
+        2
+    }
+
+    /// Frames pulled per callback when known.
+    fn buffer_frames(&self) -> Option<usize> {
+        None
+    }
+
+    /// Start pulling frames from `renderer`. The returned guard keeps the sink alive.
+    fn start(self: Box<Self>, renderer: Renderer) -> crate::Result<Box<dyn Any>>;
+}
+
+/// Plays on a cpal output device.
+pub struct CpalSink {
+    device: cpal::Device,
+    config: cpal::StreamConfig,
+    sample_format: cpal::SampleFormat,
+    follow_default_device: bool,
+    pause_when_idle: bool,
+    /// Time the output takes to fade out once paused, before the stream is.
+    output_fade: Duration,
+    /// Host polled for its default device when following it.
+    host: cpal::HostId,
+    recovery: StreamRecovery,
+    /// Raised by the error callback of the stream.
+    failed: Arc<AtomicBool>,
+}
+
+/// Sinks built outside the engine leave a failed stream alone, as they always did, only
+/// reporting it.
+const NO_RECOVERY: StreamRecovery = StreamRecovery {
+    retries: 0,
+    backoff: Duration::ZERO,
+};
+
+/// First attempt to reopen a stream that just failed, `None` when it is never retried.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn first_reopen(recovery: &StreamRecovery, now: Instant) -> Option<(u32, Instant)> {
+    (recovery.retries > 0).then_some((0, now))
+}
+
+impl CpalSink {
+    /// Play on `device` with its default output configuration.
+    pub fn new(device: cpal::Device) -> crate::Result<Self> {
+        let supported = device.default_output_config()?;
+        Ok(CpalSink {
+            device,
+            sample_format: supported.sample_format(),
+            config: supported.into(),
+            follow_default_device: false,
+            pause_when_idle: false,
+            output_fade: Duration::ZERO,
+            host: cpal::default_host().id(),
+            recovery: NO_RECOVERY,
+            failed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Play on the default host default output device.
+    pub fn default_device() -> crate::Result<Self> {
+        Self::host_default_device(cpal::default_host().id())
+    }
+
+    /// Play on the default output device of `host`.
+    pub fn host_default_device(host: cpal::HostId) -> crate::Result<Self> {
+        let device = cpal::host_from_id(host)?
+            .default_output_device()
+            .ok_or_else(|| {
+                VoiceImmersionError::DeviceUnavailable("No output device available.".into())
+            })?;
+        let mut sink = Self::new(device)?;
+        sink.host = host;
+        Ok(sink)
+    }
+
+    pub fn with_config(
+        device: cpal::Device,
+        config: cpal::StreamConfig,
+        sample_format: cpal::SampleFormat,
+    ) -> Self {
+        CpalSink {
+            device,
+            config,
+            sample_format,
+            follow_default_device: false,
+            pause_when_idle: false,
+            output_fade: Duration::ZERO,
+            host: cpal::default_host().id(),
+            recovery: NO_RECOVERY,
+            failed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Host the output device belongs to, followed for its default device.
+    pub fn set_host(&mut self, host: cpal::HostId) {
+        self.host = host;
+    }
+
+    pub fn set_config(&mut self, config: cpal::StreamConfig) {
+        self.config = config;
+    }
+
+    /// Use `config` after checking the device supports it, e.g. from `make_config`.
+    pub fn set_checked_config(&mut self, config: cpal::StreamConfig) -> crate::Result<()> {
+        self.sample_format = validate_config(&config, self.device.supported_output_configs()?)?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Use the configuration of the device closest to `sample_rate` and `buffer` frames, see
+    /// `negotiate_config`, and return it.
+    pub fn set_negotiated_config(
+        &mut self,
+        sample_rate: u32,
+        buffer: Option<u32>,
+    ) -> crate::Result<cpal::StreamConfig> {
+        let (config, format) =
+            negotiate_config(sample_rate, buffer, self.device.supported_output_configs()?)?;
+        self.sample_format = format;
+        self.config = config.clone();
+        Ok(config)
+    }
+
+    pub fn config(&self) -> &cpal::StreamConfig {
+        &self.config
+    }
+
+    /// Open the stream on `channels` channels, keeping the rate and buffer size, after
+    /// checking the device supports it.
+    pub fn set_channels(&mut self, channels: u16) -> crate::Result<()> {
+        self.set_checked_config(cpal::StreamConfig {
+            channels,
+            ..self.config.clone()
+        })
+    }
+
+    /// Move to the system default output device whenever it changes.
+    pub fn set_follow_default_device(&mut self, follow: bool) {
+        self.follow_default_device = follow;
+    }
+
+    /// Pause the stream while the engine is idle, see `EngineConfig::idle_pause_timeout`.
+    pub fn set_pause_when_idle(&mut self, pause: bool) {
+        self.pause_when_idle = pause;
+    }
+
+    /// Let the output fade out for `fade` once the engine is paused before pausing the
+    /// stream, see `EngineConfig::output_fade`.
+    pub fn set_output_fade(&mut self, fade: Duration) {
+        self.output_fade = fade;
+    }
+
+    /// Reopen the stream on the default device after an error, never by default.
+    pub fn set_recovery(&mut self, recovery: StreamRecovery) {
+        self.recovery = recovery;
+    }
+
+    /// Error callback of the streams, flagging them for recovery.
+    fn on_error(&self) -> impl FnMut(cpal::StreamError) + Send + 'static {
+        let failed = self.failed.clone();
+        move |err| {
+            eprintln!("an error occurred on stream: {}", err);
+            failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Build a stream on a renderer shared with the streams it may be replaced by.
+    fn build_shared_stream<T>(&self, renderer: Arc<Mutex<Renderer>>) -> crate::Result<cpal::Stream>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let channels = self.config.channels as usize;
+        let err_fn = self.on_error();
+        let stream = self.device.build_output_stream(
+            &self.config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| match renderer.try_lock() {
+                Ok(mut renderer) => renderer.fill(data, channels),
+                // Being handed over to another device.
+                Err(_) => data.fill(T::EQUILIBRIUM),
+            },
+            err_fn,
+            None,
+        )?;
+        Ok(stream)
+    }
+
+    fn play_shared(&self, renderer: &Arc<Mutex<Renderer>>) -> crate::Result<cpal::Stream> {
+        struct SharedStream<'a>(&'a CpalSink, Arc<Mutex<Renderer>>);
+        impl SampleTypeVisitor for SharedStream<'_> {
+            type Output = cpal::Stream;
+
+            fn visit<T>(self) -> crate::Result<cpal::Stream>
+            where
+                T: SizedSample + FromSample<f32> + Send + 'static,
+                f32: FromSample<T>,
+            {
+                self.0.build_shared_stream::<T>(self.1)
+            }
+        }
+        let stream = with_sample_type(self.sample_format, SharedStream(self, renderer.clone()))?;
+        stream.play()?;
+        Ok(stream)
+    }
+
+    /// Reopen the stream on `device`, keeping the current sample rate when it supports it.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn switch_to(
+        &self,
+        device: cpal::Device,
+        renderer: &Arc<Mutex<Renderer>>,
+    ) -> crate::Result<(CpalSink, cpal::Stream)> {
+        let mut sink = CpalSink::new(device)?;
+        sink.host = self.host;
+        sink.follow_default_device = self.follow_default_device;
+        sink.pause_when_idle = self.pause_when_idle;
+        sink.output_fade = self.output_fade;
+        sink.recovery = self.recovery;
+        sink.failed = self.failed.clone();
+        let default_rate = sink.config.sample_rate;
+        sink.config.sample_rate = self.config.sample_rate;
+        if let Ok(stream) = sink.play_shared(renderer) {
+            return Ok((sink, stream));
+        }
+        sink.config.sample_rate = default_rate;
+        if let Ok(mut renderer) = renderer.lock() {
+            renderer.set_sample_rate(default_rate.0 as f64);
+        }
+        let stream = sink.play_shared(renderer)?;
+        Ok((sink, stream))
+    }
+
+    /// Own the stream on a dedicated thread, pausing it while the engine is idle or paused,
+    /// once the output faded out, reopening it on the default device after an error and,
+    /// when following the default device, on the new one on change. Once stopped, the
+    /// stream is closed after the fade too.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn_owner(self, renderer: Renderer) -> crate::Result<Box<dyn Any>> {
+        let telemetry = renderer.telemetry().clone();
+        let renderer = Arc::new(Mutex::new(renderer));
+        let (stop, stopped) = bounded::<()>(0);
+        let (ready_sender, ready) = bounded(1);
+        let host = cpal::host_from_id(self.host)?;
+        std::thread::spawn(move || {
+            let mut sink = self;
+            let mut stream = match sink.play_shared(&renderer) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err));
+                    return;
+                }
+            };
+            let _ = ready_sender.send(Ok(()));
+            let mut current = sink.device.name().ok();
+            let mut paused = false;
+            // Time the engine got paused at, the output fading out from then on.
+            let mut pausing: Option<Instant> = None;
+            let mut next_poll = Instant::now() + DEVICE_POLL_INTERVAL;
+            // Failed attempts to reopen the stream and time of the next one.
+            let mut recovery: Option<(u32, Instant)> = None;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(IDLE_POLL_INTERVAL) {
+                if sink.failed.swap(false, Ordering::Relaxed) && recovery.is_none() {
+                    recovery = first_reopen(&sink.recovery, Instant::now());
+                    if recovery.is_none() {
+                        telemetry.emit(EngineEvent::StreamFailed {
+                            error: "The output stream failed.".into(),
+                        });
+                    }
+                }
+                if let Some((attempts, at)) = recovery {
+                    if Instant::now() < at {
+                        continue;
+                    }
+                    let reopened = host
+                        .default_output_device()
+                        .ok_or_else(|| {
+                            VoiceImmersionError::DeviceUnavailable(
+                                "No output device available.".into(),
+                            )
+                        })
+                        .and_then(|device| sink.switch_to(device, &renderer));
+                    match reopened {
+                        Ok((new_sink, new_stream)) => {
+                            drop(std::mem::replace(&mut stream, new_stream));
+                            paused = false;
+                            sink = new_sink;
+                            current = sink.device.name().ok();
+                            recovery = None;
+                            telemetry.emit(EngineEvent::DeviceSwitched {
+                                name: current.clone().unwrap_or_default(),
+                            });
+                        }
+                        Err(err) if attempts + 1 >= sink.recovery.retries => {
+                            recovery = None;
+                            telemetry.emit(EngineEvent::StreamFailed {
+                                error: err.to_string(),
+                            });
+                        }
+                        Err(_) => {
+                            let backoff = sink.recovery.backoff_after(attempts);
+                            recovery = Some((attempts + 1, Instant::now() + backoff));
+                        }
+                    }
+                    continue;
+                }
+                pausing = telemetry
+                    .is_paused()
+                    .then(|| pausing.unwrap_or_else(Instant::now));
+                let faded = pausing.is_some_and(|since| since.elapsed() >= sink.output_fade);
+                let pause = faded || (sink.pause_when_idle && telemetry.is_idle());
+                if pause != paused {
+                    if pause {
+                        let _ = stream.pause();
+                    } else {
+                        let _ = stream.play();
+                    }
+                    paused = pause;
+                }
+                if !sink.follow_default_device || Instant::now() < next_poll {
+                    continue;
+                }
+                next_poll = Instant::now() + DEVICE_POLL_INTERVAL;
+                let Some(device) = host.default_output_device() else {
+                    continue;
+                };
+                let name = device.name().ok();
+                if name == current {
+                    continue;
+                }
+                if let Ok((new_sink, new_stream)) = sink.switch_to(device, &renderer) {
+                    drop(std::mem::replace(&mut stream, new_stream));
+                    // The new stream plays, it gets paused again on the next round if idle.
+                    paused = false;
+                    sink = new_sink;
+                    current = name;
+                    telemetry.emit(EngineEvent::DeviceSwitched {
+                        name: current.clone().unwrap_or_default(),
+                    });
+                }
+            }
+            if !paused {
+                let since = pausing.unwrap_or_else(Instant::now);
+                std::thread::sleep(sink.output_fade.saturating_sub(since.elapsed()));
+            }
+        });
+        ready.recv().map_err(|_| {
+            VoiceImmersionError::StreamBuild("The stream thread ended before playing.".into())
+        })??;
+        Ok(Box::new(OwnerGuard::new(stop)))
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn sample_rate(&self) -> f64 {
+        self.config.sample_rate.0 as f64
+    }
+
+    fn channels(&self) -> usize {
+        self.config.channels as usize
+    }
+
+    fn buffer_frames(&self) -> Option<usize> {
+        match self.config.buffer_size {
+            cpal::BufferSize::Fixed(frames) => Some(frames as usize),
+            cpal::BufferSize::Default => None,
+        }
+    }
+
+    fn start(self: Box<Self>, renderer: Renderer) -> crate::Result<Box<dyn Any>> {
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.spawn_owner(renderer);
+        // No owner thread in browsers: the Web Audio stream plays until dropped, neither
+        // paused while idle nor reopened.
+        #[cfg(target_arch = "wasm32")]
+        return Ok(Box::new(self.play_shared(&Arc::new(Mutex::new(renderer)))?));
+    }
+}
+
+/// Stops the stream owner thread when dropped, disconnecting it: the thread closes its
+/// stream on its own rather than holding up the caller.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) struct OwnerGuard {
+    _stop: Sender<()>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl OwnerGuard {
+    /// Guard of the thread stopped by disconnecting `stop`.
+    pub(crate) fn new(stop: Sender<()>) -> Self {
+        OwnerGuard { _stop: stop }
+    }
+}
+
+/// Stops the rendering thread of a `RingBufferSink` when dropped.
+struct RenderGuard(Arc<AtomicBool>);
+
+impl Drop for RenderGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Renders ahead into a bounded ring buffer drained by an external consumer,
+/// e.g. a bridge to a web audio worklet.
+pub struct RingBufferSink {
+    sample_rate: f64,
+    sender: Sender<(f32, f32)>,
+}
+
+impl RingBufferSink {
+    /// Create the sink along with the consumer end, holding up to `capacity` frames.
+    pub fn new(sample_rate: f64, capacity: usize) -> (Self, Receiver<(f32, f32)>) {
+        let (sender, receiver) = bounded(capacity);
+        (
+            RingBufferSink {
+                sample_rate,
+                sender,
+            },
+            receiver,
+        )
+    }
+}
+
+impl AudioSink for RingBufferSink {
+    fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    fn start(self: Box<Self>, mut renderer: Renderer) -> crate::Result<Box<dyn Any>> {
+        let sender = self.sender;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        // Rendering stops once the consumer end or the guard is dropped, and holds while paused
+        // or idle.
+        std::thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                let telemetry = renderer.telemetry();
+                if telemetry.is_paused() || telemetry.is_idle() {
+                    std::thread::sleep(IDLE_POLL_INTERVAL);
+                } else if sender.send(renderer.next_frame()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Box::new(RenderGuard(stop)))
+    }
+}
+
+/// Stand-in for an output device, rendering into memory only when asked to by its
+/// `Capture`: no hardware nor timing involved, so that tests can check the exact output of
+/// the engine for given sources, along with `EngineBuilder::manual_control`.
+pub struct CaptureSink {
+    sample_rate: f64,
+    channels: usize,
+    renderer: Arc<Mutex<Option<Renderer>>>,
+}
+
+/// Rendering end of a `CaptureSink`.
+pub struct Capture {
+    channels: usize,
+    renderer: Arc<Mutex<Option<Renderer>>>,
+}
+
+impl CaptureSink {
+    /// Create the sink of `channels` interleaved channels along with its capture end.
+    pub fn new(sample_rate: f64, channels: usize) -> (Self, Capture) {
+        let renderer = Arc::new(Mutex::new(None));
+        let channels = Ord::max(channels, 1);
+        (
+            CaptureSink {
+                sample_rate,
+                channels,
+                renderer: renderer.clone(),
+            },
+            Capture { channels, renderer },
+        )
+    }
+}
+
+impl AudioSink for CaptureSink {
+    fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn start(self: Box<Self>, renderer: Renderer) -> crate::Result<Box<dyn Any>> {
+        *self.renderer.lock().unwrap() = Some(renderer);
+        Ok(Box::new(CaptureGuard(self.renderer)))
+    }
+}
+
+impl Capture {
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Render the next `frames` frames as a device would, interleaved. Empty before the
+    /// engine starts, while it is paused or idle and once it is stopped.
+    pub fn render(&self, frames: usize) -> Vec<f32> {
+        let mut renderer = self.renderer.lock().unwrap();
+        let Some(renderer) = renderer.as_mut() else {
+            return Vec::new();
+        };
+        let telemetry = renderer.telemetry();
+        if telemetry.is_paused() || telemetry.is_idle() {
+            return Vec::new();
+        }
+        let mut output = vec![0.0; frames * self.channels];
+        renderer.fill(&mut output, self.channels);
+        output
+    }
+}
+
+/// Drops the renderer of a `CaptureSink` along with the engine.
+struct CaptureGuard(Arc<Mutex<Option<Renderer>>>);
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renderer(level: f32) -> Renderer {
+        let mut net = Net::wrap(Box::new(dc((level, level))));
+        net.set_sample_rate(48000.0);
+        let (telemetry, _events) = Telemetry::new(Duration::from_secs(1));
+        Renderer::new(net.backend(), telemetry, 48000.0)
+    }
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: cpal::SampleFormat,
+    ) -> cpal::SupportedStreamConfigRange {
+        cpal::SupportedStreamConfigRange::new(
+            channels,
+            cpal::SampleRate(min),
+            cpal::SampleRate(max),
+            cpal::SupportedBufferSize::Range { min: 64, max: 4096 },
+            format,
+        )
+    }
+
+    #[test]
+    fn config_validation() {
+        let supported = [
+            range(2, 44100, 48000, cpal::SampleFormat::I16),
+            range(2, 44100, 96000, cpal::SampleFormat::F32),
+            range(1, 8000, 48000, cpal::SampleFormat::I16),
+        ];
+        let format = validate_config(&make_config(48000, 2, Some(512)), supported);
+        assert_eq!(format.unwrap(), cpal::SampleFormat::F32);
+        let format = validate_config(&make_config(16000, 1, None), supported);
+        assert_eq!(format.unwrap(), cpal::SampleFormat::I16);
+        assert!(validate_config(&make_config(192000, 2, None), supported).is_err());
+        assert!(validate_config(&make_config(48000, 6, None), supported).is_err());
+        assert!(validate_config(&make_config(48000, 2, Some(8192)), supported).is_err());
+        // Formats the renderer used to reject.
+        let supported = [range(2, 8000, 48000, cpal::SampleFormat::U8)];
+        let format = validate_config(&make_config(22050, 2, None), supported);
+        assert_eq!(format.unwrap(), cpal::SampleFormat::U8);
+    }
+
+    #[test]
+    fn sample_formats_dispatch_to_their_type() {
+        // Half scale through the sample type and back.
+        struct HalfScale;
+        impl SampleTypeVisitor for HalfScale {
+            type Output = f32;
+
+            fn visit<T>(self) -> crate::Result<f32>
+            where
+                T: SizedSample + FromSample<f32> + Send + 'static,
+                f32: FromSample<T>,
+            {
+                Ok(f32::from_sample_(T::from_sample_(0.5)))
+            }
+        }
+        for format in SAMPLE_FORMATS {
+            let value = with_sample_type(format, HalfScale).unwrap();
+            // 8 bits only resolve steps of 1/128.
+            let tolerance = match format {
+                cpal::SampleFormat::I8 | cpal::SampleFormat::U8 => 1e-2,
+                _ => 1e-3,
+            };
+            assert!((value - 0.5).abs() < tolerance, "{format}: {value}");
+        }
+    }
+
+    #[test]
+    fn sample_formats_map_to_their_own_type() {
+        // Format of the type each format is streamed with.
+        struct FormatOf;
+        impl SampleTypeVisitor for FormatOf {
+            type Output = cpal::SampleFormat;
+
+            fn visit<T>(self) -> crate::Result<cpal::SampleFormat>
+            where
+                T: SizedSample + FromSample<f32> + Send + 'static,
+                f32: FromSample<T>,
+            {
+                Ok(T::FORMAT)
+            }
+        }
+        for format in SAMPLE_FORMATS {
+            assert_eq!(with_sample_type(format, FormatOf).unwrap(), format);
+        }
+    }
+
+    #[test]
+    fn config_negotiation_picks_the_closest() {
+        let ranged = |channels, min, max, buffer: (u32, u32), format| {
+            cpal::SupportedStreamConfigRange::new(
+                channels,
+                cpal::SampleRate(min),
+                cpal::SampleRate(max),
+                cpal::SupportedBufferSize::Range {
+                    min: buffer.0,
+                    max: buffer.1,
+                },
+                format,
+            )
+        };
+        let supported = [
+            ranged(1, 48000, 48000, (32, 4096), cpal::SampleFormat::F32),
+            ranged(2, 44100, 44100, (64, 4096), cpal::SampleFormat::F32),
+            ranged(2, 48000, 48000, (256, 4096), cpal::SampleFormat::I16),
+            ranged(6, 48000, 48000, (256, 4096), cpal::SampleFormat::F32),
+        ];
+        let (config, format) = negotiate_config(48000, Some(128), supported).unwrap();
+        assert_eq!(config, make_config(48000, 2, Some(256)));
+        assert_eq!(format, cpal::SampleFormat::I16);
+        let (config, format) = negotiate_config(44100, None, supported).unwrap();
+        assert_eq!(config, make_config(44100, 2, None));
+        assert_eq!(format, cpal::SampleFormat::F32);
+        // Mono only as a last resort.
+        let (config, _) = negotiate_config(48000, Some(32), supported).unwrap();
+        assert_eq!(config, make_config(44100, 2, Some(64)));
+        let (config, _) = negotiate_config(48000, Some(32), supported[..1].to_vec()).unwrap();
+        assert_eq!(config, make_config(48000, 1, Some(32)));
+        assert!(negotiate_config(96000, Some(128), supported).is_err());
+        assert!(negotiate_config(48000, Some(16), supported[2..].to_vec()).is_err());
+        assert!(negotiate_config(48000, None, []).is_err());
+    }
+
+    #[test]
+    fn over_unity_output_sets_clip_flag() {
+        let mut buffer = [0.0_f32; 512];
+        let mut quiet = renderer(0.5);
+        quiet.fill(&mut buffer, 2);
+        assert!(!quiet.telemetry().stats().clipping);
+
+        let mut loud = renderer(1.5);
+        loud.fill(&mut buffer, 2);
+        assert!(loud.telemetry().stats().clipping);
+    }
+
+    #[test]
+    fn stereo_plays_on_the_front_pair_of_surround_devices() {
+        let mut net = Net::wrap(Box::new(dc((0.5, -0.25))));
+        net.set_sample_rate(48000.0);
+        let (telemetry, _events) = Telemetry::new(Duration::from_secs(1));
+        let mut renderer = Renderer::new(net.backend(), telemetry, 48000.0);
+        let mut buffer = [1.0_f32; 6 * 4];
+        renderer.fill(&mut buffer, 6);
+        assert_eq!(buffer[18..], [0.5, -0.25, 0.0, 0.0, 0.0, 0.0]);
+
+        renderer.set_layout(OutputLayout::Surround);
+        renderer.fill(&mut buffer, 6);
+        assert_eq!(buffer[18..], [0.5, -0.25, 0.125, 0.0, 0.25, -0.125]);
+
+        let mut mono = [0.0_f32; 4];
+        renderer.set_layout(OutputLayout::Direct);
+        renderer.fill(&mut mono, 1);
+        assert_eq!(mono, [0.125; 4]);
+    }
+
+    #[test]
+    fn every_graph_output_reaches_the_device() {
+        let mut net = Net::wrap(Box::new(dc((0.5, -0.25, 0.125, 1.5))));
+        net.set_sample_rate(48000.0);
+        let (telemetry, _events) = Telemetry::new(Duration::from_secs(1));
+        let mut renderer = Renderer::new(net.backend(), telemetry, 48000.0);
+        assert_eq!(renderer.outputs(), 4);
+        assert_eq!(renderer.next_outputs(), [0.5, -0.25, 0.125, 1.5]);
+        let mut buffer = [0.0_f32; 4 * 4];
+        renderer.fill(&mut buffer, 4);
+        assert_eq!(buffer[12..], [0.5, -0.25, 0.125, 1.5]);
+        assert!(renderer.telemetry().stats().clipping);
+        // Fewer device channels than outputs keep the first ones.
+        let mut stereo = [0.0_f32; 2 * 4];
+        renderer.fill(&mut stereo, 2);
+        assert_eq!(stereo[6..], [0.5, -0.25]);
+    }
+
+    #[cfg(not(feature = "no-alloc-guard"))]
+    #[test]
+    fn allocations_pass_without_the_guard() {
+        let buffer = no_alloc(|| vec![0.0f32; 1024]);
+        assert_eq!(buffer.len(), 1024);
+    }
+
+    #[test]
+    fn zero_retries_never_reopen() {
+        let now = Instant::now();
+        assert_eq!(first_reopen(&NO_RECOVERY, now), None);
+        let recovery = StreamRecovery {
+            retries: 1,
+            backoff: Duration::from_millis(100),
+        };
+        assert_eq!(first_reopen(&recovery, now), Some((0, now)));
+    }
+
+    #[test]
+    fn recovery_backoff_doubles() {
+        let recovery = StreamRecovery {
+            retries: 3,
+            backoff: Duration::from_millis(100),
+        };
+        assert_eq!(recovery.backoff_after(0), Duration::from_millis(100));
+        assert_eq!(recovery.backoff_after(2), Duration::from_millis(400));
+        assert_eq!(
+            recovery.backoff_after(40),
+            Duration::from_millis(100) * u32::MAX
+        );
+    }
+}
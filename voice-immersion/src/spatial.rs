@@ -0,0 +1,917 @@
+use nalgebra::{UnitQuaternion, Vector3};
+
+use crate::attenuation::{AttenuationModel, Cone};
+use crate::config::{EngineConfig, PanLaw};
+use crate::coordinates::CoordinateSystem;
+use crate::{
+    diffracted_occlusion, obstruction_gain, InAnotherRoom, SourceInfo, FORWARD_VECTOR, HEAD_RADIUS,
+    MAX_SOURCE_GAIN, SOUND_SPEED, STEREO_WIDTH, UP_VECTOR,
+};
+
+/// Largest interaural time difference in seconds, for a source right at one side.
+pub(crate) const MAX_ITD: f32 = 0.0007;
+
+/// Ears of the player, in world coordinates of `EngineConfig::coordinate_system`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Listener {
+    pub position: Vector3<f32>,
+    /// Facing direction, of any non-zero length.
+    pub forward: Vector3<f32>,
+    pub up: Vector3<f32>,
+    /// Head radius in world units, see `EngineConfig::head_radius`.
+    pub head_radius: f32,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Listener {
+            position: Vector3::new(0.0, 0.0, 0.0),
+            forward: FORWARD_VECTOR,
+            up: UP_VECTOR,
+            head_radius: HEAD_RADIUS,
+        }
+    }
+}
+
+impl Listener {
+    /// Listener at `position` with its head turned by `orientation`, such as a headset pose,
+    /// from facing the forward axis of `coordinate_system` with the top along its up axis.
+    pub fn from_orientation(
+        position: Vector3<f32>,
+        orientation: &UnitQuaternion<f32>,
+        coordinate_system: &CoordinateSystem,
+    ) -> Self {
+        let mut listener = Listener {
+            position,
+            ..Default::default()
+        };
+        listener.set_orientation(orientation, coordinate_system);
+        listener
+    }
+
+    /// Turn the head by `orientation`, see `from_orientation`. Rolling it tilts `up`.
+    pub fn set_orientation(
+        &mut self,
+        orientation: &UnitQuaternion<f32>,
+        coordinate_system: &CoordinateSystem,
+    ) {
+        self.forward = orientation * coordinate_system.forward_axis.vector();
+        self.up = orientation * coordinate_system.up_axis.vector();
+    }
+
+    /// Orientation of the head given by `forward` and `up`, see `from_orientation`. `up` only
+    /// needs to lean to the top of the head, it is made square to `forward`.
+    pub fn orientation(&self, coordinate_system: &CoordinateSystem) -> UnitQuaternion<f32> {
+        let reference = UnitQuaternion::face_towards(
+            &coordinate_system.forward_axis.vector(),
+            &coordinate_system.up_axis.vector(),
+        );
+        UnitQuaternion::face_towards(&self.forward, &self.up) * reference.inverse()
+    }
+}
+
+/// Sound emitter, in world coordinates of `EngineConfig::coordinate_system`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Source {
+    pub position: Vector3<f32>,
+    /// Facing direction, of any non-zero length, for the directivity.
+    pub direction: Vector3<f32>,
+    /// Room the source stands in, with bounds in world coordinates: its walls muffle the
+    /// source for listeners outside of it, see `walls_crossed`.
+    pub room: Option<InAnotherRoom>,
+    /// Size of the source in world units, see `SourceInfo::radius`.
+    pub radius: f32,
+    /// World units per second, see `SourceInfo::velocity`.
+    pub velocity: Vector3<f32>,
+    /// Omnidirectional when `None`, see `Cone`.
+    pub directivity: Option<Cone>,
+    /// Linear level, see `SourceInfo::gain`.
+    pub gain: f32,
+    /// Full level range in world units, see `SourceInfo::min_distance`.
+    pub min_distance: f32,
+    /// Audible range in world units, see `SourceInfo::max_distance`.
+    pub max_distance: Option<f32>,
+    /// Spacing of stereo channels in world units, see `SourceInfo::stereo_width`.
+    pub stereo_width: f32,
+    /// Share from 0 to 1 hidden behind obstacles, see `SourceInfo::obstruction`.
+    pub obstruction: f32,
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Source {
+            position: Vector3::new(0.0, 0.0, 0.0),
+            direction: Vector3::new(1.0, 0.0, 0.0),
+            room: None,
+            radius: 0.0,
+            velocity: Vector3::new(0.0, 0.0, 0.0),
+            directivity: None,
+            gain: 1.0,
+            min_distance: 0.0,
+            max_distance: None,
+            stereo_width: STEREO_WIDTH,
+            obstruction: 0.0,
+        }
+    }
+}
+
+/// Left gain, right gain and amplitude `listener` hears `source` with, walls included,
+/// without any audio device.
+pub fn stereo_gains(listener: &Listener, source: &Source, cfg: &EngineConfig) -> (f32, f32, f32) {
+    let info = cfg.ingest_source_info(&relative_info(listener, source));
+    let up = cfg.coordinate_system.to_engine(&listener.up);
+    let head_radius = cfg.ingest_length(listener.head_radius);
+    let (left, right, amplitude) = spatialize(&info, &up, head_radius, cfg);
+    (
+        left,
+        right,
+        amplitude
+            * diffracted_occlusion(
+                info.room.as_ref(),
+                &info.relative_position,
+                cfg.wall_diffraction,
+            )
+            * obstruction_gain(info.obstruction),
+    )
+}
+
+/// Direction and distance of a source from a listener, see `compute_spherical`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Spherical {
+    /// Degrees around the top of the head from the front, positive on the right: 90 on the
+    /// right, -90 on the left, 180 behind.
+    pub azimuth: f32,
+    /// Degrees above the horizon of the listener, -90 right below to 90 right above.
+    pub elevation: f32,
+    /// Meters from the center of the head to the center of the source.
+    pub distance: f32,
+}
+
+/// Azimuth, elevation and distance of `source` as heard by `listener`, in the default
+/// convention and units of `EngineConfig`, for debug overlays and logs.
+pub fn compute_spherical(listener: &Listener, source: &Source) -> Spherical {
+    compute_spherical_with(listener, source, &EngineConfig::default())
+}
+
+/// `compute_spherical` with the coordinate system and units of `cfg`.
+pub fn compute_spherical_with(
+    listener: &Listener,
+    source: &Source,
+    cfg: &EngineConfig,
+) -> Spherical {
+    let info = cfg.ingest_source_info(&relative_info(listener, source));
+    spherical(&info, &cfg.coordinate_system.to_engine(&listener.up))
+}
+
+/// `source` as seen from `listener`, still in their convention and units.
+pub(crate) fn relative_info(listener: &Listener, source: &Source) -> SourceInfo {
+    SourceInfo {
+        relative_position: source.position - listener.position,
+        direction: listener.forward,
+        room: source.room.clone().map(|room| InAnotherRoom {
+            bounds: room
+                .bounds
+                .map(|bounds| bounds.translated(&-listener.position)),
+            ..room
+        }),
+        radius: source.radius,
+        velocity: source.velocity,
+        source_direction: source.direction,
+        directivity: source.directivity,
+        gain: source.gain,
+        min_distance: source.min_distance,
+        max_distance: source.max_distance,
+        stereo_width: source.stereo_width,
+        obstruction: source.obstruction,
+        listener_height: listener.position.y,
+    }
+}
+
+/// Azimuths sampled around the listener by `immersion_metric`.
+const METRIC_AZIMUTHS: usize = 72;
+
+/// Stereo width of a configuration: the mean gap between the left and right gains of a
+/// source circling the listener at 1 m in the horizontal plane.
+/// 0 means every direction sounds centered (mono), 1 that every direction is hard panned;
+/// higher values sound wider but hard pans get tiring on headphones.
+pub fn immersion_metric(cfg: &EngineConfig) -> f32 {
+    let total: f32 = (0..METRIC_AZIMUTHS)
+        .map(|i| {
+            let azimuth = i as f32 / METRIC_AZIMUTHS as f32 * std::f32::consts::TAU;
+            let info = SourceInfo {
+                relative_position: Vector3::new(azimuth.cos(), 0.0, azimuth.sin()),
+                ..Default::default()
+            };
+            let (left, right, _) = spatialize(&info, &UP_VECTOR, cfg.head_radius, cfg);
+            (left - right).abs()
+        })
+        .sum();
+    total / METRIC_AZIMUTHS as f32
+}
+
+/// Left and right linear gains of the source seen by a listener facing `info.direction`,
+/// in engine coordinates and meters, with the default up vector.
+pub fn compute_stereo_gains(info: &SourceInfo) -> (f32, f32) {
+    PanLaw::default().gains(lateral(info, &UP_VECTOR, HEAD_RADIUS))
+}
+
+/// Gain of the default attenuation model at `distance` meters.
+pub fn compute_distance_attenuation(distance: f32) -> f32 {
+    AttenuationModel::default().gain(distance)
+}
+
+/// Spatialization core, from the source seen by a listener facing `info.direction` with `up` on top,
+/// with a head of `head_radius` meters.
+/// The amplitude only accounts for the distance, walls are smoothed on their own.
+pub(crate) fn spatialize(
+    info: &SourceInfo,
+    up: &Vector3<f32>,
+    head_radius: f32,
+    cfg: &EngineConfig,
+) -> (f32, f32, f32) {
+    let distance = info.relative_position.norm();
+    // Distance attenuation, from the surface of the source.
+    let distance_out = (distance - info.radius).max(0.0);
+    let rolled_off = (distance_out - info.min_distance.max(0.0)).max(0.0);
+    let mut amp = cfg.attenuation.gain(rolled_off).max(cfg.min_gain) * source_gain(info);
+    if let Some(near_field) = &cfg.near_field {
+        amp *= near_field.gain(distance_out);
+    }
+    if let Some(cone) = &info.directivity {
+        amp *= cone.gain(off_axis_angle(info));
+    }
+    // Out of range, silent rather than at the floor of the attenuation.
+    if info.max_distance.is_some_and(|max| distance_out > max) {
+        amp = 0.0;
+    }
+
+    // Orientation hears attenuation.
+    let pan = lateral(info, up, head_radius) * cfg.directional_strength.clamp(0.0, 1.0);
+    let (left, right) = cfg.output_pan_law().gains(pan);
+    let floor = cfg.pan_floor.clamp(0.0, 1.0);
+    let (left, right) = (left.max(floor), right.max(floor));
+    (left, right, amp)
+}
+
+/// Level of the source, negative and NaN gains muting it.
+fn source_gain(info: &SourceInfo) -> f32 {
+    if info.gain.is_nan() {
+        0.0
+    } else {
+        info.gain.clamp(0.0, MAX_SOURCE_GAIN)
+    }
+}
+
+/// Sine of the source azimuth, positive on the left, faded out as the listener gets
+/// enveloped by a large source or as the source gets inside the head.
+fn lateral(info: &SourceInfo, up: &Vector3<f32>, head_radius: f32) -> f32 {
+    let distance = info.relative_position.norm();
+    // No direction to pan to at the listener position.
+    if distance < f32::EPSILON {
+        return 0.0;
+    }
+    let outside_head = (distance / head_radius.max(f32::EPSILON)).min(1.0);
+    // How much the listener is inside a large source: fully within its radius,
+    // fading to a point source one radius away from its surface.
+    let envelopment = if info.radius > 0.0 {
+        (1.0 - (distance - info.radius) / info.radius).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    if envelopment < 1.0 {
+        let direction = info
+            .direction
+            .try_normalize(f32::EPSILON)
+            .unwrap_or(FORWARD_VECTOR);
+        let uv = info.relative_position.cross(&direction);
+        outside_head * (1.0 - envelopment) * (uv.norm() / distance) * uv.dot(&-up).signum()
+    } else {
+        0.0
+    }
+}
+
+/// Angle in radians between the axis the source faces and the listener, 0 when either is
+/// undefined.
+fn off_axis_angle(info: &SourceInfo) -> f32 {
+    let to_listener = -info.relative_position;
+    match (
+        to_listener.try_normalize(f32::EPSILON),
+        info.source_direction.try_normalize(f32::EPSILON),
+    ) {
+        (Some(to_listener), Some(facing)) => to_listener.dot(&facing).clamp(-1.0, 1.0).acos(),
+        _ => 0.0,
+    }
+}
+
+/// Where the channel of a stereo source `side` times `SourceInfo::stereo_width` to the right
+/// of it plays from: moved across the line of sight of the listener, so that the left
+/// channel stays on the left of the right one whichever way the listener turns. Straight
+/// above or below, the right of the listener stands for it. `info` itself for a side of 0.
+pub(crate) fn stereo_channel(info: &SourceInfo, up: &Vector3<f32>, side: f32) -> SourceInfo {
+    if side == 0.0 {
+        return info.clone();
+    }
+    let right = info
+        .relative_position
+        .cross(up)
+        .try_normalize(f32::EPSILON)
+        .or_else(|| info.direction.cross(up).try_normalize(f32::EPSILON))
+        .unwrap_or(FORWARD_VECTOR.cross(&UP_VECTOR));
+    SourceInfo {
+        relative_position: info.relative_position + right * side * info.stereo_width,
+        ..info.clone()
+    }
+}
+
+/// Sine of the source elevation above the listener horizon, from -1 right below to 1 right
+/// above, 0 at the listener position.
+pub(crate) fn elevation(info: &SourceInfo, up: &Vector3<f32>) -> f32 {
+    head_direction(info, up).z.clamp(-1.0, 1.0)
+}
+
+/// Spherical coordinates of the source seen by a listener facing `info.direction` with `up`
+/// on top, from `head_direction`. At the listener position, in front at 0 m.
+pub(crate) fn spherical(info: &SourceInfo, up: &Vector3<f32>) -> Spherical {
+    let direction = head_direction(info, up);
+    Spherical {
+        // Left is positive in the head frame, and a positive zero keeps 180 behind.
+        azimuth: (0.0 - direction.y).atan2(direction.x).to_degrees(),
+        elevation: direction.z.clamp(-1.0, 1.0).asin().to_degrees(),
+        distance: info.relative_position.norm(),
+    }
+}
+
+/// Direction of the source in the frame of the listener facing `info.direction` with `up`
+/// on top: its front, left and up components, the gains of the first-order ambisonic X, Y
+/// and Z channels. Zero at the listener position.
+pub(crate) fn head_direction(info: &SourceInfo, up: &Vector3<f32>) -> Vector3<f32> {
+    let Some(toward) = info.relative_position.try_normalize(f32::EPSILON) else {
+        return Vector3::zeros();
+    };
+    let forward = info
+        .direction
+        .try_normalize(f32::EPSILON)
+        .unwrap_or(FORWARD_VECTOR);
+    // `up` only needs to lean to the top of the head.
+    let up = (up - forward * up.dot(&forward))
+        .try_normalize(f32::EPSILON)
+        .unwrap_or(UP_VECTOR);
+    let left = up.cross(&forward);
+    Vector3::new(toward.dot(&forward), toward.dot(&left), toward.dot(&up))
+}
+
+/// Interaural time difference in seconds from the Woodworth formula, positive when the
+/// sound reaches the left ear first, for a head of `head_radius` meters. Sources behind get
+/// the delay of their front mirror.
+pub(crate) fn interaural_time_difference(
+    info: &SourceInfo,
+    up: &Vector3<f32>,
+    head_radius: f32,
+) -> f32 {
+    let sine = lateral(info, up, head_radius);
+    let azimuth = sine.clamp(-1.0, 1.0).asin();
+    (head_radius / SOUND_SPEED * (azimuth + azimuth.sin())).clamp(-MAX_ITD, MAX_ITD)
+}
+
+/// Delays in seconds of the left and right ears, from the emission of the sound to its
+/// arrival: one delay line per ear carries both the `propagation` delay to the center of the
+/// head and the interaural time difference `itd`, see `interaural_time_difference`:
+///
+/// `left = t - itd / 2` and `right = t + itd / 2`, `t` being the propagation delay,
+///
+/// so that `right - left = itd` and the ears straddle the center. For sources closer than
+/// half the ITD path, `t` is raised to `|itd| / 2` rather than a delay going negative.
+pub(crate) fn ear_delays(propagation: f32, itd: f32) -> (f32, f32) {
+    let center = propagation.max(itd.abs() / 2.0);
+    (center - itd / 2.0, center + itd / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attenuation::NearField;
+
+    /// Gain of both channels for a centered source, under the default equal-power law.
+    const CENTER: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+    fn linear() -> EngineConfig {
+        EngineConfig {
+            pan_law: PanLaw::Linear,
+            ..Default::default()
+        }
+    }
+
+    fn source_at(x: f32, y: f32, z: f32) -> Source {
+        Source {
+            position: Vector3::new(x, y, z),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn walls_between_rooms_occlude() {
+        let cfg = EngineConfig::default();
+        let room = InAnotherRoom::builder()
+            .material(crate::Material::Wood)
+            .bounds(Vector3::new(0.0, -1.0, -2.0), Vector3::new(4.0, 3.0, 2.0))
+            .build(48000.0)
+            .unwrap();
+        let (inside, outside) = (Vector3::new(3.0, 0.0, 0.0), Vector3::new(3.0, 0.0, 5.0));
+        let through_wall = |listener: Vector3<f32>, source: Vector3<f32>| {
+            let listener = Listener {
+                position: listener - Vector3::new(2.0, 0.0, 0.0),
+                ..Default::default()
+            };
+            let mut source = Source {
+                position: source,
+                ..Default::default()
+            };
+            let open_air = stereo_gains(&listener, &source, &cfg).2;
+            source.room = Some(room.clone());
+            stereo_gains(&listener, &source, &cfg).2 / open_air
+        };
+        let wall = crate::wall_transmission(&room, 1.0);
+        assert!(wall < 0.1);
+        for (listener, source, gain) in [
+            (inside, inside, 1.0),
+            (outside, outside, 1.0),
+            (inside, outside, wall),
+            (outside, inside, wall),
+        ] {
+            assert!((through_wall(listener, source) - gain).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn front_source_is_centered() {
+        let (left, right, amplitude) = stereo_gains(
+            &Listener::default(),
+            &source_at(10.0, 0.0, 0.0),
+            &EngineConfig::default(),
+        );
+        assert!((left - CENTER).abs() < 1e-6);
+        assert!((right - CENTER).abs() < 1e-6);
+        assert!((amplitude - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn side_sources_are_hard_panned() {
+        let cfg = EngineConfig::default();
+        let listener = Listener {
+            position: Vector3::new(-2.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        let (left, right, _) = stereo_gains(&listener, &source_at(-2.0, 0.0, 1.0), &cfg);
+        assert!(left.abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+        let (left, right, _) = stereo_gains(&listener, &source_at(-2.0, 0.0, -1.0), &cfg);
+        assert!((left - 1.0).abs() < 1e-6 && right.abs() < 1e-6);
+    }
+
+    #[test]
+    fn listener_turned_by_a_quaternion() {
+        let cfg = EngineConfig::default();
+        let system = CoordinateSystem::default();
+        let identity =
+            Listener::from_orientation(Vector3::zeros(), &UnitQuaternion::identity(), &system);
+        assert_eq!(identity, Listener::default());
+        assert!(identity.orientation(&system).angle() < 1e-6);
+
+        // Turned a quarter left, what was in front is now on the right.
+        let yaw = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), std::f32::consts::FRAC_PI_2);
+        let turned = Listener::from_orientation(Vector3::zeros(), &yaw, &system);
+        let (left, right, _) = stereo_gains(&turned, &source_at(1.0, 0.0, 0.0), &cfg);
+        assert!(left.abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+        assert!(turned.orientation(&system).angle_to(&yaw) < 1e-5);
+
+        // Rolled, the top of the head leans but the listener still faces forward.
+        let roll = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 0.5) * yaw;
+        let rolled = Listener::from_orientation(Vector3::zeros(), &roll, &system);
+        assert!((rolled.up - Vector3::y()).norm() > 0.1);
+        assert!(rolled.orientation(&system).angle_to(&roll) < 1e-5);
+
+        let opengl = CoordinateSystem::opengl();
+        let pose = Listener::from_orientation(Vector3::zeros(), &yaw, &opengl);
+        assert!((pose.forward - -Vector3::x()).norm() < 1e-6);
+        assert!(pose.orientation(&opengl).angle_to(&yaw) < 1e-5);
+    }
+
+    #[test]
+    fn large_source_envelops_listener() {
+        let cfg = linear();
+        let mut source = source_at(0.0, 0.0, 2.0);
+        source.radius = 5.0;
+        let (left, right, amplitude) = stereo_gains(&Listener::default(), &source, &cfg);
+        assert_eq!((left, right), (0.5, 0.5));
+        assert_eq!(amplitude, 1.0);
+
+        // Half way out of the transition band.
+        source.position.z = 7.5;
+        let (left, right, _) = stereo_gains(&Listener::default(), &source, &cfg);
+        assert!((left - 0.25).abs() < 1e-6 && (right - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pure_stereo_gains() {
+        let gains = |x: f32, z: f32| {
+            compute_stereo_gains(&SourceInfo {
+                relative_position: Vector3::new(x, 0.0, z),
+                ..Default::default()
+            })
+        };
+        let (left, right) = gains(2.0, 0.0);
+        assert!((left - CENTER).abs() < 1e-6 && (right - CENTER).abs() < 1e-6);
+        let (left, right) = gains(0.0, -2.0);
+        assert!((left - 1.0).abs() < 1e-6 && right.abs() < 1e-6);
+        let (left, right) = gains(0.0, 2.0);
+        assert!(left.abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+
+        assert_eq!(compute_distance_attenuation(0.0), 1.0);
+        assert!((compute_distance_attenuation(10.0) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn direction_length_does_not_matter() {
+        let gains = |direction: Vector3<f32>| {
+            compute_stereo_gains(&SourceInfo {
+                relative_position: Vector3::new(1.0, 0.0, -1.0),
+                direction,
+                ..Default::default()
+            })
+        };
+        let (left, right) = gains(Vector3::new(1.0, 0.0, 0.0));
+        for direction in [Vector3::new(5.0, 0.0, 0.0), Vector3::new(1e-3, 0.0, 0.0)] {
+            let (l, r) = gains(direction);
+            assert!(
+                (l - left).abs() < 1e-6 && (r - right).abs() < 1e-6,
+                "{direction:?}"
+            );
+        }
+        // Zero directions face forward.
+        let (l, r) = gains(Vector3::zeros());
+        assert!((l - left).abs() < 1e-6 && (r - right).abs() < 1e-6);
+        assert!(left > right && (0.0..=1.0).contains(&left) && (0.0..=1.0).contains(&right));
+    }
+
+    #[test]
+    fn head_radius_sets_the_itd() {
+        let info = SourceInfo {
+            relative_position: Vector3::new(3f32.sqrt(), 0.0, -1.0),
+            ..Default::default()
+        };
+        let small = interaural_time_difference(&info, &UP_VECTOR, HEAD_RADIUS);
+        let large = interaural_time_difference(&info, &UP_VECTOR, HEAD_RADIUS * 1.2);
+        assert!((large / small - 1.2).abs() < 1e-4, "{small} vs {large}");
+
+        // A larger head centers sources over a larger zone.
+        let listener = Listener {
+            head_radius: 4.0 * HEAD_RADIUS,
+            ..Default::default()
+        };
+        let source = source_at(0.0, 0.0, -2.0 * HEAD_RADIUS);
+        let (left, right, _) = stereo_gains(&listener, &source, &linear());
+        assert!((left - 0.75).abs() < 1e-6 && (right - 0.25).abs() < 1e-6);
+        let (left, _, _) = stereo_gains(&Listener::default(), &source, &linear());
+        assert_eq!(left, 1.0);
+    }
+
+    #[test]
+    fn elevation_from_the_vertical_angle() {
+        let elevation_at = |x: f32, y: f32| {
+            let info = SourceInfo {
+                relative_position: Vector3::new(x, y, 0.0),
+                ..Default::default()
+            };
+            elevation(&info, &UP_VECTOR)
+        };
+        assert_eq!(elevation_at(0.0, 3.0), 1.0);
+        assert_eq!(elevation_at(0.0, -3.0), -1.0);
+        assert_eq!(elevation_at(2.0, 0.0), 0.0);
+        assert!((elevation_at(1.0, 1.0) - CENTER).abs() < 1e-6);
+        assert_eq!(elevation_at(0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn spherical_angles_of_the_canonical_directions() {
+        let listener = Listener::default();
+        let at = |x: f32, y: f32, z: f32| compute_spherical(&listener, &source_at(x, y, z));
+        let close = |a: Spherical, azimuth: f32, elevation: f32| {
+            (a.azimuth - azimuth).abs() < 1e-4 && (a.elevation - elevation).abs() < 1e-4
+        };
+        assert!(close(at(2.0, 0.0, 0.0), 0.0, 0.0));
+        assert!(close(at(0.0, 0.0, 2.0), 90.0, 0.0));
+        assert!(close(at(0.0, 0.0, -2.0), -90.0, 0.0));
+        assert!(close(at(-2.0, 0.0, 0.0), 180.0, 0.0));
+        assert!(close(at(0.0, 2.0, 0.0), 0.0, 90.0));
+        assert!(close(at(0.0, -2.0, 0.0), 0.0, -90.0));
+        assert!(close(at(1.0, 1.0, 1.0), 45.0, 35.26439));
+        assert_eq!(at(0.0, 3.0, 4.0).distance, 5.0);
+        assert_eq!(at(0.0, 0.0, 0.0), Spherical::default());
+
+        // In the frame of the head: a listener facing the right hears the front on its left.
+        let turned = Listener {
+            forward: Vector3::new(0.0, 0.0, 1.0),
+            ..Listener::default()
+        };
+        let front = compute_spherical(&turned, &source_at(2.0, 0.0, 0.0));
+        assert!(close(front, -90.0, 0.0));
+    }
+
+    #[test]
+    fn stereo_channels_straddle_the_line_of_sight() {
+        let info = SourceInfo {
+            relative_position: Vector3::new(0.0, 0.0, -4.0),
+            stereo_width: 2.0,
+            ..Default::default()
+        };
+        // Turning to the source on its left, the listener hears the left channel on the
+        // left, behind its former facing.
+        let left = stereo_channel(&info, &UP_VECTOR, -0.5);
+        let right = stereo_channel(&info, &UP_VECTOR, 0.5);
+        assert_eq!(left.relative_position, Vector3::new(-1.0, 0.0, -4.0));
+        assert_eq!(right.relative_position, Vector3::new(1.0, 0.0, -4.0));
+        assert_eq!(stereo_channel(&info, &UP_VECTOR, 0.0), info);
+        let above = SourceInfo {
+            relative_position: Vector3::new(0.0, 3.0, 0.0),
+            ..info
+        };
+        let right = stereo_channel(&above, &UP_VECTOR, 0.5);
+        assert_eq!(right.relative_position, Vector3::new(0.0, 3.0, 1.0));
+    }
+
+    #[test]
+    fn near_field_boosts_close_sources() {
+        let cfg = EngineConfig {
+            near_field: Some(NearField::default()),
+            ..Default::default()
+        };
+        let amplitude =
+            |x: f32| stereo_gains(&Listener::default(), &source_at(x, 0.0, 0.0), &cfg).2;
+        assert!(amplitude(0.1) > 1.5 * amplitude(1.0));
+        assert!(amplitude(0.0) <= NearField::default().max_gain);
+        let far = stereo_gains(&Listener::default(), &source_at(1.0, 0.0, 0.0), &linear()).2;
+        assert_eq!(amplitude(1.0), far);
+    }
+
+    #[test]
+    fn turning_away_lowers_the_gain() {
+        let cfg = EngineConfig::default();
+        let cone = Cone::default();
+        let amplitude = |direction: Vector3<f32>| {
+            let source = Source {
+                position: Vector3::new(4.0, 0.0, 0.0),
+                direction,
+                directivity: Some(cone),
+                ..Default::default()
+            };
+            stereo_gains(&Listener::default(), &source, &cfg).2
+        };
+        let omni = stereo_gains(&Listener::default(), &source_at(4.0, 0.0, 0.0), &cfg).2;
+        assert_eq!(amplitude(Vector3::new(-1.0, 0.0, 0.0)), omni);
+        assert!((amplitude(Vector3::new(1.0, 0.0, 0.0)) - omni * cone.outer_gain).abs() < 1e-6);
+        let side = amplitude(Vector3::new(0.0, 0.0, 1.0));
+        assert!(side < omni && side > omni * cone.outer_gain);
+    }
+
+    #[test]
+    fn source_gain_scales_the_attenuation() {
+        let cfg = EngineConfig::default();
+        let amplitude = |gain: f32| {
+            let source = Source {
+                gain,
+                ..source_at(4.0, 0.0, 0.0)
+            };
+            stereo_gains(&Listener::default(), &source, &cfg).2
+        };
+        assert!((amplitude(0.5) - amplitude(1.0) * 0.5).abs() < 1e-6);
+        assert_eq!(amplitude(100.0), amplitude(MAX_SOURCE_GAIN));
+        assert_eq!(amplitude(-1.0), 0.0);
+        assert_eq!(amplitude(f32::NAN), 0.0);
+    }
+
+    #[test]
+    fn min_gain_floors_the_attenuation() {
+        let cfg = EngineConfig {
+            attenuation: AttenuationModel::Linear {
+                min_distance: 1.0,
+                max_distance: 11.0,
+            },
+            min_gain: 0.1,
+            ..Default::default()
+        };
+        let amplitude =
+            |x: f32| stereo_gains(&Listener::default(), &source_at(x, 0.0, 0.0), &cfg).2;
+        assert!((amplitude(6.0) - 0.5).abs() < 1e-6);
+        assert!((amplitude(100.0) - 0.1).abs() < 1e-6);
+        let cfg = EngineConfig {
+            min_gain: 0.0,
+            ..cfg
+        };
+        assert_eq!(
+            stereo_gains(&Listener::default(), &source_at(100.0, 0.0, 0.0), &cfg).2,
+            0.0
+        );
+    }
+
+    #[test]
+    fn source_range_bounds_the_attenuation() {
+        let cfg = EngineConfig {
+            min_gain: 0.1,
+            ..Default::default()
+        };
+        let amplitude = |x: f32| {
+            let source = Source {
+                min_distance: 5.0,
+                max_distance: Some(50.0),
+                ..source_at(x, 0.0, 0.0)
+            };
+            stereo_gains(&Listener::default(), &source, &cfg).2
+        };
+        assert_eq!(amplitude(5.0), 1.0);
+        // Rolled off from the full level range, as the model does from the source.
+        let model = |x: f32| stereo_gains(&Listener::default(), &source_at(x, 0.0, 0.0), &cfg).2;
+        assert!((amplitude(15.0) - model(10.0)).abs() < 1e-6);
+        assert!(amplitude(50.0) >= 0.1);
+        assert_eq!(amplitude(50.1), 0.0);
+    }
+
+    #[test]
+    fn source_at_listener_is_centered() {
+        let (left, right, amplitude) =
+            stereo_gains(&Listener::default(), &Source::default(), &linear());
+        assert_eq!((left, right, amplitude), (0.5, 0.5, 1.0));
+        let info = SourceInfo::default();
+        assert_eq!(
+            interaural_time_difference(&info, &UP_VECTOR, HEAD_RADIUS),
+            0.0
+        );
+
+        // Fading to centered within the head.
+        let (left, right, _) = stereo_gains(
+            &Listener::default(),
+            &source_at(0.0, 0.0, -HEAD_RADIUS / 2.0),
+            &linear(),
+        );
+        assert!((left - 0.75).abs() < 1e-6 && (right - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn equal_power_keeps_loudness_across_the_pan() {
+        let power = |law: PanLaw, pan: f32| {
+            let (left, right) = law.gains(pan);
+            left * left + right * right
+        };
+        for i in 0..=20 {
+            let pan = i as f32 / 10.0 - 1.0;
+            assert!((power(PanLaw::EqualPower, pan) - 1.0).abs() < 1e-6);
+        }
+        // Linear panning dips by 3 dB in the center.
+        assert!((power(PanLaw::Linear, 1.0) - 1.0).abs() < 1e-6);
+        assert!((power(PanLaw::Linear, 0.0) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn itd_follows_azimuth() {
+        let info = |x: f32, z: f32| SourceInfo {
+            relative_position: Vector3::new(x, 0.0, z),
+            ..Default::default()
+        };
+        assert_eq!(
+            interaural_time_difference(&info(1.0, 0.0), &UP_VECTOR, HEAD_RADIUS),
+            0.0
+        );
+        let left = interaural_time_difference(&info(0.0, -1.0), &UP_VECTOR, HEAD_RADIUS);
+        let right = interaural_time_difference(&info(0.0, 1.0), &UP_VECTOR, HEAD_RADIUS);
+        assert_eq!(left, MAX_ITD);
+        assert_eq!(right, -MAX_ITD);
+        // Woodworth at 30 degrees: r / c * (pi / 6 + 1 / 2).
+        let expected = HEAD_RADIUS / SOUND_SPEED * (std::f32::consts::FRAC_PI_6 + 0.5);
+        let front = interaural_time_difference(&info(3f32.sqrt(), -1.0), &UP_VECTOR, HEAD_RADIUS);
+        assert!((front - expected).abs() < 1e-7, "{front}");
+        let back = interaural_time_difference(&info(-(3f32.sqrt()), -1.0), &UP_VECTOR, HEAD_RADIUS);
+        assert!((back - front).abs() < 1e-7);
+    }
+
+    #[test]
+    fn immersion_metric_of_sine_pan_law() {
+        // |left - right| is |sin(azimuth)|, averaging 2 / pi around the circle.
+        let metric = immersion_metric(&linear());
+        assert!(
+            (metric - 2.0 / std::f32::consts::PI).abs() < 1e-3,
+            "{metric}"
+        );
+    }
+
+    #[test]
+    fn positions_are_scaled_to_meters() {
+        let cfg = EngineConfig {
+            units_per_meter: 100.0,
+            ..Default::default()
+        };
+        let (_, _, centimeters) =
+            stereo_gains(&Listener::default(), &source_at(1000.0, 0.0, 0.0), &cfg);
+        let (_, _, meters) = stereo_gains(
+            &Listener::default(),
+            &source_at(10.0, 0.0, 0.0),
+            &EngineConfig::default(),
+        );
+        assert!((centimeters - meters).abs() < 1e-6);
+    }
+
+    #[test]
+    fn coordinate_system_is_applied() {
+        let cfg = EngineConfig {
+            coordinate_system: CoordinateSystem::unity(),
+            ..Default::default()
+        };
+        let listener = Listener {
+            forward: Vector3::new(0.0, 0.0, 1.0),
+            ..Default::default()
+        };
+        // +X is on the right in Unity.
+        let (left, right, _) = stereo_gains(&listener, &source_at(1.0, 0.0, 0.0), &cfg);
+        assert!(left.abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn directional_strength_blends_toward_center() {
+        let gains = |directional_strength| {
+            let cfg = EngineConfig {
+                directional_strength,
+                ..Default::default()
+            };
+            let (left, right, _) =
+                stereo_gains(&Listener::default(), &source_at(0.0, 0.0, -2.0), &cfg);
+            (left, right)
+        };
+        let full = gains(1.0);
+        let default = stereo_gains(
+            &Listener::default(),
+            &source_at(0.0, 0.0, -2.0),
+            &EngineConfig::default(),
+        );
+        assert_eq!(full, (default.0, default.1));
+        assert!(full.0 > 0.99 && full.1 < 0.01, "{full:?}");
+        let (left, right) = gains(0.0);
+        assert!((left - right).abs() < 1e-6);
+        let half = gains(0.5);
+        let expected = PanLaw::EqualPower.gains(0.5);
+        assert!((half.0 - expected.0).abs() < 1e-6 && (half.1 - expected.1).abs() < 1e-6);
+        assert!(half.0 > half.1 && half.0 < full.0);
+    }
+
+    #[test]
+    fn pan_floor_keeps_the_opposite_channel() {
+        let gains = |pan_floor| {
+            let cfg = EngineConfig {
+                pan_floor,
+                ..Default::default()
+            };
+            let (left, right, _) =
+                stereo_gains(&Listener::default(), &source_at(0.0, 0.0, -2.0), &cfg);
+            (left, right)
+        };
+        // Hard left.
+        let (left, right) = gains(0.0);
+        assert!(left > 0.99 && right < 0.01, "{left} {right}");
+        let (floored_left, floored_right) = gains(0.1);
+        assert!(floored_right >= 0.1, "{floored_right}");
+        assert_eq!(floored_left, left);
+    }
+
+    #[test]
+    fn presets_center_front_sources() {
+        for coordinate_system in [
+            CoordinateSystem::default(),
+            CoordinateSystem::opengl(),
+            CoordinateSystem::unity(),
+            CoordinateSystem::unreal(),
+            CoordinateSystem::gltf(),
+        ] {
+            let cfg = EngineConfig {
+                coordinate_system,
+                ..Default::default()
+            };
+            let listener = Listener {
+                forward: coordinate_system.forward_axis.vector(),
+                up: coordinate_system.up_axis.vector(),
+                ..Default::default()
+            };
+            let front = Source {
+                position: coordinate_system.forward_axis.vector() * 2.0,
+                ..Default::default()
+            };
+            let (left, right, _) = stereo_gains(&listener, &front, &cfg);
+            assert!((left - right).abs() < 1e-6, "{coordinate_system:?}");
+            let aside = Source {
+                position: coordinate_system.right() * 2.0,
+                ..Default::default()
+            };
+            let (left, right, _) = stereo_gains(&listener, &aside, &cfg);
+            assert!(right > left + 0.5, "{coordinate_system:?}");
+        }
+    }
+}
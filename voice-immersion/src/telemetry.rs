@@ -0,0 +1,402 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+use crate::clock::Instant;
+use crate::room::RoomId;
+
+/// Events pending past this count are dropped until the receiver catches up.
+const EVENT_CAPACITY: usize = 64;
+
+/// Fraction of a buffer duration a callback may be late by before counting as an xrun.
+const XRUN_MARGIN: f64 = 0.5;
+
+/// Time constant in seconds of the RMS meter, about the integration time of a VU meter.
+const METER_RMS_TIME: f64 = 0.3;
+
+/// Time constant in seconds the peak meter falls back with.
+const METER_PEAK_RELEASE: f64 = 0.5;
+
+/// Notable engine events, see `SpatialHandle::events`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineEvent {
+    /// The output moved to another device, or reopened after a stream error.
+    DeviceSwitched { name: String },
+    /// The output stream failed and could not be reopened, see `EngineConfig::stream_recovery`.
+    /// The engine is silent from then on.
+    StreamFailed { error: String },
+    /// The mic capture reopened on the default input device `name` after a stream error.
+    MicSwitched { name: String },
+    /// The mic capture failed and could not be reopened, see `EngineConfig::stream_recovery`.
+    /// The mic input is silent from then on.
+    MicFailed { error: String },
+    /// The one-shot input given to the builder played to its end, fade out included.
+    SourceFinished,
+    /// The first listener walked into another room of the world, or out of any when `None`,
+    /// see `SpatialHandle::listener_room`.
+    ListenerRoomChanged { room: Option<RoomId> },
+}
+
+/// Snapshot of the engine counters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EngineStats {
+    /// Probable output underruns, a hint to raise the buffer size when it keeps growing.
+    pub xruns: u64,
+    /// An output sample reached full scale within the last `EngineConfig::clip_hold`.
+    pub clipping: bool,
+    /// The output is paused after a silence, see `EngineConfig::idle_pause_timeout`.
+    pub idle: bool,
+    /// Times the mic input ran dry, see `EngineConfig::mic_underrun_hold`.
+    pub mic_underruns: u64,
+}
+
+/// Worst case delays in milliseconds from the mic to the speakers, see
+/// `SpatialHandle::latency`. Device and driver delays come on top of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Latency {
+    /// Capture buffer, assumed as long as the output one, 0 without a mic.
+    pub input_ms: f64,
+    /// Mic channel full to capacity, see `EngineConfig::mic_queue`, 0 without a mic.
+    pub queue_ms: f64,
+    /// Output buffer, of `DEFAULT_BUFFER_FRAMES` when left to the device.
+    pub output_ms: f64,
+    /// Control period, before placement changes are heard.
+    pub control_ms: f64,
+}
+
+impl Latency {
+    /// Delays of an output pulling `buffer_frames` at a time at `sample_rate`, fed by a mic
+    /// channel of `mic_queue` frames when there is one.
+    pub fn estimate(
+        sample_rate: f64,
+        buffer_frames: Option<usize>,
+        mic_queue: Option<usize>,
+        control_period: Duration,
+    ) -> Self {
+        let ms = |frames: usize| frames as f64 * 1000.0 / sample_rate;
+        let output_ms = ms(buffer_frames.unwrap_or(crate::DEFAULT_BUFFER_FRAMES));
+        Latency {
+            input_ms: mic_queue.map_or(0.0, |_| output_ms),
+            queue_ms: mic_queue.map_or(0.0, ms),
+            output_ms,
+            control_ms: control_period.as_secs_f64() * 1000.0,
+        }
+    }
+
+    /// Sum of the delays.
+    pub fn total_ms(&self) -> f64 {
+        self.input_ms + self.queue_ms + self.output_ms + self.control_ms
+    }
+}
+
+/// Output levels of the first listener, see `SpatialHandle::levels`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OutputLevels {
+    /// (left, right) RMS levels, averaged over about the last 300 ms.
+    pub rms: (f32, f32),
+    /// (left, right) peak levels, falling back smoothly after each peak.
+    pub peak: (f32, f32),
+}
+
+/// State of a source as last applied by the control thread, see `EngineBuilder::on_tick`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SourceMetrics {
+    /// Distance in meters to the listener.
+    pub distance: f32,
+    /// Gain of the distance attenuation and the walls, before the master gain.
+    pub gain: f32,
+    /// (left, right) panning gains.
+    pub pan: (f32, f32),
+    /// The source is heard from another room.
+    pub in_room: bool,
+    /// Room of the world the listener stands in, see `EngineBuilder::rooms`. Always `None`
+    /// for relative placements, which have no world.
+    pub listener_room: Option<RoomId>,
+}
+
+/// `Telemetry::listener_room` out of any room.
+const NO_ROOM: usize = usize::MAX;
+
+/// Telemetry shared by the renderer, the sink and the control thread.
+pub struct Telemetry {
+    events: Sender<EngineEvent>,
+    xruns: AtomicU64,
+    start: Instant,
+    /// Nanoseconds from `start` to the last clipped buffer, `u64::MAX` before any.
+    last_clip: AtomicU64,
+    clip_hold: Duration,
+    idle: AtomicBool,
+    paused: AtomicBool,
+    /// Bits of the RMS then peak levels, left and right.
+    levels: [AtomicU32; 4],
+    /// Index of the room of the first listener, `NO_ROOM` out of any.
+    listener_room: AtomicUsize,
+}
+
+impl Telemetry {
+    /// Telemetry keeping the clip indicator on for `clip_hold` after each clip.
+    pub(crate) fn new(clip_hold: Duration) -> (Arc<Self>, Receiver<EngineEvent>) {
+        let (events, receiver) = bounded(EVENT_CAPACITY);
+        let telemetry = Telemetry {
+            events,
+            xruns: AtomicU64::new(0),
+            start: Instant::now(),
+            last_clip: AtomicU64::new(u64::MAX),
+            clip_hold,
+            idle: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            levels: Default::default(),
+            listener_room: AtomicUsize::new(NO_ROOM),
+        };
+        (Arc::new(telemetry), receiver)
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        EngineStats {
+            xruns: self.xruns.load(Ordering::Relaxed),
+            clipping: self.clipping_at(Instant::now()),
+            idle: self.is_idle(),
+            mic_underruns: 0,
+        }
+    }
+
+    /// Whether the engine has been silent for `EngineConfig::idle_pause_timeout`.
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::Relaxed);
+    }
+
+    /// Room of the world the first listener stands in, as of the last control pass.
+    pub fn listener_room(&self) -> Option<RoomId> {
+        match self.listener_room.load(Ordering::Relaxed) {
+            NO_ROOM => None,
+            index => Some(RoomId(index)),
+        }
+    }
+
+    /// Record the room of the first listener, publishing `EngineEvent::ListenerRoomChanged`
+    /// when it changed.
+    pub(crate) fn set_listener_room(&self, room: Option<RoomId>) {
+        let index = room.map_or(NO_ROOM, |room| room.0);
+        if self.listener_room.swap(index, Ordering::Relaxed) != index {
+            self.emit(EngineEvent::ListenerRoomChanged { room });
+        }
+    }
+
+    /// Whether the output is paused, see `SpatialHandle::pause`.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Latest output levels, updated once per device buffer.
+    pub fn levels(&self) -> OutputLevels {
+        let level = |index: usize| f32::from_bits(self.levels[index].load(Ordering::Relaxed));
+        OutputLevels {
+            rms: (level(0), level(1)),
+            peak: (level(2), level(3)),
+        }
+    }
+
+    pub(crate) fn set_levels(&self, levels: OutputLevels) {
+        let values = [levels.rms.0, levels.rms.1, levels.peak.0, levels.peak.1];
+        for (level, value) in self.levels.iter().zip(values) {
+            level.store(value.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_xrun(&self) {
+        self.xruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_clip(&self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.start).as_nanos() as u64;
+        self.last_clip.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn clipping_at(&self, now: Instant) -> bool {
+        let last = self.last_clip.load(Ordering::Relaxed);
+        if last == u64::MAX {
+            return false;
+        }
+        let since = now
+            .saturating_duration_since(self.start)
+            .saturating_sub(Duration::from_nanos(last));
+        since < self.clip_hold
+    }
+
+    /// Publish an event, dropping it if nobody keeps up with them.
+    pub fn emit(&self, event: EngineEvent) {
+        let _ = self.events.try_send(event);
+    }
+}
+
+/// Flags probable underruns from the time between two output callbacks: the previous buffer
+/// ran dry when the next callback comes later than the audio it carried lasts.
+pub(crate) struct XrunDetector {
+    sample_rate: f64,
+    last: Option<(Instant, f64)>,
+}
+
+impl XrunDetector {
+    pub fn new(sample_rate: f64) -> Self {
+        XrunDetector {
+            sample_rate,
+            last: None,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.last = None;
+    }
+
+    /// Record a callback at `now` asking for `frames`, returning whether it came too late.
+    pub fn callback(&mut self, now: Instant, frames: usize) -> bool {
+        let duration = frames as f64 / self.sample_rate;
+        let late = match self.last {
+            Some((last, previous)) => {
+                now.duration_since(last).as_secs_f64() > previous * (1.0 + XRUN_MARGIN)
+            }
+            None => false,
+        };
+        self.last = Some((now, duration));
+        late
+    }
+}
+
+/// Smooths the levels of each output buffer into meter readings, on the audio thread.
+pub(crate) struct LevelMeter {
+    sample_rate: f64,
+    mean_square: (f32, f32),
+    peak: (f32, f32),
+}
+
+impl LevelMeter {
+    pub fn new(sample_rate: f64) -> Self {
+        LevelMeter {
+            sample_rate,
+            mean_square: (0.0, 0.0),
+            peak: (0.0, 0.0),
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Fold a buffer of `frames` frames, with the given (left, right) sums of squares and
+    /// peaks, into the readings.
+    pub fn buffer(
+        &mut self,
+        frames: usize,
+        squares: (f32, f32),
+        peaks: (f32, f32),
+    ) -> OutputLevels {
+        if frames == 0 {
+            return self.levels();
+        }
+        let duration = frames as f64 / self.sample_rate;
+        let rms_weight = 1.0 - (-duration / METER_RMS_TIME).exp() as f32;
+        let release = (-duration / METER_PEAK_RELEASE).exp() as f32;
+        let mean_square = |previous: f32, squares: f32| {
+            previous + (squares / frames as f32 - previous) * rms_weight
+        };
+        self.mean_square = (
+            mean_square(self.mean_square.0, squares.0),
+            mean_square(self.mean_square.1, squares.1),
+        );
+        self.peak = (
+            peaks.0.max(self.peak.0 * release),
+            peaks.1.max(self.peak.1 * release),
+        );
+        self.levels()
+    }
+
+    fn levels(&self) -> OutputLevels {
+        OutputLevels {
+            rms: (self.mean_square.0.sqrt(), self.mean_square.1.sqrt()),
+            peak: self.peak,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn latency_adds_buffers_queue_and_control() {
+        // 256 frames at 48 kHz last 5.33 ms, a 2400 frame queue 50 ms.
+        let latency = Latency::estimate(48000.0, Some(256), Some(2400), Duration::from_millis(5));
+        assert!((latency.input_ms - 16.0 / 3.0).abs() < 1e-9);
+        assert!((latency.output_ms - 16.0 / 3.0).abs() < 1e-9);
+        assert!((latency.queue_ms - 50.0).abs() < 1e-9);
+        assert!((latency.total_ms() - (32.0 / 3.0 + 55.0)).abs() < 1e-9);
+        // Without a mic, only the output and the control period.
+        let latency = Latency::estimate(48000.0, None, None, Duration::from_millis(5));
+        assert_eq!((latency.input_ms, latency.queue_ms), (0.0, 0.0));
+        assert!((latency.total_ms() - (1024.0 / 48.0 + 5.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn clip_indicator_holds() {
+        let (telemetry, _events) = Telemetry::new(Duration::from_millis(500));
+        let start = Instant::now();
+        assert!(!telemetry.clipping_at(start));
+        telemetry.record_clip(start);
+        assert!(telemetry.clipping_at(start + Duration::from_millis(400)));
+        assert!(!telemetry.clipping_at(start + Duration::from_millis(600)));
+    }
+
+    #[test]
+    fn late_callbacks_are_xruns() {
+        // 512 frames at 48 kHz last 10.67 ms.
+        let mut detector = XrunDetector::new(48000.0);
+        let start = Instant::now();
+        assert!(!detector.callback(start, 512));
+        assert!(!detector.callback(start + Duration::from_millis(11), 512));
+        assert!(!detector.callback(start + Duration::from_millis(22), 512));
+        assert!(detector.callback(start + Duration::from_millis(45), 512));
+        assert!(!detector.callback(start + Duration::from_millis(56), 512));
+    }
+
+    #[test]
+    fn meter_reads_sine_levels_and_falls_back() {
+        let mut meter = LevelMeter::new(48000.0);
+        let sine = |i: usize| 0.5 * (i as f32 * 1000.0 / 48000.0 * std::f32::consts::TAU).sin();
+        let mut levels = OutputLevels::default();
+        for block in 0..200 {
+            let (mut squares, mut peak) = (0.0, 0.0_f32);
+            for i in block * 512..(block + 1) * 512 {
+                squares += sine(i) * sine(i);
+                peak = peak.max(sine(i).abs());
+            }
+            // Right channel silent.
+            levels = meter.buffer(512, (squares, 0.0), (peak, 0.0));
+        }
+        let expected = 0.5 / 2.0_f32.sqrt();
+        assert!(
+            (levels.rms.0 - expected).abs() < expected * 0.02,
+            "{levels:?}"
+        );
+        assert!((levels.peak.0 - 0.5).abs() < 0.01, "{levels:?}");
+        assert_eq!((levels.rms.1, levels.peak.1), (0.0, 0.0));
+
+        let held = meter.buffer(512, (0.0, 0.0), (0.0, 0.0));
+        assert!(held.peak.0 > 0.45 && held.rms.0 > 0.3, "{held:?}");
+        for _ in 0..200 {
+            levels = meter.buffer(512, (0.0, 0.0), (0.0, 0.0));
+        }
+        assert!(levels.peak.0 < 0.01 && levels.rms.0 < 0.01, "{levels:?}");
+    }
+}